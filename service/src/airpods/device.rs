@@ -5,13 +5,13 @@
 
 use core::fmt;
 use std::{
-   collections::HashMap,
+   collections::{HashMap, VecDeque},
    mem,
    sync::{
       Arc, Weak,
       atomic::{AtomicBool, Ordering},
    },
-   time::Duration,
+   time::{Duration, Instant},
 };
 
 use bluer::Address;
@@ -20,35 +20,57 @@ use log::{debug, error, info, warn};
 use serde_json::json;
 use smol_str::{SmolStr, ToSmolStr};
 use tokio::{
-   sync::{RwLock, oneshot},
+   sync::{Notify, RwLock, oneshot},
    task::{JoinHandle, JoinSet},
    time,
 };
 
 use crate::{
    airpods::{
+      model::{self, AirPodsModel, FeatureSet},
       parser,
       protocol::{
-         BatteryInfo, EarDetectionStatus, FeatureBitmap, FeatureCmd, FeatureId, HDR_ACK_FEATURES,
-         HDR_ACK_HANDSHAKE, HDR_BATTERY_STATE, HDR_EAR_DETECTION, HDR_METADATA, HDR_NOISE_CTL,
-         NoiseControlMode, PKT_HANDSHAKE, PKT_REQUEST_NOTIFY, PKT_SET_FEATURES,
-         build_control_packet,
+         AapSettings, BatteryInfo, Component, EarDetectionStatus, FeatureBitmap, FeatureCmd,
+         FeatureId, HDR_ACK_FEATURES, HDR_ACK_HANDSHAKE, HDR_ADAPTIVE_LEVEL, HDR_BATTERY_STATE,
+         HDR_EAR_DETECTION, HDR_LISTENING_MODES, HDR_METADATA, HDR_NOISE_CTL, HDR_STEM_PRESS,
+         HoldDuration, LidState, NoiseControlMode, PKT_HANDSHAKE, PKT_REQUEST_NOTIFY,
+         PKT_SET_FEATURES, PressSetting, PressSpeed, SerialNumbers, build_control_packet,
       },
+      recognition,
+      signal::SignalMonitor,
+      summary,
    },
    battery_study::{BatteryStudy, BatteryTracker},
-   bluetooth::l2cap::{self, L2CapReceiver, L2CapSender, Packet},
+   bluetooth::{
+      connection::{ConnectionInput, ConnectionState},
+      l2cap::{self, L2CapReceiver, L2CapSender, LinkInfo, Packet},
+   },
    error::{AirPodsError, Result},
    event::{AirPodsEvent, EventSender},
 };
 
+/// Number of received packets kept for support bundles
+const PACKET_CAPTURE_LEN: usize = 64;
+/// How long to wait for the settings bundle after requesting it
+const SETTINGS_TIMEOUT: Duration = Duration::from_secs(2);
+/// Waits before each re-sent notification request while no battery arrives
+const NOTIFY_RETRY_DELAYS: &[Duration] = &[
+   Duration::from_secs(1),
+   Duration::from_secs(2),
+   Duration::from_secs(3),
+   Duration::from_secs(5),
+];
+/// How long a reinitialized device gets to answer
+const REINITIALIZE_TIMEOUT: Duration = Duration::from_secs(5);
+
 /// Internal state for an active L2CAP connection.
 #[derive(Debug)]
-struct ConnectionState {
+struct ActiveConnection {
    sender: l2cap::L2CapSender,
    jset: JoinSet<()>,
 }
 
-impl Drop for ConnectionState {
+impl Drop for ActiveConnection {
    fn drop(&mut self) {
       self.jset.abort_all();
    }
@@ -60,14 +82,55 @@ struct AirPodsInner {
    address: Address,
    address_str: SmolStr,
    name: parking_lot::Mutex<SmolStr>,
+   serial_numbers: parking_lot::Mutex<SerialNumbers>,
    battery: AtomicCell<Option<BatteryInfo>>,
+   battery_received: AtomicCell<Option<Instant>>,
+   /// Last battery state sent out as an event, and when
+   battery_emitted: AtomicCell<Option<(BatteryInfo, Instant)>>,
+   battery_throttle: AtomicCell<BatteryThrottle>,
+   /// When each component last appeared in a battery report, by [`Component::index`]
+   component_received: AtomicCell<[Option<Instant>; 4]>,
    is_connected: AtomicBool,
+   connection_state: AtomicCell<ConnectionState>,
+   resume_pending: AtomicBool,
    ear_detection: AtomicCell<Option<EarDetectionStatus>>,
+   primary_pod: AtomicCell<Option<Component>>,
+   link: AtomicCell<Option<LinkInfo>>,
+   lid_state: AtomicCell<Option<LidState>>,
+   rssi: AtomicCell<Option<i16>>,
+   /// Minimum RSSI and debounce window for case peeks, `None` when disabled
+   peek_detection: AtomicCell<Option<(i16, Duration)>>,
+   last_peek: AtomicCell<Option<Instant>>,
+   /// Poor signal detection, `None` when disabled
+   signal_monitor: parking_lot::Mutex<Option<SignalMonitor>>,
+   model: AtomicCell<AirPodsModel>,
+   noise_button_modes: AtomicCell<Option<u32>>,
    noise_mode: AtomicCell<Option<NoiseControlMode>>,
+   adaptive_level: AtomicCell<Option<u8>>,
+   press_speed: AtomicCell<Option<PressSpeed>>,
+   hold_duration: AtomicCell<Option<HoldDuration>>,
+   noise_mode_queue_timeout: AtomicCell<Duration>,
+   queued_noise_mode: AtomicCell<Option<(NoiseControlMode, Instant)>>,
    features: FeatureBitmap,
    features_present: FeatureBitmap,
-   conn: RwLock<Option<ConnectionState>>,
+   /// Woken when a settings bundle was received
+   settings_received: Notify,
+   /// Woken when any packet was received
+   packet_received: Notify,
+   conn: RwLock<Option<ActiveConnection>>,
    battery_tracker: parking_lot::Mutex<BatteryTracker>,
+   packet_capture: parking_lot::Mutex<VecDeque<(Instant, Packet)>>,
+}
+
+/// Limits how often small battery changes are sent out as events.
+///
+/// The default sends every change.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BatteryThrottle {
+   /// Level change, in points, that is sent out right away
+   pub min_change: u8,
+   /// Time after which smaller changes are sent out too, never if zero
+   pub interval: Duration,
 }
 
 /// Represents a connected `AirPods` device.
@@ -159,6 +222,11 @@ impl AirPods {
       self.0.name.lock().clone()
    }
 
+   /// Returns the serial numbers from the device metadata, if reported yet.
+   pub fn serial_numbers(&self) -> SerialNumbers {
+      self.0.serial_numbers.lock().clone()
+   }
+
    /// Updates the name of the Airpod.
    pub fn update_name(&self, name: SmolStr) -> UpdateOp<SmolStr> {
       let mut lock = self.0.name.lock();
@@ -181,11 +249,85 @@ impl AirPods {
       UpdateOp::apply_atomic(&self.0.battery, battery.into())
    }
 
+   /// Sets how often small battery changes are sent out as events.
+   pub fn set_battery_throttle(&self, throttle: BatteryThrottle) {
+      self.0.battery_throttle.store(throttle);
+   }
+
+   /// Whether a battery state is worth an event, compared to the last one sent.
+   ///
+   /// Status changes and level changes of at least the throttle's
+   /// `min_change` always are; smaller ones once its interval has passed.
+   fn battery_event_due(&self, battery: BatteryInfo, now: Instant) -> bool {
+      let Some((last, at)) = self.0.battery_emitted.load() else {
+         return true;
+      };
+      let throttle = self.0.battery_throttle.load();
+      last.differs_by(&battery, throttle.min_change)
+         || (last != battery
+            && !throttle.interval.is_zero()
+            && now.duration_since(at) >= throttle.interval)
+   }
+
+   fn emit_battery(&self, battery: BatteryInfo, now: Instant, event_tx: &EventSender) {
+      self.0.battery_emitted.store(Some((battery, now)));
+      event_tx.emit(self, AirPodsEvent::BatteryUpdated(battery));
+   }
+
+   /// Gets how long ago the last battery report arrived.
+   pub fn battery_age(&self) -> Option<Duration> {
+      self.0.battery_received.load().map(|at| at.elapsed())
+   }
+
+   /// Gets how long ago a component last appeared in a battery report.
+   pub fn component_battery_age(&self, component: Component) -> Option<Duration> {
+      self.0.component_received.load()[component.index()].map(|at| at.elapsed())
+   }
+
    /// Checks if the Airpod is connected.
    pub fn is_connected(&self) -> bool {
       self.0.is_connected.load(Ordering::Relaxed)
    }
 
+   /// Gets the state of the AAP session.
+   pub fn connection_state(&self) -> ConnectionState {
+      self.0.connection_state.load()
+   }
+
+   /// Advances the AAP session state, emitting an event if it changed.
+   ///
+   /// Returns whether the input was valid in the current state.
+   pub fn advance_connection(&self, input: ConnectionInput, event_tx: &EventSender) -> bool {
+      match self
+         .0
+         .connection_state
+         .fetch_update(|state| state.next(input))
+      {
+         Ok(previous) => {
+            let state = self.connection_state();
+            if state != previous {
+               debug!("{}: Connection {previous} -> {state}", self.address());
+               event_tx.emit(self, AirPodsEvent::ConnectionStateChanged(state));
+            }
+            true
+         },
+         Err(state) => {
+            debug!("{}: Ignoring {input:?} while {state}", self.address());
+            false
+         },
+      }
+   }
+
+   /// Checks if a resume is scheduled for this device but hasn't fired yet.
+   pub fn is_resume_pending(&self) -> bool {
+      self.0.resume_pending.load(Ordering::Relaxed)
+   }
+
+   /// Sets whether a resume is pending, returning whether it changed.
+   pub fn update_resume_pending(&self, pending: bool) -> bool {
+      self.0.resume_pending.swap(pending, Ordering::Relaxed) != pending
+   }
+
    /// Gets the ear detection status of the Airpod.
    pub fn ear_detection(&self) -> Option<EarDetectionStatus> {
       self.0.ear_detection.load()
@@ -199,6 +341,177 @@ impl AirPods {
       UpdateOp::apply_atomic(&self.0.ear_detection, status.into())
    }
 
+   /// Gets the bud currently acting as primary, if known.
+   pub fn primary_pod(&self) -> Option<Component> {
+      self.0.primary_pod.load()
+   }
+
+   /// Sets the bud currently acting as primary.
+   pub fn update_primary_pod(&self, primary: impl Into<Option<Component>>) -> UpdateOp<Component> {
+      UpdateOp::apply_atomic(&self.0.primary_pod, primary.into())
+   }
+
+   /// Gets the parameters of the current L2CAP channel, if connected.
+   pub fn link_info(&self) -> Option<LinkInfo> {
+      self.0.link.load()
+   }
+
+   /// Gets the model of the Airpod.
+   pub fn model(&self) -> AirPodsModel {
+      self.0.model.load()
+   }
+
+   /// Sets the model of the Airpod.
+   pub fn set_model(&self, model: AirPodsModel) {
+      self.0.model.store(model);
+   }
+
+   /// Sets the model once it is identified after connecting.
+   ///
+   /// Battery reports received meanwhile were taken as is, so they are
+   /// reinterpreted for the model, e.g. dropping the phantom case of the Max.
+   /// Returns whether the model was still unknown.
+   pub fn identify_model(&self, model: AirPodsModel, event_tx: &EventSender) -> bool {
+      if model == AirPodsModel::Unknown
+         || self
+            .0
+            .model
+            .compare_exchange(AirPodsModel::Unknown, model)
+            .is_err()
+      {
+         return false;
+      }
+      info!("Identified {} as {model}", self.address());
+      if let Some(battery) = self.battery_info() {
+         let battery = model.normalize_battery(battery);
+         if self.update_battery_info(battery).is_updated() {
+            self.emit_battery(battery, Instant::now(), event_tx);
+         }
+      }
+      true
+   }
+
+   /// Gets the modes cycled by the noise control button, if reported.
+   pub fn noise_button_modes(&self) -> Option<Vec<NoiseControlMode>> {
+      self
+         .0
+         .noise_button_modes
+         .load()
+         .map(model::decode_listening_modes)
+   }
+
+   /// Gets the case lid state, if advertised.
+   pub fn lid_state(&self) -> Option<LidState> {
+      self.0.lid_state.load()
+   }
+
+   /// Sets the case lid state.
+   pub fn update_lid_state(&self, lid: impl Into<Option<LidState>>) -> UpdateOp<LidState> {
+      UpdateOp::apply_atomic(&self.0.lid_state, lid.into())
+   }
+
+   /// Gets the signal strength of the last BLE advertisement, in dBm.
+   pub fn rssi(&self) -> Option<i16> {
+      self.0.rssi.load()
+   }
+
+   /// Sets the advertisement signal strength.
+   pub fn update_rssi(&self, rssi: Option<i16>) {
+      self.0.rssi.store(rssi);
+   }
+
+   /// Enables warnings about sustained poor signal.
+   pub fn set_signal_monitor(&self, monitor: SignalMonitor) {
+      *self.0.signal_monitor.lock() = Some(monitor);
+   }
+
+   /// Records a new signal strength reading, emitting `PoorSignal` once it
+   /// stayed weak for long enough.
+   pub fn process_rssi(&self, rssi: i16, event_tx: &EventSender) {
+      self.update_rssi(Some(rssi));
+      let poor = self
+         .0
+         .signal_monitor
+         .lock()
+         .as_mut()
+         .is_some_and(|monitor| monitor.update(rssi, Instant::now()));
+      if poor {
+         info!(address:% = self.address(), rssi; "Signal of {} is poor ({rssi} dBm)", self.address());
+         event_tx.emit(self, AirPodsEvent::PoorSignal(rssi));
+      }
+   }
+
+   /// Enables case peek detection for lid openings at or above `min_rssi`,
+   /// ignoring peeks within `debounce` of the previous one.
+   pub fn set_peek_detection(&self, min_rssi: i16, debounce: Duration) {
+      self.0.peek_detection.store(Some((min_rssi, debounce)));
+   }
+
+   /// Checks whether a lid opening now counts as a peek, and records it if so.
+   fn take_peek(&self, now: Instant) -> bool {
+      let Some((min_rssi, debounce)) = self.0.peek_detection.load() else {
+         return false;
+      };
+      if self.rssi().is_none_or(|rssi| rssi < min_rssi) {
+         debug!("Case lid for {} opened out of range", self.address());
+         return false;
+      }
+      if let Some(last) = self.0.last_peek.load()
+         && now.duration_since(last) < debounce
+      {
+         return false;
+      }
+      self.0.last_peek.store(Some(now));
+      true
+   }
+
+   /// Handles Apple manufacturer data from a BLE advertisement.
+   pub fn process_advertisement(&self, data: &[u8], event_tx: &EventSender) {
+      if self.model() == AirPodsModel::Unknown
+         && let Some(model) = recognition::model_from_advertisement(data)
+      {
+         self.identify_model(model, event_tx);
+      }
+      let Some(lid) = parser::parse_lid_state(data) else {
+         return;
+      };
+      let op = self.update_lid_state(lid);
+      if op.is_updated() {
+         debug!("Case lid for {} is now {lid}", self.address());
+         // The case level may have become stale (or fresh again)
+         if let Some(battery) = self.battery_info() {
+            self.emit_battery(battery, Instant::now(), event_tx);
+         }
+      }
+      // Only a closed lid opening is a peek, not the first advertisement seen
+      if matches!(op, UpdateOp::Updated(LidState::Open))
+         && let Some(battery) = self.battery_info()
+         && self.take_peek(Instant::now())
+      {
+         info!("Case opened next to {}", self.address());
+         event_tx.emit(self, AirPodsEvent::CasePeeked(battery));
+      }
+   }
+
+   /// Gets the most recently received packets, oldest first, with their age.
+   pub fn recent_packets(&self) -> Vec<(Duration, Packet)> {
+      self
+         .0
+         .packet_capture
+         .lock()
+         .iter()
+         .map(|(at, packet)| (at.elapsed(), packet.clone()))
+         .collect()
+   }
+
+   fn capture_packet(&self, packet: &Packet) {
+      let mut capture = self.0.packet_capture.lock();
+      if capture.len() == PACKET_CAPTURE_LEN {
+         capture.pop_front();
+      }
+      capture.push_back((Instant::now(), packet.clone()));
+   }
+
    /// Gets the noise control mode of the Airpod.
    pub fn noise_mode(&self) -> Option<NoiseControlMode> {
       self.0.noise_mode.load()
@@ -212,16 +525,108 @@ impl AirPods {
       UpdateOp::apply_atomic(&self.0.noise_mode, mode.into())
    }
 
+   /// Gets the adaptive noise level from 0 to 100, if reported.
+   pub fn adaptive_level(&self) -> Option<u8> {
+      self.0.adaptive_level.load()
+   }
+
+   /// Sets the adaptive noise level.
+   pub fn update_adaptive_level(&self, level: impl Into<Option<u8>>) -> UpdateOp<u8> {
+      UpdateOp::apply_atomic(&self.0.adaptive_level, level.into())
+   }
+
+   /// Gets the stem press speed, if reported.
+   pub fn press_speed(&self) -> Option<PressSpeed> {
+      self.0.press_speed.load()
+   }
+
+   /// Gets the stem press-and-hold duration, if reported.
+   pub fn hold_duration(&self) -> Option<HoldDuration> {
+      self.0.hold_duration.load()
+   }
+
+   /// Stores a press setting, returning whether it changed.
+   fn store_press_setting(&self, setting: PressSetting) -> bool {
+      match setting {
+         PressSetting::Speed(speed) => self.0.press_speed.swap(Some(speed)) != Some(speed),
+         PressSetting::Hold(duration) => {
+            self.0.hold_duration.swap(Some(duration)) != Some(duration)
+         },
+      }
+   }
+
+   /// Formats the device state as one line with the configured template.
+   pub fn status_summary(&self) -> String {
+      summary::render(&summary::template(), |field| self.summary_field(field))
+   }
+
+   /// Gets the value of a [`summary`] placeholder, if known.
+   pub fn summary_field(&self, field: &str) -> Option<String> {
+      let level = |component| {
+         self
+            .battery_info()
+            .map(|battery| battery.get(component))
+            .filter(|state| state.is_available())
+            .map(|state| state.level.to_string())
+      };
+      match field {
+         "name" => Some(self.name().to_string()),
+         "model" => Some(self.model().to_string()),
+         "left" => level(Component::Left),
+         "right" => level(Component::Right),
+         "case" => level(Component::Case),
+         "battery" => level(Component::Headphone),
+         "noise_mode" => self.noise_mode().map(|mode| mode.label().to_string()),
+         "adaptive_level" => self.adaptive_level().map(|level| level.to_string()),
+         "connection_state" => Some(self.connection_state().to_string()),
+         _ => None,
+      }
+   }
+
    /// Converts the device state to a JSON representation.
    pub fn to_json(&self) -> serde_json::Value {
       let mut info = json!({
           "address": self.address_str().as_str(),
           "name": self.name().as_str(),
           "connected": self.is_connected(),
+          "connection_state": self.connection_state(),
+          "resume_pending": self.is_resume_pending(),
+          "model": self.model(),
+          "capabilities": self.model().capabilities_json(),
       });
 
+      if let Some(age) = self.battery_age() {
+         info["battery_age_secs"] = json!(age.as_secs());
+      }
       if let Some(battery) = self.battery_info() {
          info["battery"] = battery.to_json();
+         if battery.is_case_stale(self.lid_state()) {
+            info["battery"]["case"]["stale"] = json!(true);
+         }
+         for (key, component) in [
+            ("left", Component::Left),
+            ("right", Component::Right),
+            ("case", Component::Case),
+            ("headphone", Component::Headphone),
+         ] {
+            if let (true, Some(age)) = (
+               battery.get(component).is_available(),
+               self.component_battery_age(component),
+            ) {
+               info["battery"][key]["age_secs"] = json!(age.as_secs());
+            }
+         }
+      }
+
+      if let Some(rssi) = self.rssi() {
+         info["rssi"] = json!(rssi);
+      }
+      if let Some(lid) = self.lid_state() {
+         info["lid"] = json!(lid);
+      }
+
+      if let Some(link) = self.link_info() {
+         info["link"] = json!(link);
       }
 
       // Add battery TTL estimate
@@ -234,8 +639,35 @@ impl AirPods {
          info["noise_mode"] = json!(mode.to_str());
       }
 
+      if let Some(level) = self.adaptive_level() {
+         info["adaptive_level"] = json!(level);
+      }
+
+      if let Some(speed) = self.press_speed() {
+         info["press_speed"] = json!(speed);
+      }
+      if let Some(duration) = self.hold_duration() {
+         info["hold_duration"] = json!(duration);
+      }
+      if self.model().has_adaptive_eq() {
+         info["adaptive_eq"] = json!(true);
+      }
+
       if let Some(ear) = self.ear_detection() {
          info["ear_detection"] = ear.to_json();
+         info["single_bud_active"] = json!(ear.is_single_bud());
+      }
+
+      if let Some(modes) = self.noise_button_modes() {
+         let modes: Vec<_> = modes.into_iter().map(NoiseControlMode::to_str).collect();
+         info["noise_button_modes"] = json!(modes);
+      }
+
+      if let Some(primary) = self.primary_pod() {
+         info["primary_pod"] = json!(match primary {
+            Component::Right => "right",
+            _ => "left",
+         });
       }
 
       let features_dict: HashMap<_, _> = self
@@ -265,11 +697,89 @@ impl AirPods {
       self.0.features.set(feature, enabled)
    }
 
+   /// Lists the features of the device's model.
+   pub fn supported_features(&self) -> FeatureSet {
+      self.model().supported_features()
+   }
+
+   /// Gets the settings reported by the device so far.
+   pub fn settings(&self) -> AapSettings {
+      AapSettings {
+         noise_mode: self.noise_mode(),
+         noise_button_modes: self.noise_button_modes(),
+         adaptive_level: self.adaptive_level(),
+         press_speed: self.press_speed(),
+         hold_duration: self.hold_duration(),
+         adaptive_eq: self.model().has_adaptive_eq().then_some(true),
+         features: self.features(),
+      }
+   }
+
+   /// Requests the current settings from the device and returns them.
+   ///
+   /// Settings are pushed in response to a notification request, usually
+   /// as one bundle. If none arrives in time, the settings reported so far
+   /// are returned.
+   pub async fn get_settings(&self) -> Result<AapSettings> {
+      let received = self.0.settings_received.notified();
+      self.refresh_battery().await?;
+      if time::timeout(SETTINGS_TIMEOUT, received).await.is_err() {
+         debug!(
+            "No settings bundle from {}, using known settings",
+            self.address()
+         );
+      }
+      Ok(self.settings())
+   }
+
+   /// Stores a settings bundle, emitting a single event if anything changed.
+   fn apply_settings(&self, settings: AapSettings, event_tx: &EventSender) {
+      let mut changed = false;
+      if let Some(mask) = settings
+         .noise_button_modes
+         .as_deref()
+         .map(model::encode_listening_modes)
+      {
+         changed |= self.0.noise_button_modes.swap(Some(mask)) != Some(mask);
+      }
+      if let Some(level) = settings.adaptive_level
+         && self.model().has_adaptive_anc()
+      {
+         changed |= self.update_adaptive_level(level).is_updated();
+      }
+      if self.model().has_press_settings() {
+         for setting in [
+            settings.press_speed.map(PressSetting::Speed),
+            settings.hold_duration.map(PressSetting::Hold),
+         ]
+         .into_iter()
+         .flatten()
+         {
+            changed |= self.store_press_setting(setting);
+         }
+      }
+      for (feature, enabled) in settings.features {
+         let present = self.0.features_present.get(feature);
+         changed |= self.set_feature_enabled(feature, enabled) != enabled || !present;
+      }
+
+      // The noise mode event refreshes the device properties as well
+      let mode = settings.noise_mode;
+      if let Some(mode) = mode
+         && self.update_noise_mode(mode).is_updated()
+      {
+         event_tx.emit(self, AirPodsEvent::NoiseControlChanged(mode));
+      } else if changed {
+         event_tx.emit(self, AirPodsEvent::SettingsChanged);
+      }
+      self.0.settings_received.notify_waiters();
+   }
+
    /// Establishes an L2CAP connection to the `AirPods` device.
    ///
    /// Returns a join handle that resolves when the connection is closed.
    pub async fn connect(&self, event_tx: &EventSender) -> Result<JoinHandle<Option<AirPodsError>>> {
-      info!("Connecting to AirPods at {}", self.address());
+      info!(address:% = self.address(); "Connecting to AirPods at {}", self.address());
       let mut conn = self.0.conn.write().await;
       let _ = conn.take();
 
@@ -277,13 +787,13 @@ impl AirPods {
       let mut jset = JoinSet::new();
 
       // Perform handshake
-      let (receiver, sender) = self.start_connection(&mut jset).await?;
+      let (receiver, sender) = self.start_connection(&mut jset, event_tx).await?;
 
       // Start packet processor with direct access to fields
       let jhandle = self.start_packet_processor(receiver, event_tx.clone());
 
       // Store connection state
-      *conn = Some(ConnectionState { sender, jset });
+      *conn = Some(ActiveConnection { sender, jset });
       self.0.is_connected.store(true, Ordering::Relaxed);
 
       // Initialize battery study session
@@ -293,32 +803,33 @@ impl AirPods {
          .lock()
          .init_session(self.address(), &self.name());
 
-      info!("Successfully connected to {}", self.address());
+      info!(address:% = self.address(); "Successfully connected to {}", self.address());
       Ok(jhandle)
    }
 
+   /// Releases the AAP session.
+   ///
+   /// Cached state is kept until [`Self::clear_session_state`], so a session
+   /// that recovers quickly picks up where it left off.
    pub async fn disconnect(&self) {
       // Save battery study data before disconnecting
       self.save_battery_study();
 
       self.0.is_connected.store(false, Ordering::Relaxed);
+      self.0.link.store(None);
       let _ = self.0.conn.write().await.take();
-      info!("Disconnected from {}", self.address());
+      info!(address:% = self.address(); "Disconnected from {}", self.address());
    }
 
-   async fn notify_disconnected(&self, event_tx: &EventSender) {
-      // Save battery study data before disconnecting
-      self.save_battery_study();
-
-      self.0.is_connected.store(false, Ordering::Relaxed);
-      let _ = self.0.conn.write().await.take();
-      info!("Disconnected from {}", self.address());
-      event_tx.emit(self, AirPodsEvent::DeviceDisconnected);
+   /// Forgets state that is only reported during a session.
+   pub fn clear_session_state(&self) {
+      self.0.adaptive_level.store(None);
    }
 
    async fn start_connection(
       &self,
       jset: &mut JoinSet<()>,
+      event_tx: &EventSender,
    ) -> Result<(L2CapReceiver, L2CapSender)> {
       async fn wait_for_ack<T>(tx: &mut oneshot::Receiver<T>) -> Result<T> {
          time::timeout(Duration::from_secs(5), tx)
@@ -327,6 +838,7 @@ impl AirPods {
             .map_err(|_| AirPodsError::ConnectionClosed)
       }
 
+      let opened = Instant::now();
       let (hs_ack_tx, mut hs_ack_rx) = oneshot::channel();
       let (feat_ack_tx, mut feat_ack_rx) = oneshot::channel();
 
@@ -339,66 +851,89 @@ impl AirPods {
          });
 
       let (receiver, sender) = l2cap::connect(jset, hooks, self.address(), None).await?;
-      info!("Starting handshake sequence...");
+      self.0.link.store(Some(receiver.link_info()));
+      self.advance_connection(ConnectionInput::ChannelOpened, event_tx);
+      info!(address:% = self.address(); "Starting handshake sequence...");
 
       // Send handshake
       if let Err(e) = sender.send(PKT_HANDSHAKE).await {
-         error!("Failed to send handshake: {e:?}");
+         error!(address:% = self.address(); "Failed to send handshake: {e:?}");
          return Err(e);
       } else if let Err(e) = wait_for_ack(&mut hs_ack_rx).await {
-         warn!("No handshake acknowledgment received ({e:?}), continuing anyway...");
+         warn!(address:% = self.address(); "No handshake acknowledgment received ({e:?}), continuing anyway...");
       } else {
-         info!("Handshake acknowledged");
+         info!(address:% = self.address(); "Handshake acknowledged");
       }
 
       // Send features
       if let Err(e) = sender.send(PKT_SET_FEATURES).await {
-         error!("Failed to send features: {e:?}");
+         error!(address:% = self.address(); "Failed to send features: {e:?}");
          return Err(e);
       } else if let Err(e) = wait_for_ack(&mut feat_ack_rx).await {
-         warn!("No features acknowledgment received ({e:?}), continuing anyway...");
+         warn!(address:% = self.address(); "No features acknowledgment received ({e:?}), continuing anyway...");
       } else {
-         info!("Features acknowledged");
+         info!(address:% = self.address(); "Features acknowledged");
       }
 
       // Request notifications
       if let Err(e) = sender.send(PKT_REQUEST_NOTIFY).await {
-         error!("Failed to send notification request: {e:?}");
+         error!(address:% = self.address(); "Failed to send notification request: {e:?}");
          return Err(e);
       }
 
-      // Schedule retry for notifications with battery status check
-      let weak = WeakAirPods::new(self);
+      // Ask for the ear detection setting in case it isn't among the
+      // settings pushed after the request
+
+      let query = FeatureCmd::Query.build(FeatureId::AUTOMATIC_EAR_DETECTION.id());
+      if let Err(e) = sender.send(&query).await {
+         debug!(address:% = self.address(); "Failed to query automatic ear detection: {e:?}");
+      }
+
+      // Some models ignore the request right after a reconnect
       let mac = self.address();
-      info!("{mac}: Handshake sequence completed");
-      jset.spawn({
-            let sender = sender.clone();
-            async move {
-                const RETRY_SCHEDULE: &[Duration] = &[
-                    Duration::from_secs(2),
-                    Duration::from_secs(3),
-                    Duration::from_secs(5),
-                    Duration::from_secs(10),
-                ];
-
-                time::sleep(Duration::from_secs(1)).await;
-                for (i, delay) in RETRY_SCHEDULE.iter().enumerate() {
-                    if let Some(this) = weak.upgrade()
-                        && this.battery_info().is_some() {
-                            info!("{mac}: Battery status established after {i} retries!");
-                            return;
-                        }
-                    warn!(
-                        "{mac}: [Retry {i}] No battery status received after notification request, retrying in {delay:?}..."
-                    );
-                    let _ = sender.send(PKT_REQUEST_NOTIFY).await;
-                    time::sleep(*delay).await;
-                }
-            }
-        });
+      info!(address:% = mac; "{mac}: Handshake sequence completed");
+      jset.spawn(Self::retry_notification_request(
+         WeakAirPods::new(self),
+         sender.clone(),
+         opened,
+         NOTIFY_RETRY_DELAYS,
+      ));
       Ok((receiver, sender))
    }
 
+   /// Re-sends the notification request until a battery report arrives.
+   ///
+   /// Battery state outlives a session, so only a report received `since`
+   /// the connection opened counts; otherwise every reconnect after the
+   /// first would stop retrying at once.
+   async fn retry_notification_request(
+      weak: WeakAirPods,
+      sender: L2CapSender,
+      since: Instant,
+      delays: &[Duration],
+   ) {
+      for (i, delay) in delays.iter().enumerate() {
+         time::sleep(*delay).await;
+         let Some(this) = weak.upgrade() else {
+            return;
+         };
+         let mac = this.address();
+         if this.battery_received_since(since) {
+            info!("{mac}: Battery status established after {i} retries!");
+            return;
+         }
+         warn!(
+            "{mac}: [Retry {i}] No battery status received after notification request, retrying..."
+         );
+         let _ = sender.send(PKT_REQUEST_NOTIFY).await;
+      }
+   }
+
+   /// Whether a battery report arrived at or after `since`.
+   fn battery_received_since(&self, since: Instant) -> bool {
+      self.0.battery_received.load().is_some_and(|at| at >= since)
+   }
+
    fn start_packet_processor(
       &self,
       mut rx: l2cap::L2CapReceiver,
@@ -419,8 +954,9 @@ impl AirPods {
                   }
                },
                Err(e) => {
+                  // The manager reports the disconnect unless the session recovers
                   if let Some(this) = weak.upgrade() {
-                     this.notify_disconnected(&event_tx).await;
+                     this.disconnect().await;
                   } else {
                      warn!("{addr}: Connection closed: {e:?}");
                   }
@@ -433,6 +969,10 @@ impl AirPods {
       })
    }
 
+   /// Sets the noise control mode.
+   ///
+   /// While disconnected, the mode is queued for the next connection if a
+   /// queue timeout is set, replacing any mode queued before.
    pub async fn set_noise_control(&self, mode: NoiseControlMode) -> Result<()> {
       let conn = self.0.conn.read().await;
       if let Some(conn) = conn.as_ref() {
@@ -440,11 +980,110 @@ impl AirPods {
          conn.sender.send(&packet).await?;
          self.0.noise_mode.store(Some(mode));
          Ok(())
+      } else if self.queue_noise_mode(mode, Instant::now()) {
+         info!(
+            "{}: Not connected, queued noise mode {mode}",
+            self.address()
+         );
+         Ok(())
       } else {
          Err(AirPodsError::DeviceNotConnected)
       }
    }
 
+   /// Sets how long a noise control mode requested while disconnected is kept.
+   pub fn set_noise_mode_queue_timeout(&self, timeout: Duration) {
+      self.0.noise_mode_queue_timeout.store(timeout);
+   }
+
+   /// Queues a mode for the next connection. Returns whether queueing is enabled.
+   fn queue_noise_mode(&self, mode: NoiseControlMode, now: Instant) -> bool {
+      let timeout = self.0.noise_mode_queue_timeout.load();
+      if timeout.is_zero() {
+         return false;
+      }
+      self.0.queued_noise_mode.store(Some((mode, now + timeout)));
+      true
+   }
+
+   /// Takes the queued noise control mode, unless it expired by `now`.
+   fn take_queued_noise_mode(&self, now: Instant) -> Option<NoiseControlMode> {
+      match self.0.queued_noise_mode.take()? {
+         (mode, expiry) if now < expiry => Some(mode),
+         (mode, _) => {
+            debug!(
+               "{}: Dropping expired queued noise mode {mode}",
+               self.address()
+            );
+            None
+         },
+      }
+   }
+
+   /// Applies a noise control mode queued while disconnected, if any.
+   /// Returns whether one was queued.
+   pub async fn apply_queued_noise_mode(&self, event_tx: &EventSender) -> bool {
+      let Some(mode) = self.take_queued_noise_mode(Instant::now()) else {
+         return false;
+      };
+      match self.set_noise_control(mode).await {
+         Ok(()) => {
+            info!("{}: Applied queued noise mode {mode}", self.address());
+            event_tx.emit(self, AirPodsEvent::QueuedNoiseControlApplied(mode));
+         },
+         Err(e) => warn!(
+            "{}: Failed to apply queued noise mode {mode}: {e}",
+            self.address()
+         ),
+      }
+      true
+   }
+
+   /// Prompts the device for a fresh battery report.
+   ///
+   /// AAP has no battery query; battery state is only pushed. Re-sending the
+   /// notification request makes the device push its current state, which
+   /// arrives as a regular battery update.
+   pub async fn refresh_battery(&self) -> Result<()> {
+      let conn = self.0.conn.read().await;
+      let Some(conn) = conn.as_ref() else {
+         return Err(AirPodsError::DeviceNotConnected);
+      };
+      conn.sender.send(PKT_REQUEST_NOTIFY).await
+   }
+
+   /// Re-runs the handshake on the open connection, emitting `Reinitialized`.
+   ///
+   /// Meant for when the device stopped reporting although the link is up;
+   /// cheaper than reconnecting. Succeeds once the device answers.
+   pub async fn reinitialize(&self, event_tx: &EventSender) -> Result<()> {
+      let result = self.resend_handshake().await;
+      match &result {
+         Ok(()) => info!(address:% = self.address(); "Reinitialized {}", self.address()),
+         Err(e) => {
+            warn!(address:% = self.address(); "Failed to reinitialize {}: {e}", self.address())
+         },
+      }
+      event_tx.emit(self, AirPodsEvent::Reinitialized(result.is_ok()));
+      result
+   }
+
+   async fn resend_handshake(&self) -> Result<()> {
+      let answered = self.0.packet_received.notified();
+      {
+         let conn = self.0.conn.read().await;
+         let Some(conn) = conn.as_ref() else {
+            return Err(AirPodsError::DeviceNotConnected);
+         };
+         for packet in [PKT_HANDSHAKE, PKT_SET_FEATURES, PKT_REQUEST_NOTIFY] {
+            conn.sender.send(packet).await?;
+         }
+      }
+      time::timeout(REINITIALIZE_TIMEOUT, answered)
+         .await
+         .map_err(|_| AirPodsError::RequestTimeout)
+   }
+
    pub async fn passthrough(&self, packet: &[u8]) -> Result<()> {
       let conn = self.0.conn.read().await;
       if let Some(conn) = conn.as_ref() {
@@ -455,7 +1094,54 @@ impl AirPods {
       }
    }
 
+   /// Sets the modes the noise control button cycles through.
+   pub async fn set_noise_button_modes(&self, modes: &[NoiseControlMode]) -> Result<()> {
+      if !self.model().has_noise_control_button() {
+         return Err(AirPodsError::FeatureNotSupported(format!(
+            "noise control button on {}",
+            self.model()
+         )));
+      }
+      let conn = self.0.conn.read().await;
+      let Some(conn) = conn.as_ref() else {
+         return Err(AirPodsError::DeviceNotConnected);
+      };
+      let mask = model::encode_listening_modes(modes);
+      let packet = build_control_packet(FeatureId::LISTENING_MODE_CONFIGS.id(), mask.to_le_bytes());
+      conn.sender.send(&packet).await?;
+      self.0.noise_button_modes.store(Some(mask));
+      Ok(())
+   }
+
+   /// Sets the stem press speed or hold duration.
+   pub async fn set_press_setting(&self, setting: PressSetting) -> Result<()> {
+      if !self.model().has_press_settings() {
+         return Err(AirPodsError::FeatureNotSupported(format!(
+            "press settings on {}",
+            self.model()
+         )));
+      }
+      let conn = self.0.conn.read().await;
+      let Some(conn) = conn.as_ref() else {
+         return Err(AirPodsError::DeviceNotConnected);
+      };
+      conn.sender.send(&setting.build()).await?;
+      self.store_press_setting(setting);
+      Ok(())
+   }
+
    pub async fn set_feature(&self, feature: FeatureId, enabled: bool) -> Result<()> {
+      let model = self.model();
+      let supported = match feature {
+         FeatureId::CROWN_ROTATION_DIRECTION => model.has_crown(),
+         FeatureId::AUTOMATIC_EAR_DETECTION => model.has_automatic_ear_detection(),
+         _ => true,
+      };
+      if !supported {
+         return Err(AirPodsError::FeatureNotSupported(format!(
+            "{feature} on {model}"
+         )));
+      }
       let conn = self.0.conn.read().await;
       if let Some(conn) = conn.as_ref() {
          let packet = if enabled {
@@ -471,27 +1157,118 @@ impl AirPods {
       }
    }
 
+   /// Turns the ear detection done by the buds themselves on or off.
+   ///
+   /// This is the device setting; auto-pause is ours and stays as configured,
+   /// though it has nothing to act on while the buds don't detect ears.
+   pub async fn set_automatic_ear_detection(&self, enabled: bool) -> Result<()> {
+      self
+         .set_feature(FeatureId::AUTOMATIC_EAR_DETECTION, enabled)
+         .await
+   }
+
+   /// The device's ear detection setting, once it has been reported.
+   pub fn automatic_ear_detection(&self) -> Option<bool> {
+      let feature = FeatureId::AUTOMATIC_EAR_DETECTION;
+      self
+         .0
+         .features_present
+         .get(feature)
+         .then(|| self.feature_enabled(feature))
+   }
+
+   /// Applies a synthetic event as if the matching packet was received.
+   ///
+   /// Returns whether the device state changed, in which case the event was emitted.
+   #[cfg(any(debug_assertions, feature = "event-injection"))]
+   pub fn inject_event(&self, event: AirPodsEvent, event_tx: &EventSender) -> Result<bool> {
+      let updated = match &event {
+         AirPodsEvent::BatteryUpdated(battery) => {
+            let battery = self.model().normalize_battery(*battery);
+            let now = Instant::now();
+            self.0.battery_received.store(Some(now));
+            let mut received = self.0.component_received.load();
+            for component in Component::ALL {
+               if battery.get(component).is_available() {
+                  received[component.index()] = Some(now);
+               }
+            }
+            self.0.component_received.store(received);
+            self.update_battery_info(battery).is_updated()
+         },
+         AirPodsEvent::NoiseControlChanged(mode) => self.update_noise_mode(*mode).is_updated(),
+         AirPodsEvent::EarDetectionChanged(status) => {
+            self.update_ear_detection(*status).is_updated()
+         },
+         AirPodsEvent::AdaptiveLevelChanged(level) => {
+            self.update_adaptive_level(*level).is_updated()
+         },
+         AirPodsEvent::DeviceNameChanged(name) => self.update_name(name.clone()).is_updated(),
+         _ => {
+            return Err(AirPodsError::FeatureNotSupported(format!(
+               "injecting {event:?}"
+            )));
+         },
+      };
+      if updated {
+         event_tx.emit(self, event);
+      }
+      Ok(updated)
+   }
+
    fn process_packet(&self, address: Address, packet: Packet, event_tx: &EventSender) {
+      self.capture_packet(&packet);
+      self.0.packet_received.notify_waiters();
+
       // Battery status
       if packet.starts_with(HDR_BATTERY_STATE) {
+         // Track primary swaps so ear detection is attributed to the right bud
+         if let Some(primary) = parser::parse_primary_pod(&packet)
+            && self.update_primary_pod(primary).is_updated()
+         {
+            debug!("Primary bud for {address} is now {primary}");
+         }
+
          match parser::parse_battery_status(&packet) {
-            Ok(battery) => {
+            Ok(report) => {
+               // Components missing from the report keep their last known state
+               let merged = self.battery_info().unwrap_or_default().merge(&report);
+               let battery = self.model().normalize_battery(merged);
+               let now = Instant::now();
+               self.0.battery_received.store(Some(now));
+               let mut received = self.0.component_received.load();
+               for component in report.components() {
+                  received[component.index()] = Some(now);
+               }
+               self.0.component_received.store(received);
                debug!(
                   "Battery updated for {}: L:{}% R:{}% C:{}%",
                   address, battery.left.level, battery.right.level, battery.case.level
                );
 
-               // Send event if battery changed
                if self.update_battery_info(battery).is_updated() {
                   self
                      .0
                      .battery_tracker
                      .lock()
                      .record_battery_drop(battery.left, battery.right);
-                  event_tx.emit(self, AirPodsEvent::BatteryUpdated(battery));
                }
+               // Send an event if the battery changed enough since the last one
+               if self.battery_event_due(battery, now) {
+                  self.emit_battery(battery, now, event_tx);
+               }
+            },
+            Err(e) => warn!(address:% = address; "Failed to parse battery: {e}"),
+         }
+      }
+      // Settings bundle, sent in response to a notification request
+      else if parser::is_settings_bundle(&packet) {
+         match parser::parse_settings(&packet) {
+            Ok(settings) => {
+               debug!("Settings for {address}: {settings:?}");
+               self.apply_settings(settings, event_tx);
             },
-            Err(e) => warn!("Failed to parse battery: {e}"),
+            Err(e) => warn!(address:% = address; "Failed to parse settings: {e}"),
          }
       }
       // Noise control mode
@@ -503,12 +1280,13 @@ impl AirPods {
                   event_tx.emit(self, AirPodsEvent::NoiseControlChanged(mode));
                }
             },
-            Err(e) => warn!("Failed to parse noise mode: {e}"),
+            Err(e) => warn!(address:% = address; "Failed to parse noise mode: {e}"),
          }
       }
       // Ear detection
       else if packet.starts_with(HDR_EAR_DETECTION) {
-         match parser::parse_ear_detection(&packet) {
+         let primary = self.primary_pod().unwrap_or(Component::Left);
+         match parser::parse_ear_detection(&packet, primary) {
             Ok(status) => {
                debug!(
                   "Ear detection updated for {}: L:{} R:{}",
@@ -521,13 +1299,20 @@ impl AirPods {
                   event_tx.emit(self, AirPodsEvent::EarDetectionChanged(status));
                }
             },
-            Err(e) => warn!("Failed to parse ear detection: {e}"),
+            Err(e) => warn!(address:% = address; "Failed to parse ear detection: {e}"),
          }
       }
       // Metadata packets
       else if packet.starts_with(HDR_METADATA) {
          if let Ok(metadata) = parser::parse_metadata(&packet) {
-            debug!("Device metadata for {address}: {metadata:?}");
+            debug!(
+               "Device metadata for {address}: name {:?}",
+               metadata.name_candidate
+            );
+
+            if !metadata.serials.is_empty() {
+               *self.0.serial_numbers.lock() = metadata.serials;
+            }
 
             if let Some(new_name) = metadata.name_candidate
                && self.update_name(new_name.clone()).is_updated()
@@ -536,6 +1321,40 @@ impl AirPods {
             }
          }
       }
+      // Stem presses forwarded to us
+      else if packet.starts_with(HDR_STEM_PRESS) {
+         if let Some(press) = parser::parse_stem_press(&packet) {
+            debug!("Stem press on {address}: {press}");
+            event_tx.emit(self, AirPodsEvent::StemPressed(press));
+         }
+      }
+      // Noise control button cycle
+      else if let Some(mask) = packet.strip_prefix(HDR_LISTENING_MODES) {
+         if let Ok(mask) = <[u8; 4]>::try_from(mask) {
+            let mask = u32::from_le_bytes(mask);
+            debug!("Noise control button modes for {address}: {mask:#06b}");
+            self.0.noise_button_modes.store(Some(mask));
+         }
+      }
+      // Adaptive noise level, which would otherwise read as a feature command
+      else if packet.starts_with(HDR_ADAPTIVE_LEVEL) && self.model().has_adaptive_anc() {
+         match parser::parse_adaptive_level(&packet) {
+            Ok(level) => {
+               debug!("Adaptive noise level for {address}: {level}");
+               if self.update_adaptive_level(level).is_updated() {
+                  event_tx.emit(self, AirPodsEvent::AdaptiveLevelChanged(level));
+               }
+            },
+            Err(e) => warn!(address:% = address; "Failed to parse adaptive level: {e}"),
+         }
+      }
+      // Stem press settings, sent with the other settings on connect
+      else if let Some(setting) = parser::parse_press_setting(&packet)
+         && self.model().has_press_settings()
+      {
+         debug!("Press setting for {address}: {setting:?}");
+         self.store_press_setting(setting);
+      }
       // Other packets
       else if packet.starts_with(HDR_ACK_HANDSHAKE) {
          debug!("Received handshake ACK from {address}");
@@ -635,3 +1454,697 @@ impl AirPods {
       }
    }
 }
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::{
+      airpods::protocol::{BatteryStatus, EarState, StemPress},
+      ear_detection::{EarDetector, MediaAction},
+      event::EventBus,
+   };
+
+   const TEST_ADDRESS: Address = Address([0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF]);
+
+   #[derive(Default)]
+   struct RecordingBus(parking_lot::Mutex<Vec<AirPodsEvent>>);
+
+   impl EventBus for RecordingBus {
+      fn emit(&self, _device: &AirPods, event: AirPodsEvent) {
+         self.0.lock().push(event);
+      }
+   }
+
+   fn battery_packet(primary: Component, secondary: Component) -> Packet {
+      let mut packet = Packet::from_slice(HDR_BATTERY_STATE);
+      packet.push(3);
+      for component in [primary, secondary, Component::Case] {
+         packet.extend_from_slice(&[
+            component as u8,
+            0x01,
+            80,
+            BatteryStatus::Discharging as u8,
+            0x01,
+         ]);
+      }
+      packet
+   }
+
+   fn ear_packet(primary_in_ear: bool, secondary_in_ear: bool) -> Packet {
+      let mut packet = Packet::from_slice(HDR_EAR_DETECTION);
+      packet.extend_from_slice(&[u8::from(!primary_in_ear), u8::from(!secondary_in_ear)]);
+      packet
+   }
+
+   /// Feeds packets through the device and the ear detector, collecting ear events and actions.
+   fn run_sequence(
+      packets: impl IntoIterator<Item = Packet>,
+   ) -> (AirPods, Vec<EarDetectionStatus>, Vec<MediaAction>) {
+      let bus = Arc::new(RecordingBus::default());
+      let event_tx: EventSender = bus.clone();
+      let airpods = AirPods::new(TEST_ADDRESS, "Test AirPods".to_string(), None);
+      let mut detector = EarDetector::new(Duration::ZERO);
+
+      let mut statuses = Vec::new();
+      let mut actions = Vec::new();
+      for packet in packets {
+         airpods.process_packet(TEST_ADDRESS, packet, &event_tx);
+         for event in bus.0.lock().drain(..) {
+            if let AirPodsEvent::EarDetectionChanged(status) = event {
+               statuses.push(status);
+               actions.extend(detector.update(status, Instant::now()));
+            }
+         }
+      }
+      (airpods, statuses, actions)
+   }
+
+   #[test]
+   fn test_primary_swap_in_ear_does_not_pause() {
+      let (airpods, statuses, actions) = run_sequence([
+         battery_packet(Component::Left, Component::Right),
+         ear_packet(true, true),
+         battery_packet(Component::Right, Component::Left),
+         ear_packet(true, true),
+         battery_packet(Component::Left, Component::Right),
+         ear_packet(true, true),
+      ]);
+
+      assert_eq!(airpods.primary_pod(), Some(Component::Left));
+      assert_eq!(statuses, [EarDetectionStatus::new(true, true)]);
+      assert!(actions.is_empty());
+   }
+
+   #[test]
+   fn test_both_buds_into_case_pauses() {
+      let mut both_in_case = Packet::from_slice(HDR_EAR_DETECTION);
+      both_in_case.extend_from_slice(&[0x02, 0x02]);
+      let (_, statuses, actions) = run_sequence([
+         battery_packet(Component::Left, Component::Right),
+         ear_packet(true, true),
+         both_in_case,
+      ]);
+
+      assert_eq!(
+         statuses,
+         [
+            EarDetectionStatus::new(true, true),
+            EarDetectionStatus::from_states(EarState::InCase, EarState::InCase),
+         ]
+      );
+      assert_eq!(actions, [MediaAction::PauseInCase]);
+   }
+
+   fn lid_advertisement(closed: bool) -> [u8; 9] {
+      [
+         0x07,
+         0x19,
+         0x01,
+         0x0e,
+         0x20,
+         0x2b,
+         0x88,
+         0x8f,
+         if closed { 0x09 } else { 0x01 },
+      ]
+   }
+
+   #[test]
+   fn test_open_lid_marks_case_battery_stale() {
+      let bus = Arc::new(RecordingBus::default());
+      let event_tx: EventSender = bus.clone();
+      let airpods = AirPods::new(TEST_ADDRESS, "Test AirPods".to_string(), None);
+      airpods.process_packet(
+         TEST_ADDRESS,
+         battery_packet(Component::Left, Component::Right),
+         &event_tx,
+      );
+      assert!(airpods.to_json()["battery"]["case"].get("stale").is_none());
+
+      airpods.process_advertisement(&lid_advertisement(false), &event_tx);
+      let info = airpods.to_json();
+      assert_eq!(info["lid"], "open");
+      assert_eq!(info["battery"]["case"]["stale"], true);
+      assert_eq!(info["battery"]["case"]["level"], 80);
+
+      airpods.process_advertisement(&lid_advertisement(true), &event_tx);
+      let info = airpods.to_json();
+      assert_eq!(info["lid"], "closed");
+      assert!(info["battery"]["case"].get("stale").is_none());
+
+      // Each lid change re-announces the battery so clients refresh the case level
+      let battery_events = bus
+         .0
+         .lock()
+         .iter()
+         .filter(|e| matches!(e, AirPodsEvent::BatteryUpdated(_)))
+         .count();
+      assert_eq!(battery_events, 3);
+   }
+
+   #[test]
+   fn test_case_peek_requires_range_and_is_debounced() {
+      let bus = Arc::new(RecordingBus::default());
+      let event_tx: EventSender = bus.clone();
+      let airpods = AirPods::new(TEST_ADDRESS, "Test AirPods".to_string(), None);
+      airpods.set_peek_detection(-60, Duration::from_secs(30));
+      airpods.process_packet(
+         TEST_ADDRESS,
+         battery_packet(Component::Left, Component::Right),
+         &event_tx,
+      );
+      let peeks = || {
+         bus.0
+            .lock()
+            .iter()
+            .filter(|e| matches!(e, AirPodsEvent::CasePeeked(_)))
+            .count()
+      };
+      let open_lid = || {
+         airpods.process_advertisement(&lid_advertisement(true), &event_tx);
+         airpods.process_advertisement(&lid_advertisement(false), &event_tx);
+      };
+
+      // The first advertisement seen isn't an opening
+      airpods.update_rssi(Some(-40));
+      airpods.process_advertisement(&lid_advertisement(false), &event_tx);
+      assert_eq!(peeks(), 0);
+
+      airpods.update_rssi(Some(-75));
+      open_lid();
+      assert_eq!(peeks(), 0);
+
+      airpods.update_rssi(Some(-40));
+      open_lid();
+      open_lid();
+      assert_eq!(peeks(), 1);
+   }
+
+   fn partial_battery_packet(components: &[(Component, u8)]) -> Packet {
+      let mut packet = Packet::from_slice(HDR_BATTERY_STATE);
+      packet.push(components.len() as u8);
+      for &(component, level) in components {
+         packet.extend_from_slice(&[
+            component as u8,
+            0x01,
+            level,
+            BatteryStatus::Discharging as u8,
+            0x01,
+         ]);
+      }
+      packet
+   }
+
+   #[test]
+   fn test_partial_battery_reports_are_merged() {
+      let bus = Arc::new(RecordingBus::default());
+      let event_tx: EventSender = bus.clone();
+      let airpods = AirPods::new(TEST_ADDRESS, "Test AirPods".to_string(), None);
+      airpods.process_packet(
+         TEST_ADDRESS,
+         battery_packet(Component::Left, Component::Right),
+         &event_tx,
+      );
+
+      // Buds only: the case keeps its level
+      airpods.process_packet(
+         TEST_ADDRESS,
+         partial_battery_packet(&[(Component::Left, 70), (Component::Right, 60)]),
+         &event_tx,
+      );
+      let battery = airpods.battery_info().unwrap();
+      assert_eq!((battery.left.level, battery.right.level), (70, 60));
+      assert!(battery.case.is_available());
+      assert_eq!(battery.case.level, 80);
+
+      // Case only: the buds keep theirs
+      airpods.process_packet(
+         TEST_ADDRESS,
+         partial_battery_packet(&[(Component::Case, 50)]),
+         &event_tx,
+      );
+      let battery = airpods.battery_info().unwrap();
+      assert_eq!((battery.left.level, battery.right.level), (70, 60));
+      assert_eq!(battery.case.level, 50);
+
+      let info = airpods.to_json();
+      assert_eq!(info["battery"]["left"]["age_secs"], 0);
+      assert_eq!(info["battery"]["case"]["age_secs"], 0);
+      assert!(
+         airpods
+            .component_battery_age(Component::Headphone)
+            .is_none()
+      );
+
+      // A component listed as disconnected is cleared
+      let mut packet = partial_battery_packet(&[(Component::Case, 0)]);
+      packet[10] = BatteryStatus::Disconnected as u8;
+      airpods.process_packet(TEST_ADDRESS, packet, &event_tx);
+      let battery = airpods.battery_info().unwrap();
+      assert!(!battery.case.is_available());
+      assert_eq!(battery.left.level, 70);
+   }
+
+   #[test]
+   fn test_battery_events_are_throttled() {
+      let bus = Arc::new(RecordingBus::default());
+      let event_tx: EventSender = bus.clone();
+      let airpods = AirPods::new(TEST_ADDRESS, "Test AirPods".to_string(), None);
+      airpods.set_battery_throttle(BatteryThrottle {
+         min_change: 5,
+         interval: Duration::from_secs(300),
+      });
+      let report = |level, status: BatteryStatus| {
+         let mut packet = partial_battery_packet(&[(Component::Left, level)]);
+         packet[10] = status as u8;
+         airpods.process_packet(TEST_ADDRESS, packet, &event_tx);
+      };
+
+      // Identical reports are sent out once
+      report(80, BatteryStatus::Discharging);
+      report(80, BatteryStatus::Discharging);
+      assert_eq!(bus.0.lock().len(), 1);
+
+      // Small drops only update the cache until they add up
+      report(78, BatteryStatus::Discharging);
+      assert_eq!(bus.0.lock().len(), 1);
+      assert_eq!(airpods.battery_info().unwrap().left.level, 78);
+      report(75, BatteryStatus::Discharging);
+      assert_eq!(bus.0.lock().len(), 2);
+
+      // Charging is sent out right away
+      report(75, BatteryStatus::Charging);
+      let events = bus.0.lock();
+      assert_eq!(events.len(), 3);
+      assert!(matches!(
+         events[2],
+         AirPodsEvent::BatteryUpdated(battery) if battery.left.is_charging()
+      ));
+   }
+
+   #[test]
+   fn test_battery_before_model_is_reinterpreted() {
+      let bus = Arc::new(RecordingBus::default());
+      let event_tx: EventSender = bus.clone();
+      let airpods = AirPods::new(TEST_ADDRESS, "Test AirPods".to_string(), None);
+
+      // Taken as is while the model is unknown
+      airpods.process_packet(
+         TEST_ADDRESS,
+         battery_packet(Component::Left, Component::Right),
+         &event_tx,
+      );
+      assert!(airpods.battery_info().unwrap().case.is_available());
+      bus.0.lock().clear();
+
+      assert!(airpods.identify_model(AirPodsModel::Max, &event_tx));
+      let battery = airpods.battery_info().unwrap();
+      assert!(!battery.case.is_available());
+      assert!(!battery.left.is_available());
+      assert_eq!(battery.headphone.level, 80);
+      assert_eq!(
+         bus.0.lock().as_slice(),
+         [AirPodsEvent::BatteryUpdated(battery)]
+      );
+
+      // Only the first identification counts
+      assert!(!airpods.identify_model(AirPodsModel::Pro2, &event_tx));
+      assert_eq!(airpods.model(), AirPodsModel::Max);
+   }
+
+   #[test]
+   fn test_battery_report_records_age() {
+      let bus = Arc::new(RecordingBus::default());
+      let event_tx: EventSender = bus.clone();
+      let airpods = AirPods::new(TEST_ADDRESS, "Test AirPods".to_string(), None);
+      assert!(airpods.battery_age().is_none());
+      assert!(airpods.to_json().get("battery_age_secs").is_none());
+
+      airpods.process_packet(
+         TEST_ADDRESS,
+         battery_packet(Component::Left, Component::Right),
+         &event_tx,
+      );
+      assert!(airpods.battery_age().unwrap() < Duration::from_secs(1));
+      assert_eq!(airpods.to_json()["battery_age_secs"], 0);
+   }
+
+   #[test]
+   fn test_packet_capture_is_bounded() {
+      let bus = Arc::new(RecordingBus::default());
+      let event_tx: EventSender = bus.clone();
+      let airpods = AirPods::new(TEST_ADDRESS, "Test AirPods".to_string(), None);
+
+      for i in 0..=PACKET_CAPTURE_LEN {
+         airpods.process_packet(
+            TEST_ADDRESS,
+            Packet::from_slice(&[0xFF, i as u8]),
+            &event_tx,
+         );
+      }
+      let packets = airpods.recent_packets();
+      assert_eq!(packets.len(), PACKET_CAPTURE_LEN);
+      assert_eq!(packets[0].1.as_slice(), &[0xFF, 1]);
+      assert_eq!(
+         packets.last().unwrap().1.as_slice(),
+         &[0xFF, PACKET_CAPTURE_LEN as u8]
+      );
+   }
+
+   #[test]
+   fn test_adaptive_level_is_parsed_for_capable_models() {
+      // Captured from an AirPods Pro 2 in adaptive mode
+      let packet = Packet::from_slice(&[
+         0x04, 0x00, 0x04, 0x00, 0x09, 0x00, 0x2E, 0x32, 0x00, 0x00, 0x00,
+      ]);
+      let bus = Arc::new(RecordingBus::default());
+      let event_tx: EventSender = bus.clone();
+      let airpods = AirPods::new(TEST_ADDRESS, "Test AirPods".to_string(), None);
+
+      // Without the capability the packet is left to the feature command parser
+      airpods.process_packet(TEST_ADDRESS, packet.clone(), &event_tx);
+      assert_eq!(airpods.adaptive_level(), None);
+      assert!(airpods.to_json().get("adaptive_level").is_none());
+
+      airpods.set_model(AirPodsModel::Pro2);
+      airpods.process_packet(TEST_ADDRESS, packet.clone(), &event_tx);
+      airpods.process_packet(TEST_ADDRESS, packet, &event_tx);
+      assert_eq!(airpods.adaptive_level(), Some(50));
+      assert_eq!(airpods.to_json()["adaptive_level"], 50);
+      assert!(matches!(
+         bus.0.lock().as_slice(),
+         [AirPodsEvent::AdaptiveLevelChanged(50)]
+      ));
+   }
+
+   #[test]
+   fn test_press_settings_are_read_for_capable_models() {
+      // Captured from an AirPods Pro 2 right after connecting
+      let speed = Packet::from_slice(&[
+         0x04, 0x00, 0x04, 0x00, 0x09, 0x00, 0x17, 0x01, 0x00, 0x00, 0x00,
+      ]);
+      let hold = Packet::from_slice(&[
+         0x04, 0x00, 0x04, 0x00, 0x09, 0x00, 0x18, 0x02, 0x00, 0x00, 0x00,
+      ]);
+      let bus = Arc::new(RecordingBus::default());
+      let event_tx: EventSender = bus.clone();
+      let airpods = AirPods::new(TEST_ADDRESS, "Test AirPods".to_string(), None);
+
+      airpods.process_packet(TEST_ADDRESS, speed.clone(), &event_tx);
+      assert_eq!(airpods.press_speed(), None);
+      assert!(airpods.to_json().get("press_speed").is_none());
+
+      airpods.set_model(AirPodsModel::Pro2);
+      let features = airpods.features();
+      airpods.process_packet(TEST_ADDRESS, speed, &event_tx);
+      airpods.process_packet(TEST_ADDRESS, hold, &event_tx);
+      assert_eq!(airpods.press_speed(), Some(PressSpeed::Slower));
+      assert_eq!(airpods.hold_duration(), Some(HoldDuration::Shortest));
+      assert_eq!(airpods.to_json()["press_speed"], "slower");
+      assert_eq!(airpods.to_json()["hold_duration"], "shortest");
+      // The values must not be mistaken for feature commands
+      assert_eq!(airpods.features(), features);
+   }
+
+   #[test]
+   fn test_settings_bundle_is_parsed_at_once() {
+      let mask =
+         model::encode_listening_modes(&[NoiseControlMode::Active, NoiseControlMode::Transparency]);
+      let mut packet = Packet::new();
+      for (feature, value) in [
+         (FeatureId::NOISE_CONTROL, NoiseControlMode::Active as u32),
+         (FeatureId::LISTENING_MODE_CONFIGS, mask),
+         (FeatureId::AUTO_ANC_STRENGTH, 50),
+         (FeatureId::DOUBLE_CLICK_INTERVAL, PressSpeed::Slower as u32),
+         (
+            FeatureId::CLICK_HOLD_INTERVAL,
+            HoldDuration::Shortest as u32,
+         ),
+         (FeatureId::CONVERSATIONAL, FeatureCmd::Enable as u32),
+         (FeatureId::ADAPTIVE_VOLUME, FeatureCmd::Disable as u32),
+         // Unknown values are skipped
+         (FeatureId::CHIME_VOLUME, 0x42),
+      ] {
+         packet.extend_from_slice(&build_control_packet(feature.id(), value.to_le_bytes()));
+      }
+
+      let expected = AapSettings {
+         noise_mode: Some(NoiseControlMode::Active),
+         noise_button_modes: Some(vec![
+            NoiseControlMode::Active,
+            NoiseControlMode::Transparency,
+         ]),
+         adaptive_level: Some(50),
+         press_speed: Some(PressSpeed::Slower),
+         hold_duration: Some(HoldDuration::Shortest),
+         adaptive_eq: None,
+         features: vec![
+            (FeatureId::CONVERSATIONAL, true),
+            (FeatureId::ADAPTIVE_VOLUME, false),
+         ],
+      };
+      let settings = parser::parse_settings(&packet).unwrap();
+      assert_eq!(settings, expected);
+      assert_eq!(settings.conversational_awareness(), Some(true));
+      assert!(parser::parse_settings(&packet[..packet.len() - 1]).is_err());
+
+      let bus = Arc::new(RecordingBus::default());
+      let event_tx: EventSender = bus.clone();
+      let airpods = AirPods::new(TEST_ADDRESS, "Test AirPods".to_string(), None);
+      airpods.set_model(AirPodsModel::Pro2);
+      airpods.process_packet(TEST_ADDRESS, packet.clone(), &event_tx);
+      airpods.process_packet(TEST_ADDRESS, packet, &event_tx);
+
+      // Features are listed in id order, adaptive EQ follows from the model
+      let mut expected = expected;
+      expected.features.reverse();
+      expected.adaptive_eq = Some(true);
+      assert_eq!(airpods.settings(), expected);
+      assert_eq!(airpods.to_json()["noise_mode"], "anc");
+      // One event for the whole bundle, none when nothing changed
+      assert_eq!(
+         bus.0.lock().as_slice(),
+         [AirPodsEvent::NoiseControlChanged(NoiseControlMode::Active)]
+      );
+   }
+
+   #[test]
+   fn test_stem_press_is_forwarded() {
+      let bus = Arc::new(RecordingBus::default());
+      let event_tx: EventSender = bus.clone();
+      let airpods = AirPods::new(TEST_ADDRESS, "Test AirPods".to_string(), None);
+
+      let mut packet = Packet::from_slice(HDR_STEM_PRESS);
+      packet.extend_from_slice(&[StemPress::Long as u8, 0x01]);
+      airpods.process_packet(TEST_ADDRESS, packet, &event_tx);
+      // Unknown press types are ignored
+      let mut packet = Packet::from_slice(HDR_STEM_PRESS);
+      packet.extend_from_slice(&[0x42, 0x01]);
+      airpods.process_packet(TEST_ADDRESS, packet, &event_tx);
+
+      assert_eq!(
+         bus.0.lock().as_slice(),
+         [AirPodsEvent::StemPressed(StemPress::Long)]
+      );
+   }
+
+   #[tokio::test]
+   async fn test_noise_mode_is_queued_while_disconnected() {
+      let airpods = AirPods::new(TEST_ADDRESS, "Test AirPods".to_string(), None);
+      assert!(
+         airpods
+            .set_noise_control(NoiseControlMode::Active)
+            .await
+            .is_err()
+      );
+
+      airpods.set_noise_mode_queue_timeout(Duration::from_secs(30));
+      airpods
+         .set_noise_control(NoiseControlMode::Active)
+         .await
+         .unwrap();
+      airpods
+         .set_noise_control(NoiseControlMode::Transparency)
+         .await
+         .unwrap();
+      // Only the last request is kept
+      let now = Instant::now();
+      assert_eq!(
+         airpods.take_queued_noise_mode(now),
+         Some(NoiseControlMode::Transparency)
+      );
+      assert_eq!(airpods.take_queued_noise_mode(now), None);
+
+      // An old request is dropped instead of applied late
+      airpods
+         .set_noise_control(NoiseControlMode::Off)
+         .await
+         .unwrap();
+      assert_eq!(
+         airpods.take_queued_noise_mode(now + Duration::from_secs(31)),
+         None
+      );
+   }
+
+   #[tokio::test]
+   async fn test_automatic_ear_detection_setting() {
+      let bus = Arc::new(RecordingBus::default());
+      let event_tx: EventSender = bus.clone();
+      let airpods = AirPods::new(TEST_ADDRESS, "Test AirPods".to_string(), None);
+      assert_eq!(airpods.automatic_ear_detection(), None);
+
+      // Reported as a plain feature command
+      let disabled = FeatureCmd::Disable.build(FeatureId::AUTOMATIC_EAR_DETECTION.id());
+      airpods.process_packet(TEST_ADDRESS, disabled, &event_tx);
+      assert_eq!(airpods.automatic_ear_detection(), Some(false));
+      assert_eq!(
+         airpods.to_json()["features"]["automatic_ear_detection"],
+         false
+      );
+
+      // Models without the setting are refused before anything is sent
+      airpods.set_model(AirPodsModel::Beats);
+      assert!(matches!(
+         airpods.set_automatic_ear_detection(true).await,
+         Err(AirPodsError::FeatureNotSupported(_))
+      ));
+      airpods.set_model(AirPodsModel::Pro2);
+      assert!(matches!(
+         airpods.set_automatic_ear_detection(true).await,
+         Err(AirPodsError::DeviceNotConnected)
+      ));
+   }
+
+   #[test]
+   fn test_connection_transitions_emit_events() {
+      let bus = Arc::new(RecordingBus::default());
+      let event_tx: EventSender = bus.clone();
+      let airpods = AirPods::new(TEST_ADDRESS, "Test AirPods".to_string(), None);
+
+      assert!(!airpods.advance_connection(ConnectionInput::HandshakeComplete, &event_tx));
+      for input in [
+         ConnectionInput::Start,
+         ConnectionInput::ChannelOpened,
+         ConnectionInput::HandshakeComplete,
+         ConnectionInput::Lost,
+         ConnectionInput::Lost,
+         ConnectionInput::Closed,
+      ] {
+         assert!(airpods.advance_connection(input, &event_tx));
+      }
+      assert_eq!(airpods.to_json()["connection_state"], "disconnected");
+
+      // Repeated inputs that keep the state don't emit again
+      let states: Vec<_> = bus
+         .0
+         .lock()
+         .iter()
+         .filter_map(|e| match e {
+            AirPodsEvent::ConnectionStateChanged(state) => Some(*state),
+            _ => None,
+         })
+         .collect();
+      assert_eq!(
+         states,
+         [
+            ConnectionState::Connecting,
+            ConnectionState::Handshaking,
+            ConnectionState::Connected,
+            ConnectionState::Reconnecting,
+            ConnectionState::Disconnected,
+         ]
+      );
+   }
+
+   #[test]
+   fn test_primary_swap_keeps_bud_attribution() {
+      let (airpods, statuses, _) = run_sequence([
+         battery_packet(Component::Left, Component::Right),
+         // Left (primary) in ear, right out
+         ear_packet(true, false),
+         battery_packet(Component::Right, Component::Left),
+         // Same physical state, now reported with the right bud first
+         ear_packet(false, true),
+      ]);
+
+      assert_eq!(airpods.primary_pod(), Some(Component::Right));
+      assert_eq!(statuses, [EarDetectionStatus::new(true, false)]);
+   }
+
+   #[test]
+   fn test_serial_numbers_from_metadata() {
+      let mut packet = Packet::from_slice(HDR_METADATA);
+      packet.extend_from_slice(b"\x00\x02\x01\x05");
+      // The case serial is left out
+      let fields = [
+         "Work AirPods",
+         "A2084",
+         "Apple Inc.",
+         "",
+         "6A300",
+         "6A300",
+         "1.0.0",
+         "com.apple.accessoryupdater",
+         "H2LLEFT",
+         "H2LRIGHT",
+      ];
+      for field in fields {
+         packet.extend_from_slice(field.as_bytes());
+         packet.push(0);
+      }
+      let (airpods, _, _) = run_sequence([packet]);
+
+      let serials = airpods.serial_numbers();
+      assert_eq!(serials.left.as_deref(), Some("H2LLEFT"));
+      assert_eq!(serials.right.as_deref(), Some("H2LRIGHT"));
+      assert_eq!(serials.case, None);
+   }
+
+   #[test]
+   fn test_single_bud_state() {
+      let (airpods, statuses, _) = run_sequence([
+         battery_packet(Component::Left, Component::Right),
+         ear_packet(true, true),
+         ear_packet(true, false),
+      ]);
+      assert!(!statuses[0].is_single_bud());
+      assert!(statuses[1].is_single_bud());
+      assert_eq!(airpods.to_json()["single_bud_active"], true);
+
+      // The other bud in the case still leaves one in an ear
+      let one_in_case = EarDetectionStatus::from_states(EarState::InCase, EarState::InEar);
+      assert!(one_in_case.is_single_bud());
+      let none_in_ear = EarDetectionStatus::from_states(EarState::InCase, EarState::OutOfEar);
+      assert!(!none_in_ear.is_single_bud());
+   }
+
+   #[tokio::test]
+   async fn test_notifications_are_requested_again_after_reconnect() {
+      let airpods = AirPods::new(TEST_ADDRESS, "Test AirPods".to_string(), None);
+      let event_tx: EventSender = Arc::new(RecordingBus::default());
+      let delays = &[Duration::from_millis(1); 3];
+      let session = async |since| {
+         let (sender, sent) = l2cap::recording_sender();
+         AirPods::retry_notification_request(WeakAirPods::new(&airpods), sender, since, delays)
+            .await;
+         let sent = sent.lock().clone();
+         sent
+            .iter()
+            .filter(|p| p.as_slice() == PKT_REQUEST_NOTIFY)
+            .count()
+      };
+
+      // No battery yet, so every retry asks again
+      assert_eq!(session(Instant::now()).await, 3);
+      let before_report = Instant::now();
+      airpods.process_packet(
+         TEST_ADDRESS,
+         battery_packet(Component::Left, Component::Right),
+         &event_tx,
+      );
+      // The battery from the last session doesn't count after a reconnect
+      assert_eq!(session(Instant::now()).await, 3);
+      // A report during this session stops the retries
+      assert_eq!(session(before_report).await, 0);
+   }
+}
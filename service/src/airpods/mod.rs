@@ -4,6 +4,9 @@
 //! device management, protocol parsing, and packet handling.
 
 pub mod device;
+pub mod model;
 pub mod parser;
 pub mod protocol;
 pub mod recognition;
+pub mod signal;
+pub mod summary;
@@ -0,0 +1,280 @@
+//! `AirPods` model identification and per-model capabilities.
+//!
+//! Models are identified from the Apple product ID. Behavior that differs
+//! between models (case battery, crown, noise control button) is queried
+//! through the capability methods on [`AirPodsModel`] rather than by matching
+//! on models throughout the code.
+
+use serde::Serialize;
+use serde_json::json;
+
+use crate::airpods::protocol::{BatteryInfo, BatteryState, NoiseControlMode};
+
+/// Known `AirPods` models.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, strum::Display)]
+pub enum AirPodsModel {
+   #[serde(rename = "AirPods 2")]
+   #[strum(serialize = "AirPods 2")]
+   Gen2,
+   #[serde(rename = "AirPods 3")]
+   #[strum(serialize = "AirPods 3")]
+   Gen3,
+   #[serde(rename = "AirPods Pro")]
+   #[strum(serialize = "AirPods Pro")]
+   Pro,
+   #[serde(rename = "AirPods Pro 2")]
+   #[strum(serialize = "AirPods Pro 2")]
+   Pro2,
+   #[serde(rename = "AirPods Max")]
+   #[strum(serialize = "AirPods Max")]
+   Max,
+   Beats,
+   #[default]
+   Unknown,
+}
+
+impl AirPodsModel {
+   /// Identifies the model from its Apple product ID.
+   pub const fn from_product_id(product_id: u32) -> Self {
+      match product_id {
+         0x200E => Self::Gen2,
+         0x200A => Self::Gen3,
+         0x2024 => Self::Pro,
+         0x2014 => Self::Pro2,
+         0x2013 => Self::Max,
+         0x2002 | 0x200F | 0x2012 => Self::Beats,
+         _ => Self::Unknown,
+      }
+   }
+
+   /// Whether the model comes with a charging case that reports its battery.
+   ///
+   /// The Max only has a smart case without a battery of its own.
+   pub const fn has_case_battery(self) -> bool {
+      !matches!(self, Self::Max)
+   }
+
+   /// Whether the model has a single battery instead of one per bud.
+   pub const fn has_single_battery(self) -> bool {
+      matches!(self, Self::Max)
+   }
+
+   /// Whether the model has a digital crown.
+   pub const fn has_crown(self) -> bool {
+      matches!(self, Self::Max)
+   }
+
+   /// Whether the buds' own ear detection can be turned off.
+   ///
+   /// With it off, the buds stop reporting ear state and neither they nor
+   /// our auto-pause react to taking them out.
+   pub const fn has_automatic_ear_detection(self) -> bool {
+      matches!(
+         self,
+         Self::Gen2 | Self::Gen3 | Self::Pro | Self::Pro2 | Self::Max
+      )
+   }
+
+   /// Whether the model has a dedicated noise control button whose cycle can be configured.
+   pub const fn has_noise_control_button(self) -> bool {
+      matches!(self, Self::Max)
+   }
+
+   /// Whether the model has force sensor stems with adjustable press speed and hold duration.
+   pub const fn has_press_settings(self) -> bool {
+      matches!(self, Self::Gen3 | Self::Pro | Self::Pro2)
+   }
+
+   /// Noise control modes the model can switch between, none without noise control.
+   pub fn noise_modes(self) -> Vec<NoiseControlMode> {
+      match self {
+         Self::Pro | Self::Max => vec![
+            NoiseControlMode::Off,
+            NoiseControlMode::Active,
+            NoiseControlMode::Transparency,
+         ],
+         Self::Pro2 => vec![
+            NoiseControlMode::Off,
+            NoiseControlMode::Active,
+            NoiseControlMode::Transparency,
+            NoiseControlMode::Adaptive,
+         ],
+         _ => vec![],
+      }
+   }
+
+   /// Whether the model lowers media and switches to transparency when the wearer speaks.
+   pub const fn has_conversational_awareness(self) -> bool {
+      matches!(self, Self::Pro2)
+   }
+
+   /// Whether the model has adaptive noise control and reports how much adaptation is applied.
+   pub const fn has_adaptive_anc(self) -> bool {
+      matches!(self, Self::Pro2)
+   }
+
+   /// Whether the model has adaptive EQ.
+   ///
+   /// It is always active on these models and can't be turned off, so AAP
+   /// neither reports nor accepts a setting for it.
+   pub const fn has_adaptive_eq(self) -> bool {
+      matches!(self, Self::Gen3 | Self::Pro | Self::Pro2 | Self::Max)
+   }
+
+   /// Adjusts a battery report to what the model actually has.
+   ///
+   /// Drops the phantom case battery of models without one and folds bud
+   /// levels into the headphone battery for single-battery models.
+   pub fn normalize_battery(self, mut battery: BatteryInfo) -> BatteryInfo {
+      if !self.has_case_battery() {
+         battery.case = BatteryState::new();
+      }
+      if self.has_single_battery() {
+         // Bud levels are the latest reading, the headphone one may be merged from before
+         if let Some(bud) = [battery.left, battery.right]
+            .into_iter()
+            .filter(|bud| bud.is_available())
+            .max_by_key(|bud| bud.level)
+         {
+            battery.headphone = bud;
+         }
+         battery.left = BatteryState::new();
+         battery.right = BatteryState::new();
+      }
+      battery
+   }
+
+   /// Lists what the model supports, so UIs can leave out controls that won't work.
+   ///
+   /// An unknown model supports nothing.
+   pub fn supported_features(self) -> FeatureSet {
+      FeatureSet {
+         model: self,
+         noise_modes: self.noise_modes(),
+         adaptive_anc: self.has_adaptive_anc(),
+         conversational_awareness: self.has_conversational_awareness(),
+         noise_control_button: self.has_noise_control_button(),
+         press_settings: self.has_press_settings(),
+         adaptive_eq: self.has_adaptive_eq(),
+         crown: self.has_crown(),
+         automatic_ear_detection: self.has_automatic_ear_detection(),
+      }
+   }
+
+   pub fn capabilities_json(self) -> serde_json::Value {
+      json!({
+         "case_battery": self.has_case_battery(),
+         "single_battery": self.has_single_battery(),
+         "crown": self.has_crown(),
+         "noise_control_button": self.has_noise_control_button(),
+         "adaptive_anc": self.has_adaptive_anc(),
+         "press_settings": self.has_press_settings(),
+         "adaptive_eq": self.has_adaptive_eq(),
+      })
+   }
+}
+
+/// Features supported by a model, see [`AirPodsModel::supported_features`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FeatureSet {
+   pub model: AirPodsModel,
+   pub noise_modes: Vec<NoiseControlMode>,
+   pub adaptive_anc: bool,
+   pub conversational_awareness: bool,
+   pub noise_control_button: bool,
+   pub press_settings: bool,
+   pub adaptive_eq: bool,
+   pub crown: bool,
+   pub automatic_ear_detection: bool,
+}
+
+impl FeatureSet {
+   pub fn to_json(&self) -> serde_json::Value {
+      let noise_modes: Vec<_> = self.noise_modes.iter().map(|mode| mode.to_str()).collect();
+      json!({
+         "model": self.model,
+         "noise_modes": noise_modes,
+         "adaptive_anc": self.adaptive_anc,
+         "conversational_awareness": self.conversational_awareness,
+         "noise_control_button": self.noise_control_button,
+         "press_settings": self.press_settings,
+         "adaptive_eq": self.adaptive_eq,
+         "crown": self.crown,
+         "automatic_ear_detection": self.automatic_ear_detection,
+      })
+   }
+}
+
+/// Encodes the modes cycled by the noise control button as a listening mode bitmask.
+pub fn encode_listening_modes(modes: &[NoiseControlMode]) -> u32 {
+   modes
+      .iter()
+      .fold(0, |mask, mode| mask | (1 << mode.index()))
+}
+
+/// Decodes a listening mode bitmask into the modes it enables.
+pub fn decode_listening_modes(mask: u32) -> Vec<NoiseControlMode> {
+   (0..u32::BITS as usize)
+      .filter(|i| mask & (1 << i) != 0)
+      .filter_map(NoiseControlMode::from_index)
+      .collect()
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::airpods::protocol::BatteryStatus;
+
+   const fn state(level: u8) -> BatteryState {
+      BatteryState {
+         level,
+         status: BatteryStatus::Discharging,
+      }
+   }
+
+   #[test]
+   fn test_max_has_no_case_battery() {
+      let mut battery = BatteryInfo::new();
+      battery.left = state(70);
+      battery.case = state(0);
+
+      let max = AirPodsModel::Max.normalize_battery(battery);
+      assert!(!max.case.is_available());
+      assert!(!max.left.is_available());
+      assert_eq!(max.headphone, state(70));
+
+      // Other models are left untouched
+      assert_eq!(AirPodsModel::Pro2.normalize_battery(battery), battery);
+   }
+
+   #[test]
+   fn test_listening_modes_roundtrip() {
+      let modes = [NoiseControlMode::Active, NoiseControlMode::Transparency];
+      let mask = encode_listening_modes(&modes);
+      assert_eq!(mask, 0b0110);
+      assert_eq!(decode_listening_modes(mask), modes);
+      assert_eq!(decode_listening_modes(0xF0), []);
+   }
+
+   #[test]
+   fn test_supported_features() {
+      let pro2 = AirPodsModel::Pro2.supported_features();
+      assert!(pro2.noise_modes.contains(&NoiseControlMode::Adaptive));
+      assert!(pro2.adaptive_anc && pro2.conversational_awareness);
+      assert_eq!(
+         AirPodsModel::Max.supported_features().to_json()["noise_modes"][1],
+         "anc"
+      );
+
+      // Nothing is offered until the model is known
+      let unknown = AirPodsModel::Unknown.supported_features();
+      assert!(unknown.noise_modes.is_empty());
+      assert!(!unknown.adaptive_eq && !unknown.press_settings && !unknown.crown);
+      assert!(!unknown.automatic_ear_detection);
+      assert!(
+         AirPodsModel::Gen2
+            .supported_features()
+            .automatic_ear_detection
+      );
+   }
+}
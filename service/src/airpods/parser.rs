@@ -9,9 +9,14 @@ use log::{debug, warn};
 use smol_str::SmolStr;
 
 use crate::{
-   airpods::protocol::{
-      BatteryInfo, BatteryState, BatteryStatus, Component, EarDetectionStatus, HDR_BATTERY_STATE,
-      HDR_EAR_DETECTION, HDR_METADATA, NoiseControlMode,
+   airpods::{
+      model,
+      protocol::{
+         AapSettings, BatteryInfo, BatteryReport, BatteryState, BatteryStatus, Component,
+         EarDetectionStatus, EarState, FeatureId, HDR_ADAPTIVE_LEVEL, HDR_BATTERY_STATE,
+         HDR_CMD_CTL, HDR_EAR_DETECTION, HDR_METADATA, HDR_STEM_PRESS, HoldDuration, LidState,
+         NoiseControlMode, PressSetting, PressSpeed, SerialNumbers, StemPress,
+      },
    },
    error::Result,
 };
@@ -53,8 +58,9 @@ pub enum ProtoError {
 /// Parses a battery status packet from `AirPods`.
 ///
 /// The packet format contains battery information for up to 3 components
-/// (left, right, case).
-pub fn parse_battery_status(data: &[u8]) -> Result<BatteryInfo> {
+/// (left, right, case). Components it doesn't list are reported as such, so
+/// that they can keep their last known state.
+pub fn parse_battery_status(data: &[u8]) -> Result<BatteryReport> {
    if !data.starts_with(HDR_BATTERY_STATE) {
       return Err(
          ProtoError::WrongPacketType {
@@ -105,6 +111,7 @@ pub fn parse_battery_status(data: &[u8]) -> Result<BatteryInfo> {
    }
 
    let mut battery_info = BatteryInfo::new();
+   let mut listed = 0;
 
    for i in 0..battery_count {
       let offset = 7 + (5 * i) as usize;
@@ -137,6 +144,7 @@ pub fn parse_battery_status(data: &[u8]) -> Result<BatteryInfo> {
       });
 
       debug!("Parsed component: {component} = {level}% ({bat_status})");
+      listed |= component as u8;
 
       if bat_status != BatteryStatus::Disconnected {
          let battery_state = BatteryState {
@@ -161,7 +169,10 @@ pub fn parse_battery_status(data: &[u8]) -> Result<BatteryInfo> {
       }
    }
    debug!("Battery parsed - {battery_info}");
-   Ok(battery_info)
+   Ok(BatteryReport {
+      battery: battery_info,
+      listed,
+   })
 }
 
 pub fn parse_noise_mode(data: &[u8]) -> Result<NoiseControlMode> {
@@ -182,7 +193,147 @@ pub fn parse_noise_mode(data: &[u8]) -> Result<NoiseControlMode> {
    Ok(mode)
 }
 
-pub fn parse_ear_detection(data: &[u8]) -> Result<EarDetectionStatus> {
+/// Parses the adaptive noise level reported while adaptive ANC is active.
+///
+/// The level is a little-endian value from 0 to 100 following the header.
+pub fn parse_adaptive_level(data: &[u8]) -> Result<u8> {
+   let Some(rest) = data.strip_prefix(HDR_ADAPTIVE_LEVEL) else {
+      return Err(
+         ProtoError::WrongPacketType {
+            expected: "adaptive level",
+         }
+         .into(),
+      );
+   };
+   let Ok(value) = <[u8; 4]>::try_from(rest) else {
+      return Err(
+         ProtoError::PacketSizeMismatch {
+            expected: HDR_ADAPTIVE_LEVEL.len() + 4,
+            actual: data.len(),
+         }
+         .into(),
+      );
+   };
+   match u8::try_from(u32::from_le_bytes(value)) {
+      Ok(level) if level <= 100 => Ok(level),
+      _ => Err(
+         ProtoError::InvalidFormat {
+            reason: "adaptive level out of range",
+         }
+         .into(),
+      ),
+   }
+}
+
+/// Parses a stem press speed or hold duration setting.
+///
+/// These share the control packet format with feature toggles, and their
+/// values overlap with the toggle commands, so they must be checked first.
+pub fn parse_press_setting(data: &[u8]) -> Option<PressSetting> {
+   let rest = data.strip_prefix(HDR_CMD_CTL)?;
+   let (&feature, value) = rest.split_first()?;
+   let value = u32::from_le_bytes(value.try_into().ok()?);
+   match FeatureId::from_id(feature) {
+      FeatureId::DOUBLE_CLICK_INTERVAL => PressSpeed::from_repr(value).map(PressSetting::Speed),
+      FeatureId::CLICK_HOLD_INTERVAL => HoldDuration::from_repr(value).map(PressSetting::Hold),
+      _ => None,
+   }
+}
+
+/// Parses a stem press forwarded by the buds.
+pub fn parse_stem_press(data: &[u8]) -> Option<StemPress> {
+   StemPress::from_repr(*data.strip_prefix(HDR_STEM_PRESS)?.first()?)
+}
+
+/// Length of a control packet: header, setting and a 4-byte value
+const CONTROL_PACKET_LEN: usize = HDR_CMD_CTL.len() + 5;
+
+/// Whether a packet holds several control packets, as the settings bundle does.
+pub fn is_settings_bundle(data: &[u8]) -> bool {
+   data.starts_with(HDR_CMD_CTL) && data.len() > CONTROL_PACKET_LEN
+}
+
+/// Parses the settings the device reports as consecutive control packets.
+///
+/// Settings that can't be interpreted are skipped, so one unknown value
+/// doesn't lose the rest of the bundle.
+pub fn parse_settings(data: &[u8]) -> Result<AapSettings> {
+   if !data.starts_with(HDR_CMD_CTL) {
+      return Err(
+         ProtoError::WrongPacketType {
+            expected: "settings",
+         }
+         .into(),
+      );
+   }
+   if !data.len().is_multiple_of(CONTROL_PACKET_LEN) {
+      return Err(
+         ProtoError::PacketSizeMismatch {
+            expected: data.len().next_multiple_of(CONTROL_PACKET_LEN),
+            actual: data.len(),
+         }
+         .into(),
+      );
+   }
+
+   let mut settings = AapSettings::default();
+   for packet in data.chunks_exact(CONTROL_PACKET_LEN) {
+      let Some((&feature, value)) = packet
+         .strip_prefix(HDR_CMD_CTL)
+         .and_then(<[u8]>::split_first)
+      else {
+         return Err(
+            ProtoError::InvalidFormat {
+               reason: "settings entry is not a control packet",
+            }
+            .into(),
+         );
+      };
+      let feature = FeatureId::from_id(feature);
+      let value = u32::from_le_bytes(value.try_into().unwrap_or_default());
+      match feature {
+         FeatureId::NOISE_CONTROL => settings.noise_mode = NoiseControlMode::from_repr(value),
+         FeatureId::LISTENING_MODE_CONFIGS => {
+            settings.noise_button_modes = Some(model::decode_listening_modes(value));
+         },
+         FeatureId::AUTO_ANC_STRENGTH => {
+            settings.adaptive_level = u8::try_from(value).ok().filter(|level| *level <= 100);
+         },
+         FeatureId::DOUBLE_CLICK_INTERVAL | FeatureId::CLICK_HOLD_INTERVAL => {
+            match parse_press_setting(packet) {
+               Some(PressSetting::Speed(speed)) => settings.press_speed = Some(speed),
+               Some(PressSetting::Hold(duration)) => settings.hold_duration = Some(duration),
+               None => debug!("Unknown {feature} value in settings: {value}"),
+            }
+         },
+         _ => match value {
+            1 | 2 => settings.features.push((feature, value == 1)),
+            _ => debug!("Unknown {feature} value in settings: {value}"),
+         },
+      }
+   }
+   Ok(settings)
+}
+
+/// Determines the primary bud from a battery status packet.
+///
+/// The primary bud is reported before the secondary one, so the first
+/// left/right component in the packet is taken as primary.
+pub fn parse_primary_pod(data: &[u8]) -> Option<Component> {
+   let count = *data.strip_prefix(HDR_BATTERY_STATE)?.first()?;
+   data
+      .get(7..)?
+      .chunks_exact(5)
+      .take(count as usize)
+      .filter_map(|c| Component::from_repr(c[0]))
+      .find(|c| matches!(c, Component::Left | Component::Right))
+}
+
+/// Parses an ear detection packet.
+///
+/// The packet reports the primary bud first, so `primary` is needed to
+/// attribute the states to the left and right buds.
+pub fn parse_ear_detection(data: &[u8], primary: Component) -> Result<EarDetectionStatus> {
    if !data.starts_with(HDR_EAR_DETECTION) {
       return Err(
          ProtoError::WrongPacketType {
@@ -200,14 +351,63 @@ pub fn parse_ear_detection(data: &[u8]) -> Result<EarDetectionStatus> {
          .into(),
       );
    }
-   let left_out = data[6] == 0x01;
-   let right_out = data[7] == 0x01;
-   Ok(EarDetectionStatus::new(!left_out, !right_out))
+   let primary_state = EarState::from_byte(data[6]);
+   let secondary_state = EarState::from_byte(data[7]);
+   let (left, right) = if primary == Component::Right {
+      (secondary_state, primary_state)
+   } else {
+      (primary_state, secondary_state)
+   };
+   Ok(EarDetectionStatus::from_states(left, right))
+}
+
+/// Apple's Bluetooth SIG company identifier
+pub const APPLE_COMPANY_ID: u16 = 0x004c;
+/// Continuity message type of proximity pairing advertisements
+pub const PROXIMITY_PAIRING: u8 = 0x07;
+
+/// Parses the case lid state from Apple manufacturer data.
+///
+/// Proximity pairing advertisements carry a lid indicator byte whose bit 3
+/// is set while the lid is closed.
+pub fn parse_lid_state(data: &[u8]) -> Option<LidState> {
+   if *data.first()? != PROXIMITY_PAIRING {
+      return None;
+   }
+   let indicator = *data.get(8)?;
+   Some(if indicator & 0x08 != 0 {
+      LidState::Closed
+   } else {
+      LidState::Open
+   })
 }
 
 #[derive(Debug, Default)]
 pub struct Metadata {
    pub name_candidate: Option<SmolStr>,
+   pub serials: SerialNumbers,
+}
+
+/// Picks the serial numbers out of the NUL-separated metadata strings.
+///
+/// The strings follow a few bytes of binary data and are, in order: name,
+/// model number, manufacturer, serial of the set, two firmware versions,
+/// hardware revision, updater identifier, then the left and right serials.
+/// Fields a device leaves out are empty.
+fn parse_serials(payload: &[u8]) -> SerialNumbers {
+   let fields: Vec<&[u8]> = payload
+      .split(|&b| b == 0)
+      .skip_while(|field| !field.last().is_some_and(u8::is_ascii_graphic))
+      .collect();
+   let field = |index: usize| {
+      let text = str::from_utf8(fields.get(index)?).ok()?.trim();
+      (!text.is_empty() && text.chars().all(|c| c.is_ascii_graphic())).then(|| text.into())
+   };
+   SerialNumbers {
+      left: field(8),
+      right: field(9),
+      case: field(3),
+   }
 }
 
 pub fn parse_metadata(data: &[u8]) -> Result<Metadata> {
@@ -245,5 +445,8 @@ pub fn parse_metadata(data: &[u8]) -> Result<Metadata> {
       }
    }
 
-   Ok(Metadata { name_candidate })
+   Ok(Metadata {
+      name_candidate,
+      serials: parse_serials(&data[6..]),
+   })
 }
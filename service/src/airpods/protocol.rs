@@ -15,6 +15,7 @@ use std::{
 
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use smol_str::SmolStr;
 
 use crate::bluetooth::l2cap::Packet;
 
@@ -32,12 +33,15 @@ pub const PKT_REQUEST_NOTIFY: &[u8] = &[
 pub const HDR_BATTERY_STATE: &[u8] = b"\x04\x00\x04\x00\x04\x00";
 pub const HDR_NOISE_CTL: &[u8] = b"\x04\x00\x04\x00\x09\x00\x0D";
 pub const HDR_CMD_CTL: &[u8] = b"\x04\x00\x04\x00\x09\x00";
+pub const HDR_LISTENING_MODES: &[u8] = b"\x04\x00\x04\x00\x09\x00\x1A";
+pub const HDR_ADAPTIVE_LEVEL: &[u8] = b"\x04\x00\x04\x00\x09\x00\x2E";
 
 // ACK packet headers
 pub const HDR_ACK_HANDSHAKE: &[u8] = b"\x01\x00\x04\x00";
 pub const HDR_ACK_FEATURES: &[u8] = b"\x04\x00\x04\x00\x2b";
 pub const HDR_METADATA: &[u8] = b"\x04\x00\x04\x00\x1d";
 pub const HDR_EAR_DETECTION: &[u8] = b"\x04\x00\x04\x00\x06\x00";
+pub const HDR_STEM_PRESS: &[u8] = b"\x04\x00\x04\x00\x19\x00";
 
 /// Represents different components of `AirPods`.
 #[repr(u8)]
@@ -60,6 +64,16 @@ pub enum Component {
    Case = 0x08,
 }
 
+impl Component {
+   /// All components, in the order of their bit.
+   pub const ALL: [Self; 4] = [Self::Headphone, Self::Right, Self::Left, Self::Case];
+
+   /// Position of the component's bit, for indexing per-component arrays.
+   pub const fn index(self) -> usize {
+      (self as u8).trailing_zeros() as usize
+   }
+}
+
 /// Battery status for `AirPods` components.
 #[derive(
    Default,
@@ -113,11 +127,161 @@ pub enum NoiseControlMode {
    Adaptive = 0x04,
 }
 
+/// How quickly presses must follow each other to count as a double or triple press.
+#[derive(
+   Debug,
+   Clone,
+   Copy,
+   PartialEq,
+   Eq,
+   Serialize,
+   strum::FromRepr,
+   strum::Display,
+   strum::EnumString,
+   strum::IntoStaticStr,
+)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+#[repr(u32)]
+pub enum PressSpeed {
+   Default = 0x00,
+   Slower = 0x01,
+   Slowest = 0x02,
+}
+
+/// How long the stem must be pressed to count as a press and hold.
+#[derive(
+   Debug,
+   Clone,
+   Copy,
+   PartialEq,
+   Eq,
+   Serialize,
+   strum::FromRepr,
+   strum::Display,
+   strum::EnumString,
+   strum::IntoStaticStr,
+)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+#[repr(u32)]
+pub enum HoldDuration {
+   Default = 0x00,
+   Shorter = 0x01,
+   Shortest = 0x02,
+}
+
+/// A stem press forwarded by the buds.
+///
+/// The buds only report presses they are set to forward to the host instead
+/// of handling them themselves.
+#[derive(
+   Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, strum::FromRepr, strum::Display,
+)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+#[repr(u8)]
+pub enum StemPress {
+   Single = 0x05,
+   Double = 0x06,
+   Triple = 0x07,
+   Long = 0x08,
+}
+
+/// A stem press accessibility setting reported by the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PressSetting {
+   Speed(PressSpeed),
+   Hold(HoldDuration),
+}
+
+impl PressSetting {
+   /// Builds the control packet that applies this setting.
+   pub fn build(self) -> Packet {
+      let (feature, value) = match self {
+         Self::Speed(speed) => (FeatureId::DOUBLE_CLICK_INTERVAL, speed as u32),
+         Self::Hold(duration) => (FeatureId::CLICK_HOLD_INTERVAL, duration as u32),
+      };
+      build_control_packet(feature.id(), value.to_le_bytes())
+   }
+}
+
+/// The settings a device reports together after a notification request.
+///
+/// Settings the device didn't report are `None`, or missing from `features`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct AapSettings {
+   pub noise_mode: Option<NoiseControlMode>,
+   /// Modes cycled by the noise control button
+   pub noise_button_modes: Option<Vec<NoiseControlMode>>,
+   pub adaptive_level: Option<u8>,
+   pub press_speed: Option<PressSpeed>,
+   pub hold_duration: Option<HoldDuration>,
+   /// Whether adaptive EQ is active, which follows from the model
+   pub adaptive_eq: Option<bool>,
+   /// Other toggles, such as conversational awareness
+   pub features: Vec<(FeatureId, bool)>,
+}
+
+impl AapSettings {
+   /// Whether conversational awareness is enabled, if reported.
+   pub fn conversational_awareness(&self) -> Option<bool> {
+      self
+         .features
+         .iter()
+         .find(|(feature, _)| *feature == FeatureId::CONVERSATIONAL)
+         .map(|(_, enabled)| *enabled)
+   }
+
+   pub fn to_json(&self) -> serde_json::Value {
+      let mut info = json!({});
+      if let Some(mode) = self.noise_mode {
+         info["noise_mode"] = json!(mode.to_str());
+      }
+      if let Some(modes) = &self.noise_button_modes {
+         let modes: Vec<_> = modes.iter().map(|mode| mode.to_str()).collect();
+         info["noise_button_modes"] = json!(modes);
+      }
+      if let Some(level) = self.adaptive_level {
+         info["adaptive_level"] = json!(level);
+      }
+      if let Some(speed) = self.press_speed {
+         info["press_speed"] = json!(speed);
+      }
+      if let Some(duration) = self.hold_duration {
+         info["hold_duration"] = json!(duration);
+      }
+      if let Some(enabled) = self.adaptive_eq {
+         info["adaptive_eq"] = json!(enabled);
+      }
+      if let Some(enabled) = self.conversational_awareness() {
+         info["conversational_awareness"] = json!(enabled);
+      }
+      let features: serde_json::Map<_, _> = self
+         .features
+         .iter()
+         .map(|(feature, enabled)| (feature.to_str().to_string(), json!(enabled)))
+         .collect();
+      info["features"] = features.into();
+      info
+   }
+}
+
 impl NoiseControlMode {
    pub fn to_str(self) -> &'static str {
       self.into()
    }
 
+   /// Human-readable name of the mode.
+   pub const fn label(self) -> &'static str {
+      match self {
+         Self::Off => "Off",
+         Self::Active => "Noise Cancellation",
+         Self::Transparency => "Transparency",
+         Self::Adaptive => "Adaptive",
+      }
+   }
+
    pub const fn index(self) -> usize {
       (self as usize) - 1 // NOTE: Fix if values change!
    }
@@ -179,7 +343,6 @@ impl<T> NoiseControlMap<T> {
       self.data.iter().filter(|v| v.is_some()).count()
    }
 
-   #[cfg(test)]
    pub fn is_empty(&self) -> bool {
       self.data.iter().all(|v| v.is_none())
    }
@@ -188,6 +351,10 @@ impl<T> NoiseControlMap<T> {
 pub const KNOWN_FEATURES: &[(u8, &str)] = &[
    (FeatureId::MIC_MODE.id(), "mic_mode"),
    (FeatureId::BUTTON_SEND_MODE.id(), "button_send_mode"),
+   (
+      FeatureId::AUTOMATIC_EAR_DETECTION.id(),
+      "automatic_ear_detection",
+   ),
    (FeatureId::NOISE_CONTROL.id(), "noise_control"),
    (FeatureId::SINGLE_CLICK_MODE.id(), "single_click_mode"),
    (FeatureId::DOUBLE_CLICK_MODE.id(), "double_click_mode"),
@@ -305,6 +472,9 @@ impl FeatureId {
    pub const DOUBLE_CLICK_INTERVAL: Self = Self(0x17);
    pub const CLICK_HOLD_INTERVAL: Self = Self(0x18);
 
+   // Ear Detection, done by the buds themselves (not our auto-pause)
+   pub const AUTOMATIC_EAR_DETECTION: Self = Self(0x0A);
+
    // Listening Mode
    pub const LISTENING_MODE_CONFIGS: Self = Self(0x1A);
    pub const ONE_BUD_ANC: Self = Self(0x1B);
@@ -414,6 +584,15 @@ impl BatteryState {
    }
 }
 
+/// Lid state of the charging case, as advertised over BLE.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, strum::Display)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum LidState {
+   Open,
+   Closed,
+}
+
 /// Complete battery information for all `AirPods` components.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub struct BatteryInfo {
@@ -433,6 +612,12 @@ impl fmt::Display for BatteryInfo {
    }
 }
 
+impl Default for BatteryInfo {
+   fn default() -> Self {
+      Self::new()
+   }
+}
+
 impl BatteryInfo {
    pub const fn new() -> Self {
       Self {
@@ -451,6 +636,51 @@ impl BatteryInfo {
       }
    }
 
+   /// Whether the case level may be a stale reading.
+   ///
+   /// Some firmware only refreshes the case battery while the lid is closed,
+   /// so with the lid open the reported value can be the last known one.
+   pub fn is_case_stale(&self, lid: Option<LidState>) -> bool {
+      self.case.is_available() && lid == Some(LidState::Open)
+   }
+
+   /// Whether any component changed its status, or its level by at least
+   /// `min_change` points.
+   pub fn differs_by(&self, other: &Self, min_change: u8) -> bool {
+      Component::ALL.into_iter().any(|component| {
+         let (old, new) = (self.get(component), other.get(component));
+         old.status != new.status || old.level.abs_diff(new.level) >= min_change.max(1)
+      })
+   }
+
+   /// Gets the state of one component.
+   pub const fn get(&self, component: Component) -> BatteryState {
+      match component {
+         Component::Left => self.left,
+         Component::Right => self.right,
+         Component::Case => self.case,
+         Component::Headphone => self.headphone,
+      }
+   }
+
+   /// Gets the state of one component for modification.
+   pub const fn get_mut(&mut self, component: Component) -> &mut BatteryState {
+      match component {
+         Component::Left => &mut self.left,
+         Component::Right => &mut self.right,
+         Component::Case => &mut self.case,
+         Component::Headphone => &mut self.headphone,
+      }
+   }
+
+   /// Applies a battery report, keeping the components it doesn't list.
+   pub fn merge(mut self, report: &BatteryReport) -> Self {
+      for component in report.components() {
+         *self.get_mut(component) = report.battery.get(component);
+      }
+      self
+   }
+
    pub fn to_json(self) -> serde_json::Value {
       json!({
           "left": self.left.to_json(),
@@ -461,6 +691,76 @@ impl BatteryInfo {
    }
 }
 
+/// Serial numbers reported in the device metadata.
+///
+/// These identify the hardware, so they are only shown where asked for.
+/// Not every device reports one for its case.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SerialNumbers {
+   pub left: Option<SmolStr>,
+   pub right: Option<SmolStr>,
+   /// Serial of the whole set, as printed on the case
+   pub case: Option<SmolStr>,
+}
+
+impl SerialNumbers {
+   pub const fn is_empty(&self) -> bool {
+      self.left.is_none() && self.right.is_none() && self.case.is_none()
+   }
+
+   pub fn to_json(&self) -> serde_json::Value {
+      json!({
+         "left": self.left,
+         "right": self.right,
+         "case": self.case,
+      })
+   }
+}
+
+/// Battery states listed in a single battery status packet.
+///
+/// Packets don't always list every component; early in a connection one may
+/// carry only the buds or only the case.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatteryReport {
+   /// Listed components; the others are left at their defaults
+   pub battery: BatteryInfo,
+   /// Mask of the listed components' bits
+   pub listed: u8,
+}
+
+impl BatteryReport {
+   /// Whether the packet listed a component.
+   pub const fn lists(&self, component: Component) -> bool {
+      self.listed & component as u8 != 0
+   }
+
+   /// Iterates over the listed components.
+   pub fn components(&self) -> impl Iterator<Item = Component> + '_ {
+      Component::ALL.into_iter().filter(|c| self.lists(*c))
+   }
+}
+
+/// Where a single bud is, as reported by ear detection.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EarState {
+   InEar,
+   OutOfEar,
+   InCase,
+}
+
+impl EarState {
+   /// Decodes the per-bud byte of an ear detection packet.
+   pub const fn from_byte(byte: u8) -> Self {
+      match byte {
+         0x01 => Self::OutOfEar,
+         0x02 => Self::InCase,
+         _ => Self::InEar,
+      }
+   }
+}
+
 /// Ear detection status for left and right `AirPods`.
 #[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq, Eq)]
 #[repr(transparent)]
@@ -469,19 +769,57 @@ pub struct EarDetectionStatus(NonZeroU8);
 impl EarDetectionStatus {
    pub const LEFT: u8 = 1 << 0;
    pub const RIGHT: u8 = 1 << 1;
+   pub const LEFT_IN_CASE: u8 = 1 << 2;
+   pub const RIGHT_IN_CASE: u8 = 1 << 3;
    pub const VALID: u8 = 0x80;
 
+   #[cfg(test)]
    pub const fn new(left_in_ear: bool, right_in_ear: bool) -> Self {
-      let mut flags = Self::VALID;
-      if left_in_ear {
-         flags |= Self::LEFT;
-      }
-      if right_in_ear {
-         flags |= Self::RIGHT;
-      }
+      let left = if left_in_ear {
+         EarState::InEar
+      } else {
+         EarState::OutOfEar
+      };
+      let right = if right_in_ear {
+         EarState::InEar
+      } else {
+         EarState::OutOfEar
+      };
+      Self::from_states(left, right)
+   }
+
+   pub const fn from_states(left: EarState, right: EarState) -> Self {
+      let flags = Self::VALID
+         | match left {
+            EarState::InEar => Self::LEFT,
+            EarState::OutOfEar => 0,
+            EarState::InCase => Self::LEFT_IN_CASE,
+         }
+         | match right {
+            EarState::InEar => Self::RIGHT,
+            EarState::OutOfEar => 0,
+            EarState::InCase => Self::RIGHT_IN_CASE,
+         };
       Self(NonZeroU8::new(flags).expect("(x|valid) != 0"))
    }
 
+   const fn state(self, in_ear: u8, in_case: u8) -> EarState {
+      if self.0.get() & in_ear != 0 {
+         EarState::InEar
+      } else if self.0.get() & in_case != 0 {
+         EarState::InCase
+      } else {
+         EarState::OutOfEar
+      }
+   }
+
+   pub const fn left(self) -> EarState {
+      self.state(Self::LEFT, Self::LEFT_IN_CASE)
+   }
+   pub const fn right(self) -> EarState {
+      self.state(Self::RIGHT, Self::RIGHT_IN_CASE)
+   }
+
    pub const fn is_left_in_ear(&self) -> bool {
       self.0.get() & Self::LEFT != 0
    }
@@ -489,10 +827,18 @@ impl EarDetectionStatus {
       self.0.get() & Self::RIGHT != 0
    }
 
+   /// Whether exactly one bud is in an ear, so audio plays in mono.
+   pub const fn is_single_bud(self) -> bool {
+      self.is_left_in_ear() != self.is_right_in_ear()
+   }
+
    pub fn to_json(self) -> serde_json::Value {
       json!({
           "left_in_ear": self.is_left_in_ear(),
           "right_in_ear": self.is_right_in_ear(),
+          "single_bud_active": self.is_single_bud(),
+          "left_in_case": self.left() == EarState::InCase,
+          "right_in_case": self.right() == EarState::InCase,
       })
    }
 }
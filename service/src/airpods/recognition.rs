@@ -2,10 +2,14 @@
 //!
 //! This module contains the logic for recognizing `AirPods` devices
 //! based on various criteria such as modalias, manufacturer data,
-//! services, and name/alias patterns.
+//! services, and name/alias patterns. The product IDs and name patterns
+//! can be extended through [`RecognitionConfig`].
 
+use bluer::Modalias;
 use uuid::Uuid;
 
+use crate::{airpods::model::AirPodsModel, config::RecognitionConfig};
+
 /// Patterns to match `AirPods` devices (case-insensitive)
 const AIRPOD_PATTERNS: &[&str] = &["airpods", "beats", "powerbeats"];
 // Note: "earpods" are wired earphones, not Bluetooth AirPods
@@ -42,21 +46,74 @@ static APPLE_SERVICES: [Uuid; 3] = [
    Uuid::from_u128(0x0000fd32_0000_1000_8000_00805f9b34fb), // Apple service
 ];
 
+/// Iterates over the built-in and configured product IDs.
+fn product_ids(config: &RecognitionConfig) -> impl Iterator<Item = u32> + '_ {
+   AIRPOD_PIDS.iter().chain(&config.product_ids).copied()
+}
+
 /// Check if device is AirPods based on manufacturer data
-fn check_manufacturer_data(data: &[u8]) -> bool {
+fn check_manufacturer_data(data: &[u8], config: &RecognitionConfig) -> bool {
    // Apple TLV format: [0] type, [1] len, [2..5] ?, [6] product_id, ...
    if data.len() > PID_OFFSET && data[0] == PP_TYPE {
       let product_id = data[PID_OFFSET];
-      return AIRPOD_PIDS.iter().any(|&x| (x & 0xFF) as u8 == product_id);
+      return product_ids(config).any(|x| (x & 0xFF) as u8 == product_id);
    }
    false
 }
 
-pub async fn is_device_airpods(dev: &bluer::Device) -> bool {
+/// Returns the built-in or configured pattern contained in a device name, if any.
+fn matching_pattern<'a>(name: &str, config: &'a RecognitionConfig) -> Option<&'a str> {
+   let name = name.to_ascii_lowercase();
+   AIRPOD_PATTERNS
+      .iter()
+      .copied()
+      .chain(config.name_patterns.iter().map(String::as_str))
+      .find(|pattern| !pattern.is_empty() && name.contains(&pattern.to_ascii_lowercase()))
+}
+
+/// Identifies the model of a device from its modalias or manufacturer data.
+pub async fn detect_model(dev: &bluer::Device) -> AirPodsModel {
+   if let Ok(Some(modalias)) = dev.modalias().await
+      && let Some(model) = model_from_modalias(&modalias)
+   {
+      return model;
+   }
+
+   if let Ok(Some(mfg_data)) = dev.manufacturer_data().await
+      && let Some(apple_data) = mfg_data.get(&APPLE_CID)
+      && let Some(model) = model_from_advertisement(apple_data)
+   {
+      return model;
+   }
+   AirPodsModel::Unknown
+}
+
+/// Identifies the model from the Apple manufacturer data of an advertisement.
+pub fn model_from_advertisement(apple_data: &[u8]) -> Option<AirPodsModel> {
+   // Manufacturer data only carries the low byte of the product ID
+   if apple_data.len() <= PID_OFFSET || apple_data[0] != PP_TYPE {
+      return None;
+   }
+   AIRPOD_PIDS
+      .iter()
+      .find(|&&x| (x & 0xFF) as u8 == apple_data[PID_OFFSET])
+      .map(|&pid| AirPodsModel::from_product_id(pid))
+}
+
+/// Identifies the model from a modalias, if it is an Apple one.
+pub const fn model_from_modalias(modalias: &Modalias) -> Option<AirPodsModel> {
+   if modalias.vendor == APPLE_VID {
+      Some(AirPodsModel::from_product_id(modalias.product))
+   } else {
+      None
+   }
+}
+
+pub async fn is_device_airpods(dev: &bluer::Device, config: &RecognitionConfig) -> bool {
    // 1. Check modalias (most reliable for connected devices)
    if let Ok(Some(modalias)) = dev.modalias().await
       && modalias.vendor == APPLE_VID
-      && AIRPOD_PIDS.contains(&modalias.product)
+      && product_ids(config).any(|pid| pid == modalias.product)
    {
       log::debug!(
          "AirPods detected via modalias: vendor={:#06x}, product={:#06x}",
@@ -69,7 +126,7 @@ pub async fn is_device_airpods(dev: &bluer::Device) -> bool {
    // 2. Check manufacturer data (useful for advertising/unconnected devices)
    if let Ok(Some(mfg_data)) = dev.manufacturer_data().await
       && let Some(apple_data) = mfg_data.get(&APPLE_CID)
-      && check_manufacturer_data(apple_data)
+      && check_manufacturer_data(apple_data, config)
    {
       log::debug!("AirPods detected via manufacturer data");
       return true;
@@ -84,23 +141,49 @@ pub async fn is_device_airpods(dev: &bluer::Device) -> bool {
    }
 
    // 4. Last-chance name/alias pattern matching
-   if let Ok(Some(mut name)) = dev.name().await {
-      name.make_ascii_lowercase();
-      for pattern in AIRPOD_PATTERNS {
-         if name.contains(pattern) {
-            log::debug!("AirPods detected via name pattern: {name} => {pattern}");
-            return true;
-         }
-      }
+   if let Ok(Some(name)) = dev.name().await
+      && let Some(pattern) = matching_pattern(&name, config)
+   {
+      log::debug!("AirPods detected via name pattern: {name} => {pattern}");
+      return true;
    }
-   if let Ok(mut alias) = dev.alias().await {
-      alias.make_ascii_lowercase();
-      for pattern in AIRPOD_PATTERNS {
-         if alias.contains(pattern) {
-            log::debug!("AirPods detected via alias pattern: {alias} => {pattern}");
-            return true;
-         }
-      }
+   if let Ok(alias) = dev.alias().await
+      && let Some(pattern) = matching_pattern(&alias, config)
+   {
+      log::debug!("AirPods detected via alias pattern: {alias} => {pattern}");
+      return true;
    }
    false
 }
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   #[test]
+   fn test_configured_criteria_extend_builtin_ones() {
+      let config = RecognitionConfig {
+         product_ids: vec![0x2019],
+         name_patterns: vec!["Studio Buds".to_string(), String::new()],
+      };
+      let advertisement = |pid: u8| [PP_TYPE, 0x19, 0x01, 0x0e, 0x20, 0x2b, pid];
+
+      assert!(check_manufacturer_data(&advertisement(0x14), &config));
+      assert!(check_manufacturer_data(&advertisement(0x19), &config));
+      assert!(!check_manufacturer_data(
+         &advertisement(0x19),
+         &RecognitionConfig::default()
+      ));
+
+      assert_eq!(
+         matching_pattern("Jane's AirPods Pro", &config),
+         Some("airpods")
+      );
+      assert_eq!(
+         matching_pattern("jane's studio buds+", &config),
+         Some("Studio Buds")
+      );
+      // An empty pattern doesn't match everything
+      assert_eq!(matching_pattern("Keyboard", &config), None);
+   }
+}
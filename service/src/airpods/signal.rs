@@ -0,0 +1,85 @@
+//! Detection of sustained poor Bluetooth signal.
+//!
+//! Audio tends to stutter once the connection gets weak, so a warning is
+//! raised when the advertisement RSSI stays below a threshold for a while.
+//! It is only raised again after the signal recovered by a margin, so a
+//! reading hovering around the threshold doesn't warn repeatedly.
+
+use std::time::{Duration, Instant};
+
+/// Tracks RSSI readings of a device against a threshold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SignalMonitor {
+   /// Readings below this, in dBm, count as poor
+   threshold: i16,
+   /// How long readings must stay poor before warning
+   duration: Duration,
+   /// How far above the threshold readings must rise to count as recovered
+   hysteresis: i16,
+   /// Since when readings have been poor
+   poor_since: Option<Instant>,
+   /// Whether the current stretch of poor signal was already reported
+   reported: bool,
+}
+
+impl SignalMonitor {
+   pub const fn new(threshold: i16, duration: Duration, hysteresis: i16) -> Self {
+      Self {
+         threshold,
+         duration,
+         hysteresis,
+         poor_since: None,
+         reported: false,
+      }
+   }
+
+   /// Records a reading, returning whether the signal just became poor for long enough.
+   pub fn update(&mut self, rssi: i16, now: Instant) -> bool {
+      if rssi >= self.threshold.saturating_add(self.hysteresis) {
+         self.poor_since = None;
+         self.reported = false;
+         return false;
+      }
+      if rssi >= self.threshold {
+         // Within the margin the signal neither worsens nor recovers
+         if !self.reported {
+            self.poor_since = None;
+         }
+         return false;
+      }
+      let since = *self.poor_since.get_or_insert(now);
+      if self.reported || now.duration_since(since) < self.duration {
+         return false;
+      }
+      self.reported = true;
+      true
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   #[test]
+   fn test_poor_signal_needs_duration_and_recovery() {
+      let start = Instant::now();
+      let at = |secs| start + Duration::from_secs(secs);
+      let mut monitor = SignalMonitor::new(-80, Duration::from_secs(10), 5);
+
+      // A momentary dip doesn't warn
+      assert!(!monitor.update(-85, at(0)));
+      assert!(!monitor.update(-70, at(5)));
+      assert!(!monitor.update(-85, at(6)));
+      assert!(!monitor.update(-85, at(15)));
+      assert!(monitor.update(-90, at(16)));
+
+      // Hovering around the threshold doesn't warn again
+      assert!(!monitor.update(-78, at(17)));
+      assert!(!monitor.update(-85, at(30)));
+
+      // Only a real recovery re-arms the warning
+      assert!(!monitor.update(-70, at(31)));
+      assert!(!monitor.update(-85, at(32)));
+      assert!(monitor.update(-85, at(42)));
+   }
+}
@@ -0,0 +1,140 @@
+//! One-line status summaries for trays and other compact UIs.
+//!
+//! A template is made of segments separated by `" · "`, each of which may
+//! contain `{field}` placeholders:
+//!
+//! | Placeholder          | Value                                        |
+//! |----------------------|----------------------------------------------|
+//! | `{name}`             | Device name                                  |
+//! | `{model}`            | Model, e.g. `AirPods Pro`                    |
+//! | `{left}`, `{right}`  | Bud battery level in percent                 |
+//! | `{case}`             | Case battery level in percent                |
+//! | `{battery}`          | Battery level of single-battery models       |
+//! | `{noise_mode}`       | Noise control mode, e.g. `Transparency`      |
+//! | `{adaptive_level}`   | Adaptive noise level from 0 to 100           |
+//! | `{connection_state}` | AAP session state, e.g. `connected`          |
+//!
+//! A segment with a placeholder whose value isn't known, such as the case
+//! battery of AirPods Max or of a case out of range, is left out entirely.
+
+use parking_lot::RwLock;
+
+/// Separator between template segments
+pub const SEPARATOR: &str = " · ";
+
+/// Placeholders filled in from the device state
+pub const PLACEHOLDERS: &[&str] = &[
+   "name",
+   "model",
+   "left",
+   "right",
+   "case",
+   "battery",
+   "noise_mode",
+   "adaptive_level",
+   "connection_state",
+];
+
+/// Template used unless one is configured
+pub const DEFAULT_TEMPLATE: &str =
+   "{name} · L {left}% R {right}% · {battery}% · Case {case}% · {noise_mode}";
+
+/// Configured template, installed at startup
+static TEMPLATE: RwLock<String> = RwLock::new(String::new());
+
+/// Installs the template used by [`template`].
+pub fn configure(template: Option<String>) {
+   *TEMPLATE.write() = template.unwrap_or_default();
+}
+
+/// Returns the configured template, or [`DEFAULT_TEMPLATE`] if none is.
+pub fn template() -> String {
+   let template = TEMPLATE.read();
+   if template.is_empty() {
+      DEFAULT_TEMPLATE.to_string()
+   } else {
+      template.clone()
+   }
+}
+
+/// Fills in a template, leaving out segments with unknown values.
+///
+/// `lookup` returns the value of a placeholder, or `None` if it is unknown
+/// or not a placeholder at all.
+pub fn render(template: &str, lookup: impl Fn(&str) -> Option<String>) -> String {
+   template
+      .split(SEPARATOR)
+      .filter_map(|segment| render_segment(segment, &lookup))
+      .filter(|segment| !segment.trim().is_empty())
+      .collect::<Vec<_>>()
+      .join(SEPARATOR)
+}
+
+/// Checks that a template only uses known placeholders, `extra` ones included.
+pub fn validate(template: &str, extra: &[&str]) -> Result<(), String> {
+   let mut rest = template;
+   while let Some(start) = rest.find('{') {
+      let Some(len) = rest[start..].find('}') else {
+         return Err("unclosed placeholder".to_string());
+      };
+      let field = &rest[start + 1..start + len];
+      if !PLACEHOLDERS.contains(&field) && !extra.contains(&field) {
+         return Err(format!("unknown placeholder {{{field}}}"));
+      }
+      rest = &rest[start + len + 1..];
+   }
+   Ok(())
+}
+
+fn render_segment(segment: &str, lookup: &impl Fn(&str) -> Option<String>) -> Option<String> {
+   let mut rendered = String::with_capacity(segment.len());
+   let mut rest = segment;
+   while let Some(start) = rest.find('{') {
+      let Some(len) = rest[start..].find('}') else {
+         break;
+      };
+      rendered.push_str(&rest[..start]);
+      rendered.push_str(&lookup(&rest[start + 1..start + len])?);
+      rest = &rest[start + len + 1..];
+   }
+   rendered.push_str(rest);
+   Some(rendered)
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   #[test]
+   fn test_render_omits_unknown_segments() {
+      let lookup = |field: &str| {
+         match field {
+            "name" => Some("AirPods Pro"),
+            "left" => Some("80"),
+            "right" => Some("75"),
+            "case" => Some("50"),
+            "noise_mode" => Some("Transparency"),
+            _ => None,
+         }
+         .map(str::to_string)
+      };
+      assert_eq!(
+         render(DEFAULT_TEMPLATE, lookup),
+         "AirPods Pro · L 80% R 75% · Case 50% · Transparency"
+      );
+      assert_eq!(
+         render("{name} · {bogus} · {case}%", lookup),
+         "AirPods Pro · 50%"
+      );
+      assert_eq!(render("Battery {left", lookup), "Battery {left");
+      assert_eq!(render("{adaptive_level}", lookup), "");
+   }
+
+   #[test]
+   fn test_validate_rejects_unknown_placeholders() {
+      assert_eq!(validate(DEFAULT_TEMPLATE, &[]), Ok(()));
+      assert_eq!(validate("{level}%", &["level"]), Ok(()));
+      assert!(validate("{level}%", &[]).is_err());
+      assert!(validate("{name", &[]).is_err());
+   }
+}
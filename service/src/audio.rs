@@ -0,0 +1,442 @@
+//! Audio routing integration for PulseAudio and PipeWire.
+//!
+//! This module drives `pactl` (shipped by both PulseAudio and `pipewire-pulse`)
+//! to route playback to the `AirPods` when they connect, and optionally to
+//! restore the previous default sink when they go away. It also watches what
+//! the `AirPods` are used for, to pick a noise control mode per context, and
+//! tells whether they are the active output at all.
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use bluer::Address;
+use log::{debug, info, warn};
+use parking_lot::Mutex;
+use serde::Deserialize;
+use tokio::{process::Command, task::JoinHandle, time};
+
+use crate::{
+   airpods::{
+      device::{AirPods, WeakAirPods},
+      protocol::NoiseControlMode,
+   },
+   config::AudioConfig,
+   error::{AirPodsError, Result},
+};
+
+/// How long to wait for the Bluetooth sink to show up after the AAP connection
+const SINK_WAIT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Interval between sink lookups while waiting
+const SINK_POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// Interval between checks of what the `AirPods` are used for
+const CONTEXT_POLL_INTERVAL: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Clone, Deserialize)]
+struct Sink {
+   index: u32,
+   name: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Source {
+   index: u32,
+   name: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct SourceOutput {
+   source: u32,
+   #[serde(default)]
+   corked: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct SinkInput {
+   index: u32,
+   sink: u32,
+   #[serde(default)]
+   corked: bool,
+   #[serde(default)]
+   properties: HashMap<String, serde_json::Value>,
+}
+
+impl SinkInput {
+   fn application(&self) -> &str {
+      self
+         .properties
+         .get("application.name")
+         .and_then(|v| v.as_str())
+         .unwrap_or("unknown")
+   }
+}
+
+async fn pactl(args: &[&str]) -> Result<String> {
+   let output = Command::new("pactl").args(args).output().await?;
+   if !output.status.success() {
+      return Err(AirPodsError::AudioRouting(format!(
+         "pactl {} exited with {}: {}",
+         args.join(" "),
+         output.status,
+         String::from_utf8_lossy(&output.stderr).trim()
+      )));
+   }
+   Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+async fn pactl_json<T: for<'de> Deserialize<'de>>(what: &str) -> Result<Vec<T>> {
+   let out = pactl(&["--format=json", "list", what]).await?;
+   serde_json::from_str(&out)
+      .map_err(|e| AirPodsError::AudioRouting(format!("Invalid pactl output for {what}: {e}")))
+}
+
+async fn default_sink() -> Result<String> {
+   Ok(pactl(&["get-default-sink"]).await?.trim().to_string())
+}
+
+/// Checks whether a sink name belongs to the Bluetooth device with the given address.
+///
+/// PipeWire names these `bluez_output.AA_BB_CC_DD_EE_FF.1`, PulseAudio uses
+/// `bluez_sink.AA_BB_CC_DD_EE_FF.a2dp_sink`.
+fn is_device_sink(name: &str, address: Address) -> bool {
+   let mangled = address.to_string().replace(':', "_");
+   name.starts_with("bluez_") && name.contains(&mangled)
+}
+
+/// Checks whether a source name is the microphone of the Bluetooth device.
+///
+/// PipeWire names these `bluez_input.AA_BB_CC_DD_EE_FF.0`, PulseAudio uses
+/// `bluez_source.AA_BB_CC_DD_EE_FF.headset_head_unit`. Monitors of the
+/// device's sink are not microphones.
+fn is_device_source(name: &str, address: Address) -> bool {
+   is_device_sink(name, address) && !name.ends_with(".monitor")
+}
+
+/// Checks whether the `AirPods` are the default audio sink.
+///
+/// Returns `None` when the sound server can't be queried, e.g. without `pactl`.
+pub async fn is_default_output(address: Address) -> Option<bool> {
+   match default_sink().await {
+      Ok(sink) => Some(is_device_sink(&sink, address)),
+      Err(e) => {
+         debug!("{address}: Failed to query the default sink: {e}");
+         None
+      },
+   }
+}
+
+async fn find_device_sink(address: Address) -> Result<Option<Sink>> {
+   let sinks: Vec<Sink> = pactl_json("sinks").await?;
+   Ok(sinks.into_iter().find(|s| is_device_sink(&s.name, address)))
+}
+
+async fn wait_for_device_sink(address: Address) -> Result<Option<Sink>> {
+   let deadline = time::Instant::now() + SINK_WAIT_TIMEOUT;
+   loop {
+      if let Some(sink) = find_device_sink(address).await? {
+         return Ok(Some(sink));
+      }
+      if time::Instant::now() >= deadline {
+         return Ok(None);
+      }
+      time::sleep(SINK_POLL_INTERVAL).await;
+   }
+}
+
+/// Switches the default audio sink to the `AirPods` on connect.
+///
+/// This type is cheaply cloneable.
+#[derive(Clone)]
+pub struct SinkSwitcher {
+   config: AudioConfig,
+   /// Default sink that was active before we switched, per device
+   previous: Arc<Mutex<HashMap<Address, String>>>,
+}
+
+impl SinkSwitcher {
+   pub fn new(config: AudioConfig) -> Self {
+      Self {
+         config,
+         previous: Arc::default(),
+      }
+   }
+
+   /// Handles a device connection, switching audio over in the background.
+   pub fn device_connected(&self, address: Address) {
+      if !self.config.switch_sink_on_connect {
+         return;
+      }
+      let this = self.clone();
+      tokio::spawn(async move {
+         if let Err(e) = this.switch_to_device(address).await {
+            warn!("{address}: Failed to switch audio sink: {e}");
+         }
+      });
+   }
+
+   /// Handles a device disconnection, restoring the previous default sink if configured.
+   pub fn device_disconnected(&self, address: Address) {
+      let Some(previous) = self.previous.lock().remove(&address) else {
+         return;
+      };
+      tokio::spawn(async move {
+         if let Err(e) = restore_default(address, &previous).await {
+            warn!("{address}: Failed to restore audio sink {previous}: {e}");
+         }
+      });
+   }
+
+   async fn switch_to_device(&self, address: Address) -> Result<()> {
+      let Some(target) = wait_for_device_sink(address).await? else {
+         debug!("{address}: No audio sink appeared, leaving routing unchanged");
+         return Ok(());
+      };
+
+      let current = default_sink().await?;
+      if current == target.name {
+         debug!("{address}: {} is already the default sink", target.name);
+         return Ok(());
+      }
+
+      let sinks: Vec<Sink> = pactl_json("sinks").await?;
+      let Some(current_index) = sinks.iter().find(|s| s.name == current).map(|s| s.index) else {
+         debug!("{address}: Default sink {current} not found, leaving routing unchanged");
+         return Ok(());
+      };
+
+      let inputs: Vec<SinkInput> = pactl_json("sink-inputs").await?;
+      let playing: Vec<_> = inputs
+         .iter()
+         .filter(|i| i.sink == current_index && !i.corked)
+         .collect();
+      if playing.is_empty() {
+         debug!("{address}: Nothing is playing on {current}, leaving routing unchanged");
+         return Ok(());
+      }
+
+      pactl(&["set-default-sink", &target.name]).await?;
+      info!(
+         "{address}: Default sink switched from {current} to {}",
+         target.name
+      );
+
+      for input in playing {
+         let index = input.index.to_string();
+         match pactl(&["move-sink-input", &index, &target.name]).await {
+            Ok(_) => info!(
+               "{address}: Moved stream #{index} ({}) from {current} to {}",
+               input.application(),
+               target.name
+            ),
+            Err(e) => warn!("{address}: Failed to move stream #{index}: {e}"),
+         }
+      }
+
+      if self.config.restore_sink_on_disconnect {
+         self.previous.lock().insert(address, current);
+      }
+      Ok(())
+   }
+}
+
+/// What the `AirPods` are being used for.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum AudioContext {
+   #[default]
+   Idle,
+   /// Something is playing to the `AirPods`
+   Media,
+   /// The `AirPods` microphone is in use
+   Call,
+}
+
+impl AudioContext {
+   /// Returns the noise control mode to switch to on entering this context, if any.
+   const fn noise_mode(self, config: &AudioConfig) -> Option<NoiseControlMode> {
+      match self {
+         Self::Media if config.anc_for_media => Some(NoiseControlMode::Active),
+         Self::Call if config.transparency_for_calls => Some(NoiseControlMode::Transparency),
+         _ => None,
+      }
+   }
+}
+
+async fn detect_context(address: Address) -> Result<AudioContext> {
+   let sources: Vec<Source> = pactl_json("sources").await?;
+   if let Some(source) = sources.iter().find(|s| is_device_source(&s.name, address)) {
+      let outputs: Vec<SourceOutput> = pactl_json("source-outputs").await?;
+      if outputs
+         .iter()
+         .any(|o| o.source == source.index && !o.corked)
+      {
+         return Ok(AudioContext::Call);
+      }
+   }
+
+   let Some(sink) = find_device_sink(address).await? else {
+      return Ok(AudioContext::Idle);
+   };
+   let inputs: Vec<SinkInput> = pactl_json("sink-inputs").await?;
+   Ok(
+      if inputs.iter().any(|i| i.sink == sink.index && !i.corked) {
+         AudioContext::Media
+      } else {
+         AudioContext::Idle
+      },
+   )
+}
+
+/// Switches noise control when the `AirPods` start playing media or a call starts.
+///
+/// Modes are only changed on entering a context, so picking another mode by
+/// hand sticks until the context changes again. This type is cheaply cloneable.
+#[derive(Clone)]
+pub struct ContextNoiseControl {
+   config: AudioConfig,
+   watchers: Arc<Mutex<HashMap<Address, JoinHandle<()>>>>,
+}
+
+impl ContextNoiseControl {
+   pub fn new(config: AudioConfig) -> Self {
+      Self {
+         config,
+         watchers: Arc::default(),
+      }
+   }
+
+   /// Starts watching the audio context of a connected device.
+   pub fn device_connected(&self, device: &AirPods) {
+      if !self.config.anc_for_media && !self.config.transparency_for_calls {
+         return;
+      }
+      let handle = tokio::spawn(watch_context(
+         WeakAirPods::new(device),
+         device.address(),
+         self.config.clone(),
+      ));
+      if let Some(previous) = self.watchers.lock().insert(device.address(), handle) {
+         previous.abort();
+      }
+   }
+
+   /// Stops watching a device.
+   pub fn device_disconnected(&self, address: Address) {
+      if let Some(handle) = self.watchers.lock().remove(&address) {
+         handle.abort();
+      }
+   }
+}
+
+async fn watch_context(device: WeakAirPods, address: Address, config: AudioConfig) {
+   let mut context = AudioContext::Idle;
+   loop {
+      time::sleep(CONTEXT_POLL_INTERVAL).await;
+      let Some(device) = device.upgrade() else {
+         return;
+      };
+      let current = match detect_context(address).await {
+         Ok(current) => current,
+         Err(e) => {
+            debug!("{address}: Failed to detect audio context: {e}");
+            continue;
+         },
+      };
+      if current == context {
+         continue;
+      }
+      debug!("{address}: Audio context changed from {context:?} to {current:?}");
+      context = current;
+
+      let Some(mode) = current.noise_mode(&config) else {
+         continue;
+      };
+      if device.noise_mode() == Some(mode) {
+         continue;
+      }
+      match device.set_noise_control(mode).await {
+         Ok(()) => info!("{address}: Switched noise control to {mode} for {current:?}"),
+         Err(e) => warn!("{address}: Failed to switch noise control to {mode}: {e}"),
+      }
+   }
+}
+
+async fn restore_default(address: Address, previous: &str) -> Result<()> {
+   let sinks: Vec<Sink> = pactl_json("sinks").await?;
+   if !sinks.iter().any(|s| s.name == previous) {
+      debug!("{address}: Previous sink {previous} no longer exists, not restoring");
+      return Ok(());
+   }
+   pactl(&["set-default-sink", previous]).await?;
+   info!("{address}: Restored default sink to {previous}");
+   Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   const TEST_ADDRESS: Address = Address([0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF]);
+
+   #[test]
+   fn test_is_device_sink() {
+      assert!(is_device_sink(
+         "bluez_output.AA_BB_CC_DD_EE_FF.1",
+         TEST_ADDRESS
+      ));
+      assert!(is_device_sink(
+         "bluez_sink.AA_BB_CC_DD_EE_FF.a2dp_sink",
+         TEST_ADDRESS
+      ));
+      assert!(!is_device_sink(
+         "bluez_output.11_22_33_44_55_66.1",
+         TEST_ADDRESS
+      ));
+      assert!(!is_device_sink(
+         "alsa_output.pci-0000_00_1f.3.analog-stereo",
+         TEST_ADDRESS
+      ));
+   }
+
+   #[test]
+   fn test_is_device_source() {
+      assert!(is_device_source(
+         "bluez_input.AA_BB_CC_DD_EE_FF.0",
+         TEST_ADDRESS
+      ));
+      assert!(is_device_source(
+         "bluez_source.AA_BB_CC_DD_EE_FF.headset_head_unit",
+         TEST_ADDRESS
+      ));
+      assert!(!is_device_source(
+         "bluez_output.AA_BB_CC_DD_EE_FF.1.monitor",
+         TEST_ADDRESS
+      ));
+   }
+
+   #[test]
+   fn test_context_rules_are_independent() {
+      let config = |anc_for_media, transparency_for_calls| AudioConfig {
+         anc_for_media,
+         transparency_for_calls,
+         ..AudioConfig::default()
+      };
+
+      let both = config(true, true);
+      assert_eq!(AudioContext::Idle.noise_mode(&both), None);
+      assert_eq!(
+         AudioContext::Media.noise_mode(&both),
+         Some(NoiseControlMode::Active)
+      );
+      assert_eq!(
+         AudioContext::Call.noise_mode(&both),
+         Some(NoiseControlMode::Transparency)
+      );
+
+      let media_only = config(true, false);
+      assert_eq!(AudioContext::Call.noise_mode(&media_only), None);
+      let calls_only = config(false, true);
+      assert_eq!(AudioContext::Media.noise_mode(&calls_only), None);
+      assert_eq!(
+         AudioContext::Call.noise_mode(&calls_only),
+         Some(NoiseControlMode::Transparency)
+      );
+   }
+}
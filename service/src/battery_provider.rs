@@ -0,0 +1,248 @@
+//! `BlueZ` battery provider for native desktop battery reporting.
+//!
+//! `BlueZ` exposes a single `org.bluez.Battery1` per device, which UPower
+//! turns into the headset entry shown by the stock GNOME and KDE battery
+//! menus. We register as an external battery provider on the system bus and
+//! publish one battery object per connected `AirPods`, reporting the combined
+//! bud level. Case levels stay on our own D-Bus interface since desktops have
+//! no notion of a charging case.
+
+use std::collections::{HashMap, HashSet};
+
+use bluer::{Address, Session};
+use log::{debug, info, warn};
+use tokio::sync::mpsc;
+use zbus::{Connection, connection, interface, zvariant::OwnedObjectPath};
+
+use crate::{airpods::protocol::BatteryInfo, error::Result};
+
+/// Object path under which battery objects are published
+const PROVIDER_PATH: &str = "/org/kairpods/battery";
+/// Source reported to `BlueZ`
+const PROVIDER_SOURCE: &str = "kAirPods";
+
+/// Combines the component levels into the single level reported to the desktop.
+///
+/// Headphones report their own battery; for buds the lowest available bud
+/// decides, since that is the one that runs out first.
+pub fn combined_level(battery: &BatteryInfo) -> Option<u8> {
+   if battery.headphone.is_available() {
+      return Some(battery.headphone.level);
+   }
+   [battery.left, battery.right]
+      .into_iter()
+      .filter(|bud| bud.is_available())
+      .map(|bud| bud.level)
+      .min()
+}
+
+/// A battery exported through `org.bluez.BatteryProvider1`.
+struct ProvidedBattery {
+   percentage: u8,
+   device: OwnedObjectPath,
+}
+
+#[interface(name = "org.bluez.BatteryProvider1")]
+impl ProvidedBattery {
+   #[zbus(property)]
+   fn percentage(&self) -> u8 {
+      self.percentage
+   }
+
+   #[zbus(property)]
+   fn device(&self) -> OwnedObjectPath {
+      self.device.clone()
+   }
+
+   #[zbus(property)]
+   fn source(&self) -> &str {
+      PROVIDER_SOURCE
+   }
+}
+
+enum Update {
+   Level(Address, u8),
+   Removed(Address),
+}
+
+/// Handle to the background task publishing batteries to `BlueZ`.
+#[derive(Clone)]
+pub struct BatteryProvider {
+   tx: mpsc::UnboundedSender<Update>,
+}
+
+impl BatteryProvider {
+   /// Spawns the provider task.
+   pub fn spawn() -> Self {
+      let (tx, rx) = mpsc::unbounded_channel();
+      tokio::spawn(async move {
+         if let Err(e) = run(rx).await {
+            warn!("Battery provider stopped: {e}");
+         }
+      });
+      Self { tx }
+   }
+
+   /// Publishes a battery update for a device.
+   pub fn battery_updated(&self, address: Address, battery: &BatteryInfo) {
+      if let Some(level) = combined_level(battery) {
+         let _ = self.tx.send(Update::Level(address, level));
+      }
+   }
+
+   /// Withdraws the battery of a disconnected device.
+   pub fn device_disconnected(&self, address: Address) {
+      let _ = self.tx.send(Update::Removed(address));
+   }
+}
+
+/// Formats an address the way `BlueZ` uses it in object paths.
+fn path_segment(address: Address) -> String {
+   format!("dev_{}", address.to_string().replace(':', "_"))
+}
+
+struct Provider {
+   connection: Connection,
+   session: Session,
+   registered_adapters: HashSet<String>,
+   batteries: HashMap<Address, String>,
+}
+
+impl Provider {
+   /// Finds the adapter the device is connected through.
+   async fn find_adapter(&self, address: Address) -> Result<Option<String>> {
+      for name in self.session.adapter_names().await? {
+         let adapter = self.session.adapter(&name)?;
+         if let Ok(device) = adapter.device(address)
+            && device.is_connected().await.unwrap_or(false)
+         {
+            return Ok(Some(name));
+         }
+      }
+      Ok(None)
+   }
+
+   async fn register_on(&mut self, adapter: &str) -> Result<()> {
+      if self.registered_adapters.contains(adapter) {
+         return Ok(());
+      }
+      self
+         .connection
+         .call_method(
+            Some("org.bluez"),
+            format!("/org/bluez/{adapter}").as_str(),
+            Some("org.bluez.BatteryProviderManager1"),
+            "RegisterBatteryProvider",
+            &(zbus::zvariant::ObjectPath::from_static_str_unchecked(
+               PROVIDER_PATH,
+            ),),
+         )
+         .await?;
+      info!("Registered battery provider on {adapter}");
+      self.registered_adapters.insert(adapter.to_string());
+      Ok(())
+   }
+
+   async fn set_level(&mut self, address: Address, level: u8) -> Result<()> {
+      let object_server = self.connection.object_server();
+      if let Some(path) = self.batteries.get(&address) {
+         let iface = object_server
+            .interface::<_, ProvidedBattery>(path.as_str())
+            .await?;
+         if iface.get().await.percentage != level {
+            iface.get_mut().await.percentage = level;
+            iface
+               .get()
+               .await
+               .percentage_changed(iface.signal_emitter())
+               .await?;
+         }
+         return Ok(());
+      }
+
+      let Some(adapter) = self.find_adapter(address).await? else {
+         debug!("{address}: No adapter has the device connected, not publishing battery");
+         return Ok(());
+      };
+      let device =
+         OwnedObjectPath::try_from(format!("/org/bluez/{adapter}/{}", path_segment(address)))
+            .map_err(zbus::Error::from)?;
+      let path = format!("{PROVIDER_PATH}/{}", path_segment(address));
+      object_server
+         .at(
+            path.as_str(),
+            ProvidedBattery {
+               percentage: level,
+               device,
+            },
+         )
+         .await?;
+      self.batteries.insert(address, path);
+      self.register_on(&adapter).await
+   }
+
+   async fn remove(&mut self, address: Address) -> Result<()> {
+      if let Some(path) = self.batteries.remove(&address) {
+         self
+            .connection
+            .object_server()
+            .remove::<ProvidedBattery, _>(path.as_str())
+            .await?;
+      }
+      Ok(())
+   }
+}
+
+async fn run(mut rx: mpsc::UnboundedReceiver<Update>) -> Result<()> {
+   let connection = connection::Builder::system()?
+      .serve_at(PROVIDER_PATH, zbus::fdo::ObjectManager)?
+      .build()
+      .await?;
+   let mut provider = Provider {
+      connection,
+      session: Session::new().await?,
+      registered_adapters: HashSet::new(),
+      batteries: HashMap::new(),
+   };
+
+   while let Some(update) = rx.recv().await {
+      let result = match update {
+         Update::Level(address, level) => provider.set_level(address, level).await,
+         Update::Removed(address) => provider.remove(address).await,
+      };
+      if let Err(e) = result {
+         warn!("Failed to update battery provider: {e}");
+      }
+   }
+   Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::airpods::protocol::{BatteryState, BatteryStatus};
+
+   const fn state(level: u8) -> BatteryState {
+      BatteryState {
+         level,
+         status: BatteryStatus::Normal,
+      }
+   }
+
+   #[test]
+   fn test_combined_level() {
+      let mut battery = BatteryInfo::new();
+      assert_eq!(combined_level(&battery), None);
+
+      battery.left = state(80);
+      assert_eq!(combined_level(&battery), Some(80));
+
+      battery.right = state(60);
+      battery.case = state(10);
+      assert_eq!(combined_level(&battery), Some(60));
+
+      let mut max = BatteryInfo::new();
+      max.headphone = state(45);
+      assert_eq!(combined_level(&max), Some(45));
+   }
+}
@@ -0,0 +1,111 @@
+//! AAP connection state machine.
+//!
+//! The state of a device's AAP session only changes by feeding a
+//! [`ConnectionInput`] to [`ConnectionState::next`], which rejects inputs
+//! that make no sense in the current state. This keeps the manager and the
+//! device from ever disagreeing about which transitions are possible.
+
+use serde::Serialize;
+
+/// State of the AAP session with a device.
+#[derive(
+   Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, strum::Display, strum::IntoStaticStr,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum ConnectionState {
+   /// No AAP session; channel and tasks are torn down
+   #[default]
+   Disconnected,
+   /// Opening the L2CAP channel
+   Connecting,
+   /// Channel is open, exchanging the handshake and feature packets
+   Handshaking,
+   /// Session is established and packets are being processed
+   Connected,
+   /// Session failed and will be re-established once possible
+   Reconnecting,
+}
+
+/// Something that happened to an AAP session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionInput {
+   /// A connection attempt was started
+   Start,
+   /// The L2CAP channel was opened
+   ChannelOpened,
+   /// The handshake completed
+   HandshakeComplete,
+   /// The session failed while the device is still reachable
+   Lost,
+   /// The session was closed and its resources released
+   Closed,
+}
+
+impl ConnectionState {
+   pub fn to_str(self) -> &'static str {
+      self.into()
+   }
+
+   /// Returns the state after `input`, or `None` if the input is invalid here.
+   pub const fn next(self, input: ConnectionInput) -> Option<Self> {
+      match (self, input) {
+         (Self::Disconnected | Self::Reconnecting, ConnectionInput::Start) => {
+            Some(Self::Connecting)
+         },
+         (Self::Connecting, ConnectionInput::ChannelOpened) => Some(Self::Handshaking),
+         (Self::Handshaking, ConnectionInput::HandshakeComplete) => Some(Self::Connected),
+         (
+            Self::Connecting | Self::Handshaking | Self::Connected | Self::Reconnecting,
+            ConnectionInput::Lost,
+         ) => Some(Self::Reconnecting),
+         (_, ConnectionInput::Closed) => Some(Self::Disconnected),
+         _ => None,
+      }
+   }
+
+   /// Whether a session is established or being established.
+   pub const fn is_active(self) -> bool {
+      matches!(self, Self::Connecting | Self::Handshaking | Self::Connected)
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   #[test]
+   fn test_connection_lifecycle() {
+      use ConnectionInput::*;
+
+      let state = ConnectionState::default();
+      assert_eq!(state.next(ChannelOpened), None);
+      assert_eq!(state.next(Lost), None);
+
+      let state = state.next(Start).unwrap();
+      assert_eq!(state, ConnectionState::Connecting);
+      assert_eq!(state.next(HandshakeComplete), None);
+      let state = state.next(ChannelOpened).unwrap();
+      assert_eq!(state, ConnectionState::Handshaking);
+      let state = state.next(HandshakeComplete).unwrap();
+      assert_eq!(state, ConnectionState::Connected);
+      assert_eq!(state.next(Start), None);
+
+      // A failure waits to reconnect, then starts over
+      let state = state.next(Lost).unwrap();
+      assert_eq!(state, ConnectionState::Reconnecting);
+      assert!(!state.is_active());
+      assert_eq!(state.next(Start), Some(ConnectionState::Connecting));
+
+      // Closing is possible from everywhere
+      for state in [
+         ConnectionState::Disconnected,
+         ConnectionState::Connecting,
+         ConnectionState::Handshaking,
+         ConnectionState::Connected,
+         ConnectionState::Reconnecting,
+      ] {
+         assert_eq!(state.next(Closed), Some(ConnectionState::Disconnected));
+      }
+   }
+}
@@ -2,14 +2,20 @@
 //!
 //! This module provides async L2CAP socket handling with separate
 //! sender and receiver channels for communicating with `AirPods`.
+//!
+//! All outgoing packets go through a single writer task fed by
+//! [`L2CapSender`], so concurrent features (battery polling, noise control,
+//! renames, ...) can never interleave their writes on the channel. Each
+//! request carries a oneshot that reports the result of its write.
 
-use std::{sync::Arc, time::Duration};
+use std::{future::Future, io, sync::Arc, time::Duration};
 
 use bluer::{
    Address, AddressType,
    l2cap::{SeqPacket, Socket, SocketAddr},
 };
 use log::{debug, warn};
+use serde::Serialize;
 use smallvec::SmallVec;
 use tokio::{
    sync::{mpsc, oneshot},
@@ -37,18 +43,52 @@ enum Command {
    },
 }
 
+/// Parameters of an established L2CAP channel, for diagnostics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct LinkInfo {
+   pub psm: u16,
+   /// Negotiated MTU for sending, if the kernel reports it
+   pub send_mtu: Option<usize>,
+   /// Negotiated MTU for receiving, if the kernel reports it
+   pub recv_mtu: Option<usize>,
+   /// Size of our receive buffer; longer packets are truncated
+   pub recv_buffer: usize,
+}
+
+impl LinkInfo {
+   fn query(sp: &SeqPacket, psm: u16) -> Self {
+      Self {
+         psm,
+         send_mtu: sp.send_mtu().ok(),
+         recv_mtu: sp.recv_mtu().ok(),
+         recv_buffer: L2CAP_MTU,
+      }
+   }
+
+   /// Whether the device may send packets that don't fit our receive buffer.
+   pub fn may_truncate(&self) -> bool {
+      self.recv_mtu.is_some_and(|mtu| mtu > self.recv_buffer)
+   }
+}
+
 /// Receiver half of an L2CAP connection.
 ///
 /// Provides async packet reception from the `AirPods` device.
 #[derive(Debug)]
 pub struct L2CapReceiver {
    rx: mpsc::Receiver<Result<Packet>>,
+   link: LinkInfo,
 }
 
 impl L2CapReceiver {
    pub async fn recv(&mut self) -> Result<Packet> {
       self.rx.recv().await.ok_or(AirPodsError::ConnectionClosed)?
    }
+
+   /// Gets the parameters negotiated for the channel.
+   pub const fn link_info(&self) -> LinkInfo {
+      self.link
+   }
 }
 
 /// Sender half of an L2CAP connection.
@@ -87,6 +127,17 @@ impl L2CapSender {
    }
 }
 
+/// Outgoing side of the transport the writer task owns.
+trait PacketWriter: Send + Sync + 'static {
+   fn write(&self, data: &[u8]) -> impl Future<Output = io::Result<usize>> + Send;
+}
+
+impl PacketWriter for SeqPacket {
+   fn write(&self, data: &[u8]) -> impl Future<Output = io::Result<usize>> + Send {
+      self.send(data)
+   }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum HookDisposition {
    Discard,
@@ -97,6 +148,12 @@ pub struct Hooks {
    hooks: Vec<Hook>,
 }
 
+impl Default for Hooks {
+   fn default() -> Self {
+      Self::new()
+   }
+}
+
 impl Hooks {
    pub const fn new() -> Self {
       Self { hooks: Vec::new() }
@@ -177,6 +234,15 @@ pub async fn connect(
       .await
       .map_err(|_| AirPodsError::RequestTimeout)??;
 
+   let link = LinkInfo::query(&seq_packet, psm);
+   debug!("{address}: L2CAP link {link:?}");
+   if link.may_truncate() {
+      warn!(
+         "{address}: Receive MTU {:?} exceeds the {L2CAP_MTU} byte buffer, long packets will be truncated",
+         link.recv_mtu
+      );
+   }
+
    let (cmd_tx, cmd_rx) = mpsc::channel(128);
    let (in_tx, in_rx) = mpsc::channel(128);
 
@@ -184,7 +250,10 @@ pub async fn connect(
    jset.spawn(recv_thread(address, in_tx, seq_packet.clone(), hooks));
    jset.spawn(send_thread(address, cmd_rx, seq_packet));
 
-   Ok((L2CapReceiver { rx: in_rx }, L2CapSender { tx: cmd_tx }))
+   Ok((
+      L2CapReceiver { rx: in_rx, link },
+      L2CapSender { tx: cmd_tx },
+   ))
 }
 
 async fn recv_thread(
@@ -201,7 +270,7 @@ async fn recv_thread(
          return;
       }
       let recvd = &stack[..n];
-      debug!("← {adr}: {}", hex::encode(recvd));
+      debug!(address:% = adr, direction = "in"; "← {adr}: {}", hex::encode(recvd));
       let bytes = Packet::from_slice(recvd);
       hooks.passthrough(&bytes);
       if let Err(e) = tx.send(Ok(bytes)).await {
@@ -212,12 +281,12 @@ async fn recv_thread(
    }
 }
 
-async fn send_thread(adr: Address, mut rx: mpsc::Receiver<Command>, sp: Arc<SeqPacket>) {
+async fn send_thread(adr: Address, mut rx: mpsc::Receiver<Command>, sp: Arc<impl PacketWriter>) {
    while let Some(cmd) = rx.recv().await {
       match cmd {
          Command::Send { data, then } => {
-            debug!("→ {adr}: {}", hex::encode(&data));
-            if let Err(e) = sp.send(&data).await {
+            debug!(address:% = adr, direction = "out"; "→ {adr}: {}", hex::encode(&data));
+            if let Err(e) = sp.write(&data).await {
                warn!("Failed to send data: {e}");
                let _ = then.send(Err(AirPodsError::Io(e)));
             } else {
@@ -228,3 +297,111 @@ async fn send_thread(adr: Address, mut rx: mpsc::Receiver<Command>, sp: Arc<SeqP
    }
    warn!("User shutdown");
 }
+
+/// Sender that records packets instead of sending them, for tests.
+#[cfg(test)]
+pub fn recording_sender() -> (L2CapSender, Arc<parking_lot::Mutex<Vec<Packet>>>) {
+   struct Recorder(Arc<parking_lot::Mutex<Vec<Packet>>>);
+
+   impl PacketWriter for Recorder {
+      async fn write(&self, data: &[u8]) -> io::Result<usize> {
+         self.0.lock().push(Packet::from_slice(data));
+         Ok(data.len())
+      }
+   }
+
+   let sent = Arc::default();
+   let (tx, rx) = mpsc::channel(128);
+   tokio::spawn(send_thread(
+      Address::any(),
+      rx,
+      Arc::new(Recorder(Arc::clone(&sent))),
+   ));
+   (L2CapSender { tx }, sent)
+}
+
+#[cfg(test)]
+mod tests {
+   use std::sync::atomic::{AtomicUsize, Ordering};
+
+   use super::*;
+
+   #[test]
+   fn test_link_info_truncation() {
+      let link = |recv_mtu| LinkInfo {
+         psm: PSM_CONTROL,
+         send_mtu: Some(672),
+         recv_mtu,
+         recv_buffer: L2CAP_MTU,
+      };
+      assert!(!link(None).may_truncate());
+      assert!(!link(Some(L2CAP_MTU)).may_truncate());
+      assert!(link(Some(1021)).may_truncate());
+   }
+
+   /// Transport that writes byte by byte, yielding in between, so that
+   /// overlapping writes would interleave their bytes.
+   #[derive(Default)]
+   struct FakeTransport {
+      stream: parking_lot::Mutex<Vec<u8>>,
+      in_flight: AtomicUsize,
+      max_in_flight: AtomicUsize,
+   }
+
+   impl PacketWriter for FakeTransport {
+      async fn write(&self, data: &[u8]) -> io::Result<usize> {
+         let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+         self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+         for &byte in data {
+            self.stream.lock().push(byte);
+            tokio::task::yield_now().await;
+         }
+         self.in_flight.fetch_sub(1, Ordering::SeqCst);
+         Ok(data.len())
+      }
+   }
+
+   #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+   async fn test_concurrent_writes_keep_framing() {
+      const WRITERS: u8 = 8;
+      const PACKETS: u8 = 25;
+
+      let transport = Arc::new(FakeTransport::default());
+      let (tx, rx) = mpsc::channel(128);
+      let writer = tokio::spawn(send_thread(Address::any(), rx, transport.clone()));
+      let sender = L2CapSender { tx };
+
+      let mut tasks = JoinSet::new();
+      for id in 0..WRITERS {
+         let sender = sender.clone();
+         tasks.spawn(async move {
+            for seq in 0..PACKETS {
+               // Length-prefixed frame filled with the writer id
+               let len = 3 + usize::from(seq % 7);
+               let mut packet = vec![len as u8, id, seq];
+               packet.resize(len, id);
+               sender.send(&packet).await.unwrap();
+            }
+         });
+      }
+      tasks.join_all().await;
+      drop(sender);
+      writer.await.unwrap();
+
+      assert_eq!(transport.max_in_flight.load(Ordering::SeqCst), 1);
+
+      // Every frame must be intact and each writer's frames in order
+      let stream = transport.stream.lock();
+      let mut next_seq = [0u8; WRITERS as usize];
+      let mut rest = stream.as_slice();
+      while let Some(&len) = rest.first() {
+         let (frame, tail) = rest.split_at(usize::from(len));
+         let id = frame[1];
+         assert_eq!(frame[2], next_seq[usize::from(id)]);
+         assert!(frame[3..].iter().all(|&b| b == id));
+         next_seq[usize::from(id)] += 1;
+         rest = tail;
+      }
+      assert!(next_seq.iter().all(|&n| n == PACKETS));
+   }
+}
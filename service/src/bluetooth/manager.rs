@@ -5,11 +5,17 @@
 
 use std::{
    collections::{HashMap, HashSet},
-   time::Duration,
+   time::{Duration, Instant},
 };
 
-use bluer::{Adapter, AdapterEvent, Address, Session};
-use futures::stream::StreamExt;
+use bluer::{
+   Adapter, AdapterEvent, Address, DeviceEvent, DeviceProperty, Session,
+   monitor::{Monitor, MonitorEvent, Pattern, RssiSamplingPeriod, Type, data_type},
+};
+use futures::{
+   future,
+   stream::{AbortHandle, Abortable, SelectAll, StreamExt},
+};
 use log::{debug, error, info, warn};
 use smol_str::SmolStr;
 use tokio::{
@@ -20,11 +26,19 @@ use tokio::{
 };
 
 use crate::{
-   airpods::{self, device::AirPods},
+   airpods::{
+      self,
+      device::{AirPods, BatteryThrottle, WeakAirPods},
+      model::AirPodsModel,
+      parser::{APPLE_COMPANY_ID, PROXIMITY_PAIRING},
+      signal::SignalMonitor,
+   },
    battery_study::BatteryStudy,
+   bluetooth::connection::{ConnectionInput, ConnectionState},
    config::Config,
    error::{AirPodsError, Result},
    event::{AirPodsEvent, EventSender},
+   noise_profile,
 };
 use rand::Rng;
 
@@ -42,6 +56,10 @@ const MAX_AAP_RETRY_DELAY: Duration = Duration::from_secs(120);
 const DEVICE_TICK_INTERVAL: Duration = Duration::from_secs(10);
 /// Channel buffer size
 const CHANNEL_BUFFER_SIZE: usize = 1000;
+/// Leading bytes of proximity pairing manufacturer data (company ID, message type)
+const PROXIMITY_PAIRING_PREFIX: [u8; 3] = [0x4c, 0x00, PROXIMITY_PAIRING];
+/// Period over which `BlueZ` averages the RSSI of proximity advertisements
+const PROXIMITY_RSSI_PERIOD: Duration = Duration::from_secs(1);
 
 // === Adapter Management ===
 
@@ -68,23 +86,94 @@ enum BluetoothState {
    Disconnected,
 }
 
-#[derive(Debug, Copy, Clone, PartialEq)]
-enum AAPState {
-   Disconnected,
-   Connecting,
-   Connected,
-   Failed(&'static str),
-   WaitingToReconnect,
-}
-
 struct ManagedDevice {
    device: AirPods,
    bluetooth_state: BluetoothState,
-   aap_state: AAPState,
    adapter_name: SmolStr,
    aap_retry_count: u32,
    last_aap_error: Option<String>,
    aap_handle: Option<JoinHandle<()>>,
+   /// When a dropped connection is reported as disconnected unless it recovers
+   disconnect_deadline: Option<Instant>,
+}
+
+impl ManagedDevice {
+   /// Records a Bluetooth connect signal, returning whether it starts a new
+   /// logical connection.
+   ///
+   /// `BlueZ` may report `Connected=true` several times for one connection (once
+   /// per profile, then for the device), so only the first signal after a
+   /// disconnect runs the connect pipeline.
+   fn mark_bluetooth_connected(&mut self) -> bool {
+      let is_new = self.bluetooth_state == BluetoothState::Disconnected;
+      self.bluetooth_state = BluetoothState::Connected;
+      is_new
+   }
+
+   fn connection_state(&self) -> ConnectionState {
+      self.device.connection_state()
+   }
+
+   /// Starts the grace period of a dropped connection, returning whether it
+   /// wasn't running yet.
+   fn begin_grace(&mut self, now: Instant, grace: Duration) -> bool {
+      if self.disconnect_deadline.is_some() {
+         return false;
+      }
+      self.disconnect_deadline = Some(now + grace);
+      true
+   }
+
+   /// Ends the grace period once the connection is back, returning whether
+   /// it was running, in which case the disconnect was never reported.
+   const fn end_grace(&mut self) -> bool {
+      self.disconnect_deadline.take().is_some()
+   }
+
+   /// Checks whether the grace period ran out, ending it if so.
+   fn grace_expired(&mut self, now: Instant) -> bool {
+      if self
+         .disconnect_deadline
+         .is_some_and(|deadline| deadline <= now)
+      {
+         self.disconnect_deadline = None;
+         return true;
+      }
+      false
+   }
+
+   /// Reports the device as disconnected and forgets its session state.
+   fn finish_disconnect(&self, event_tx: &EventSender) {
+      self.device.clear_session_state();
+      event_tx.emit(&self.device, AirPodsEvent::DeviceDisconnected);
+   }
+
+   /// Tears down the AAP session and marks it disconnected.
+   ///
+   /// This is the only way a device becomes [`ConnectionState::Disconnected`],
+   /// so a disconnected device never has a channel or tasks left behind.
+   async fn close(&mut self, event_tx: &EventSender) {
+      if let Some(handle) = self.aap_handle.take() {
+         handle.abort();
+      }
+      self.device.disconnect().await;
+      self.aap_retry_count = 0;
+      self
+         .device
+         .advance_connection(ConnectionInput::Closed, event_tx);
+   }
+
+   /// Tears down a failed AAP session that should be re-established later.
+   async fn fail(&mut self, reason: String, event_tx: &EventSender) {
+      if let Some(handle) = self.aap_handle.take() {
+         handle.abort();
+      }
+      self.device.disconnect().await;
+      self.last_aap_error = Some(reason);
+      self
+         .device
+         .advance_connection(ConnectionInput::Lost, event_tx);
+   }
 }
 
 // === Commands ===
@@ -103,13 +192,19 @@ enum ManagerCommand {
    AAPConnected(Address),
    AAPDisconnected(Address, bool), // address, is_error
    DeviceLost(Address),
+   DisconnectGraceExpired(Address),
+   ProximityAdvertisement(Vec<u8>, Option<i16>), // apple_data, rssi
 
    // User commands
    EstablishAAP(Address, Option<oneshot::Sender<Result<()>>>),
    DisconnectAAP(Address, Option<oneshot::Sender<Result<()>>>),
+   Reinitialize(Address, oneshot::Sender<Result<()>>),
    GetDeviceState(Address, oneshot::Sender<Option<AirPods>>),
+   ResolveDevice(String, oneshot::Sender<Result<AirPods>>),
    GetAllDeviceStates(oneshot::Sender<Vec<AirPods>>),
    CountDevices(oneshot::Sender<u32>),
+   #[cfg(any(debug_assertions, feature = "event-injection"))]
+   InjectEvent(Address, AirPodsEvent, oneshot::Sender<Result<bool>>),
 }
 
 // === Main Manager ===
@@ -117,7 +212,8 @@ enum ManagerCommand {
 /// Main Bluetooth manager that handles device discovery and connections.
 ///
 /// This type provides a high-level interface for managing `AirPods` devices
-/// across all available Bluetooth adapters.
+/// across all available Bluetooth adapters. This type is cheaply cloneable.
+#[derive(Clone)]
 pub struct BluetoothManager {
    inbox: mpsc::Sender<ManagerCommand>,
 }
@@ -157,6 +253,17 @@ impl BluetoothManager {
       rx.await.map_err(|_| AirPodsError::ManagerShutdown)?
    }
 
+   /// Re-runs the handshake with a connected device without reconnecting.
+   pub async fn reinitialize(&self, address: Address) -> Result<()> {
+      let (tx, rx) = oneshot::channel();
+      self
+         .inbox
+         .send(ManagerCommand::Reinitialize(address, tx))
+         .await
+         .map_err(|_| AirPodsError::ManagerShutdown)?;
+      rx.await.map_err(|_| AirPodsError::ManagerShutdown)?
+   }
+
    pub async fn get_device(&self, address: Address) -> Result<AirPods> {
       let (tx, rx) = oneshot::channel();
       self
@@ -171,6 +278,20 @@ impl BluetoothManager {
          .ok_or(AirPodsError::DeviceNotFound(address))
    }
 
+   /// Finds a device by its MAC address or friendly name.
+   ///
+   /// Names match the `BlueZ` alias or the name reported by the device,
+   /// ignoring case. Fails if no device or more than one device matches.
+   pub async fn resolve_device(&self, name_or_addr: &str) -> Result<AirPods> {
+      let (tx, rx) = oneshot::channel();
+      self
+         .inbox
+         .send(ManagerCommand::ResolveDevice(name_or_addr.to_string(), tx))
+         .await
+         .map_err(|_| AirPodsError::ManagerShutdown)?;
+      rx.await.map_err(|_| AirPodsError::ManagerShutdown)?
+   }
+
    pub async fn all_devices(&self) -> Vec<AirPods> {
       let (tx, rx) = oneshot::channel();
       if self
@@ -196,6 +317,20 @@ impl BluetoothManager {
       }
       rx.await.unwrap_or_default()
    }
+
+   /// Feeds a synthetic event for a device, simulating the device if it isn't known.
+   ///
+   /// Returns whether the device state changed.
+   #[cfg(any(debug_assertions, feature = "event-injection"))]
+   pub async fn inject_event(&self, address: Address, event: AirPodsEvent) -> Result<bool> {
+      let (tx, rx) = oneshot::channel();
+      self
+         .inbox
+         .send(ManagerCommand::InjectEvent(address, event, tx))
+         .await
+         .map_err(|_| AirPodsError::ManagerShutdown)?;
+      rx.await.map_err(|_| AirPodsError::ManagerShutdown)?
+   }
 }
 
 // === Manager Actor ===
@@ -203,6 +338,8 @@ impl BluetoothManager {
 struct ManagerActor {
    config: Config,
    event_tx: EventSender,
+   /// How long dropped connections may take to recover before being reported
+   disconnect_grace: Duration,
    command_rx: mpsc::Receiver<ManagerCommand>,
    loopback_rx: mpsc::Receiver<ManagerCommand>,
    loopback_tx: mpsc::Sender<ManagerCommand>,
@@ -212,7 +349,8 @@ struct ManagerActor {
    // State
    adapters: HashMap<SmolStr, AdapterInfo>,
    devices: HashMap<Address, ManagedDevice>,
-   aap_connecting: HashSet<Address>, // Prevent duplicate AAP connections
+   /// Recognized devices waiting to be connected by the system
+   connect_watches: HashSet<Address>,
 }
 
 impl ManagerActor {
@@ -228,6 +366,7 @@ impl ManagerActor {
 
       let (loopback_tx, loopback_rx) = mpsc::channel(CHANNEL_BUFFER_SIZE);
       Self {
+         disconnect_grace: Duration::from_secs(config.disconnect_grace_secs),
          config,
          event_tx,
          command_rx,
@@ -237,7 +376,7 @@ impl ManagerActor {
          battery_study,
          adapters: HashMap::new(),
          devices: HashMap::new(),
-         aap_connecting: HashSet::new(),
+         connect_watches: HashSet::new(),
       }
    }
 
@@ -382,22 +521,34 @@ impl ManagerActor {
             return;
          };
 
-         while let Some(event) = events.next().await {
-            match event {
-               AdapterEvent::DeviceAdded(addr) => {
-                  debug!("Device added on {name}: {addr}");
-                  let _ = loopback
-                     .send(ManagerCommand::DeviceDiscovered(addr, name.clone()))
-                     .await;
-               },
-               AdapterEvent::DeviceRemoved(addr) => {
-                  debug!("Device removed on {name}: {addr}");
-                  let _ = loopback.send(ManagerCommand::DeviceLost(addr)).await;
-               },
-               // Note: bluer doesn't provide DeviceConnected/Disconnected events
-               // We'll detect connection changes through periodic scanning
-               _ => {},
+         let adapter_events = async {
+            while let Some(event) = events.next().await {
+               match event {
+                  AdapterEvent::DeviceAdded(addr) => {
+                     debug!("Device added on {name}: {addr}");
+                     let _ = loopback
+                        .send(ManagerCommand::DeviceDiscovered(addr, name.clone()))
+                        .await;
+                  },
+                  AdapterEvent::DeviceRemoved(addr) => {
+                     debug!("Device removed on {name}: {addr}");
+                     let _ = loopback.send(ManagerCommand::DeviceLost(addr)).await;
+                  },
+                  // Note: bluer doesn't provide DeviceConnected/Disconnected events.
+                  // Recognized devices are watched until connected, everything
+                  // else is picked up by periodic scanning
+                  _ => {},
+               }
             }
+         };
+         // Advertisements are followed for as long as the adapter is around
+         let proximity = async {
+            Self::watch_proximity(&adapter, &name, &loopback).await;
+            future::pending::<()>().await;
+         };
+         select! {
+            () = adapter_events => {},
+            () = proximity => {},
          }
 
          // If we exit the event loop, adapter is probably gone
@@ -407,6 +558,193 @@ impl ManagerActor {
       })
    }
 
+   /// Follows the proximity pairing advertisements of nearby `AirPods`.
+   ///
+   /// `BlueZ` doesn't refresh the manufacturer data or RSSI of a paired device
+   /// outside of discovery, and `AirPods` advertise from a rotating private
+   /// address anyway. A passive advertisement monitor sees those adverts, which
+   /// are forwarded to the manager to be matched to a connected device.
+   /// Returns right away if the monitor can't be registered.
+   async fn watch_proximity(
+      adapter: &Adapter,
+      name: &SmolStr,
+      loopback: &mpsc::Sender<ManagerCommand>,
+   ) {
+      let monitor = Monitor {
+         monitor_type: Type::OrPatterns,
+         patterns: Some(vec![Pattern::new(
+            data_type::MANUFACTURER_SPECIFIC_DATA,
+            0,
+            &PROXIMITY_PAIRING_PREFIX,
+         )]),
+         rssi_sampling_period: Some(RssiSamplingPeriod::Period(PROXIMITY_RSSI_PERIOD)),
+         ..Default::default()
+      };
+      // Dropping either the manager or the handle unregisters the monitor
+      let registered = match adapter.monitor().await {
+         Ok(manager) => manager
+            .register(monitor)
+            .await
+            .map(|handle| (manager, handle)),
+         Err(e) => Err(e),
+      };
+      let (_manager, mut found) = match registered {
+         Ok(registered) => registered,
+         Err(e) => {
+            warn!(
+               "Cannot monitor advertisements on {name}, case lid state and signal strength \
+                won't update: {e}"
+            );
+            return;
+         },
+      };
+      debug!("Monitoring proximity advertisements on {name}");
+
+      // Latest Apple manufacturer data of every advertiser in range
+      let mut advertisers: HashMap<Address, (AbortHandle, Vec<u8>)> = HashMap::new();
+      let mut updates = SelectAll::new();
+      loop {
+         select! {
+            event = found.next() => match event {
+               Some(MonitorEvent::DeviceFound(id)) => {
+                  let addr = id.device;
+                  let Ok(device) = adapter.device(addr) else {
+                     continue;
+                  };
+                  let Ok(events) = device.events().await else {
+                     continue;
+                  };
+                  let (abort, registration) = AbortHandle::new_pair();
+                  updates.push(Abortable::new(
+                     events.map(move |event| (addr, event)),
+                     registration,
+                  ));
+
+                  let data = device
+                     .manufacturer_data()
+                     .await
+                     .ok()
+                     .flatten()
+                     .and_then(|mut data| data.remove(&APPLE_COMPANY_ID))
+                     .unwrap_or_default();
+                  let rssi = device.rssi().await.ok().flatten();
+                  if let Some((stale, _)) = advertisers.insert(addr, (abort, data.clone())) {
+                     stale.abort();
+                  }
+                  let _ = loopback
+                     .send(ManagerCommand::ProximityAdvertisement(data, rssi))
+                     .await;
+               },
+               Some(MonitorEvent::DeviceLost(id)) => {
+                  if let Some((abort, _)) = advertisers.remove(&id.device) {
+                     abort.abort();
+                  }
+               },
+               Some(_) => {},
+               None => {
+                  debug!("Advertisement monitor on {name} was released");
+                  return;
+               },
+            },
+            Some((addr, DeviceEvent::PropertyChanged(property))) = updates.next(),
+               if !updates.is_empty() =>
+            {
+               let Some((_, data)) = advertisers.get_mut(&addr) else {
+                  continue;
+               };
+               let rssi = match property {
+                  DeviceProperty::ManufacturerData(mut mfg) => {
+                     let Some(apple) = mfg.remove(&APPLE_COMPANY_ID) else {
+                        continue;
+                     };
+                     *data = apple;
+                     None
+                  },
+                  DeviceProperty::Rssi(rssi) => Some(rssi),
+                  _ => continue,
+               };
+               let _ = loopback
+                  .send(ManagerCommand::ProximityAdvertisement(data.clone(), rssi))
+                  .await;
+            },
+         }
+      }
+   }
+
+   /// Rediscovers a recognized device as soon as the system connects it,
+   /// instead of waiting for the next poll.
+   ///
+   /// The task exits once the device connects or goes away.
+   fn start_connect_watcher(
+      device: bluer::Device,
+      adapter_name: SmolStr,
+      loopback: mpsc::Sender<ManagerCommand>,
+   ) {
+      tokio::spawn(async move {
+         let Ok(mut events) = device.events().await else {
+            return;
+         };
+         while let Some(DeviceEvent::PropertyChanged(property)) = events.next().await {
+            if matches!(property, DeviceProperty::Connected(true)) {
+               debug!("Recognized device {} was connected", device.address());
+               let _ = loopback
+                  .send(ManagerCommand::DeviceDiscovered(
+                     device.address(),
+                     adapter_name,
+                  ))
+                  .await;
+               return;
+            }
+         }
+      });
+   }
+
+   /// Forwards BLE advertisement updates of a device (e.g. case lid state and RSSI).
+   ///
+   /// The task exits when the device goes away or its stream of `BlueZ` events ends.
+   fn start_advertisement_monitor(
+      device: bluer::Device,
+      airpods: WeakAirPods,
+      event_tx: EventSender,
+   ) {
+      tokio::spawn(async move {
+         if let Some(airpods) = airpods.upgrade() {
+            airpods.update_rssi(device.rssi().await.ok().flatten());
+            if let Ok(Some(data)) = device.manufacturer_data().await
+               && let Some(apple) = data.get(&APPLE_COMPANY_ID)
+            {
+               airpods.process_advertisement(apple, &event_tx);
+            }
+         }
+
+         let Ok(mut events) = device.events().await else {
+            return;
+         };
+         while let Some(DeviceEvent::PropertyChanged(property)) = events.next().await {
+            let Some(airpods) = airpods.upgrade() else {
+               break;
+            };
+            match property {
+               // Only set from inquiries and adverts, never for the established link,
+               // so the signal monitor is fed by proximity advertisements instead
+               DeviceProperty::Rssi(rssi) => airpods.update_rssi(Some(rssi)),
+               // BlueZ may only learn the product ID once the device is connected
+               DeviceProperty::Modalias(modalias) => {
+                  if let Some(model) = airpods::recognition::model_from_modalias(&modalias) {
+                     airpods.identify_model(model, &event_tx);
+                  }
+               },
+               DeviceProperty::ManufacturerData(data) => {
+                  if let Some(apple) = data.get(&APPLE_COMPANY_ID) {
+                     airpods.process_advertisement(apple, &event_tx);
+                  }
+               },
+               _ => {},
+            }
+         }
+      });
+   }
+
    async fn check_connected_devices(&self, adapter_name: &SmolStr) {
       let Some(adapter_info) = self.adapters.get(adapter_name) else {
          return;
@@ -437,7 +775,7 @@ impl ManagerActor {
       if self.config.is_known_device(&addr.to_string()).is_some() {
          return true;
       }
-      airpods::recognition::is_device_airpods(device).await
+      airpods::recognition::is_device_airpods(device, &self.config.recognition).await
    }
 
    async fn handle_command(&mut self, cmd: ManagerCommand) -> bool {
@@ -446,7 +784,7 @@ impl ManagerActor {
             self.handle_adapter_available(name, adapter).await;
          },
          ManagerCommand::AdapterLost(name) => {
-            self.handle_adapter_lost(name);
+            self.handle_adapter_lost(name).await;
          },
          ManagerCommand::AdapterError(name, error) => {
             self.handle_adapter_error(&name, error);
@@ -458,16 +796,22 @@ impl ManagerActor {
             self.handle_bluetooth_connected(addr).await;
          },
          ManagerCommand::BluetoothDisconnected(addr) => {
-            self.handle_bluetooth_disconnected(addr);
+            self.handle_bluetooth_disconnected(addr).await;
          },
          ManagerCommand::AAPConnected(addr) => {
             self.handle_aap_connected(addr);
          },
          ManagerCommand::AAPDisconnected(addr, is_error) => {
-            self.handle_aap_disconnected(addr, is_error);
+            self.handle_aap_disconnected(addr, is_error).await;
          },
          ManagerCommand::DeviceLost(addr) => {
-            self.handle_device_lost(addr);
+            self.handle_device_lost(addr).await;
+         },
+         ManagerCommand::DisconnectGraceExpired(addr) => {
+            self.handle_disconnect_grace_expired(addr).await;
+         },
+         ManagerCommand::ProximityAdvertisement(data, rssi) => {
+            self.handle_proximity_advertisement(&data, rssi);
          },
          ManagerCommand::EstablishAAP(addr, reply) => {
             let result = self.establish_aap_connection(addr).await;
@@ -481,10 +825,25 @@ impl ManagerActor {
                let _ = reply.send(result);
             }
          },
+         ManagerCommand::Reinitialize(addr, reply) => {
+            let Some(managed) = self.devices.get(&addr) else {
+               let _ = reply.send(Err(AirPodsError::DeviceNotFound(addr)));
+               return true;
+            };
+            // Waiting for the answer must not hold up the actor
+            let device = managed.device.clone();
+            let event_tx = self.event_tx.clone();
+            tokio::spawn(async move {
+               let _ = reply.send(device.reinitialize(&event_tx).await);
+            });
+         },
          ManagerCommand::GetDeviceState(addr, reply) => {
             let state = self.devices.get(&addr).map(|d| d.device.clone());
             let _ = reply.send(state);
          },
+         ManagerCommand::ResolveDevice(query, reply) => {
+            let _ = reply.send(self.resolve_device(&query).await);
+         },
          ManagerCommand::GetAllDeviceStates(reply) => {
             let states = self.devices.values().map(|d| d.device.clone()).collect();
             let _ = reply.send(states);
@@ -493,10 +852,31 @@ impl ManagerActor {
             let count = self.devices.len() as u32;
             let _ = reply.send(count);
          },
+         #[cfg(any(debug_assertions, feature = "event-injection"))]
+         ManagerCommand::InjectEvent(addr, event, reply) => {
+            let _ = reply.send(self.inject_event(addr, event));
+         },
       }
       true
    }
 
+   #[cfg(any(debug_assertions, feature = "event-injection"))]
+   fn inject_event(&mut self, addr: Address, event: AirPodsEvent) -> Result<bool> {
+      let managed = self.devices.entry(addr).or_insert_with(|| {
+         info!("Simulating AirPods at {addr}");
+         ManagedDevice {
+            device: AirPods::new(addr, "Simulated AirPods".to_string(), None),
+            bluetooth_state: BluetoothState::Disconnected,
+            adapter_name: SmolStr::default(),
+            aap_retry_count: 0,
+            last_aap_error: None,
+            aap_handle: None,
+            disconnect_deadline: None,
+         }
+      });
+      managed.device.inject_event(event, &self.event_tx)
+   }
+
    async fn handle_adapter_available(&mut self, name: SmolStr, adapter: Adapter) {
       info!("Adapter available: {name}");
 
@@ -524,7 +904,7 @@ impl ManagerActor {
             .filter(|(_, d)| {
                d.adapter_name == name
                   && d.bluetooth_state == BluetoothState::Connected
-                  && matches!(d.aap_state, AAPState::Failed(_) | AAPState::Disconnected)
+                  && !d.connection_state().is_active()
             })
             .map(|(addr, _)| *addr)
             .collect();
@@ -537,7 +917,7 @@ impl ManagerActor {
       }
    }
 
-   fn handle_adapter_lost(&mut self, name: SmolStr) {
+   async fn handle_adapter_lost(&mut self, name: SmolStr) {
       warn!("Adapter lost: {name}");
 
       if let Some(info) = self.adapters.get_mut(&name) {
@@ -552,11 +932,9 @@ impl ManagerActor {
          // Mark all AAP connections on this adapter as failed
          for device in self.devices.values_mut() {
             if device.adapter_name == name {
-               device.aap_state = AAPState::Failed("Adapter lost");
-               // Abort AAP handle if it exists
-               if let Some(handle) = device.aap_handle.take() {
-                  handle.abort();
-               }
+               device
+                  .fail("Adapter lost".to_string(), &self.event_tx)
+                  .await;
                self
                   .event_tx
                   .emit(&device.device, AirPodsEvent::DeviceError);
@@ -599,6 +977,21 @@ impl ManagerActor {
       }
    }
 
+   fn handle_proximity_advertisement(&self, data: &[u8], rssi: Option<i16>) {
+      let Some(model) = airpods::recognition::model_from_advertisement(data) else {
+         return;
+      };
+      let Some(managed) = advert_owner(model, self.devices.values()) else {
+         return;
+      };
+      // The signal strength tells whether a lid opening is nearby, and is the
+      // only reading of a connected device's signal that BlueZ keeps current
+      if let Some(rssi) = rssi {
+         managed.device.process_rssi(rssi, &self.event_tx);
+      }
+      managed.device.process_advertisement(data, &self.event_tx);
+   }
+
    async fn handle_device_discovered(&mut self, addr: Address, adapter_name: SmolStr) {
       // Check if we already know about this device
       if self.devices.contains_key(&addr) {
@@ -621,8 +1014,12 @@ impl ManagerActor {
       // Only proceed if already connected by bluetoothd
       if !device.is_connected().await.unwrap_or(false) {
          debug!("Discovered AirPods at {addr} but not connected by system");
+         if self.connect_watches.insert(addr) {
+            Self::start_connect_watcher(device, adapter_name, self.loopback_tx.clone());
+         }
          return;
       }
+      self.connect_watches.remove(&addr);
 
       let name = device
          .name()
@@ -634,14 +1031,34 @@ impl ManagerActor {
 
       // Create managed device
       let airpods = AirPods::new(addr, name, self.battery_study.clone());
+      airpods.set_model(airpods::recognition::detect_model(&device).await);
+      airpods.set_noise_mode_queue_timeout(Duration::from_secs(self.config.noise_mode_queue_secs));
+      airpods.set_battery_throttle(BatteryThrottle {
+         min_change: self.config.battery_min_change,
+         interval: Duration::from_secs(self.config.battery_event_interval_secs),
+      });
+      if self.config.peek.enabled {
+         airpods.set_peek_detection(
+            self.config.peek.min_rssi,
+            Duration::from_secs(self.config.peek.debounce_secs),
+         );
+      }
+      if self.config.signal.enabled {
+         airpods.set_signal_monitor(SignalMonitor::new(
+            self.config.signal.min_rssi,
+            Duration::from_secs(self.config.signal.duration_secs),
+            self.config.signal.hysteresis_db,
+         ));
+      }
+      Self::start_advertisement_monitor(device, WeakAirPods::new(&airpods), self.event_tx.clone());
       let managed = ManagedDevice {
          device: airpods,
          bluetooth_state: BluetoothState::Connected,
-         aap_state: AAPState::Disconnected,
          adapter_name,
          aap_retry_count: 0,
          last_aap_error: None,
          aap_handle: None,
+         disconnect_deadline: None,
       };
 
       self.devices.insert(addr, managed);
@@ -652,9 +1069,8 @@ impl ManagerActor {
 
    async fn handle_bluetooth_connected(&mut self, addr: Address) {
       // Check if this is an AirPods device
-      let is_airpods = if let Some(device) = self.devices.get_mut(&addr) {
-         device.bluetooth_state = BluetoothState::Connected;
-         true
+      let is_new_connection = if let Some(device) = self.devices.get_mut(&addr) {
+         device.mark_bluetooth_connected()
       } else {
          // Check if this is a newly connected AirPods
          for (adapter_name, adapter_info) in &self.adapters {
@@ -672,91 +1088,149 @@ impl ManagerActor {
          false
       };
 
-      if is_airpods {
+      if is_new_connection {
          // Automatically establish AAP connection
          let _ = self.establish_aap_connection(addr).await;
       }
    }
 
-   fn handle_bluetooth_disconnected(&mut self, addr: Address) {
+   async fn handle_bluetooth_disconnected(&mut self, addr: Address) {
       if let Some(device) = self.devices.get_mut(&addr) {
          device.bluetooth_state = BluetoothState::Disconnected;
 
-         // Clean up AAP connection
-         if let Some(handle) = device.aap_handle.take() {
-            handle.abort();
+         // Clean up AAP connection, waiting for BlueZ to reconnect during the grace period
+         if self.disconnect_grace.is_zero() {
+            device.close(&self.event_tx).await;
+         } else {
+            device
+               .fail("Bluetooth disconnected".to_string(), &self.event_tx)
+               .await;
          }
-         device.aap_state = AAPState::Disconnected;
+         self.defer_disconnect(addr);
+      }
+   }
 
-         self
-            .event_tx
-            .emit(&device.device, AirPodsEvent::DeviceDisconnected);
+   /// Reports a dropped connection as disconnected once the grace period
+   /// passes without it recovering.
+   fn defer_disconnect(&mut self, addr: Address) {
+      let Some(device) = self.devices.get_mut(&addr) else {
+         return;
+      };
+      let grace = self.disconnect_grace;
+      if grace.is_zero() {
+         device.finish_disconnect(&self.event_tx);
+         return;
+      }
+      if device.begin_grace(Instant::now(), grace) {
+         info!("Connection to {addr} dropped, waiting {grace:?} for it to recover");
+         let loopback = self.loopback_tx.clone();
+         tokio::spawn(async move {
+            time::sleep(grace).await;
+            let _ = loopback
+               .send(ManagerCommand::DisconnectGraceExpired(addr))
+               .await;
+         });
       }
+   }
 
-      self.aap_connecting.remove(&addr);
+   async fn handle_disconnect_grace_expired(&mut self, addr: Address) {
+      let Some(device) = self.devices.get_mut(&addr) else {
+         return;
+      };
+      if !device.grace_expired(Instant::now()) {
+         return;
+      }
+      info!("Connection to {addr} did not recover");
+      if device.bluetooth_state == BluetoothState::Disconnected {
+         device.close(&self.event_tx).await;
+      }
+      device.finish_disconnect(&self.event_tx);
    }
 
    fn handle_aap_connected(&mut self, addr: Address) {
       if let Some(device) = self.devices.get_mut(&addr) {
-         device.aap_state = AAPState::Connected;
+         device
+            .device
+            .advance_connection(ConnectionInput::HandshakeComplete, &self.event_tx);
          device.aap_retry_count = 0;
          device.last_aap_error = None;
 
-         self
-            .event_tx
-            .emit(&device.device, AirPodsEvent::DeviceConnected);
-      }
+         let recovered = device.end_grace();
+         if recovered {
+            info!("Connection to {addr} recovered");
+         } else {
+            self
+               .event_tx
+               .emit(&device.device, AirPodsEvent::DeviceConnected);
+         }
 
-      self.aap_connecting.remove(&addr);
+         // A mode requested while away wins over the profile
+         let airpods = device.device.clone();
+         let event_tx = self.event_tx.clone();
+         tokio::spawn(async move {
+            if !airpods.apply_queued_noise_mode(&event_tx).await && !recovered {
+               noise_profile::apply(&airpods).await;
+            }
+         });
+      }
    }
 
-   fn handle_aap_disconnected(&mut self, addr: Address, is_error: bool) {
-      if let Some(device) = self.devices.get_mut(&addr) {
-         if is_error && device.bluetooth_state == BluetoothState::Connected {
-            // Only retry AAP if Bluetooth is still connected
-            device.aap_state = AAPState::WaitingToReconnect;
-            device.aap_retry_count += 1;
+   async fn handle_aap_disconnected(&mut self, addr: Address, is_error: bool) {
+      let Some(device) = self.devices.get_mut(&addr) else {
+         return;
+      };
+      let bluetooth_connected = device.bluetooth_state == BluetoothState::Connected;
+      if is_error && (bluetooth_connected || !self.disconnect_grace.is_zero()) {
+         device
+            .fail("AAP connection failed".to_string(), &self.event_tx)
+            .await;
+      } else {
+         device.close(&self.event_tx).await;
+      }
 
-            // Schedule AAP reconnection with backoff
-            let loopback = self.loopback_tx.clone();
-            let delay = calc_retry_delay(device.aap_retry_count);
-            info!("AAP connection to {addr} failed, retrying in {delay:?}");
+      // Only retry AAP if Bluetooth is still connected
+      if is_error && bluetooth_connected {
+         device.aap_retry_count += 1;
 
-            tokio::spawn(async move {
-               time::sleep(delay).await;
-               let _ = loopback
-                  .send(ManagerCommand::EstablishAAP(addr, None))
-                  .await;
-            });
-         } else {
-            device.aap_state = AAPState::Disconnected;
-            device.aap_retry_count = 0;
-         }
-      }
+         // Schedule AAP reconnection with backoff
+         let loopback = self.loopback_tx.clone();
+         let delay = calc_retry_delay(device.aap_retry_count);
+         info!("AAP connection to {addr} failed, retrying in {delay:?}");
 
-      self.aap_connecting.remove(&addr);
+         tokio::spawn(async move {
+            time::sleep(delay).await;
+            let _ = loopback
+               .send(ManagerCommand::EstablishAAP(addr, None))
+               .await;
+         });
+      }
+      if is_error {
+         self.defer_disconnect(addr);
+      }
    }
 
-   fn handle_device_lost(&mut self, addr: Address) {
-      if let Some(device) = self.devices.remove(&addr) {
-         self
-            .event_tx
-            .emit(&device.device, AirPodsEvent::DeviceDisconnected);
+   async fn handle_device_lost(&mut self, addr: Address) {
+      self.connect_watches.remove(&addr);
+      if let Some(mut device) = self.devices.remove(&addr) {
+         device.close(&self.event_tx).await;
+         device.finish_disconnect(&self.event_tx);
       }
-      self.aap_connecting.remove(&addr);
    }
 
    async fn establish_aap_connection(&mut self, addr: Address) -> Result<()> {
-      // Check if already connecting
-      if self.aap_connecting.contains(&addr) {
-         return Err(AirPodsError::AlreadyConnecting);
-      }
-
       let device = self
          .devices
          .get_mut(&addr)
          .ok_or(AirPodsError::DeviceNotFound(addr))?;
 
+      // Check if already connecting
+      if matches!(
+         device.connection_state(),
+         ConnectionState::Connecting | ConnectionState::Handshaking
+      ) {
+         return Err(AirPodsError::AlreadyConnecting);
+      }
+
       // Check adapter is available
       let adapter_info = self
          .adapters
@@ -775,12 +1249,18 @@ impl ManagerActor {
       // Get BlueZ device to verify it's paired
       let bluer_device = adapter_info.adapter.device(addr)?;
       if !bluer_device.is_paired().await.unwrap_or(false) {
-         // Clean up on early exit
-         self.aap_connecting.remove(&addr);
          return Err(AirPodsError::DeviceNotPaired);
       }
 
+      // Replace a session that is still up
+      if device.connection_state() == ConnectionState::Connected {
+         device.close(&self.event_tx).await;
+      }
+
       // Spawn AAP connection task
+      device
+         .device
+         .advance_connection(ConnectionInput::Start, &self.event_tx);
       let airpods = device.device.clone();
       let event_tx = self.event_tx.clone();
       let loopback = self.loopback_tx.clone();
@@ -825,10 +1305,6 @@ impl ManagerActor {
       // Track AAP handle
       device.aap_handle = Some(handle);
 
-      // Mark as connecting only after spawn succeeds
-      self.aap_connecting.insert(addr);
-      device.aap_state = AAPState::Connecting;
-
       Ok(())
    }
 
@@ -838,18 +1314,10 @@ impl ManagerActor {
          .get_mut(&addr)
          .ok_or(AirPodsError::DeviceNotFound(addr))?;
 
-      // Abort AAP connection if active
-      if let Some(handle) = device.aap_handle.take() {
-         handle.abort();
-      }
-
-      device.aap_state = AAPState::Disconnected;
-      device.device.disconnect().await;
-
-      self.aap_connecting.remove(&addr);
-      self
-         .event_tx
-         .emit(&device.device, AirPodsEvent::DeviceDisconnected);
+      // Abort AAP connection if active, reporting it right away as it was requested
+      device.end_grace();
+      device.close(&self.event_tx).await;
+      device.finish_disconnect(&self.event_tx);
 
       Ok(())
    }
@@ -874,7 +1342,7 @@ impl ManagerActor {
             // Give it a moment to finish
             let _ = timeout(Duration::from_secs(1), handle).await;
          }
-         device.device.disconnect().await;
+         device.close(&self.event_tx).await;
       }
    }
 
@@ -926,11 +1394,27 @@ impl ManagerActor {
       }
    }
 
+   async fn resolve_device(&self, query: &str) -> Result<AirPods> {
+      let mut candidates = Vec::with_capacity(self.devices.len());
+      for (addr, device) in &self.devices {
+         let mut names = vec![device.device.name().to_string()];
+         if let Some(adapter_info) = self.adapters.get(&device.adapter_name)
+            && let Ok(bluer_device) = adapter_info.adapter.device(*addr)
+            && let Ok(alias) = bluer_device.alias().await
+         {
+            names.push(alias);
+         }
+         candidates.push((*addr, names));
+      }
+      let addr = resolve_name(query, &candidates)?;
+      Ok(self.devices[&addr].device.clone())
+   }
+
    fn has_aap_connection(&self, addr: Address) -> bool {
       self
          .devices
          .get(&addr)
-         .is_some_and(|d| matches!(d.aap_state, AAPState::Connected | AAPState::Connecting))
+         .is_some_and(|d| d.connection_state().is_active())
    }
 
    fn tick_all_devices(&self) {
@@ -966,6 +1450,53 @@ impl ManagerActor {
    }
 }
 
+/// Picks the device a MAC address or case-insensitive name refers to.
+///
+/// Each candidate is a device address with the names it is known by.
+fn resolve_name(query: &str, candidates: &[(Address, Vec<String>)]) -> Result<Address> {
+   if let Ok(addr) = query.parse::<Address>() {
+      return candidates
+         .iter()
+         .any(|(candidate, _)| *candidate == addr)
+         .then_some(addr)
+         .ok_or(AirPodsError::DeviceNotFound(addr));
+   }
+
+   let matches: Vec<_> = candidates
+      .iter()
+      .filter(|(_, names)| names.iter().any(|name| name.eq_ignore_ascii_case(query)))
+      .collect();
+   match matches.as_slice() {
+      [] => Err(AirPodsError::DeviceNameNotFound(query.to_string())),
+      [(addr, _)] => Ok(*addr),
+      _ => Err(AirPodsError::AmbiguousDeviceName {
+         name: query.to_string(),
+         candidates: matches
+            .iter()
+            .map(|(addr, names)| format!("{} ({addr})", names[0]))
+            .collect(),
+      }),
+   }
+}
+
+/// Picks the connected device a proximity advertisement of `model` came from.
+///
+/// Advertisements are sent from a rotating private address, so only the model
+/// tells them apart. A device whose model isn't known yet could be any, and
+/// nothing is picked unless exactly one device fits.
+fn advert_owner<'a>(
+   model: AirPodsModel,
+   devices: impl IntoIterator<Item = &'a ManagedDevice>,
+) -> Option<&'a ManagedDevice> {
+   let mut candidates = devices.into_iter().filter(|managed| {
+      let known = managed.device.model();
+      managed.bluetooth_state == BluetoothState::Connected
+         && (known == model || known == AirPodsModel::Unknown)
+   });
+   let owner = candidates.next()?;
+   candidates.next().is_none().then_some(owner)
+}
+
 fn calc_retry_delay(retry_count: u32) -> Duration {
    let base_delay = Duration::from_secs(2);
    let exponential = base_delay * (1 << retry_count.min(4));
@@ -973,3 +1504,117 @@ fn calc_retry_delay(retry_count: u32) -> Duration {
    let jitter = rand::thread_rng().gen_range(0..1000);
    delay + Duration::from_millis(jitter)
 }
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   fn managed_device() -> ManagedDevice {
+      managed_device_at(Address([0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF]))
+   }
+
+   fn managed_device_at(addr: Address) -> ManagedDevice {
+      ManagedDevice {
+         device: AirPods::new(addr, "AirPods".to_string(), None),
+         bluetooth_state: BluetoothState::Disconnected,
+         adapter_name: SmolStr::new_static("hci0"),
+         aap_retry_count: 0,
+         last_aap_error: None,
+         aap_handle: None,
+         disconnect_deadline: None,
+      }
+   }
+
+   #[test]
+   fn test_resolve_name() {
+      let left = Address([0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0x01]);
+      let right = Address([0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0x02]);
+      let candidates = [
+         (
+            left,
+            vec!["AirPods Pro".to_string(), "Work Buds".to_string()],
+         ),
+         (right, vec!["AirPods Pro".to_string()]),
+      ];
+
+      assert_eq!(resolve_name("work buds", &candidates).unwrap(), left);
+      assert_eq!(
+         resolve_name("AA:BB:CC:DD:EE:02", &candidates).unwrap(),
+         right
+      );
+      assert!(matches!(
+         resolve_name("AA:BB:CC:DD:EE:03", &candidates),
+         Err(AirPodsError::DeviceNotFound(_))
+      ));
+      assert!(matches!(
+         resolve_name("Max", &candidates),
+         Err(AirPodsError::DeviceNameNotFound(_))
+      ));
+
+      let err = resolve_name("airpods pro", &candidates).unwrap_err();
+      assert_eq!(
+         err.to_string(),
+         "Device name \"airpods pro\" is ambiguous, candidates: \
+          AirPods Pro (AA:BB:CC:DD:EE:01), AirPods Pro (AA:BB:CC:DD:EE:02)"
+      );
+   }
+
+   #[test]
+   fn test_duplicate_connect_signals_start_one_handshake() {
+      let mut device = managed_device();
+
+      let handshakes = (0..3).filter(|_| device.mark_bluetooth_connected()).count();
+      assert_eq!(handshakes, 1);
+      assert_eq!(device.bluetooth_state, BluetoothState::Connected);
+
+      // A genuine disconnect allows the next connect to run the pipeline again
+      device.bluetooth_state = BluetoothState::Disconnected;
+      assert!(device.mark_bluetooth_connected());
+      assert!(!device.mark_bluetooth_connected());
+   }
+
+   #[test]
+   fn test_disconnect_grace_period() {
+      let mut device = managed_device();
+      let grace = Duration::from_secs(8);
+      let start = Instant::now();
+
+      // Repeated drops don't extend the grace period
+      assert!(device.begin_grace(start, grace));
+      assert!(!device.begin_grace(start + Duration::from_secs(5), grace));
+      assert!(!device.grace_expired(start + Duration::from_secs(7)));
+
+      // Recovering in time means the disconnect is never reported
+      assert!(device.end_grace());
+      assert!(!device.grace_expired(start + grace));
+
+      assert!(device.begin_grace(start, grace));
+      assert!(device.grace_expired(start + grace));
+      // A stale expiry or a later reconnect doesn't see the grace period
+      assert!(!device.grace_expired(start + grace));
+      assert!(!device.end_grace());
+   }
+
+   #[test]
+   fn test_advert_owner() {
+      let mut pro = managed_device_at(Address([0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0x01]));
+      pro.device.set_model(AirPodsModel::Pro);
+      let mut max = managed_device_at(Address([0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0x02]));
+      max.device.set_model(AirPodsModel::Max);
+
+      // Adverts only count for connected devices
+      assert!(advert_owner(AirPodsModel::Pro, [&pro, &max]).is_none());
+      pro.bluetooth_state = BluetoothState::Connected;
+      max.bluetooth_state = BluetoothState::Connected;
+      let owner = advert_owner(AirPodsModel::Pro, [&pro, &max]).unwrap();
+      assert_eq!(owner.device.address(), pro.device.address());
+      assert!(advert_owner(AirPodsModel::Gen3, [&pro, &max]).is_none());
+
+      // A device of unknown model could have sent any of them
+      let mut unknown = managed_device_at(Address([0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0x03]));
+      unknown.bluetooth_state = BluetoothState::Connected;
+      let owner = advert_owner(AirPodsModel::Gen3, [&pro, &unknown]).unwrap();
+      assert_eq!(owner.device.address(), unknown.device.address());
+      assert!(advert_owner(AirPodsModel::Pro, [&pro, &unknown]).is_none());
+   }
+}
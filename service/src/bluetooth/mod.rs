@@ -3,5 +3,6 @@
 //! This module provides Bluetooth connectivity including L2CAP socket
 //! management and device discovery/connection handling.
 
+pub mod connection;
 pub mod l2cap;
 pub mod manager;
@@ -3,16 +3,29 @@
 //! This module handles loading and saving configuration from disk,
 //! including known devices and connection parameters.
 
-use std::{env, fs, path::PathBuf};
+use std::{collections::BTreeMap, env, fs, path::PathBuf};
 
 use serde::{Deserialize, Serialize};
 use smol_str::SmolStr;
 
-use crate::error::{AirPodsError, Result};
+use crate::{
+   airpods::protocol::StemPress,
+   error::{AirPodsError, Result},
+};
+
+/// Schema version written by this build.
+///
+/// - 1: unversioned files written before the `version` field existed
+/// - 2: known device addresses are stored uppercase, as `BlueZ` formats them
+pub const CONFIG_VERSION: u32 = 2;
 
 /// Main configuration structure for the service.
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Config {
+   /// Schema version of the file. Older files are migrated on load.
+   #[serde(default = "legacy_version")]
+   pub version: u32,
+
    #[serde(default)]
    pub known_devices: Vec<KnownDevice>,
 
@@ -30,6 +43,577 @@ pub struct Config {
 
    #[serde(default)]
    pub log_filter: Option<SmolStr>,
+
+   /// `"human"` readable logs, or `"json"` lines for log collectors. The
+   /// `AIRPODS_LOG_FORMAT` environment variable takes precedence.
+   #[serde(default)]
+   pub log_format: LogFormat,
+
+   /// Template of the one-line status summary for trays, see
+   /// [`crate::airpods::summary`] for the placeholders. Unset uses
+   /// `"{name} · L {left}% R {right}% · {battery}% · Case {case}% · {noise_mode}"`.
+   #[serde(default)]
+   pub status_template: Option<String>,
+
+   /// Keep a noise control change requested while the `AirPods` are
+   /// reconnecting for this many seconds, and apply it once they are back.
+   /// A newer request replaces the queued one. Zero fails such requests.
+   #[serde(default)]
+   pub noise_mode_queue_secs: u64,
+
+   /// How long a dropped connection may take to recover, in seconds, before
+   /// it is reported as disconnected and per-session state is cleared. A
+   /// connection that recovers in time never reports the disconnect. Zero
+   /// reports disconnects immediately.
+   #[serde(default = "default_disconnect_grace")]
+   pub disconnect_grace_secs: u64,
+
+   /// Smallest battery level change, in percentage points, reported right
+   /// away. Charging and connection changes always are.
+   #[serde(default = "default_battery_min_change")]
+   pub battery_min_change: u8,
+
+   /// Report smaller battery changes too once this many seconds passed
+   /// since the last report. Zero never does.
+   #[serde(default)]
+   pub battery_event_interval_secs: u64,
+
+   #[serde(default)]
+   pub audio: AudioConfig,
+
+   #[serde(default)]
+   pub media: MediaConfig,
+
+   #[serde(default)]
+   pub quiet_hours: QuietHoursConfig,
+
+   #[serde(default)]
+   pub recognition: RecognitionConfig,
+
+   #[serde(default)]
+   pub peek: PeekConfig,
+
+   #[serde(default)]
+   pub signal: SignalConfig,
+
+   #[serde(default)]
+   pub notifications: NotificationsConfig,
+
+   #[serde(default)]
+   pub noise_profiles: NoiseProfilesConfig,
+
+   /// Publish battery levels through `BlueZ` so desktop battery menus show them.
+   #[serde(default = "default_true")]
+   pub battery_provider: bool,
+
+   /// Answer `GetSerialNumbers` on D-Bus. Off by default since serial
+   /// numbers identify the hardware to any program on the session bus.
+   #[serde(default)]
+   pub expose_serial_numbers: bool,
+
+   /// What starting the daemon does while another instance is running:
+   /// `"exit"` with an error, or `"forward"` the start to the running
+   /// instance and exit successfully, so a duplicate autostart entry doesn't
+   /// show up as a failure. Commands such as `--resume-all` always go to the
+   /// running instance.
+   #[serde(default)]
+   pub second_instance: SecondInstance,
+
+   /// Show a tray icon with battery levels and noise control modes, for
+   /// desktops without the Plasma applet. Only available when built with
+   /// the `tray` feature.
+   #[serde(default)]
+   pub tray: bool,
+
+   /// Settings unknown to this version, kept so that saving doesn't drop
+   /// options written by a newer release.
+   #[serde(flatten)]
+   pub extra: toml::Table,
+}
+
+/// Audio routing behavior applied when devices connect or disconnect.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct AudioConfig {
+   /// Make the `AirPods` the default sink on connect if audio is playing elsewhere,
+   /// moving the playing streams over.
+   #[serde(default)]
+   pub switch_sink_on_connect: bool,
+
+   /// Restore the previous default sink once the `AirPods` disconnect.
+   #[serde(default)]
+   pub restore_sink_on_disconnect: bool,
+
+   /// Switch to noise cancellation when media starts playing on the `AirPods`.
+   #[serde(default)]
+   pub anc_for_media: bool,
+
+   /// Switch to transparency when a call starts, i.e. the `AirPods`
+   /// microphone is being recorded from.
+   #[serde(default)]
+   pub transparency_for_calls: bool,
+}
+
+/// Noise control modes applied on connect, picked by location profile.
+///
+/// The current profile is the one set with `SetNoiseProfile`, or else the
+/// one bound to an active network connection, or else `default`.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct NoiseProfilesConfig {
+   /// Noise control mode (`"off"`, `"anc"`, `"transparency"` or
+   /// `"adaptive"`) of each profile, e.g. `office = "anc"`.
+   #[serde(default)]
+   pub modes: BTreeMap<String, String>,
+
+   /// Profile of each network, by NetworkManager connection name, e.g.
+   /// `"Office Wi-Fi" = "office"`. Wi-Fi connections are named after their
+   /// SSID unless renamed.
+   #[serde(default)]
+   pub networks: BTreeMap<String, String>,
+
+   /// Profile used when none is set and no network matches.
+   #[serde(default)]
+   pub default: Option<String>,
+}
+
+/// Hours of the day in which the daemon keeps quiet, e.g. overnight.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct QuietHoursConfig {
+   /// Time of day, as `"HH:MM"` in the system timezone, at which the quiet
+   /// hours start. A start after the end spans midnight. Off unless both are set.
+   #[serde(default)]
+   pub start: Option<String>,
+
+   /// Time of day at which the quiet hours end.
+   #[serde(default)]
+   pub end: Option<String>,
+}
+
+/// Extra criteria for recognizing devices as `AirPods`, on top of the built-in ones.
+///
+/// Devices listed in `known_devices` are always recognized by address.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct RecognitionConfig {
+   /// Additional Apple product IDs (e.g. `0x2019`), matched against the
+   /// modalias and against the low byte in proximity pairing advertisements.
+   #[serde(default)]
+   pub product_ids: Vec<u32>,
+
+   /// Additional case-insensitive substrings of the device name or alias.
+   #[serde(default)]
+   pub name_patterns: Vec<String>,
+}
+
+/// Battery popup when the case is opened next to the computer, like on iOS.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PeekConfig {
+   /// Emit `CasePeeked` with the battery levels when the case lid opens nearby.
+   #[serde(default = "default_true")]
+   pub enabled: bool,
+
+   /// Minimum advertisement signal strength, in dBm, for the case to count as nearby.
+   #[serde(default = "default_peek_min_rssi")]
+   pub min_rssi: i16,
+
+   /// Also show a desktop notification with the battery levels.
+   #[serde(default)]
+   pub notify: bool,
+
+   /// Ignore peeks within this many seconds of the previous one.
+   #[serde(default = "default_peek_debounce")]
+   pub debounce_secs: u64,
+}
+
+/// Desktop notifications for device events, all off unless enabled.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct NotificationsConfig {
+   /// Shown when a device connects.
+   #[serde(default)]
+   pub connected: NotificationTemplate,
+
+   /// Shown when a device disconnects.
+   #[serde(default)]
+   pub disconnected: NotificationTemplate,
+
+   /// Shown once per discharge when the battery reaches `low_battery_level`.
+   #[serde(default)]
+   pub low_battery: NotificationTemplate,
+
+   /// Shown when the noise control mode changes.
+   #[serde(default)]
+   pub noise_mode: NotificationTemplate,
+
+   /// Shown when only one bud is left in an ear and audio turns mono.
+   #[serde(default)]
+   pub single_bud: NotificationTemplate,
+
+   /// Battery level, in percent, at or below which the battery is low. The
+   /// lower bud decides for models with two.
+   #[serde(default = "default_low_battery_level")]
+   pub low_battery_level: u8,
+
+   #[serde(default)]
+   pub speech: SpeechConfig,
+}
+
+/// Announcements read out by a text-to-speech command, off unless a command is set.
+///
+/// Texts are templates like the notification ones, with their segments read
+/// out as separate phrases. An empty text turns its announcement off.
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq, Eq)]
+pub struct SpeechConfig {
+   /// Command given the text as its last argument, e.g. `["spd-say"]`.
+   #[serde(default)]
+   pub command: Vec<String>,
+
+   /// Said when a device connects.
+   #[serde(default)]
+   pub connected: Option<String>,
+
+   /// Said once per discharge when the battery reaches `low_battery_level`.
+   #[serde(default)]
+   pub low_battery: Option<String>,
+}
+
+/// Text and icon of a notification.
+///
+/// `summary` and `body` are templates with the placeholders of
+/// `status_template`, plus `{level}` for the level deciding low battery.
+/// Unset fields, and templates found invalid at startup, use the defaults.
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq, Eq)]
+pub struct NotificationTemplate {
+   #[serde(default)]
+   pub enabled: bool,
+
+   #[serde(default)]
+   pub summary: Option<String>,
+
+   #[serde(default)]
+   pub body: Option<String>,
+
+   /// Icon name from the desktop icon theme.
+   #[serde(default)]
+   pub icon: Option<String>,
+}
+
+/// Warning about a weak connection, which tends to make audio stutter.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SignalConfig {
+   /// Emit `PoorSignal` when the signal stays weak. Purely advisory, the
+   /// connection is left alone.
+   ///
+   /// `BlueZ` doesn't report the RSSI of an established connection, so the
+   /// signal is read from proximity advertisements and needs advertisement
+   /// monitoring to work.
+   #[serde(default = "default_true")]
+   pub enabled: bool,
+
+   /// Proximity advertisement signal strength, in dBm, below which the signal is poor.
+   #[serde(default = "default_poor_rssi")]
+   pub min_rssi: i16,
+
+   /// How long the signal must stay poor before warning, in seconds.
+   #[serde(default = "default_poor_signal_duration")]
+   pub duration_secs: u64,
+
+   /// How many dB above `min_rssi` the signal must rise before another
+   /// warning can be raised.
+   #[serde(default = "default_signal_hysteresis")]
+   pub hysteresis_db: i16,
+
+   /// Also show a desktop notification.
+   #[serde(default)]
+   pub notify: bool,
+}
+
+/// Automatic media control driven by ear detection.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MediaConfig {
+   /// Master switch for media control. When disabled, ear detection never
+   /// pauses or resumes players. Setting `AIRPODS_DISABLE_MEDIA_CONTROL`
+   /// in the environment also disables it.
+   #[serde(default = "default_true")]
+   pub enabled: bool,
+
+   /// Only let ear detection control media while the `AirPods` are the default
+   /// audio sink, so removing idle `AirPods` doesn't pause audio playing on
+   /// speakers. Ear state is still tracked. If the sound server can't be
+   /// queried, media is controlled as usual.
+   #[serde(default = "default_true")]
+   pub require_active_output: bool,
+
+   /// Also pause media while the session is locked, resuming on unlock
+   /// unless the buds are out by then.
+   #[serde(default)]
+   pub pause_on_lock: bool,
+
+   /// List the players we paused in `$XDG_RUNTIME_DIR/kairpods-paused`, so
+   /// playerctl scripts can tell automatic pauses apart and take players
+   /// over by removing them from the file before we resume them.
+   #[serde(default)]
+   pub pause_marker: bool,
+
+   /// Keep the players we paused in `$XDG_RUNTIME_DIR/kairpods-resume.json`
+   /// and still resume them if the daemon restarted meanwhile, as long as
+   /// they were paused less than this many seconds ago. Zero forgets them.
+   #[serde(default = "default_resume_after_restart")]
+   pub resume_after_restart_sec: u64,
+
+   /// Address of the session bus whose players are controlled, e.g.
+   /// `"unix:path=/run/user/1000/bus"`, so that a system service can control
+   /// a logged-in user's media. Unset uses our own session bus. Whoever can
+   /// reach our D-Bus interface can then pause and resume that user's players.
+   #[serde(default)]
+   pub session_bus: Option<String>,
+
+   /// Send Pause to players whose playback status can't be read, instead of
+   /// skipping them. They are only resumed if the Pause call succeeded.
+   #[serde(default)]
+   pub pause_unknown_status: bool,
+
+   /// How long the ear state must stay the same before acting on it, in
+   /// milliseconds, so a jostled bud's sensor chatter doesn't pause and
+   /// resume in a row. Comes before `pause_hold_ms` and `resume_delay_ms`.
+   /// Zero acts on every change.
+   #[serde(default = "default_ear_settle")]
+   pub ear_settle_ms: u64,
+
+   /// How long the buds must stay out before media is paused, in milliseconds.
+   /// Returning them within this window cancels the pause. Zero pauses immediately.
+   #[serde(default)]
+   pub pause_hold_ms: u64,
+
+   /// How players are paused: at once, or after a quick volume fade-out.
+   #[serde(default)]
+   pub pause_mode: PauseMode,
+
+   /// How long to wait after the buds return before resuming, in milliseconds.
+   /// A pending resume can be cancelled over D-Bus. Zero resumes immediately.
+   #[serde(default)]
+   pub resume_delay_ms: u64,
+
+   /// Fade the volume back in over this many milliseconds when resuming.
+   /// Only affects players with a writable volume. Zero resumes at full volume.
+   #[serde(default)]
+   pub resume_fade_ms: u64,
+
+   /// Don't resume players paused more than this many seconds ago, and
+   /// forget them instead. Zero always resumes.
+   #[serde(default = "default_resume_timeout")]
+   pub resume_timeout_sec: u64,
+
+   /// Check this many milliseconds after resuming whether each player is
+   /// actually playing, and send Play once more if not. Zero doesn't check.
+   #[serde(default)]
+   pub resume_verify_ms: u64,
+
+   /// How long a player must have been playing, in milliseconds, to be
+   /// resumed after a pause. Shorter playback, such as a notification sound,
+   /// is still paused but not resumed. Zero tracks every playing player.
+   #[serde(default)]
+   pub min_playing_ms: u64,
+
+   /// What to do on reinsertion when another player started while media was paused.
+   #[serde(default)]
+   pub resume_policy: ResumePolicy,
+
+   /// Order in which several paused players are resumed.
+   #[serde(default)]
+   pub resume_order: ResumeOrder,
+
+   /// Also control MPRIS players on the system bus, as some kiosk and embedded
+   /// players use it. They are matched like session players in the lists below.
+   #[serde(default)]
+   pub system_bus_players: bool,
+
+   /// Players to prefer, most important first, by bus name without the
+   /// `org.mpris.MediaPlayer2.` prefix (e.g. `"spotify"`). Matching is
+   /// case-insensitive and ignores instance suffixes such as
+   /// `firefox.instance_1_42`; `*` and `?` wildcards match the whole name
+   /// (e.g. `"chromium*"`). Listed players come first in list order;
+   /// all others follow alphabetically.
+   #[serde(default)]
+   pub player_priority: Vec<String>,
+
+   /// Only ever pause these players, matched like `player_priority`. When
+   /// set, `ignored_players` is not consulted. Empty pauses every player.
+   #[serde(default)]
+   pub only_players: Vec<String>,
+
+   /// Players to never pause, matched like `player_priority`, such as
+   /// `"plasma-browser-integration"`. Ignored while `only_players` is set.
+   #[serde(default)]
+   pub ignored_players: Vec<String>,
+
+   /// Players to mute instead of pause on ear removal, matched like
+   /// `player_priority`. Useful for live streams that lose their position
+   /// when paused. The previous volume is restored on reinsertion; players
+   /// without a writable volume are paused as usual.
+   #[serde(default)]
+   pub mute_players: Vec<String>,
+
+   /// Action fired when both buds are removed and reinserted twice within
+   /// `double_removal_window_ms`. Off unless set.
+   #[serde(default)]
+   pub double_removal_action: Option<GestureAction>,
+
+   /// Stem press (`"single"`, `"double"`, `"triple"` or `"long"`) that turns
+   /// auto-pause on or off, like the `MediaControlEnabled` property. Only
+   /// presses the buds forward instead of handling themselves are seen.
+   /// Off unless set.
+   #[serde(default)]
+   pub auto_pause_toggle_press: Option<StemPress>,
+
+   /// Stem press that stops the active player instead of pausing it, e.g. so
+   /// a podcast starts over. Only presses the buds forward are seen. Off
+   /// unless set.
+   #[serde(default)]
+   pub stop_press: Option<StemPress>,
+
+   /// Window for the double-removal gesture, in milliseconds, measured from
+   /// the first removal to the second reinsertion.
+   #[serde(default = "default_double_removal_window")]
+   pub double_removal_window_ms: u64,
+
+   /// Whether taking out a single bud pauses, as set by `one_out_action`
+   /// and `one_in_case_action`, or only having both out does.
+   #[serde(default)]
+   pub pause_on: PauseOn,
+
+   /// Whether media resumes as soon as a bud is back in and nothing else
+   /// calls for a pause, or only once both buds are in. Only makes a
+   /// difference with `pause_on = "both_ears_out"` or single bud actions set
+   /// to `"ignore"`. The `resume_timeout_sec` still counts from the pause, so
+   /// sitting with one bud in for longer than that forgets the players.
+   #[serde(default)]
+   pub resume_on: ResumeOn,
+
+   /// What to do when one bud is taken out while the other stays in.
+   #[serde(default)]
+   pub one_out_action: SingleBudAction,
+
+   /// What to do when one bud is put in the case while the other stays in.
+   #[serde(default)]
+   pub one_in_case_action: SingleBudAction,
+
+   /// Turn noise control off while both buds are out of the ears, to save
+   /// battery, and switch back on reinsertion. A mode picked meanwhile,
+   /// for example on reconnecting, is kept instead.
+   #[serde(default)]
+   pub noise_off_when_removed: bool,
+
+   /// How long to wait for a player to answer a D-Bus call, in milliseconds,
+   /// before giving up on it. Zero waits as long as it takes.
+   #[serde(default = "default_call_timeout")]
+   pub call_timeout_ms: u64,
+
+   /// How many players a pause checks and pauses at once. Higher values
+   /// pause many players faster at the cost of more parallel D-Bus calls.
+   #[serde(default = "default_max_concurrent_players")]
+   pub max_concurrent_players: usize,
+}
+
+/// Whether to resume our paused players when another player has started meanwhile.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ResumePolicy {
+   /// Resume regardless of what else is playing
+   Always,
+   /// Don't resume and forget the paused players
+   #[default]
+   SkipIfOtherPlaying,
+   /// Keep the paused players and resume them once nothing else is playing
+   WhenIdle,
+}
+
+/// How a player is paused.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PauseMode {
+   /// Pause right away
+   #[default]
+   Hard,
+   /// Fade the volume out first, then pause and put the volume back. Players
+   /// without a writable volume are paused right away.
+   Fade,
+}
+
+/// Order in which our paused players are resumed.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ResumeOrder {
+   /// The order they were paused in, sorted by `player_priority`
+   #[default]
+   Paused,
+   /// The player that most recently started playing first, so two sources
+   /// are less likely to overlap audibly. Players that were already playing
+   /// when the daemon started come last.
+   MostRecentFirst,
+}
+
+/// Ear state that pauses media.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PauseOn {
+   /// One bud leaving the ear already does, see [`SingleBudAction`]
+   #[default]
+   SingleEarOut,
+   /// Only both buds being out does; one bud out counts as listening
+   BothEarsOut,
+}
+
+/// Ear state that resumes media we paused.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ResumeOn {
+   /// The first bud back in, unless its state still calls for a pause
+   #[default]
+   FirstEarIn,
+   /// Only both buds back in
+   BothEarsIn,
+}
+
+/// Reaction to only one bud leaving the ear.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SingleBudAction {
+   /// Pause playback like when both buds are out
+   #[default]
+   Pause,
+   /// Lower the volume until both buds are back in
+   Duck,
+   /// Keep playing
+   Ignore,
+}
+
+/// Media action that can be bound to an ear detection gesture.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum GestureAction {
+   /// Stop playback instead of resuming it
+   Stop,
+   /// Skip to the next track and resume
+   Next,
+}
+
+/// How log records are written.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+   #[default]
+   Human,
+   /// One JSON object per line
+   Json,
+}
+
+/// What a second instance of the daemon does.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SecondInstance {
+   /// Exit with an error status
+   #[default]
+   Exit,
+   /// Leave everything to the running instance and exit successfully
+   Forward,
 }
 
 /// Represents a known `AirPods` device.
@@ -39,6 +623,41 @@ pub struct KnownDevice {
    pub name: String,
 }
 
+const fn legacy_version() -> u32 {
+   1
+}
+
+/// Upgrades a parsed configuration file to [`CONFIG_VERSION`] in place.
+///
+/// Files from a newer release are left alone. Returns whether anything changed.
+fn migrate(table: &mut toml::Table) -> bool {
+   let version = table
+      .get("version")
+      .and_then(toml::Value::as_integer)
+      .unwrap_or(legacy_version().into());
+   if version >= CONFIG_VERSION.into() {
+      return false;
+   }
+
+   if version < 2
+      && let Some(devices) = table
+         .get_mut("known_devices")
+         .and_then(toml::Value::as_array_mut)
+   {
+      for address in devices
+         .iter_mut()
+         .filter_map(|device| device.get_mut("address"))
+      {
+         if let Some(upper) = address.as_str().map(str::to_ascii_uppercase) {
+            *address = upper.into();
+         }
+      }
+   }
+
+   table.insert("version".to_string(), i64::from(CONFIG_VERSION).into());
+   true
+}
+
 const fn default_poll_interval() -> u64 {
    30
 }
@@ -55,15 +674,172 @@ const fn default_reconnect_delay() -> u64 {
    10
 }
 
+const fn default_disconnect_grace() -> u64 {
+   8
+}
+
+const fn default_battery_min_change() -> u8 {
+   1
+}
+
+const fn default_true() -> bool {
+   true
+}
+
+const fn default_double_removal_window() -> u64 {
+   3000
+}
+
+const fn default_ear_settle() -> u64 {
+   300
+}
+
+const fn default_resume_timeout() -> u64 {
+   300
+}
+
+const fn default_resume_after_restart() -> u64 {
+   3600
+}
+
+const fn default_call_timeout() -> u64 {
+   500
+}
+
+const fn default_max_concurrent_players() -> usize {
+   8
+}
+
+const fn default_peek_min_rssi() -> i16 {
+   -60
+}
+
+const fn default_poor_rssi() -> i16 {
+   -80
+}
+
+const fn default_low_battery_level() -> u8 {
+   20
+}
+
+const fn default_poor_signal_duration() -> u64 {
+   10
+}
+
+const fn default_signal_hysteresis() -> i16 {
+   5
+}
+
+const fn default_peek_debounce() -> u64 {
+   30
+}
+
+impl Default for PeekConfig {
+   fn default() -> Self {
+      Self {
+         enabled: default_true(),
+         min_rssi: default_peek_min_rssi(),
+         notify: false,
+         debounce_secs: default_peek_debounce(),
+      }
+   }
+}
+
+impl Default for NotificationsConfig {
+   fn default() -> Self {
+      Self {
+         connected: NotificationTemplate::default(),
+         disconnected: NotificationTemplate::default(),
+         low_battery: NotificationTemplate::default(),
+         noise_mode: NotificationTemplate::default(),
+         single_bud: NotificationTemplate::default(),
+         low_battery_level: default_low_battery_level(),
+         speech: SpeechConfig::default(),
+      }
+   }
+}
+
+impl Default for SignalConfig {
+   fn default() -> Self {
+      Self {
+         enabled: default_true(),
+         min_rssi: default_poor_rssi(),
+         duration_secs: default_poor_signal_duration(),
+         hysteresis_db: default_signal_hysteresis(),
+         notify: false,
+      }
+   }
+}
+
+impl Default for MediaConfig {
+   fn default() -> Self {
+      Self {
+         enabled: default_true(),
+         require_active_output: default_true(),
+         pause_on_lock: false,
+         pause_marker: false,
+         resume_after_restart_sec: default_resume_after_restart(),
+         session_bus: None,
+         pause_unknown_status: false,
+         ear_settle_ms: default_ear_settle(),
+         pause_hold_ms: 0,
+         pause_mode: PauseMode::default(),
+         resume_delay_ms: 0,
+         resume_fade_ms: 0,
+         resume_timeout_sec: default_resume_timeout(),
+         resume_verify_ms: 0,
+         min_playing_ms: 0,
+         resume_policy: ResumePolicy::default(),
+         resume_order: ResumeOrder::default(),
+         system_bus_players: false,
+         player_priority: vec![],
+         only_players: vec![],
+         ignored_players: vec![],
+         mute_players: vec![],
+         double_removal_action: None,
+         auto_pause_toggle_press: None,
+         stop_press: None,
+         double_removal_window_ms: default_double_removal_window(),
+         pause_on: PauseOn::default(),
+         resume_on: ResumeOn::default(),
+         one_out_action: SingleBudAction::default(),
+         one_in_case_action: SingleBudAction::default(),
+         noise_off_when_removed: false,
+         call_timeout_ms: default_call_timeout(),
+         max_concurrent_players: default_max_concurrent_players(),
+      }
+   }
+}
+
 impl Default for Config {
    fn default() -> Self {
       Self {
+         version: CONFIG_VERSION,
          known_devices: vec![],
          poll_interval: default_poll_interval(),
          connection_retry_count: default_retry_count(),
          reconnect_delay_sec: default_reconnect_delay(),
          notification_retries: default_notification_retries(),
          log_filter: None,
+         log_format: LogFormat::default(),
+         status_template: None,
+         noise_mode_queue_secs: 0,
+         disconnect_grace_secs: default_disconnect_grace(),
+         battery_min_change: default_battery_min_change(),
+         battery_event_interval_secs: 0,
+         audio: AudioConfig::default(),
+         media: MediaConfig::default(),
+         quiet_hours: QuietHoursConfig::default(),
+         recognition: RecognitionConfig::default(),
+         peek: PeekConfig::default(),
+         signal: SignalConfig::default(),
+         notifications: NotificationsConfig::default(),
+         noise_profiles: NoiseProfilesConfig::default(),
+         battery_provider: default_true(),
+         expose_serial_numbers: false,
+         second_instance: SecondInstance::default(),
+         tray: false,
+         extra: toml::Table::new(),
       }
    }
 }
@@ -75,7 +851,11 @@ impl Config {
 
       if config_path.exists() {
          let contents = fs::read_to_string(&config_path)?;
-         Ok(toml::from_str(&contents)?)
+         let (config, migrated) = Self::parse(&contents)?;
+         if migrated {
+            config.save()?;
+         }
+         Ok(config)
       } else {
          // Create default config
          let config = Self::default();
@@ -84,7 +864,46 @@ impl Config {
       }
    }
 
+   /// Parses a configuration file, migrating it to the current schema.
+   ///
+   /// Returns whether the file was migrated and needs to be written back.
+   fn parse(contents: &str) -> Result<(Self, bool)> {
+      let mut table: toml::Table = toml::from_str(contents)?;
+      let migrated = migrate(&mut table);
+      Ok((toml::Value::Table(table).try_into()?, migrated))
+   }
+
+   /// Serializes the whole configuration for backing it up or moving it to
+   /// another machine. The file carries its schema version, so [`Self::import`]
+   /// of a newer build migrates it.
+   pub fn export(&self) -> Result<String> {
+      Ok(toml::to_string_pretty(self)?)
+   }
+
+   /// Parses an exported configuration, migrating and validating it.
+   ///
+   /// Returns the configuration along with the top-level settings and
+   /// sections that differ from `self`.
+   pub fn import(&self, contents: &str) -> Result<(Self, Vec<String>)> {
+      let (imported, _) = Self::parse(contents)?;
+      let current = toml::Value::try_from(self)?;
+      let new = toml::Value::try_from(&imported)?;
+      let (Some(current), Some(new)) = (current.as_table(), new.as_table()) else {
+         return Ok((imported, vec![]));
+      };
+      let mut changed: Vec<String> = current
+         .keys()
+         .chain(new.keys().filter(|key| !current.contains_key(*key)))
+         .filter(|key| *key != "version" && current.get(*key) != new.get(*key))
+         .cloned()
+         .collect();
+      changed.sort();
+      Ok((imported, changed))
+   }
+
    /// Saves the current configuration to disk.
+   ///
+   /// The file is replaced atomically so a crash never leaves it truncated.
    pub fn save(&self) -> Result<()> {
       let config_path = Self::config_path()?;
 
@@ -94,7 +913,9 @@ impl Config {
       }
 
       let contents = toml::to_string_pretty(self)?;
-      fs::write(&config_path, contents)?;
+      let tmp_path = config_path.with_extension("toml.tmp");
+      fs::write(&tmp_path, contents)?;
+      fs::rename(&tmp_path, &config_path)?;
 
       Ok(())
    }
@@ -121,3 +942,64 @@ impl Config {
          .map(|d| d.name.as_str())
    }
 }
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   #[test]
+   fn test_import_reports_changed_sections() {
+      let current = Config::default();
+      let mut exported = current.clone();
+      exported.poll_interval = 60;
+      exported.media.pause_hold_ms = 500;
+      let contents = exported.export().unwrap();
+
+      let (imported, changed) = current.import(&contents).unwrap();
+      assert_eq!(changed, ["media", "poll_interval"]);
+      assert_eq!(imported.media.pause_hold_ms, 500);
+
+      let (_, changed) = current.import(&current.export().unwrap()).unwrap();
+      assert!(changed.is_empty());
+      assert!(current.import("poll_interval = \"often\"").is_err());
+   }
+
+   #[test]
+   fn test_migrate_v1_to_v2() {
+      let v1 = r#"
+         poll_interval = 20
+         future_option = "kept"
+
+         [[known_devices]]
+         address = "aa:bb:cc:dd:ee:ff"
+         name = "AirPods"
+
+         [future_section]
+         value = 1
+      "#;
+
+      let (config, migrated) = Config::parse(v1).unwrap();
+      assert!(migrated);
+      assert_eq!(config.version, CONFIG_VERSION);
+      assert_eq!(config.poll_interval, 20);
+      assert_eq!(config.is_known_device("AA:BB:CC:DD:EE:FF"), Some("AirPods"));
+
+      // Unknown settings survive a save and reload
+      let saved = toml::to_string_pretty(&config).unwrap();
+      let (reloaded, migrated) = Config::parse(&saved).unwrap();
+      assert!(!migrated);
+      assert_eq!(reloaded.extra["future_option"].as_str(), Some("kept"));
+      assert_eq!(
+         reloaded.extra["future_section"]["value"].as_integer(),
+         Some(1)
+      );
+   }
+
+   #[test]
+   fn test_newer_version_is_not_migrated() {
+      let (config, migrated) = Config::parse("version = 99\npoll_interval = 5").unwrap();
+      assert!(!migrated);
+      assert_eq!(config.version, 99);
+      assert_eq!(config.poll_interval, 5);
+   }
+}
@@ -0,0 +1,237 @@
+//! Transport-independent command handling.
+//!
+//! The D-Bus service and the optional control socket both translate their
+//! requests into [`Command`]s and run them through [`execute`], so every
+//! control surface behaves the same.
+
+use std::path::PathBuf;
+
+use bluer::Address;
+use log::info;
+use serde_json::json;
+
+use crate::{
+   airpods::{
+      device::AirPods,
+      model::AirPodsModel,
+      protocol::{FeatureId, NoiseControlMode, PressSetting},
+   },
+   bluetooth::manager::BluetoothManager,
+   ear_detection,
+   error::Result,
+   media_control::{self, LoopStatus},
+   noise_profile, support,
+};
+
+/// A control request understood by the service.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+   GetDevices,
+   GetDevice(Address),
+   ResolveDevice(String),
+   GetStatusSummary(Address),
+   /// Fetch the current settings from the device
+   GetSettings(Address),
+   /// List the features of a device's model, or of the first connected device
+   GetSupportedFeatures(Option<Address>),
+   /// Serial numbers from the device metadata; callers decide whether to
+   /// expose them
+   GetSerialNumbers(Address),
+   SetNoiseMode(Address, NoiseControlMode),
+   SetFeature(Address, FeatureId, bool),
+   SetNoiseButtonModes(Address, Vec<NoiseControlMode>),
+   SetPressSetting(Address, PressSetting),
+   /// Describe the noise profiles and which one is current
+   GetNoiseProfile,
+   /// Pick the noise profile, or go back to picking it by network, and
+   /// apply it to connected devices
+   SetNoiseProfile(Option<String>),
+   Passthrough(Address, Vec<u8>),
+   RefreshBattery(Address),
+   /// Re-run the handshake on the open connection without reconnecting
+   Reinitialize(Address),
+   ConnectDevice(Address),
+   DisconnectDevice(Address),
+   SelfTest,
+   GetPausedPlayers,
+   GetRecentActions,
+   GetEarStats,
+   CancelResume(Address),
+   /// Resume every paused player and forget what auto-pause was tracking
+   ResumeAll,
+   /// Set the repeat mode of the active player
+   SetLoopStatus(LoopStatus),
+   /// Turn shuffle on or off for the active player
+   SetShuffle(bool),
+   /// Feed a synthetic event for a device through the normal pipeline
+   #[cfg(any(debug_assertions, feature = "event-injection"))]
+   InjectEvent(Address, crate::event::AirPodsEvent),
+   /// Write a support bundle to the path, or a default location, optionally redacted
+   ExportSupportBundle {
+      path: Option<PathBuf>,
+      redact: bool,
+   },
+}
+
+impl Command {
+   /// Whether running this command changes state exposed in device listings.
+   pub const fn changes_devices(&self) -> bool {
+      matches!(
+         self,
+         Self::SetNoiseMode(..)
+            | Self::SetFeature(..)
+            | Self::SetNoiseButtonModes(..)
+            | Self::SetPressSetting(..)
+      )
+   }
+}
+
+/// Parses a comma-separated list of modes for the noise control button to cycle through.
+pub fn parse_noise_button_modes(value: &str) -> Result<Vec<NoiseControlMode>, String> {
+   let modes = value
+      .split(',')
+      .map(|mode| {
+         mode
+            .trim()
+            .parse()
+            .map_err(|_| format!("Invalid noise mode: {mode:?}"))
+      })
+      .collect::<Result<Vec<NoiseControlMode>, _>>()?;
+   if modes.len() < 2 {
+      return Err("The noise control button needs at least two modes".to_string());
+   }
+   Ok(modes)
+}
+
+/// Runs a command against the Bluetooth manager, returning its JSON result.
+pub async fn execute(manager: &BluetoothManager, command: Command) -> Result<serde_json::Value> {
+   match command {
+      Command::GetDevices => {
+         let states: Vec<serde_json::Value> = manager
+            .all_devices()
+            .await
+            .into_iter()
+            .map(|d| d.to_json())
+            .collect();
+         Ok(json!(states))
+      },
+      Command::GetDevice(addr) => Ok(manager.get_device(addr).await?.to_json()),
+      Command::ResolveDevice(name) => Ok(manager.resolve_device(&name).await?.to_json()),
+      Command::GetStatusSummary(addr) => {
+         Ok(json!(manager.get_device(addr).await?.status_summary()))
+      },
+      Command::GetSettings(addr) => Ok(manager
+         .get_device(addr)
+         .await?
+         .get_settings()
+         .await?
+         .to_json()),
+      Command::GetSerialNumbers(addr) => {
+         Ok(manager.get_device(addr).await?.serial_numbers().to_json())
+      },
+      Command::GetSupportedFeatures(addr) => {
+         let features = match addr {
+            Some(addr) => manager.get_device(addr).await?.supported_features(),
+            None => manager
+               .all_devices()
+               .await
+               .iter()
+               .find(|device| device.is_connected())
+               .map_or_else(
+                  || AirPodsModel::Unknown.supported_features(),
+                  AirPods::supported_features,
+               ),
+         };
+         Ok(features.to_json())
+      },
+      Command::SetNoiseMode(addr, mode) => {
+         manager
+            .get_device(addr)
+            .await?
+            .set_noise_control(mode)
+            .await?;
+         info!("Set noise mode to {mode} for {addr}");
+         Ok(json!(true))
+      },
+      Command::GetNoiseProfile => Ok(noise_profile::to_json().await),
+      Command::SetNoiseProfile(profile) => {
+         noise_profile::select(profile)?;
+         for device in manager.all_devices().await {
+            if device.is_connected() {
+               noise_profile::apply(&device).await;
+            }
+         }
+         Ok(json!(true))
+      },
+      Command::SetFeature(addr, feature, enabled) => {
+         manager
+            .get_device(addr)
+            .await?
+            .set_feature(feature, enabled)
+            .await?;
+         info!("Set feature {feature} to {enabled} for {addr}");
+         Ok(json!(true))
+      },
+      Command::SetNoiseButtonModes(addr, modes) => {
+         manager
+            .get_device(addr)
+            .await?
+            .set_noise_button_modes(&modes)
+            .await?;
+         info!("Set noise control button modes to {modes:?} for {addr}");
+         Ok(json!(true))
+      },
+      Command::SetPressSetting(addr, setting) => {
+         manager
+            .get_device(addr)
+            .await?
+            .set_press_setting(setting)
+            .await?;
+         info!("Set {setting:?} for {addr}");
+         Ok(json!(true))
+      },
+      Command::Passthrough(addr, packet) => {
+         manager.get_device(addr).await?.passthrough(&packet).await?;
+         Ok(json!(true))
+      },
+      Command::RefreshBattery(addr) => {
+         // The fresh report arrives later as a regular battery update
+         let device = manager.get_device(addr).await?;
+         device.refresh_battery().await?;
+         Ok(json!({
+            "battery": device.battery_info().map(|b| b.to_json()),
+            "age_secs": device.battery_age().map(|age| age.as_secs()),
+         }))
+      },
+      Command::Reinitialize(addr) => {
+         manager.reinitialize(addr).await?;
+         Ok(json!(true))
+      },
+      Command::ConnectDevice(addr) => {
+         manager.establish_aap(addr).await?;
+         Ok(json!(true))
+      },
+      Command::DisconnectDevice(addr) => {
+         manager.disconnect_aap(addr).await?;
+         Ok(json!(true))
+      },
+      Command::SelfTest => Ok(media_control::self_test().await),
+      Command::GetPausedPlayers => Ok(json!(media_control::paused_players())),
+      Command::GetRecentActions => Ok(json!(media_control::recent_actions())),
+      Command::GetEarStats => Ok(ear_detection::ear_stats()),
+      Command::CancelResume(addr) => {
+         // The bridge reports the cleared pending state through the event bus
+         manager.get_device(addr).await?;
+         Ok(json!(ear_detection::cancel_resume(addr).await))
+      },
+      Command::ResumeAll => Ok(json!(media_control::resume_all().await)),
+      Command::SetLoopStatus(status) => Ok(json!(media_control::set_loop_status(status).await?)),
+      Command::SetShuffle(enabled) => Ok(json!(media_control::set_shuffle(enabled).await?)),
+      #[cfg(any(debug_assertions, feature = "event-injection"))]
+      Command::InjectEvent(addr, event) => Ok(json!(manager.inject_event(addr, event).await?)),
+      Command::ExportSupportBundle { path, redact } => {
+         let path = support::export(manager, path, redact).await?;
+         Ok(json!(path.display().to_string()))
+      },
+   }
+}
@@ -0,0 +1,376 @@
+//! Unix-domain socket control server.
+//!
+//! This is an alternative to the D-Bus interface for environments without a
+//! session bus. Clients send one JSON request per line and receive one JSON
+//! response per line:
+//!
+//! ```text
+//! → {"method": "set_noise_mode", "address": "AA:BB:CC:DD:EE:FF", "value": "anc"}
+//! ← {"ok": true, "result": true}
+//! ```
+//!
+//! Methods mirror the D-Bus interface: `get_devices`, `get_device`,
+//! `resolve_device`, `get_status_summary`, `get_settings`, `get_supported_features`, `set_noise_mode`,
+//! `set_feature`, `set_noise_button_modes`, `set_press_speed`, `set_hold_duration`,
+//! `passthrough`, `refresh_battery`, `reinitialize`, `connect_device`, `disconnect_device`, `self_test`, `get_paused_players`,
+//! `get_recent_actions`, `get_ear_stats`, `cancel_resume`, `resume_all`,
+//! `set_loop_status`, `set_shuffle`, `get_noise_profile`, `set_noise_profile`
+//! and `export_support_bundle`.
+//!
+//! `set_noise_profile` goes back to picking the profile by network without
+//! a `profile`.
+//! `export_support_bundle` takes an optional `path` and redacts unless
+//! `redact` is `false`.
+
+use std::{env, fs, io, os::unix::fs::PermissionsExt, path::PathBuf, str::FromStr};
+
+use bluer::Address;
+use log::{debug, info};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::{
+   io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+   net::{UnixListener, UnixStream},
+};
+
+use crate::{
+   airpods::protocol::PressSetting,
+   bluetooth::manager::BluetoothManager,
+   control::{self, Command},
+   error::Result,
+};
+
+/// A request as received on the socket.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "method", rename_all = "snake_case")]
+enum Request {
+   GetDevices,
+   GetDevice {
+      address: String,
+   },
+   ResolveDevice {
+      name: String,
+   },
+   GetStatusSummary {
+      address: String,
+   },
+   GetSettings {
+      address: String,
+   },
+   GetSupportedFeatures {
+      #[serde(default)]
+      address: Option<String>,
+   },
+   SetNoiseMode {
+      address: String,
+      value: String,
+   },
+   SetFeature {
+      address: String,
+      feature: String,
+      enabled: bool,
+   },
+   SetNoiseButtonModes {
+      address: String,
+      value: String,
+   },
+   SetPressSpeed {
+      address: String,
+      value: String,
+   },
+   SetHoldDuration {
+      address: String,
+      value: String,
+   },
+   Passthrough {
+      address: String,
+      packet: String,
+   },
+   RefreshBattery {
+      address: String,
+   },
+   Reinitialize {
+      address: String,
+   },
+   ConnectDevice {
+      address: String,
+   },
+   DisconnectDevice {
+      address: String,
+   },
+   SelfTest,
+   GetPausedPlayers,
+   GetRecentActions,
+   GetEarStats,
+   CancelResume {
+      address: String,
+   },
+   ResumeAll,
+   SetLoopStatus {
+      value: String,
+   },
+   SetShuffle {
+      enabled: bool,
+   },
+   GetNoiseProfile,
+   SetNoiseProfile {
+      #[serde(default)]
+      profile: Option<String>,
+   },
+   ExportSupportBundle {
+      #[serde(default)]
+      path: Option<PathBuf>,
+      #[serde(default = "default_redact")]
+      redact: bool,
+   },
+}
+
+const fn default_redact() -> bool {
+   true
+}
+
+fn parse_address(address: &str) -> Result<Address, String> {
+   Address::from_str(address).map_err(|e| format!("Invalid address {address:?}: {e}"))
+}
+
+impl TryFrom<Request> for Command {
+   type Error = String;
+
+   fn try_from(request: Request) -> Result<Self, Self::Error> {
+      Ok(match request {
+         Request::GetDevices => Self::GetDevices,
+         Request::GetDevice { address } => Self::GetDevice(parse_address(&address)?),
+         Request::ResolveDevice { name } => Self::ResolveDevice(name),
+         Request::GetStatusSummary { address } => Self::GetStatusSummary(parse_address(&address)?),
+         Request::GetSettings { address } => Self::GetSettings(parse_address(&address)?),
+         Request::GetSupportedFeatures { address } => {
+            Self::GetSupportedFeatures(address.as_deref().map(parse_address).transpose()?)
+         },
+         Request::SetNoiseMode { address, value } => Self::SetNoiseMode(
+            parse_address(&address)?,
+            value
+               .parse()
+               .map_err(|_| format!("Invalid noise mode: {value:?}"))?,
+         ),
+         Request::SetFeature {
+            address,
+            feature,
+            enabled,
+         } => Self::SetFeature(
+            parse_address(&address)?,
+            feature
+               .parse()
+               .map_err(|_| format!("Invalid feature: {feature:?}"))?,
+            enabled,
+         ),
+         Request::SetNoiseButtonModes { address, value } => Self::SetNoiseButtonModes(
+            parse_address(&address)?,
+            control::parse_noise_button_modes(&value)?,
+         ),
+         Request::SetPressSpeed { address, value } => Self::SetPressSetting(
+            parse_address(&address)?,
+            PressSetting::Speed(
+               value
+                  .parse()
+                  .map_err(|_| format!("Invalid press speed: {value:?}"))?,
+            ),
+         ),
+         Request::SetHoldDuration { address, value } => Self::SetPressSetting(
+            parse_address(&address)?,
+            PressSetting::Hold(
+               value
+                  .parse()
+                  .map_err(|_| format!("Invalid hold duration: {value:?}"))?,
+            ),
+         ),
+         Request::Passthrough { address, packet } => Self::Passthrough(
+            parse_address(&address)?,
+            hex::decode(packet).map_err(|e| format!("Invalid packet: {e}"))?,
+         ),
+         Request::RefreshBattery { address } => Self::RefreshBattery(parse_address(&address)?),
+         Request::Reinitialize { address } => Self::Reinitialize(parse_address(&address)?),
+         Request::ConnectDevice { address } => Self::ConnectDevice(parse_address(&address)?),
+         Request::DisconnectDevice { address } => Self::DisconnectDevice(parse_address(&address)?),
+         Request::SelfTest => Self::SelfTest,
+         Request::GetPausedPlayers => Self::GetPausedPlayers,
+         Request::GetRecentActions => Self::GetRecentActions,
+         Request::GetEarStats => Self::GetEarStats,
+         Request::CancelResume { address } => Self::CancelResume(parse_address(&address)?),
+         Request::ResumeAll => Self::ResumeAll,
+         Request::SetLoopStatus { value } => Self::SetLoopStatus(
+            value
+               .parse()
+               .map_err(|_| format!("Invalid loop status: {value:?}"))?,
+         ),
+         Request::SetShuffle { enabled } => Self::SetShuffle(enabled),
+         Request::GetNoiseProfile => Self::GetNoiseProfile,
+         Request::SetNoiseProfile { profile } => Self::SetNoiseProfile(profile),
+         Request::ExportSupportBundle { path, redact } => {
+            Self::ExportSupportBundle { path, redact }
+         },
+      })
+   }
+}
+
+/// Returns the socket path, `$XDG_RUNTIME_DIR/kairpods.sock` unless overridden.
+fn socket_path() -> Option<PathBuf> {
+   if let Ok(path) = env::var("AIRPODS_CONTROL_SOCKET") {
+      return Some(PathBuf::from(path));
+   }
+   dirs::runtime_dir().map(|dir| dir.join("kairpods.sock"))
+}
+
+/// Whether another instance is listening on the control socket.
+pub async fn is_served() -> bool {
+   match socket_path() {
+      Some(path) => UnixStream::connect(path).await.is_ok(),
+      None => false,
+   }
+}
+
+/// Listens on the control socket and serves clients until the listener fails.
+pub async fn serve(manager: BluetoothManager) -> Result<()> {
+   let path = socket_path()
+      .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "XDG_RUNTIME_DIR is not set"))?;
+
+   // Remove a stale socket left behind by a previous run
+   let _ = fs::remove_file(&path);
+   let listener = UnixListener::bind(&path)?;
+   fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+   info!("Control socket listening at {}", path.display());
+
+   loop {
+      let (stream, _) = listener.accept().await?;
+      let manager = manager.clone();
+      tokio::spawn(async move {
+         if let Err(e) = handle_client(stream, &manager).await {
+            debug!("Control socket client error: {e}");
+         }
+      });
+   }
+}
+
+async fn handle_client(stream: UnixStream, manager: &BluetoothManager) -> io::Result<()> {
+   let (reader, mut writer) = stream.into_split();
+   let mut lines = BufReader::new(reader).lines();
+
+   while let Some(line) = lines.next_line().await? {
+      if line.trim().is_empty() {
+         continue;
+      }
+      let response = match handle_request(manager, &line).await {
+         Ok(result) => json!({ "ok": true, "result": result }),
+         Err(error) => json!({ "ok": false, "error": error }),
+      };
+      writer.write_all(format!("{response}\n").as_bytes()).await?;
+   }
+   Ok(())
+}
+
+async fn handle_request(
+   manager: &BluetoothManager,
+   line: &str,
+) -> Result<serde_json::Value, String> {
+   let request: Request =
+      serde_json::from_str(line).map_err(|e| format!("Invalid request: {e}"))?;
+   let command = Command::try_from(request)?;
+   control::execute(manager, command)
+      .await
+      .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::airpods::protocol::{FeatureId, NoiseControlMode, PressSpeed};
+
+   const TEST_ADDRESS: Address = Address([0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF]);
+
+   fn parse(line: &str) -> Result<Command, String> {
+      let request: Request = serde_json::from_str(line).map_err(|e| e.to_string())?;
+      Command::try_from(request)
+   }
+
+   #[test]
+   fn test_parse_requests() {
+      assert_eq!(
+         parse(r#"{"method":"get_devices"}"#),
+         Ok(Command::GetDevices)
+      );
+      assert_eq!(
+         parse(r#"{"method":"reinitialize","address":"AA:BB:CC:DD:EE:FF"}"#),
+         Ok(Command::Reinitialize(Address::new([
+            0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF
+         ])))
+      );
+      assert_eq!(
+         parse(r#"{"method":"set_noise_profile","profile":"office"}"#),
+         Ok(Command::SetNoiseProfile(Some("office".to_string())))
+      );
+      assert_eq!(
+         parse(r#"{"method":"set_noise_profile"}"#),
+         Ok(Command::SetNoiseProfile(None))
+      );
+      assert_eq!(
+         parse(r#"{"method":"get_status_summary","address":"AA:BB:CC:DD:EE:FF"}"#),
+         Ok(Command::GetStatusSummary(TEST_ADDRESS))
+      );
+      assert_eq!(
+         parse(r#"{"method":"get_settings","address":"AA:BB:CC:DD:EE:FF"}"#),
+         Ok(Command::GetSettings(TEST_ADDRESS))
+      );
+      assert_eq!(
+         parse(r#"{"method":"get_supported_features"}"#),
+         Ok(Command::GetSupportedFeatures(None))
+      );
+      assert_eq!(
+         parse(r#"{"method":"get_supported_features","address":"AA:BB:CC:DD:EE:FF"}"#),
+         Ok(Command::GetSupportedFeatures(Some(TEST_ADDRESS)))
+      );
+      assert_eq!(
+         parse(r#"{"method":"set_noise_mode","address":"AA:BB:CC:DD:EE:FF","value":"anc"}"#),
+         Ok(Command::SetNoiseMode(
+            TEST_ADDRESS,
+            NoiseControlMode::Active
+         ))
+      );
+      assert_eq!(
+         parse(
+            r#"{"method":"set_feature","address":"AA:BB:CC:DD:EE:FF","feature":"conversational","enabled":true}"#
+         ),
+         Ok(Command::SetFeature(
+            TEST_ADDRESS,
+            FeatureId::CONVERSATIONAL,
+            true
+         ))
+      );
+      assert_eq!(
+         parse(r#"{"method":"set_press_speed","address":"AA:BB:CC:DD:EE:FF","value":"slower"}"#),
+         Ok(Command::SetPressSetting(
+            TEST_ADDRESS,
+            PressSetting::Speed(PressSpeed::Slower)
+         ))
+      );
+      assert_eq!(
+         parse(r#"{"method":"passthrough","address":"AA:BB:CC:DD:EE:FF","packet":"0400"}"#),
+         Ok(Command::Passthrough(TEST_ADDRESS, vec![0x04, 0x00]))
+      );
+      assert_eq!(
+         parse(r#"{"method":"export_support_bundle"}"#),
+         Ok(Command::ExportSupportBundle {
+            path: None,
+            redact: true
+         })
+      );
+   }
+
+   #[test]
+   fn test_parse_invalid_requests() {
+      assert!(parse(r#"{"method":"self_destruct"}"#).is_err());
+      assert!(parse(r#"{"method":"get_device","address":"nope"}"#).is_err());
+      assert!(
+         parse(r#"{"method":"set_noise_mode","address":"AA:BB:CC:DD:EE:FF","value":"loud"}"#)
+            .is_err()
+      );
+   }
+}
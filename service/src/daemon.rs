@@ -0,0 +1,605 @@
+//! Embeddable service facade.
+//!
+//! [`KAirPods`] owns everything the daemon runs: the Bluetooth manager, the
+//! event processor driving media control and audio routing, the D-Bus
+//! service and background tasks. `kairpodsd` is a thin wrapper around it;
+//! other applications can build one with [`KAirPodsBuilder`] and talk to it
+//! through a [`Handle`] instead of D-Bus.
+
+#[cfg(feature = "tray")]
+use std::sync::OnceLock;
+use std::{
+   sync::{Arc, Weak},
+   time::Duration,
+};
+
+use crossbeam::queue::SegQueue;
+use log::{info, warn};
+use tokio::{signal, sync::Notify, time};
+use zbus::{
+   Connection,
+   fdo::{DBusProxy, RequestNameFlags, RequestNameReply},
+   names::BusName,
+   object_server::InterfaceRef,
+};
+
+use crate::{
+   airpods::{device::AirPods, protocol::StemPress, summary},
+   audio::{ContextNoiseControl, SinkSwitcher},
+   battery_provider::BatteryProvider,
+   battery_study::BatteryStudy,
+   bluetooth::manager::BluetoothManager,
+   config::Config,
+   control::{self, Command},
+   dbus::{AirPodsService, AirPodsServiceSignals},
+   ear_detection::EarDetectionBridge,
+   error::{AirPodsError, Result},
+   event::{AirPodsEvent, EventBus},
+   media_control::{self, ActionReason, MediaEvent},
+   noise_profile,
+   notification::{self, Notifier},
+   quiet_hours, session_lock,
+   supervisor::Supervisor,
+};
+
+/// Configures and starts a [`KAirPods`] instance.
+pub struct KAirPodsBuilder {
+   config: Option<Config>,
+   dbus: bool,
+   battery_study: bool,
+}
+
+impl Default for KAirPodsBuilder {
+   fn default() -> Self {
+      Self::new()
+   }
+}
+
+impl KAirPodsBuilder {
+   /// Creates a builder that serves D-Bus and records battery studies.
+   pub fn new() -> Self {
+      Self {
+         config: None,
+         dbus: true,
+         battery_study: true,
+      }
+   }
+
+   /// Uses this configuration instead of loading it from disk.
+   pub fn config(mut self, config: Config) -> Self {
+      self.config = Some(config);
+      self
+   }
+
+   /// Whether to claim `org.kairpods` on the session bus. On by default.
+   ///
+   /// Embedders that only use a [`Handle`] can turn it off so they don't
+   /// conflict with a running daemon.
+   pub const fn dbus(mut self, enabled: bool) -> Self {
+      self.dbus = enabled;
+      self
+   }
+
+   /// Whether to record battery drain in the battery study database. On by default.
+   pub const fn battery_study(mut self, enabled: bool) -> Self {
+      self.battery_study = enabled;
+      self
+   }
+
+   /// Starts the service.
+   pub async fn build(self) -> Result<KAirPods> {
+      let config = match self.config {
+         Some(config) => config,
+         None => Config::load().unwrap_or_else(|e| {
+            warn!("Failed to load configuration: {e:?}");
+            Config::default()
+         }),
+      };
+
+      // Claim the bus name before touching any device, so that a second
+      // instance backs off instead of fighting over the AAP channels
+      let connection = if self.dbus {
+         match claim_dbus_name().await {
+            Ok(connection) => Some(connection),
+            Err(e @ AirPodsError::AlreadyRunning(_)) => return Err(e),
+            Err(e) if cfg!(feature = "control-socket") => {
+               warn!("D-Bus unavailable ({e}), continuing with the control socket only");
+               None
+            },
+            Err(e) => return Err(e),
+         }
+      } else {
+         None
+      };
+      #[cfg(feature = "control-socket")]
+      if crate::control_socket::is_served().await {
+         return Err(AirPodsError::AlreadyRunning(None));
+      }
+
+      // Background subscriptions, torn down together on shutdown
+      let supervisor = Supervisor::new();
+
+      media_control::configure(config.media.clone());
+      media_control::restore_paused();
+      summary::configure(config.status_template.clone());
+      noise_profile::configure(&config.noise_profiles);
+      media_control::spawn_playback_watcher(&supervisor);
+      quiet_hours::configure(&config.quiet_hours);
+      if config.media.pause_on_lock {
+         session_lock::spawn_watcher(&supervisor);
+      }
+
+      // Create event channel
+      let event_bus = EventProcessor::new(&config);
+
+      // Initialize battery study database
+      let battery_study = if self.battery_study {
+         match BatteryStudy::open() {
+            Ok(study) => {
+               info!("Battery study database initialized");
+               Some(study)
+            },
+            Err(e) => {
+               warn!("Failed to initialize battery study database: {e}");
+               None
+            },
+         }
+      } else {
+         None
+      };
+
+      // Create Bluetooth manager with event sender and config
+      let manager = BluetoothManager::new(event_bus.clone(), config.clone(), battery_study).await?;
+
+      #[cfg(feature = "tray")]
+      if config.tray {
+         let _ = event_bus
+            .tray
+            .set(crate::tray::Tray::spawn(&supervisor, manager.clone()));
+      }
+
+      // Start the control socket for environments without D-Bus
+      #[cfg(feature = "control-socket")]
+      supervisor.spawn("control socket", {
+         let manager = manager.clone();
+         async move {
+            if let Err(e) = crate::control_socket::serve(manager).await {
+               warn!("Control socket stopped: {e}");
+            }
+         }
+      });
+
+      if let Some(connection) = &connection {
+         connection
+            .object_server()
+            .at(
+               "/org/kairpods/manager",
+               AirPodsService::new(manager.clone(), config.expose_serial_numbers),
+            )
+            .await?;
+         info!("kAirPods D-Bus service started at org.kairpods");
+      }
+
+      // Start event processor
+      event_bus
+         .spawn_dispatcher(&supervisor, connection.clone())
+         .await?;
+
+      Ok(KAirPods {
+         handle: Handle { manager },
+         config,
+         connection,
+         supervisor,
+      })
+   }
+}
+
+/// A running kAirPods service.
+pub struct KAirPods {
+   handle: Handle,
+   config: Config,
+   connection: Option<Connection>,
+   supervisor: Supervisor,
+}
+
+impl KAirPods {
+   pub fn builder() -> KAirPodsBuilder {
+      KAirPodsBuilder::new()
+   }
+
+   /// Gets a handle for controlling the service.
+   pub fn handle(&self) -> Handle {
+      self.handle.clone()
+   }
+
+   /// Gets the configuration the service was started with.
+   pub const fn config(&self) -> &Config {
+      &self.config
+   }
+
+   /// Runs until interrupted with Ctrl-C, then shuts down.
+   pub async fn run(self) -> Result<()> {
+      signal::ctrl_c().await?;
+      info!("Shutting down kAirPods service...");
+      self.shutdown().await;
+      Ok(())
+   }
+
+   /// Stops background tasks and releases the D-Bus name.
+   pub async fn shutdown(self) {
+      self.supervisor.shutdown().await;
+      drop(self.connection);
+   }
+}
+
+/// Cheaply cloneable handle to a running service.
+#[derive(Clone)]
+pub struct Handle {
+   manager: BluetoothManager,
+}
+
+impl Handle {
+   /// Runs a command, the same way the D-Bus interface does.
+   pub async fn execute(&self, command: Command) -> Result<serde_json::Value> {
+      control::execute(&self.manager, command).await
+   }
+
+   /// Gets the Bluetooth manager, for access beyond [`Command`]s.
+   pub const fn manager(&self) -> &BluetoothManager {
+      &self.manager
+   }
+}
+
+/// Connects to the session bus and claims `org.kairpods`, failing with
+/// [`AirPodsError::AlreadyRunning`] if another instance owns it.
+async fn claim_dbus_name() -> Result<Connection> {
+   let connection = Connection::session().await?;
+   let reply = connection
+      .request_name_with_flags("org.kairpods", RequestNameFlags::DoNotQueue.into())
+      .await;
+   match reply {
+      Ok(RequestNameReply::PrimaryOwner | RequestNameReply::AlreadyOwner) => Ok(connection),
+      Ok(_) | Err(zbus::Error::NameTaken) => Err(AirPodsError::AlreadyRunning(
+         name_owner_pid(&connection).await.ok(),
+      )),
+      Err(e) => Err(e.into()),
+   }
+}
+
+async fn name_owner_pid(connection: &Connection) -> zbus::Result<u32> {
+   let name = BusName::try_from("org.kairpods")?;
+   Ok(DBusProxy::new(connection)
+      .await?
+      .get_connection_unix_process_id(name)
+      .await?)
+}
+
+struct EventProcessor {
+   queue: SegQueue<(AirPods, AirPodsEvent)>,
+   notifier: Notify,
+   sink_switcher: SinkSwitcher,
+   context_noise_control: ContextNoiseControl,
+   ear_detection: EarDetectionBridge,
+   battery_provider: Option<BatteryProvider>,
+   /// Configurable notifications for device events
+   notifications: Notifier,
+   /// Show a desktop notification for case peeks
+   peek_notify: bool,
+   /// Show a desktop notification for poor signal
+   signal_notify: bool,
+   /// Stem press that toggles auto-pause
+   auto_pause_toggle_press: Option<StemPress>,
+   /// Stem press that stops the active player
+   stop_press: Option<StemPress>,
+   /// Tray icon, started once the manager is up
+   #[cfg(feature = "tray")]
+   tray: OnceLock<crate::tray::Tray>,
+}
+
+impl EventProcessor {
+   fn new(config: &Config) -> Arc<Self> {
+      Arc::new_cyclic(|this: &Weak<Self>| Self {
+         queue: SegQueue::new(),
+         notifier: Notify::new(),
+         sink_switcher: SinkSwitcher::new(config.audio.clone()),
+         context_noise_control: ContextNoiseControl::new(config.audio.clone()),
+         ear_detection: EarDetectionBridge::spawn(&config.media, this.clone()),
+         battery_provider: config.battery_provider.then(BatteryProvider::spawn),
+         notifications: Notifier::new(&config.notifications),
+         peek_notify: config.peek.notify,
+         signal_notify: config.signal.notify,
+         auto_pause_toggle_press: config.media.auto_pause_toggle_press,
+         stop_press: config.media.stop_press,
+         #[cfg(feature = "tray")]
+         tray: OnceLock::new(),
+      })
+   }
+}
+
+impl EventProcessor {
+   async fn recv(self: &Arc<Self>) -> Option<(AirPods, AirPodsEvent)> {
+      loop {
+         if let Some(event) = self.queue.pop() {
+            return Some(event);
+         }
+         let notify = self.notifier.notified();
+         if let Some(event) = self.queue.pop() {
+            return Some(event);
+         }
+         if Arc::strong_count(self) == 1 {
+            return None;
+         }
+         let _ = time::timeout(Duration::from_secs(1), notify).await;
+      }
+   }
+
+   /// Runs the side effects of an event that don't depend on D-Bus.
+   fn react(&self, device: &AirPods, event: &AirPodsEvent) {
+      self.notifications.handle(device, event);
+      #[cfg(feature = "tray")]
+      if let Some(tray) = self.tray.get()
+         && matches!(
+            event,
+            AirPodsEvent::DeviceConnected
+               | AirPodsEvent::DeviceDisconnected
+               | AirPodsEvent::ConnectionStateChanged(_)
+               | AirPodsEvent::BatteryUpdated(_)
+               | AirPodsEvent::NoiseControlChanged(_)
+               | AirPodsEvent::QueuedNoiseControlApplied(_)
+               | AirPodsEvent::DeviceNameChanged(_)
+         )
+      {
+         tray.refresh();
+      }
+      match event {
+         AirPodsEvent::DeviceConnected => {
+            self.sink_switcher.device_connected(device.address());
+            self.context_noise_control.device_connected(device);
+         },
+         AirPodsEvent::DeviceDisconnected => {
+            self.sink_switcher.device_disconnected(device.address());
+            self
+               .context_noise_control
+               .device_disconnected(device.address());
+            if let Some(provider) = &self.battery_provider {
+               provider.device_disconnected(device.address());
+            }
+         },
+         AirPodsEvent::BatteryUpdated(battery) => {
+            if let Some(provider) = &self.battery_provider {
+               provider.battery_updated(device.address(), battery);
+            }
+         },
+         AirPodsEvent::EarDetectionChanged(ear_detection) => {
+            // Handle play/pause based on ear detection
+            self.ear_detection.update(device.clone(), *ear_detection);
+         },
+         AirPodsEvent::StemPressed(press) if self.auto_pause_toggle_press == Some(*press) => {
+            let enabled = !media_control::is_enabled();
+            info!(
+               "Auto-pause {} by a {press} press",
+               if enabled { "enabled" } else { "disabled" }
+            );
+            media_control::set_enabled(enabled);
+         },
+         AirPodsEvent::StemPressed(press) if self.stop_press == Some(*press) => {
+            info!("Stopping the active player by a {press} press");
+            tokio::spawn(media_control::send_stop(ActionReason::Gesture));
+         },
+         AirPodsEvent::PoorSignal(_) if self.signal_notify => {
+            let summary = format!("{} signal is weak", device.name());
+            tokio::spawn(async move {
+               let body = "Audio may stutter. Move closer to the computer.";
+               if let Err(e) = notification::show(&summary, body).await {
+                  warn!("Failed to show signal notification: {e}");
+               }
+            });
+         },
+         AirPodsEvent::CasePeeked(battery) if self.peek_notify => {
+            let summary = device.name().to_string();
+            let body = notification::battery_summary(battery);
+            tokio::spawn(async move {
+               if let Err(e) = notification::show(&summary, &body).await {
+                  warn!("Failed to show battery notification: {e}");
+               }
+            });
+         },
+         _ => {},
+      }
+   }
+
+   async fn dispatch(
+      &self,
+      iface: &InterfaceRef<AirPodsService>,
+      (device, event): (AirPods, AirPodsEvent),
+   ) -> Result<()> {
+      let addr_str = device.address_str();
+      match event {
+         AirPodsEvent::DeviceConnected => {
+            iface.device_connected(addr_str).await?;
+            // Emit property changes
+            iface
+               .get_mut()
+               .await
+               .devices_changed(iface.signal_emitter())
+               .await?;
+            iface
+               .get_mut()
+               .await
+               .connected_count_changed(iface.signal_emitter())
+               .await?;
+         },
+         AirPodsEvent::DeviceDisconnected => {
+            iface.device_disconnected(addr_str).await?;
+            // Emit property changes
+            iface
+               .get_mut()
+               .await
+               .devices_changed(iface.signal_emitter())
+               .await?;
+            iface
+               .get_mut()
+               .await
+               .connected_count_changed(iface.signal_emitter())
+               .await?;
+         },
+         AirPodsEvent::BatteryUpdated(battery) => {
+            iface
+               .battery_updated(addr_str, &battery.to_json().to_string())
+               .await?;
+            // Emit property change for devices (battery state changed)
+            iface
+               .get_mut()
+               .await
+               .devices_changed(iface.signal_emitter())
+               .await?;
+         },
+         AirPodsEvent::NoiseControlChanged(mode) => {
+            iface.noise_control_changed(addr_str, mode.to_str()).await?;
+            // Emit property change for devices (noise control state changed)
+            iface
+               .get_mut()
+               .await
+               .devices_changed(iface.signal_emitter())
+               .await?;
+         },
+         AirPodsEvent::QueuedNoiseControlApplied(mode) => {
+            iface
+               .queued_noise_control_applied(addr_str, mode.to_str())
+               .await?;
+            iface.noise_control_changed(addr_str, mode.to_str()).await?;
+            iface
+               .get_mut()
+               .await
+               .devices_changed(iface.signal_emitter())
+               .await?;
+         },
+         AirPodsEvent::AdaptiveLevelChanged(_) | AirPodsEvent::SettingsChanged => {
+            // Only exposed through the device properties
+            iface
+               .get_mut()
+               .await
+               .devices_changed(iface.signal_emitter())
+               .await?;
+         },
+         AirPodsEvent::CasePeeked(battery) => {
+            iface
+               .case_peeked(addr_str, &battery.to_json().to_string())
+               .await?;
+         },
+         AirPodsEvent::PoorSignal(rssi) => {
+            iface.poor_signal(addr_str, rssi).await?;
+         },
+         AirPodsEvent::Reinitialized(success) => {
+            iface.reinitialized(addr_str, success).await?;
+         },
+         AirPodsEvent::EarDetectionChanged(ear_detection) => {
+            iface
+               .ear_detection_changed(addr_str, &ear_detection.to_json().to_string())
+               .await?;
+            // Emit property change for devices (ear detection state changed)
+            iface
+               .get_mut()
+               .await
+               .devices_changed(iface.signal_emitter())
+               .await?;
+         },
+         AirPodsEvent::DeviceNameChanged(name) => {
+            iface.device_name_changed(addr_str, &name).await?;
+            // Emit property change for devices (name changed)
+            iface
+               .get_mut()
+               .await
+               .devices_changed(iface.signal_emitter())
+               .await?;
+         },
+         AirPodsEvent::ResumePendingChanged(pending) => {
+            iface.resume_pending(addr_str, pending).await?;
+            iface
+               .get_mut()
+               .await
+               .devices_changed(iface.signal_emitter())
+               .await?;
+         },
+         AirPodsEvent::ConnectionStateChanged(state) => {
+            iface
+               .connection_state_changed(addr_str, state.to_str())
+               .await?;
+            iface
+               .get_mut()
+               .await
+               .devices_changed(iface.signal_emitter())
+               .await?;
+         },
+         AirPodsEvent::StemPressed(press) => {
+            if self.auto_pause_toggle_press == Some(press) {
+               iface
+                  .get_mut()
+                  .await
+                  .media_control_enabled_changed(iface.signal_emitter())
+                  .await?;
+            }
+         },
+         AirPodsEvent::DeviceError => {
+            iface.device_error(addr_str).await?;
+            // Emit property change for devices (error state might affect device info)
+            iface
+               .get_mut()
+               .await
+               .devices_changed(iface.signal_emitter())
+               .await?;
+         },
+      }
+      Ok(())
+   }
+
+   async fn spawn_dispatcher(
+      self: Arc<Self>,
+      supervisor: &Supervisor,
+      connection: Option<Connection>,
+   ) -> Result<()> {
+      let iface = match &connection {
+         Some(connection) => Some(
+            connection
+               .object_server()
+               .interface::<_, AirPodsService>("/org/kairpods/manager")
+               .await?,
+         ),
+         None => None,
+      };
+      if let Some(iface) = iface.clone() {
+         let mut media_events = media_control::subscribe();
+         supervisor.spawn("media signal forwarder", async move {
+            while let Some(event) = media_events.recv().await {
+               let sent = match event {
+                  MediaEvent::AutoPaused(players) => iface.media_auto_paused(&players).await,
+                  MediaEvent::AutoResumed(players) => iface.media_auto_resumed(&players).await,
+               };
+               if let Err(e) = sent {
+                  warn!("Error emitting media signal: {e}");
+               }
+            }
+         });
+      }
+      supervisor.spawn("event dispatcher", async move {
+         while let Some((device, event)) = self.recv().await {
+            self.react(&device, &event);
+            let Some(iface) = &iface else {
+               continue;
+            };
+            if let Err(e) = self.dispatch(iface, (device, event)).await {
+               warn!("Error dispatching event: {e}");
+            }
+         }
+      });
+
+      Ok(())
+   }
+}
+
+impl EventBus for EventProcessor {
+   fn emit(&self, device: &AirPods, event: AirPodsEvent) {
+      self.queue.push((device.clone(), event));
+      self.notifier.notify_waiters();
+   }
+}
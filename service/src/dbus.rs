@@ -1,21 +1,26 @@
 use std::{collections::HashMap, fmt, str::FromStr};
 
 use bluer::Address;
-use log::info;
 use zbus::{fdo, interface, object_server::SignalEmitter, zvariant};
 
 use crate::{
-   airpods::protocol::{FeatureId, NoiseControlMode},
+   airpods::protocol::{FeatureId, NoiseControlMode, PressSetting},
    bluetooth::manager::BluetoothManager,
+   control::{self, Command},
+   media_control, quiet_hours,
 };
 
 pub struct AirPodsService {
    bluetooth_manager: BluetoothManager,
+   expose_serial_numbers: bool,
 }
 
 impl AirPodsService {
-   pub const fn new(bluetooth_manager: BluetoothManager) -> Self {
-      Self { bluetooth_manager }
+   pub const fn new(bluetooth_manager: BluetoothManager, expose_serial_numbers: bool) -> Self {
+      Self {
+         bluetooth_manager,
+         expose_serial_numbers,
+      }
    }
 }
 
@@ -26,27 +31,68 @@ fn to_arg_error<T: fmt::Display>(e: T) -> fdo::Error {
 #[interface(name = "org.kairpods.manager")]
 impl AirPodsService {
    async fn get_devices(&self) -> fdo::Result<String> {
-      let states: Vec<serde_json::Value> = self
-         .bluetooth_manager
-         .all_devices()
-         .await
-         .into_iter()
-         .map(|d| d.to_json())
-         .collect();
-      Ok(serde_json::to_string(&states).unwrap())
+      let devices = control::execute(&self.bluetooth_manager, Command::GetDevices).await?;
+      Ok(devices.to_string())
    }
 
    async fn get_device(&self, address: String) -> fdo::Result<String> {
       let addr = Address::from_str(&address).map_err(to_arg_error)?;
-      let dev = self.bluetooth_manager.get_device(addr).await?;
-      Ok(dev.to_json().to_string())
+      let dev = control::execute(&self.bluetooth_manager, Command::GetDevice(addr)).await?;
+      Ok(dev.to_string())
+   }
+
+   async fn resolve_device(&self, name: String) -> fdo::Result<String> {
+      let dev = control::execute(&self.bluetooth_manager, Command::ResolveDevice(name)).await?;
+      Ok(dev["address"].as_str().unwrap_or_default().to_string())
+   }
+
+   async fn get_status_summary(&self, address: String) -> fdo::Result<String> {
+      let addr = Address::from_str(&address).map_err(to_arg_error)?;
+      let summary =
+         control::execute(&self.bluetooth_manager, Command::GetStatusSummary(addr)).await?;
+      Ok(summary.as_str().unwrap_or_default().to_string())
+   }
+
+   async fn get_settings(&self, address: String) -> fdo::Result<String> {
+      let addr = Address::from_str(&address).map_err(to_arg_error)?;
+      let settings = control::execute(&self.bluetooth_manager, Command::GetSettings(addr)).await?;
+      Ok(settings.to_string())
+   }
+
+   async fn get_supported_features(&self) -> fdo::Result<String> {
+      let features =
+         control::execute(&self.bluetooth_manager, Command::GetSupportedFeatures(None)).await?;
+      Ok(features.to_string())
+   }
+
+   async fn get_serial_numbers(&self, address: String) -> fdo::Result<String> {
+      if !self.expose_serial_numbers {
+         return Err(fdo::Error::AccessDenied(
+            "Serial numbers are not exposed, set expose_serial_numbers = true".to_string(),
+         ));
+      }
+      let addr = Address::from_str(&address).map_err(to_arg_error)?;
+      let serials =
+         control::execute(&self.bluetooth_manager, Command::GetSerialNumbers(addr)).await?;
+      Ok(serials.to_string())
+   }
+
+   async fn get_noise_profile(&self) -> fdo::Result<String> {
+      let profile = control::execute(&self.bluetooth_manager, Command::GetNoiseProfile).await?;
+      Ok(profile.to_string())
+   }
+
+   async fn set_noise_profile(&self, profile: String) -> fdo::Result<bool> {
+      // An empty name goes back to picking the profile by network
+      let profile = Some(profile).filter(|profile| !profile.is_empty());
+      control::execute(&self.bluetooth_manager, Command::SetNoiseProfile(profile)).await?;
+      Ok(true)
    }
 
    async fn passthrough(&self, address: String, packet: String) -> fdo::Result<bool> {
       let addr = Address::from_str(&address).map_err(to_arg_error)?;
-      let dev = self.bluetooth_manager.get_device(addr).await?;
       let packet = hex::decode(packet).map_err(to_arg_error)?;
-      dev.passthrough(&packet).await?;
+      control::execute(&self.bluetooth_manager, Command::Passthrough(addr, packet)).await?;
       Ok(true)
    }
 
@@ -59,9 +105,7 @@ impl AirPodsService {
    ) -> fdo::Result<bool> {
       let addr = Address::from_str(&address).map_err(to_arg_error)?;
 
-      let dev = self.bluetooth_manager.get_device(addr).await?;
-
-      match action.as_str() {
+      let command = match action.as_str() {
          "set_noise_mode" => {
             let mode_str = params
                .get("value")
@@ -73,12 +117,7 @@ impl AirPodsService {
                .parse()
                .map_err(|_| to_arg_error(format_args!("Invalid noise mode: {mode_str:?}")))?;
 
-            dev.set_noise_control(mode).await?;
-
-            info!("Set noise mode to {mode} for {address}");
-
-            // Emit property change immediately so UI updates
-            self.devices_changed(&emitter).await?;
+            Command::SetNoiseMode(addr, mode)
          },
 
          "set_feature" => {
@@ -102,33 +141,143 @@ impl AirPodsService {
                   ))
                })?;
 
-            dev.set_feature(feature, enabled).await?;
-            info!("Set feature {feature} to {enabled} for {address}");
+            Command::SetFeature(addr, feature, enabled)
+         },
+
+         "set_noise_button_modes" => {
+            let modes_str = params
+               .get("value")
+               .ok_or_else(|| to_arg_error("Missing 'value' parameter"))?
+               .downcast_ref::<String>()
+               .map_err(|e| to_arg_error(format_args!("Invalid 'value' parameter: {e}")))?;
+
+            let modes = control::parse_noise_button_modes(&modes_str).map_err(to_arg_error)?;
+            Command::SetNoiseButtonModes(addr, modes)
+         },
+
+         "set_press_speed" | "set_hold_duration" => {
+            let value = params
+               .get("value")
+               .ok_or_else(|| to_arg_error("Missing 'value' parameter"))?
+               .downcast_ref::<String>()
+               .map_err(|e| to_arg_error(format_args!("Invalid 'value' parameter: {e}")))?;
 
-            // Emit property change immediately so UI updates
-            self.devices_changed(&emitter).await?;
+            let setting = if action == "set_press_speed" {
+               value.parse().map(PressSetting::Speed)
+            } else {
+               value.parse().map(PressSetting::Hold)
+            };
+            let setting = setting
+               .map_err(|_| to_arg_error(format_args!("Invalid {action} value: {value:?}")))?;
+            Command::SetPressSetting(addr, setting)
          },
 
          _ => {
             return Err(to_arg_error(format_args!("Unknown action: {action}")));
          },
+      };
+
+      let changes_devices = command.changes_devices();
+      control::execute(&self.bluetooth_manager, command).await?;
+
+      // Emit property change immediately so UI updates
+      if changes_devices {
+         self.devices_changed(&emitter).await?;
       }
 
       Ok(true)
    }
 
+   async fn refresh_battery(&self, address: String) -> fdo::Result<String> {
+      let addr = Address::from_str(&address).map_err(to_arg_error)?;
+      let battery =
+         control::execute(&self.bluetooth_manager, Command::RefreshBattery(addr)).await?;
+      Ok(battery.to_string())
+   }
+
+   async fn reinitialize(&self, address: String) -> fdo::Result<bool> {
+      let addr = Address::from_str(&address).map_err(to_arg_error)?;
+      control::execute(&self.bluetooth_manager, Command::Reinitialize(addr)).await?;
+      Ok(true)
+   }
+
    async fn connect_device(&self, address: String) -> fdo::Result<bool> {
       let addr = Address::from_str(&address).map_err(to_arg_error)?;
-      self.bluetooth_manager.establish_aap(addr).await?;
+      control::execute(&self.bluetooth_manager, Command::ConnectDevice(addr)).await?;
       Ok(true)
    }
 
    async fn disconnect_device(&self, address: String) -> fdo::Result<bool> {
       let addr = Address::from_str(&address).map_err(to_arg_error)?;
-      self.bluetooth_manager.disconnect_aap(addr).await?;
+      control::execute(&self.bluetooth_manager, Command::DisconnectDevice(addr)).await?;
       Ok(true)
    }
 
+   async fn self_test(&self) -> fdo::Result<String> {
+      let report = control::execute(&self.bluetooth_manager, Command::SelfTest).await?;
+      Ok(report.to_string())
+   }
+
+   async fn get_paused_players(&self) -> fdo::Result<Vec<String>> {
+      let players = control::execute(&self.bluetooth_manager, Command::GetPausedPlayers).await?;
+      Ok(serde_json::from_value(players).unwrap_or_default())
+   }
+
+   async fn get_recent_actions(&self) -> fdo::Result<String> {
+      let actions = control::execute(&self.bluetooth_manager, Command::GetRecentActions).await?;
+      Ok(actions.to_string())
+   }
+
+   async fn get_ear_stats(&self) -> fdo::Result<String> {
+      let stats = control::execute(&self.bluetooth_manager, Command::GetEarStats).await?;
+      Ok(stats.to_string())
+   }
+
+   async fn export_support_bundle(&self, path: String, redact: bool) -> fdo::Result<String> {
+      // An empty path writes to the cache directory
+      let path = (!path.is_empty()).then(|| path.into());
+      let path = control::execute(
+         &self.bluetooth_manager,
+         Command::ExportSupportBundle { path, redact },
+      )
+      .await?;
+      Ok(path.as_str().unwrap_or_default().to_string())
+   }
+
+   async fn cancel_resume(&self, address: String) -> fdo::Result<bool> {
+      let addr = Address::from_str(&address).map_err(to_arg_error)?;
+      let cancelled =
+         control::execute(&self.bluetooth_manager, Command::CancelResume(addr)).await?;
+      Ok(cancelled.as_bool().unwrap_or(false))
+   }
+
+   async fn resume_all(&self) -> fdo::Result<Vec<String>> {
+      let resumed = control::execute(&self.bluetooth_manager, Command::ResumeAll).await?;
+      Ok(serde_json::from_value(resumed).unwrap_or_default())
+   }
+
+   async fn set_loop_status(&self, status: String) -> fdo::Result<String> {
+      let status = status
+         .parse()
+         .map_err(|_| to_arg_error(format_args!("Invalid loop status: {status:?}")))?;
+      let player =
+         control::execute(&self.bluetooth_manager, Command::SetLoopStatus(status)).await?;
+      Ok(player.as_str().unwrap_or_default().to_string())
+   }
+
+   async fn set_shuffle(&self, enabled: bool) -> fdo::Result<String> {
+      let player = control::execute(&self.bluetooth_manager, Command::SetShuffle(enabled)).await?;
+      Ok(player.as_str().unwrap_or_default().to_string())
+   }
+
+   #[cfg(any(debug_assertions, feature = "event-injection"))]
+   async fn inject_event(&self, event: String) -> fdo::Result<bool> {
+      let (addr, event) = crate::inject::parse(&event).map_err(to_arg_error)?;
+      let updated =
+         control::execute(&self.bluetooth_manager, Command::InjectEvent(addr, event)).await?;
+      Ok(updated.as_bool().unwrap_or(false))
+   }
+
    // Signals
    #[zbus(signal)]
    pub async fn device_connected(emitter: &SignalEmitter<'_>, address: &str) -> zbus::Result<()>;
@@ -144,6 +293,27 @@ impl AirPodsService {
       battery: &str,
    ) -> zbus::Result<()>;
 
+   #[zbus(signal)]
+   pub async fn case_peeked(
+      emitter: &SignalEmitter<'_>,
+      address: &str,
+      battery: &str,
+   ) -> zbus::Result<()>;
+
+   #[zbus(signal)]
+   pub async fn reinitialized(
+      emitter: &SignalEmitter<'_>,
+      address: &str,
+      success: bool,
+   ) -> zbus::Result<()>;
+
+   #[zbus(signal)]
+   pub async fn poor_signal(
+      emitter: &SignalEmitter<'_>,
+      address: &str,
+      rssi: i16,
+   ) -> zbus::Result<()>;
+
    #[zbus(signal)]
    pub async fn noise_control_changed(
       emitter: &SignalEmitter<'_>,
@@ -151,6 +321,13 @@ impl AirPodsService {
       mode: &str,
    ) -> zbus::Result<()>;
 
+   #[zbus(signal)]
+   pub async fn queued_noise_control_applied(
+      emitter: &SignalEmitter<'_>,
+      address: &str,
+      mode: &str,
+   ) -> zbus::Result<()>;
+
    #[zbus(signal)]
    pub async fn ear_detection_changed(
       emitter: &SignalEmitter<'_>,
@@ -165,9 +342,35 @@ impl AirPodsService {
       name: &str,
    ) -> zbus::Result<()>;
 
+   #[zbus(signal)]
+   pub async fn resume_pending(
+      emitter: &SignalEmitter<'_>,
+      address: &str,
+      pending: bool,
+   ) -> zbus::Result<()>;
+
+   #[zbus(signal)]
+   pub async fn connection_state_changed(
+      emitter: &SignalEmitter<'_>,
+      address: &str,
+      state: &str,
+   ) -> zbus::Result<()>;
+
    #[zbus(signal)]
    pub async fn device_error(emitter: &SignalEmitter<'_>, address: &str) -> zbus::Result<()>;
 
+   #[zbus(signal)]
+   pub async fn media_auto_paused(
+      emitter: &SignalEmitter<'_>,
+      players: &[String],
+   ) -> zbus::Result<()>;
+
+   #[zbus(signal)]
+   pub async fn media_auto_resumed(
+      emitter: &SignalEmitter<'_>,
+      players: &[String],
+   ) -> zbus::Result<()>;
+
    // Properties for polling-free updates
    #[zbus(property)]
    async fn devices(&self) -> String {
@@ -178,4 +381,20 @@ impl AirPodsService {
    async fn connected_count(&self) -> u32 {
       self.bluetooth_manager.count_devices().await
    }
+
+   #[zbus(property)]
+   async fn media_control_enabled(&self) -> bool {
+      media_control::is_enabled()
+   }
+
+   #[zbus(property)]
+   async fn set_media_control_enabled(&mut self, enabled: bool) {
+      media_control::set_enabled(enabled);
+   }
+
+   /// Whether it is the configured quiet hours, in which nothing is announced.
+   #[zbus(property)]
+   async fn quiet_hours_active(&self) -> bool {
+      quiet_hours::is_quiet()
+   }
 }
@@ -0,0 +1,1264 @@
+//! Ear detection state machine for automatic play/pause.
+//!
+//! Ear detection updates from each device are fed through an [`EarDetector`],
+//! which decides when media should be paused or resumed. A single background
+//! task owns the detectors of all devices and fires their pending timers.
+//! Unless configured otherwise, it only acts while the `AirPods` are the
+//! default audio sink.
+//! With `pause_on_lock`, locking the session pauses media as well, see
+//! [`SessionLock`].
+//! Optionally, noise control is turned off while both buds are out, see
+//! [`NoiseSaver`].
+//! It also keeps [`EarStats`], a bounded history of ear state transitions
+//! with daily counters, for diagnostics.
+
+use std::{
+   collections::{HashMap, VecDeque},
+   sync::{OnceLock, Weak},
+   time::{Duration, Instant, SystemTime},
+};
+
+use bluer::Address;
+use log::{debug, info, warn};
+use parking_lot::Mutex;
+use serde_json::json;
+use tokio::{
+   select,
+   sync::{mpsc, oneshot},
+   time,
+};
+
+use crate::{
+   airpods::{
+      device::AirPods,
+      protocol::{EarDetectionStatus, EarState, NoiseControlMode},
+   },
+   audio,
+   config::{GestureAction, MediaConfig, PauseOn, ResumeOn, SingleBudAction},
+   event::{AirPodsEvent, EventBus},
+   media_control::{self, ActionReason, MediaControlError},
+};
+
+/// Media action requested by the ear detection state machine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MediaAction {
+   Pause,
+   /// Pause because both buds went in the case
+   PauseInCase,
+   Duck,
+   Resume,
+   Stop,
+   Next,
+}
+
+impl MediaAction {
+   /// Whether this action affects whatever is playing, rather than only
+   /// players we paused ourselves.
+   const fn affects_playing(self) -> bool {
+      !matches!(self, Self::Resume)
+   }
+}
+
+impl From<GestureAction> for MediaAction {
+   fn from(action: GestureAction) -> Self {
+      match action {
+         GestureAction::Stop => Self::Stop,
+         GestureAction::Next => Self::Next,
+      }
+   }
+}
+
+/// Recognizes two full out→in cycles of both buds within a window.
+#[derive(Debug)]
+struct DoubleRemoval {
+   action: MediaAction,
+   window: Duration,
+   /// When both buds went out in the current cycle
+   cycle_start: Option<Instant>,
+   /// Start of the previous completed cycle
+   previous_start: Option<Instant>,
+}
+
+impl DoubleRemoval {
+   /// Tracks a status, returning the action if it completed the gesture.
+   fn update(&mut self, status: EarDetectionStatus, now: Instant) -> Option<MediaAction> {
+      let both_out = !status.is_left_in_ear() && !status.is_right_in_ear();
+      let both_in = status.is_left_in_ear() && status.is_right_in_ear();
+      if both_out {
+         self.cycle_start.get_or_insert(now);
+         return None;
+      }
+      if !both_in {
+         return None;
+      }
+      let start = self.cycle_start.take()?;
+      match self.previous_start {
+         Some(previous) if now.duration_since(previous) <= self.window => {
+            self.previous_start = None;
+            Some(self.action)
+         },
+         _ => {
+            self.previous_start = Some(start);
+            None
+         },
+      }
+   }
+}
+
+/// Turns noise control off while both buds are out of the ears.
+///
+/// The mode in use at removal is switched back to on reinsertion, unless
+/// the mode changed from off meanwhile: whatever picked it wins.
+#[derive(Debug, Default)]
+struct NoiseSaver {
+   /// Mode to restore once a bud is back in
+   saved: Option<NoiseControlMode>,
+}
+
+impl NoiseSaver {
+   /// Tracks a status, returning the mode to switch to, if any.
+   fn update(
+      &mut self,
+      status: EarDetectionStatus,
+      current: Option<NoiseControlMode>,
+   ) -> Option<NoiseControlMode> {
+      if !status.is_left_in_ear() && !status.is_right_in_ear() {
+         let mode = current.filter(|&mode| mode != NoiseControlMode::Off)?;
+         if self.saved.is_some() {
+            return None;
+         }
+         self.saved = Some(mode);
+         return Some(NoiseControlMode::Off);
+      }
+      let saved = self.saved.take()?;
+      (current == Some(NoiseControlMode::Off)).then_some(saved)
+   }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+   /// Buds are in ear (or no state has been seen yet)
+   Listening,
+   /// Buds went out; the action (pause or duck) fires at the deadline unless they return first
+   PausePending(Instant, MediaAction),
+   /// Pause has been issued
+   Paused,
+   /// Volume has been lowered while one bud is out
+   Ducked,
+   /// Buds are back in; resume fires at the deadline unless cancelled
+   ResumePending(Instant),
+}
+
+/// Per-device ear detection state machine.
+#[derive(Debug)]
+pub struct EarDetector {
+   settle: Duration,
+   /// Latest status and when it counts as settled, see [`EarDetector::with_settle`]
+   settling: Option<(Instant, EarDetectionStatus)>,
+   /// Settled status the noise saver has yet to see
+   noise_status: Option<EarDetectionStatus>,
+   hold: Duration,
+   resume_delay: Duration,
+   phase: Phase,
+   double_removal: Option<DoubleRemoval>,
+   noise_saver: Option<NoiseSaver>,
+   one_out: SingleBudAction,
+   one_in_case: SingleBudAction,
+   pause_on: PauseOn,
+   resume_on: ResumeOn,
+}
+
+impl Phase {
+   /// Phase reached once `action` has been performed.
+   const fn after(action: MediaAction) -> Self {
+      match action {
+         MediaAction::Duck => Self::Ducked,
+         _ => Self::Paused,
+      }
+   }
+}
+
+impl EarDetector {
+   /// Creates a detector that requires the buds to stay out for `hold` before pausing.
+   pub const fn new(hold: Duration) -> Self {
+      Self {
+         settle: Duration::ZERO,
+         settling: None,
+         noise_status: None,
+         hold,
+         resume_delay: Duration::ZERO,
+         phase: Phase::Listening,
+         double_removal: None,
+         noise_saver: None,
+         one_out: SingleBudAction::Pause,
+         one_in_case: SingleBudAction::Pause,
+         pause_on: PauseOn::SingleEarOut,
+         resume_on: ResumeOn::FirstEarIn,
+      }
+   }
+
+   /// Sets whether one bud out is enough to pause, and whether one bud back
+   /// in is enough to resume. Both are by default.
+   pub const fn with_ear_policy(mut self, pause_on: PauseOn, resume_on: ResumeOn) -> Self {
+      self.pause_on = pause_on;
+      self.resume_on = resume_on;
+      self
+   }
+
+   /// Whether the buds are back for media we paused to resume, once nothing
+   /// else calls for a pause.
+   const fn is_back(&self, status: EarDetectionStatus) -> bool {
+      match self.resume_on {
+         ResumeOn::FirstEarIn => true,
+         ResumeOn::BothEarsIn => {
+            matches!(
+               (status.left(), status.right()),
+               (EarState::InEar, EarState::InEar)
+            )
+         },
+      }
+   }
+
+   /// Sets how to react when one bud is held out or put in the case while
+   /// the other stays in. Both pause by default.
+   pub const fn with_single_bud_actions(
+      mut self,
+      one_out: SingleBudAction,
+      one_in_case: SingleBudAction,
+   ) -> Self {
+      self.one_out = one_out;
+      self.one_in_case = one_in_case;
+      self
+   }
+
+   /// Returns the action a status calls for, or `None` if it counts as listening.
+   const fn target(&self, status: EarDetectionStatus) -> Option<MediaAction> {
+      let single_pauses = matches!(self.pause_on, PauseOn::SingleEarOut);
+      let policy = match (status.left(), status.right()) {
+         (EarState::InEar, EarState::InEar) => return None,
+         (EarState::InEar, _) | (_, EarState::InEar) if !single_pauses => return None,
+         (EarState::InEar, EarState::OutOfEar) | (EarState::OutOfEar, EarState::InEar) => {
+            self.one_out
+         },
+         (EarState::InEar, EarState::InCase) | (EarState::InCase, EarState::InEar) => {
+            self.one_in_case
+         },
+         (EarState::InCase, EarState::InCase) => return Some(MediaAction::PauseInCase),
+         _ => return Some(MediaAction::Pause),
+      };
+      match policy {
+         SingleBudAction::Pause => Some(MediaAction::Pause),
+         SingleBudAction::Duck => Some(MediaAction::Duck),
+         SingleBudAction::Ignore => None,
+      }
+   }
+
+   /// Only acts on a status once it stayed the same for `settle`, so sensor
+   /// chatter within it comes down to its last status, or to nothing.
+   pub const fn with_settle(mut self, settle: Duration) -> Self {
+      self.settle = settle;
+      self
+   }
+
+   /// Waits `delay` after the buds return before resuming.
+   pub const fn with_resume_delay(mut self, delay: Duration) -> Self {
+      self.resume_delay = delay;
+      self
+   }
+
+   /// Fires `action` instead of resuming when both buds are removed and
+   /// reinserted twice within `window`.
+   pub const fn with_double_removal(mut self, action: MediaAction, window: Duration) -> Self {
+      self.double_removal = Some(DoubleRemoval {
+         action,
+         window,
+         cycle_start: None,
+         previous_start: None,
+      });
+      self
+   }
+
+   /// Turns noise control off while both buds are out, see [`NoiseSaver`].
+   pub fn with_noise_saver(mut self) -> Self {
+      self.noise_saver = Some(NoiseSaver::default());
+      self
+   }
+
+   /// Returns the noise control mode the latest settled status calls for,
+   /// given the current one.
+   ///
+   /// Like pausing, this waits for the status to settle, so sensor chatter
+   /// doesn't switch noise control back and forth.
+   pub fn noise_mode_for(&mut self, current: Option<NoiseControlMode>) -> Option<NoiseControlMode> {
+      let status = self.noise_status.take()?;
+      self.noise_saver.as_mut()?.update(status, current)
+   }
+
+   /// Feeds a new ear detection status, returning the action to perform now, if any.
+   ///
+   /// With a settle time the status is only acted on from [`EarDetector::poll`],
+   /// once no other one followed it within that time.
+   pub fn update(&mut self, status: EarDetectionStatus, now: Instant) -> Option<MediaAction> {
+      if self.settle.is_zero() {
+         return self.apply(status, now);
+      }
+      self.settling = Some((now + self.settle, status));
+      None
+   }
+
+   /// Acts on a settled status.
+   ///
+   /// Both buds going in the case pauses right away, skipping the hold window,
+   /// and cancels a pending resume: audio can't play from buds in the case.
+   fn apply(&mut self, status: EarDetectionStatus, now: Instant) -> Option<MediaAction> {
+      self.noise_status = Some(status);
+      let gesture = self
+         .double_removal
+         .as_mut()
+         .and_then(|gesture| gesture.update(status, now));
+
+      if let Some(action) = gesture {
+         debug!("Double removal recognized, firing {action:?}");
+         self.phase = Phase::Listening;
+         return Some(action);
+      }
+      match (self.phase, self.target(status)) {
+         (
+            Phase::Listening | Phase::PausePending(_, _) | Phase::Ducked,
+            Some(MediaAction::PauseInCase),
+         ) => {
+            debug!("Both buds are in the case, pausing");
+            self.phase = Phase::Paused;
+            Some(MediaAction::PauseInCase)
+         },
+         (Phase::Listening, Some(action)) if self.hold.is_zero() => {
+            self.phase = Phase::after(action);
+            Some(action)
+         },
+         (Phase::Listening, Some(action)) => {
+            self.phase = Phase::PausePending(now + self.hold, action);
+            None
+         },
+         (Phase::PausePending(_, _), None) => {
+            debug!("Buds returned within the hold window, cancelling pause");
+            self.phase = Phase::Listening;
+            None
+         },
+         (Phase::PausePending(deadline, _), Some(action)) => {
+            // Keep the window, but act on the latest state when it expires
+            self.phase = Phase::PausePending(deadline, action);
+            None
+         },
+         (Phase::Paused, None) if !self.is_back(status) => {
+            debug!("One bud is back, waiting for the other to resume");
+            None
+         },
+         (Phase::ResumePending(_), None) if !self.is_back(status) => {
+            debug!("Bud removed again before resuming, staying paused");
+            self.phase = Phase::Paused;
+            None
+         },
+         (Phase::Paused, None) if self.resume_delay.is_zero() => {
+            self.phase = Phase::Listening;
+            Some(MediaAction::Resume)
+         },
+         (Phase::Paused, None) => {
+            self.phase = Phase::ResumePending(now + self.resume_delay);
+            None
+         },
+         (Phase::Ducked, None) => {
+            self.phase = Phase::Listening;
+            Some(MediaAction::Resume)
+         },
+         (Phase::Ducked, Some(MediaAction::Pause)) => {
+            self.phase = Phase::Paused;
+            Some(MediaAction::Pause)
+         },
+         (Phase::ResumePending(_), Some(_)) => {
+            debug!("Buds removed again before resuming, staying paused");
+            self.phase = Phase::Paused;
+            None
+         },
+         _ => None,
+      }
+   }
+
+   /// Whether a resume is scheduled but has not fired yet.
+   pub const fn is_resume_pending(&self) -> bool {
+      matches!(self.phase, Phase::ResumePending(_))
+   }
+
+   /// Cancels a pending resume, leaving media paused. Returns whether one was pending.
+   pub const fn cancel_resume(&mut self) -> bool {
+      if self.is_resume_pending() {
+         self.phase = Phase::Listening;
+         true
+      } else {
+         false
+      }
+   }
+
+   /// Whether the buds being out keeps media paused, or is about to pause it.
+   pub const fn holds_paused(&self) -> bool {
+      matches!(
+         self.phase,
+         Phase::PausePending(_, _) | Phase::Paused | Phase::Ducked
+      )
+   }
+
+   /// Fires any timer that has expired by `now`.
+   pub fn poll(&mut self, now: Instant) -> Option<MediaAction> {
+      if let Some((settled, status)) = self.settling
+         && now >= settled
+      {
+         self.settling = None;
+         return self.apply(status, now);
+      }
+      match self.phase {
+         Phase::PausePending(deadline, action) if now >= deadline => {
+            self.phase = Phase::after(action);
+            Some(action)
+         },
+         Phase::ResumePending(deadline) if now >= deadline => {
+            self.phase = Phase::Listening;
+            Some(MediaAction::Resume)
+         },
+         _ => None,
+      }
+   }
+
+   /// Returns the instant at which [`EarDetector::poll`] needs to be called next.
+   pub fn deadline(&self) -> Option<Instant> {
+      let phase = match self.phase {
+         Phase::PausePending(deadline, _) | Phase::ResumePending(deadline) => Some(deadline),
+         _ => None,
+      };
+      let settling = self.settling.map(|(settled, _)| settled);
+      phase.into_iter().chain(settling).min()
+   }
+}
+
+/// Pausing while the session is locked, coordinated with ear detection.
+///
+/// Locking pauses whatever plays, and unlocking resumes it unless the buds
+/// are out, in which case putting them back in resumes as usual. Nothing is
+/// resumed by ear detection while locked.
+#[derive(Debug, Default)]
+pub struct SessionLock {
+   locked: bool,
+}
+
+impl SessionLock {
+   /// Handles the session being locked or unlocked, returning the action to perform.
+   ///
+   /// `ear_paused` tells whether ear detection of any device holds media paused.
+   pub const fn update(&mut self, locked: bool, ear_paused: bool) -> Option<MediaAction> {
+      if self.locked == locked {
+         return None;
+      }
+      self.locked = locked;
+      if locked {
+         Some(MediaAction::Pause)
+      } else if ear_paused {
+         None
+      } else {
+         Some(MediaAction::Resume)
+      }
+   }
+
+   /// Whether ear detection may perform an action in the current lock state.
+   pub fn allows(&self, action: MediaAction) -> bool {
+      !(self.locked && action == MediaAction::Resume)
+   }
+}
+
+/// Number of transitions kept in the ear state history
+const MAX_EAR_HISTORY: usize = 64;
+const MS_PER_DAY: u64 = 24 * 60 * 60 * 1000;
+
+/// Ear state statistics of all devices since the bridge started
+static EAR_STATS: Mutex<EarStats> = Mutex::new(EarStats::new());
+
+/// A change in ear state, kept for diagnostics.
+#[derive(Debug, Clone, Copy)]
+struct EarTransition {
+   /// Unix timestamp in milliseconds
+   timestamp: u64,
+   address: Address,
+   status: EarDetectionStatus,
+}
+
+/// Recent ear state transitions and counters of what they triggered today.
+///
+/// Days are counted in UTC; the counters reset when one ends, while the
+/// history keeps its last [`MAX_EAR_HISTORY`] entries regardless.
+#[derive(Debug)]
+pub struct EarStats {
+   history: VecDeque<EarTransition>,
+   /// Day the counters belong to, in days since the Unix epoch
+   day: u64,
+   transitions: u32,
+   pauses: u32,
+   ducks: u32,
+   resumes: u32,
+}
+
+impl EarStats {
+   const fn new() -> Self {
+      Self {
+         history: VecDeque::new(),
+         day: 0,
+         transitions: 0,
+         pauses: 0,
+         ducks: 0,
+         resumes: 0,
+      }
+   }
+
+   /// Resets the counters if `now` is on a later day than they were counted on.
+   const fn roll_over(&mut self, now: u64) {
+      let day = now / MS_PER_DAY;
+      if day != self.day {
+         self.day = day;
+         self.transitions = 0;
+         self.pauses = 0;
+         self.ducks = 0;
+         self.resumes = 0;
+      }
+   }
+
+   fn record_transition(&mut self, address: Address, status: EarDetectionStatus, now: u64) {
+      self.roll_over(now);
+      self.transitions += 1;
+      if self.history.len() == MAX_EAR_HISTORY {
+         self.history.pop_front();
+      }
+      self.history.push_back(EarTransition {
+         timestamp: now,
+         address,
+         status,
+      });
+   }
+
+   const fn record_action(&mut self, action: MediaAction, now: u64) {
+      self.roll_over(now);
+      match action {
+         MediaAction::Pause | MediaAction::PauseInCase => self.pauses += 1,
+         MediaAction::Duck => self.ducks += 1,
+         MediaAction::Resume => self.resumes += 1,
+         MediaAction::Stop | MediaAction::Next => {},
+      }
+   }
+
+   fn report(&mut self, now: u64) -> serde_json::Value {
+      self.roll_over(now);
+      let history: Vec<_> = self
+         .history
+         .iter()
+         .map(|t| {
+            let mut entry = t.status.to_json();
+            entry["timestamp"] = json!(t.timestamp);
+            entry["address"] = json!(t.address.to_string());
+            entry
+         })
+         .collect();
+      json!({
+         "today": {
+            "transitions": self.transitions,
+            "pauses": self.pauses,
+            "ducks": self.ducks,
+            "resumes": self.resumes,
+         },
+         "history": history,
+      })
+   }
+}
+
+fn unix_ms() -> u64 {
+   SystemTime::UNIX_EPOCH
+      .elapsed()
+      .map_or(0, |t| t.as_millis() as u64)
+}
+
+/// Returns today's ear detection counters and the recent transitions, oldest first.
+pub fn ear_stats() -> serde_json::Value {
+   EAR_STATS.lock().report(unix_ms())
+}
+
+enum Message {
+   Update(AirPods, EarDetectionStatus),
+   CancelResume(Address, oneshot::Sender<bool>),
+   SessionLocked(bool),
+}
+
+/// Sender of the running bridge task, for requests that don't go through the event processor
+static BRIDGE: OnceLock<mpsc::UnboundedSender<Message>> = OnceLock::new();
+
+/// Handle to the background task translating ear detection into media actions.
+#[derive(Clone)]
+pub struct EarDetectionBridge {
+   tx: mpsc::UnboundedSender<Message>,
+}
+
+impl EarDetectionBridge {
+   /// Spawns the bridge task, which reports resume state changes on `event_tx`.
+   pub fn spawn(config: &MediaConfig, event_tx: Weak<dyn EventBus>) -> Self {
+      let (tx, rx) = mpsc::unbounded_channel();
+      let settle = Duration::from_millis(config.ear_settle_ms);
+      let hold = Duration::from_millis(config.pause_hold_ms);
+      let resume_delay = Duration::from_millis(config.resume_delay_ms);
+      let gesture = config.double_removal_action.map(|action| {
+         (
+            MediaAction::from(action),
+            Duration::from_millis(config.double_removal_window_ms),
+         )
+      });
+      let (one_out, one_in_case) = (config.one_out_action, config.one_in_case_action);
+      let (pause_on, resume_on) = (config.pause_on, config.resume_on);
+      let noise_off = config.noise_off_when_removed;
+      let new_detector = move || {
+         let mut detector = EarDetector::new(hold)
+            .with_settle(settle)
+            .with_resume_delay(resume_delay)
+            .with_single_bud_actions(one_out, one_in_case)
+            .with_ear_policy(pause_on, resume_on);
+         if noise_off {
+            detector = detector.with_noise_saver();
+         }
+         match gesture {
+            Some((action, window)) => detector.with_double_removal(action, window),
+            None => detector,
+         }
+      };
+      tokio::spawn(run(
+         rx,
+         new_detector,
+         config.require_active_output,
+         event_tx,
+      ));
+      let _ = BRIDGE.set(tx.clone());
+      Self { tx }
+   }
+
+   /// Forwards an ear detection update for a device.
+   pub fn update(&self, device: AirPods, status: EarDetectionStatus) {
+      let _ = self.tx.send(Message::Update(device, status));
+   }
+}
+
+/// Reports the session being locked or unlocked.
+pub fn session_lock_changed(locked: bool) {
+   if let Some(bridge) = BRIDGE.get() {
+      let _ = bridge.send(Message::SessionLocked(locked));
+   }
+}
+
+/// Cancels a pending resume for a device, leaving its media paused.
+///
+/// Returns whether a resume was pending.
+pub async fn cancel_resume(address: Address) -> bool {
+   let Some(bridge) = BRIDGE.get() else {
+      return false;
+   };
+   let (tx, rx) = oneshot::channel();
+   if bridge.send(Message::CancelResume(address, tx)).is_err() {
+      return false;
+   }
+   rx.await.unwrap_or(false)
+}
+
+async fn run(
+   mut rx: mpsc::UnboundedReceiver<Message>,
+   new_detector: impl Fn() -> EarDetector,
+   require_active_output: bool,
+   event_tx: Weak<dyn EventBus>,
+) {
+   let mut detectors: HashMap<Address, (AirPods, EarDetector)> = HashMap::new();
+   let mut session_lock = SessionLock::default();
+   loop {
+      let deadline = detectors
+         .values()
+         .filter_map(|(_, detector)| detector.deadline())
+         .min();
+      let sleep =
+         time::sleep_until(deadline.map_or_else(time::Instant::now, time::Instant::from_std));
+
+      let mut actions = Vec::new();
+      let mut lock_action = None;
+      select! {
+         message = rx.recv() => {
+            match message {
+               None => break,
+               Some(Message::Update(device, status)) => {
+                  let address = device.address();
+                  EAR_STATS.lock().record_transition(address, status, unix_ms());
+                  let (_, detector) = detectors
+                     .entry(address)
+                     .or_insert_with(|| (device, new_detector()));
+                  actions.extend(detector.update(status, Instant::now()).map(|a| (address, a)));
+               },
+               Some(Message::CancelResume(address, reply)) => {
+                  let cancelled = detectors
+                     .get_mut(&address)
+                     .is_some_and(|(_, detector)| detector.cancel_resume());
+                  if cancelled {
+                     info!("{address}: Pending resume cancelled");
+                     media_control::forget_paused_players(ActionReason::Manual, Some(address));
+                  }
+                  let _ = reply.send(cancelled);
+               },
+               Some(Message::SessionLocked(locked)) => {
+                  let ear_paused = detectors.values().any(|(_, detector)| detector.holds_paused());
+                  lock_action = session_lock.update(locked, ear_paused);
+                  info!("Session {}", if locked { "locked" } else { "unlocked" });
+               },
+            }
+         }
+         () = sleep, if deadline.is_some() => {
+            let now = Instant::now();
+            for (address, (_, detector)) in &mut detectors {
+               actions.extend(detector.poll(now).map(|a| (*address, a)));
+            }
+         }
+      }
+
+      // Follows the settled status, from an update or a settle timer
+      let noise_changes: Vec<_> = detectors
+         .values_mut()
+         .filter_map(|(device, detector)| {
+            let mode = detector.noise_mode_for(device.noise_mode())?;
+            Some((device.clone(), mode))
+         })
+         .collect();
+
+      // Publish resume pending changes so clients can show "resuming…"
+      if let Some(event_tx) = event_tx.upgrade() {
+         for (device, detector) in detectors.values() {
+            let pending = detector.is_resume_pending();
+            if device.update_resume_pending(pending) {
+               event_tx.emit(device, AirPodsEvent::ResumePendingChanged(pending));
+            }
+         }
+      }
+
+      // Independent of media control, this only saves battery
+      for (device, mode) in noise_changes {
+         match device.set_noise_control(mode).await {
+            Ok(()) => info!("{}: Switched noise control to {mode}", device.address()),
+            Err(e) => debug!("{}: Failed to switch noise control: {e}", device.address()),
+         }
+      }
+
+      match lock_action {
+         _ if !media_control::is_enabled() => {},
+         Some(MediaAction::Pause) => {
+            report_media_error(
+               "pause",
+               media_control::send_pause(ActionReason::SessionLock, None).await,
+            );
+         },
+         Some(MediaAction::Resume) => {
+            report_media_error(
+               "resume",
+               media_control::send_play(ActionReason::SessionLock, None).await,
+            );
+         },
+         _ => {},
+      }
+
+      for (address, action) in actions {
+         if !media_control::is_enabled() {
+            debug!("{address}: Media control is disabled, ignoring {action:?}");
+            continue;
+         }
+         if !session_lock.allows(action) {
+            debug!("{address}: Session is locked, ignoring {action:?}");
+            continue;
+         }
+         // Resuming only touches players we paused, so it is never held back
+         if require_active_output
+            && action.affects_playing()
+            && audio::is_default_output(address).await == Some(false)
+         {
+            debug!("{address}: Not the active audio output, ignoring {action:?}");
+            continue;
+         }
+         debug!("{address}: Ear detection requested {action:?}");
+         EAR_STATS.lock().record_action(action, unix_ms());
+         match action {
+            MediaAction::Pause => {
+               report_media_error(
+                  "pause",
+                  media_control::send_pause(ActionReason::EarRemoval, Some(address)).await,
+               );
+            },
+            MediaAction::PauseInCase => {
+               report_media_error(
+                  "pause",
+                  media_control::send_pause(ActionReason::InCase, Some(address)).await,
+               );
+            },
+            MediaAction::Duck => media_control::send_duck(ActionReason::EarRemoval).await,
+            MediaAction::Resume => {
+               report_media_error(
+                  "resume",
+                  media_control::send_play(ActionReason::EarInsertion, Some(address)).await,
+               );
+            },
+            MediaAction::Stop => {
+               media_control::send_stop_paused(ActionReason::Gesture, Some(address)).await
+            },
+            MediaAction::Next => {
+               media_control::send_next_and_resume(ActionReason::Gesture, Some(address)).await
+            },
+         }
+      }
+   }
+}
+
+/// Logs why pausing or resuming media failed; having no players is expected.
+fn report_media_error<T>(action: &str, result: Result<T, MediaControlError>) {
+   match result {
+      Ok(_) => {},
+      Err(MediaControlError::NoPlayers) => debug!("No MPRIS media players found"),
+      Err(e) => warn!("Failed to {action} media: {e}"),
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   const BOTH_IN: EarDetectionStatus = EarDetectionStatus::new(true, true);
+   const LEFT_OUT: EarDetectionStatus = EarDetectionStatus::new(false, true);
+   const BOTH_OUT: EarDetectionStatus = EarDetectionStatus::new(false, false);
+
+   fn status(left: EarState, right: EarState) -> EarDetectionStatus {
+      EarDetectionStatus::from_states(left, right)
+   }
+
+   #[test]
+   fn test_ear_stats_reset_daily() {
+      let address = Address([0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF]);
+      let morning = 20_000 * MS_PER_DAY + 8 * 60 * 60 * 1000;
+      let mut stats = EarStats::new();
+
+      stats.record_transition(address, BOTH_OUT, morning);
+      stats.record_action(MediaAction::Pause, morning);
+      stats.record_transition(address, BOTH_IN, morning + 1000);
+      stats.record_action(MediaAction::Resume, morning + 1000);
+      let today = stats.report(morning + 2000);
+      assert_eq!(
+         today["today"],
+         json!({"transitions": 2, "pauses": 1, "ducks": 0, "resumes": 1})
+      );
+      assert_eq!(today["history"][0]["address"], "AA:BB:CC:DD:EE:FF");
+      assert_eq!(today["history"][1]["left_in_ear"], true);
+
+      // The counters start over the next day, the history is kept
+      let tomorrow = stats.report(morning + MS_PER_DAY);
+      assert_eq!(tomorrow["today"]["transitions"], 0);
+      assert_eq!(tomorrow["today"]["pauses"], 0);
+      assert_eq!(tomorrow["history"].as_array().unwrap().len(), 2);
+
+      for i in 0..MAX_EAR_HISTORY as u64 {
+         stats.record_transition(address, LEFT_OUT, morning + MS_PER_DAY + i);
+      }
+      assert_eq!(stats.history.len(), MAX_EAR_HISTORY);
+   }
+
+   #[test]
+   fn test_mixed_states_follow_their_policy() {
+      use EarState::{InCase, InEar, OutOfEar};
+
+      let cases = [
+         ((InEar, OutOfEar), Some(MediaAction::Duck)),
+         ((OutOfEar, InEar), Some(MediaAction::Duck)),
+         ((InEar, InCase), Some(MediaAction::Pause)),
+         ((InCase, InEar), Some(MediaAction::Pause)),
+         // Neither bud in an ear always pauses
+         ((OutOfEar, InCase), Some(MediaAction::Pause)),
+         ((InCase, OutOfEar), Some(MediaAction::Pause)),
+         ((InCase, InCase), Some(MediaAction::PauseInCase)),
+      ];
+      let now = Instant::now();
+      for ((left, right), expected) in cases {
+         let mut detector = EarDetector::new(Duration::ZERO)
+            .with_single_bud_actions(SingleBudAction::Duck, SingleBudAction::Pause);
+         assert_eq!(detector.update(BOTH_IN, now), None);
+         assert_eq!(
+            detector.update(status(left, right), now),
+            expected,
+            "{left:?}/{right:?}"
+         );
+         assert_eq!(
+            detector.update(BOTH_IN, now),
+            Some(MediaAction::Resume),
+            "{left:?}/{right:?}"
+         );
+      }
+
+      // Ignored combinations keep playing
+      let mut detector = EarDetector::new(Duration::ZERO)
+         .with_single_bud_actions(SingleBudAction::Ignore, SingleBudAction::Ignore);
+      assert_eq!(detector.update(status(InEar, OutOfEar), now), None);
+      assert_eq!(detector.update(status(InCase, InEar), now), None);
+      assert_eq!(detector.update(BOTH_IN, now), None);
+   }
+
+   #[test]
+   fn test_ducked_escalates_to_pause() {
+      let mut detector = EarDetector::new(Duration::ZERO)
+         .with_single_bud_actions(SingleBudAction::Duck, SingleBudAction::Pause);
+      let now = Instant::now();
+
+      assert_eq!(detector.update(LEFT_OUT, now), Some(MediaAction::Duck));
+      // Putting the held bud in the case while the other stays in doesn't duck again
+      let in_case = status(EarState::InCase, EarState::InEar);
+      assert_eq!(detector.update(in_case, now), Some(MediaAction::Pause));
+      // Once paused, a held-out bud keeps media paused
+      assert_eq!(detector.update(LEFT_OUT, now), None);
+      assert_eq!(detector.update(BOTH_OUT, now), None);
+      assert_eq!(detector.update(BOTH_IN, now), Some(MediaAction::Resume));
+   }
+
+   #[test]
+   fn test_in_case_status_roundtrip() {
+      let in_case = status(EarState::InCase, EarState::OutOfEar);
+      assert_eq!(in_case.left(), EarState::InCase);
+      assert_eq!(in_case.right(), EarState::OutOfEar);
+      assert!(!in_case.is_left_in_ear());
+      assert_eq!(BOTH_IN.left(), EarState::InEar);
+      assert_eq!(LEFT_OUT.left(), EarState::OutOfEar);
+   }
+
+   #[test]
+   fn test_immediate_pause_without_hold() {
+      let mut detector = EarDetector::new(Duration::ZERO);
+      let now = Instant::now();
+
+      assert_eq!(detector.update(BOTH_IN, now), None);
+      assert_eq!(detector.update(LEFT_OUT, now), Some(MediaAction::Pause));
+      assert_eq!(detector.update(BOTH_OUT, now), None);
+      assert_eq!(detector.update(BOTH_IN, now), Some(MediaAction::Resume));
+      assert_eq!(detector.deadline(), None);
+   }
+
+   #[test]
+   fn test_hold_fires_after_window() {
+      let hold = Duration::from_millis(500);
+      let mut detector = EarDetector::new(hold);
+      let now = Instant::now();
+
+      assert_eq!(detector.update(BOTH_IN, now), None);
+      assert_eq!(detector.update(BOTH_OUT, now), None);
+      assert_eq!(detector.deadline(), Some(now + hold));
+
+      assert_eq!(detector.poll(now + Duration::from_millis(499)), None);
+      assert_eq!(detector.poll(now + hold), Some(MediaAction::Pause));
+      assert_eq!(detector.poll(now + hold * 2), None);
+
+      assert_eq!(
+         detector.update(BOTH_IN, now + hold * 3),
+         Some(MediaAction::Resume)
+      );
+   }
+
+   #[test]
+   fn test_double_removal_within_window() {
+      let window = Duration::from_millis(3000);
+      let mut detector =
+         EarDetector::new(Duration::ZERO).with_double_removal(MediaAction::Stop, window);
+      let start = Instant::now();
+      let at = |ms| start + Duration::from_millis(ms);
+
+      assert_eq!(detector.update(BOTH_IN, at(0)), None);
+      assert_eq!(detector.update(BOTH_OUT, at(0)), Some(MediaAction::Pause));
+      assert_eq!(detector.update(BOTH_IN, at(600)), Some(MediaAction::Resume));
+      assert_eq!(
+         detector.update(BOTH_OUT, at(1200)),
+         Some(MediaAction::Pause)
+      );
+      // Second reinsertion within the window fires the gesture instead of resuming
+      assert_eq!(detector.update(BOTH_IN, at(1800)), Some(MediaAction::Stop));
+
+      // A third cycle starts over rather than chaining onto the gesture
+      assert_eq!(
+         detector.update(BOTH_OUT, at(2000)),
+         Some(MediaAction::Pause)
+      );
+      assert_eq!(
+         detector.update(BOTH_IN, at(2400)),
+         Some(MediaAction::Resume)
+      );
+   }
+
+   #[test]
+   fn test_double_removal_outside_window_or_single_bud() {
+      let window = Duration::from_millis(3000);
+      let mut detector =
+         EarDetector::new(Duration::ZERO).with_double_removal(MediaAction::Stop, window);
+      let start = Instant::now();
+      let at = |ms| start + Duration::from_millis(ms);
+
+      detector.update(BOTH_OUT, at(0));
+      assert_eq!(detector.update(BOTH_IN, at(500)), Some(MediaAction::Resume));
+      detector.update(BOTH_OUT, at(2800));
+      assert_eq!(
+         detector.update(BOTH_IN, at(3500)),
+         Some(MediaAction::Resume)
+      );
+
+      // Removing only one bud is not a full cycle
+      detector.update(LEFT_OUT, at(4000));
+      assert_eq!(
+         detector.update(BOTH_IN, at(4200)),
+         Some(MediaAction::Resume)
+      );
+   }
+
+   #[test]
+   fn test_resume_delay_pending_and_cancel() {
+      let delay = Duration::from_millis(800);
+      let mut detector = EarDetector::new(Duration::ZERO).with_resume_delay(delay);
+      let now = Instant::now();
+
+      assert_eq!(detector.update(BOTH_OUT, now), Some(MediaAction::Pause));
+      assert_eq!(detector.update(BOTH_IN, now), None);
+      assert!(detector.is_resume_pending());
+      assert_eq!(detector.deadline(), Some(now + delay));
+
+      // Executing the resume clears the pending state
+      assert_eq!(detector.poll(now + delay), Some(MediaAction::Resume));
+      assert!(!detector.is_resume_pending());
+
+      // Cancelling clears it without resuming
+      let later = now + delay * 2;
+      assert_eq!(detector.update(BOTH_OUT, later), Some(MediaAction::Pause));
+      assert_eq!(detector.update(BOTH_IN, later), None);
+      assert!(detector.cancel_resume());
+      assert!(!detector.is_resume_pending());
+      assert!(!detector.cancel_resume());
+      assert_eq!(detector.poll(later + delay), None);
+
+      // Removing the buds again keeps media paused
+      assert_eq!(detector.update(BOTH_OUT, later), Some(MediaAction::Pause));
+      assert_eq!(detector.update(BOTH_IN, later), None);
+      assert_eq!(detector.update(LEFT_OUT, later), None);
+      assert!(!detector.is_resume_pending());
+      assert_eq!(detector.update(BOTH_IN, later), None);
+      assert!(detector.is_resume_pending());
+   }
+
+   #[test]
+   fn test_both_in_case_pauses_without_hold() {
+      let hold = Duration::from_millis(500);
+      let both_in_case = status(EarState::InCase, EarState::InCase);
+      let now = Instant::now();
+
+      // Skips the hold window, whether or not a removal was already pending
+      let mut detector = EarDetector::new(hold);
+      assert_eq!(
+         detector.update(both_in_case, now),
+         Some(MediaAction::PauseInCase)
+      );
+      assert_eq!(detector.deadline(), None);
+      assert_eq!(detector.update(BOTH_IN, now), Some(MediaAction::Resume));
+      assert_eq!(detector.update(BOTH_OUT, now), None);
+      assert_eq!(
+         detector.update(both_in_case, now),
+         Some(MediaAction::PauseInCase)
+      );
+      assert_eq!(detector.poll(now + hold), None);
+
+      // Clears a pending resume, keeping media paused
+      let mut detector = EarDetector::new(Duration::ZERO).with_resume_delay(hold);
+      assert_eq!(detector.update(BOTH_OUT, now), Some(MediaAction::Pause));
+      assert_eq!(detector.update(BOTH_IN, now), None);
+      assert!(detector.is_resume_pending());
+      assert_eq!(detector.update(both_in_case, now), None);
+      assert!(!detector.is_resume_pending());
+      assert_eq!(detector.poll(now + hold), None);
+   }
+
+   #[test]
+   fn test_session_lock_coexists_with_ear_detection() {
+      let now = Instant::now();
+      let mut detector = EarDetector::new(Duration::ZERO);
+      let mut lock = SessionLock::default();
+
+      // Lock pauses, unlock resumes
+      assert_eq!(
+         lock.update(true, detector.holds_paused()),
+         Some(MediaAction::Pause)
+      );
+      assert_eq!(lock.update(true, detector.holds_paused()), None);
+      assert_eq!(
+         lock.update(false, detector.holds_paused()),
+         Some(MediaAction::Resume)
+      );
+
+      // Buds taken out and back in while locked don't resume until unlocked
+      lock.update(true, detector.holds_paused());
+      let action = detector.update(BOTH_OUT, now).unwrap();
+      assert!(lock.allows(action));
+      let action = detector.update(BOTH_IN, now).unwrap();
+      assert_eq!(action, MediaAction::Resume);
+      assert!(!lock.allows(action));
+      assert_eq!(
+         lock.update(false, detector.holds_paused()),
+         Some(MediaAction::Resume)
+      );
+
+      // Unlocking with the buds out leaves resuming to their reinsertion
+      lock.update(true, detector.holds_paused());
+      assert_eq!(detector.update(BOTH_OUT, now), Some(MediaAction::Pause));
+      assert_eq!(lock.update(false, detector.holds_paused()), None);
+      let action = detector.update(BOTH_IN, now).unwrap();
+      assert_eq!(action, MediaAction::Resume);
+      assert!(lock.allows(action));
+   }
+
+   /// Feeds a status, returning the noise control mode it calls for.
+   fn noise_mode_for(
+      detector: &mut EarDetector,
+      status: EarDetectionStatus,
+      current: Option<NoiseControlMode>,
+   ) -> Option<NoiseControlMode> {
+      detector.update(status, Instant::now());
+      detector.noise_mode_for(current)
+   }
+
+   #[test]
+   fn test_noise_off_while_both_buds_out() {
+      let mut detector = EarDetector::new(Duration::ZERO).with_noise_saver();
+      let anc = Some(NoiseControlMode::Active);
+      let off = Some(NoiseControlMode::Off);
+
+      assert_eq!(noise_mode_for(&mut detector, BOTH_IN, anc), None);
+      assert_eq!(noise_mode_for(&mut detector, LEFT_OUT, anc), None);
+      assert_eq!(noise_mode_for(&mut detector, BOTH_OUT, anc), off);
+      // Repeated reports while out don't switch again
+      assert_eq!(noise_mode_for(&mut detector, BOTH_OUT, off), None);
+      assert_eq!(noise_mode_for(&mut detector, BOTH_IN, off), anc);
+
+      // A mode picked while out, as on reconnecting, is kept
+      assert_eq!(noise_mode_for(&mut detector, BOTH_OUT, anc), off);
+      let transparency = Some(NoiseControlMode::Transparency);
+      assert_eq!(noise_mode_for(&mut detector, BOTH_IN, transparency), None);
+
+      // Already off, so there is nothing to restore
+      assert_eq!(noise_mode_for(&mut detector, BOTH_OUT, off), None);
+      assert_eq!(noise_mode_for(&mut detector, BOTH_IN, off), None);
+
+      let mut disabled = EarDetector::new(Duration::ZERO);
+      assert_eq!(noise_mode_for(&mut disabled, BOTH_OUT, anc), None);
+   }
+
+   #[test]
+   fn test_return_within_hold_cancels_pause() {
+      let hold = Duration::from_millis(500);
+      let mut detector = EarDetector::new(hold);
+      let now = Instant::now();
+
+      assert_eq!(detector.update(LEFT_OUT, now), None);
+      // Further changes while out don't restart the window
+      assert_eq!(
+         detector.update(BOTH_OUT, now + Duration::from_millis(200)),
+         None
+      );
+      assert_eq!(detector.deadline(), Some(now + hold));
+
+      assert_eq!(
+         detector.update(BOTH_IN, now + Duration::from_millis(400)),
+         None
+      );
+      assert_eq!(detector.deadline(), None);
+      assert_eq!(detector.poll(now + hold), None);
+   }
+
+   #[test]
+   fn test_pause_on_both_out_and_resume_on_both_in() {
+      let now = Instant::now();
+      let mut detector = EarDetector::new(Duration::ZERO)
+         .with_ear_policy(PauseOn::BothEarsOut, ResumeOn::BothEarsIn);
+
+      // One bud out keeps playing
+      assert_eq!(detector.update(LEFT_OUT, now), None);
+      assert_eq!(detector.update(BOTH_OUT, now), Some(MediaAction::Pause));
+      // One bud back isn't enough
+      assert_eq!(detector.update(LEFT_OUT, now), None);
+      assert!(detector.holds_paused());
+      assert_eq!(detector.update(BOTH_IN, now), Some(MediaAction::Resume));
+
+      // With the first bud in resuming, one bud back does
+      let mut detector = EarDetector::new(Duration::ZERO)
+         .with_ear_policy(PauseOn::BothEarsOut, ResumeOn::FirstEarIn);
+      assert_eq!(detector.update(BOTH_OUT, now), Some(MediaAction::Pause));
+      assert_eq!(detector.update(LEFT_OUT, now), Some(MediaAction::Resume));
+      assert_eq!(detector.update(BOTH_IN, now), None);
+
+      // A pending resume goes back to paused if only one bud stays in
+      let mut detector = EarDetector::new(Duration::ZERO)
+         .with_resume_delay(Duration::from_secs(1))
+         .with_ear_policy(PauseOn::BothEarsOut, ResumeOn::BothEarsIn);
+      assert_eq!(detector.update(BOTH_OUT, now), Some(MediaAction::Pause));
+      assert_eq!(detector.update(BOTH_IN, now), None);
+      assert!(detector.is_resume_pending());
+      assert_eq!(detector.update(LEFT_OUT, now), None);
+      assert!(!detector.is_resume_pending());
+      assert_eq!(detector.poll(now + Duration::from_secs(2)), None);
+   }
+
+   #[test]
+   fn test_sensor_chatter_settles() {
+      let start = Instant::now();
+      let at = |ms| start + Duration::from_millis(ms);
+      let mut detector = EarDetector::new(Duration::ZERO).with_settle(Duration::from_millis(300));
+      assert_eq!(detector.update(BOTH_IN, at(0)), None);
+      assert_eq!(detector.poll(at(300)), None);
+
+      // Out and back in within the window does nothing at all
+      assert_eq!(detector.update(LEFT_OUT, at(1000)), None);
+      assert_eq!(detector.update(BOTH_IN, at(1100)), None);
+      assert_eq!(detector.deadline(), Some(at(1400)));
+      assert_eq!(detector.poll(at(1400)), None);
+      assert_eq!(detector.deadline(), None);
+
+      // Out, in and out again pauses once, after the last change settled
+      detector.update(LEFT_OUT, at(2000));
+      detector.update(BOTH_IN, at(2100));
+      detector.update(LEFT_OUT, at(2200));
+      assert_eq!(detector.poll(at(2400)), None);
+      assert_eq!(detector.poll(at(2500)), Some(MediaAction::Pause));
+      assert_eq!(detector.poll(at(3000)), None);
+
+      detector.update(BOTH_IN, at(4000));
+      assert_eq!(detector.poll(at(4300)), Some(MediaAction::Resume));
+   }
+
+   #[test]
+   fn test_noise_saver_waits_for_the_status_to_settle() {
+      let start = Instant::now();
+      let at = |ms| start + Duration::from_millis(ms);
+      let anc = Some(NoiseControlMode::Active);
+      let mut detector = EarDetector::new(Duration::ZERO)
+         .with_settle(Duration::from_millis(300))
+         .with_noise_saver();
+
+      // Out and back in within the window leaves noise control alone
+      detector.update(BOTH_OUT, at(0));
+      assert_eq!(detector.noise_mode_for(anc), None);
+      detector.update(BOTH_IN, at(100));
+      assert_eq!(detector.noise_mode_for(anc), None);
+      detector.poll(at(400));
+      assert_eq!(detector.noise_mode_for(anc), None);
+
+      // Staying out switches it off once settled, and only then
+      detector.update(BOTH_OUT, at(1000));
+      detector.poll(at(1200));
+      assert_eq!(detector.noise_mode_for(anc), None);
+      detector.poll(at(1300));
+      assert_eq!(detector.noise_mode_for(anc), Some(NoiseControlMode::Off));
+   }
+}
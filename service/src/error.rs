@@ -30,6 +30,18 @@ pub enum AirPodsError {
    #[error("Device not found: {0}")]
    DeviceNotFound(Address),
 
+   #[error("No device named {0:?}")]
+   DeviceNameNotFound(String),
+
+   #[error("Device name {name:?} is ambiguous, candidates: {}", candidates.join(", "))]
+   AmbiguousDeviceName {
+      name: String,
+      candidates: Vec<String>,
+   },
+
+   #[error("No noise profile named {0:?}")]
+   NoiseProfileNotFound(String),
+
    #[error("Device not connected")]
    DeviceNotConnected,
 
@@ -66,6 +78,12 @@ pub enum AirPodsError {
    #[error("Manager has been shut down")]
    ManagerShutdown,
 
+   #[error(
+      "kairpodsd is already running{}",
+      .0.map(|pid| format!(" as PID {pid}")).unwrap_or_default()
+   )]
+   AlreadyRunning(Option<u32>),
+
    #[error("Already connecting to device")]
    AlreadyConnecting,
 
@@ -75,6 +93,15 @@ pub enum AirPodsError {
    #[error("Adapter not available")]
    AdapterNotAvailable,
 
+   #[error("No media player found")]
+   NoMediaPlayer,
+
+   #[error("Audio routing error: {0}")]
+   AudioRouting(String),
+
+   #[error("Support bundle error: {0}")]
+   SupportBundle(#[from] zip::result::ZipError),
+
    #[error("Battery study error: {0}")]
    BatteryStudy(#[from] battery_study::Error),
 }
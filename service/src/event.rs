@@ -8,21 +8,40 @@ use std::sync::Arc;
 
 use smol_str::SmolStr;
 
-use crate::airpods::{
-   device::AirPods,
-   protocol::{BatteryInfo, EarDetectionStatus, NoiseControlMode},
+use crate::{
+   airpods::{
+      device::AirPods,
+      protocol::{BatteryInfo, EarDetectionStatus, NoiseControlMode, StemPress},
+   },
+   bluetooth::connection::ConnectionState,
 };
 
 /// Events that can be emitted by the `AirPods` service.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum AirPodsEvent {
    DeviceConnected,
    DeviceDisconnected,
    DeviceError,
+   ConnectionStateChanged(ConnectionState),
    BatteryUpdated(BatteryInfo),
    NoiseControlChanged(NoiseControlMode),
+   /// A noise control mode queued while disconnected was applied
+   QueuedNoiseControlApplied(NoiseControlMode),
+   AdaptiveLevelChanged(u8),
+   /// The case lid was opened close to this computer
+   CasePeeked(BatteryInfo),
+   /// The signal stayed weak for a while, carrying the latest RSSI in dBm
+   PoorSignal(i16),
    EarDetectionChanged(EarDetectionStatus),
    DeviceNameChanged(SmolStr),
+   ResumePendingChanged(bool),
+   /// The buds forwarded a stem press
+   StemPressed(StemPress),
+   /// Settings other than the noise control mode changed at once
+   SettingsChanged,
+   /// The handshake was re-run on the open connection, carrying whether
+   /// the device answered
+   Reinitialized(bool),
 }
 
 /// Trait for implementing event emission.
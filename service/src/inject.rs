@@ -0,0 +1,176 @@
+//! Synthetic events for UI development.
+//!
+//! The `InjectEvent` D-Bus method takes a JSON object naming a device and an
+//! event. The device state is updated the way the matching packet would
+//! update it, and the event goes through the normal pipeline, so signals,
+//! media control and the battery provider react as they do to real
+//! hardware. Unknown addresses get a disconnected simulated device.
+//!
+//! ```text
+//! {"address": "AA:BB:CC:DD:EE:FF", "event": "battery",
+//!  "left": {"level": 80, "charging": false}, "right": {"level": 75}, "case": null}
+//! {"address": "AA:BB:CC:DD:EE:FF", "event": "noise_control", "mode": "anc"}
+//! {"address": "AA:BB:CC:DD:EE:FF", "event": "ear_detection", "left": "in_ear", "right": "in_case"}
+//! {"address": "AA:BB:CC:DD:EE:FF", "event": "adaptive_level", "level": 40}
+//! {"address": "AA:BB:CC:DD:EE:FF", "event": "device_name", "name": "Test AirPods"}
+//! ```
+//!
+//! Battery components that are missing or `null` are unavailable, and
+//! `charging` defaults to `false`; single-battery models use `headphone`.
+//! Noise modes are `off`, `anc`, `transparency` and `adaptive`, ear states
+//! `in_ear`, `out_of_ear` and `in_case`.
+//!
+//! This is only compiled into debug builds, or with the `event-injection`
+//! feature.
+
+use std::str::FromStr;
+
+use bluer::Address;
+use serde::Deserialize;
+use smol_str::SmolStr;
+
+use crate::{
+   airpods::protocol::{
+      BatteryInfo, BatteryState, BatteryStatus, EarDetectionStatus, EarState, NoiseControlMode,
+   },
+   event::AirPodsEvent,
+};
+
+#[derive(Debug, Deserialize)]
+struct Request {
+   address: String,
+   #[serde(flatten)]
+   event: Event,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Event {
+   Battery {
+      left: Option<Component>,
+      right: Option<Component>,
+      case: Option<Component>,
+      headphone: Option<Component>,
+   },
+   NoiseControl {
+      mode: String,
+   },
+   EarDetection {
+      left: EarState,
+      right: EarState,
+   },
+   AdaptiveLevel {
+      level: u8,
+   },
+   DeviceName {
+      name: SmolStr,
+   },
+}
+
+#[derive(Debug, Deserialize)]
+struct Component {
+   level: u8,
+   #[serde(default)]
+   charging: bool,
+}
+
+impl Component {
+   fn state(component: Option<Self>) -> Result<BatteryState, String> {
+      let Some(Self { level, charging }) = component else {
+         return Ok(BatteryState::new());
+      };
+      if level > 100 {
+         return Err(format!("Invalid battery level: {level}"));
+      }
+      let status = if charging {
+         BatteryStatus::Charging
+      } else {
+         BatteryStatus::Discharging
+      };
+      Ok(BatteryState { level, status })
+   }
+}
+
+/// Parses an injection request into the device address and the event to feed.
+pub fn parse(json: &str) -> Result<(Address, AirPodsEvent), String> {
+   let request: Request = serde_json::from_str(json).map_err(|e| format!("Invalid event: {e}"))?;
+   let address = Address::from_str(&request.address)
+      .map_err(|e| format!("Invalid address {:?}: {e}", request.address))?;
+   let event = match request.event {
+      Event::Battery {
+         left,
+         right,
+         case,
+         headphone,
+      } => AirPodsEvent::BatteryUpdated(BatteryInfo {
+         left: Component::state(left)?,
+         right: Component::state(right)?,
+         case: Component::state(case)?,
+         headphone: Component::state(headphone)?,
+      }),
+      Event::NoiseControl { mode } => AirPodsEvent::NoiseControlChanged(
+         NoiseControlMode::from_str(&mode).map_err(|_| format!("Invalid noise mode: {mode:?}"))?,
+      ),
+      Event::EarDetection { left, right } => {
+         AirPodsEvent::EarDetectionChanged(EarDetectionStatus::from_states(left, right))
+      },
+      Event::AdaptiveLevel { level } if level > 100 => {
+         return Err(format!("Invalid adaptive level: {level}"));
+      },
+      Event::AdaptiveLevel { level } => AirPodsEvent::AdaptiveLevelChanged(level),
+      Event::DeviceName { name } => AirPodsEvent::DeviceNameChanged(name),
+   };
+   Ok((address, event))
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   const TEST_ADDRESS: Address = Address([0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF]);
+
+   #[test]
+   fn test_parse_events() {
+      let (address, event) = parse(
+         r#"{"address":"AA:BB:CC:DD:EE:FF","event":"battery","left":{"level":80},"right":{"level":75,"charging":true},"case":null}"#,
+      )
+      .unwrap();
+      assert_eq!(address, TEST_ADDRESS);
+      let AirPodsEvent::BatteryUpdated(battery) = event else {
+         panic!("Unexpected event: {event:?}");
+      };
+      assert_eq!(battery.left.level, 80);
+      assert!(!battery.left.is_charging());
+      assert!(battery.right.is_charging());
+      assert!(!battery.case.is_available());
+
+      let (_, event) = parse(
+         r#"{"address":"AA:BB:CC:DD:EE:FF","event":"ear_detection","left":"in_ear","right":"in_case"}"#,
+      )
+      .unwrap();
+      assert!(matches!(
+         event,
+         AirPodsEvent::EarDetectionChanged(status)
+            if status.left() == EarState::InEar && status.right() == EarState::InCase
+      ));
+
+      let (_, event) =
+         parse(r#"{"address":"AA:BB:CC:DD:EE:FF","event":"noise_control","mode":"anc"}"#).unwrap();
+      assert!(matches!(
+         event,
+         AirPodsEvent::NoiseControlChanged(NoiseControlMode::Active)
+      ));
+   }
+
+   #[test]
+   fn test_parse_invalid_events() {
+      assert!(parse(r#"{"address":"nope","event":"adaptive_level","level":40}"#).is_err());
+      assert!(parse(r#"{"address":"AA:BB:CC:DD:EE:FF","event":"explode"}"#).is_err());
+      assert!(
+         parse(r#"{"address":"AA:BB:CC:DD:EE:FF","event":"adaptive_level","level":101}"#).is_err()
+      );
+      assert!(
+         parse(r#"{"address":"AA:BB:CC:DD:EE:FF","event":"noise_control","mode":"loud"}"#).is_err()
+      );
+   }
+}
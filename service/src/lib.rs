@@ -0,0 +1,39 @@
+//! `AirPods` integration for Linux desktops.
+//!
+//! This crate implements the Apple Accessory Protocol over L2CAP along with
+//! battery monitoring, noise control, ear detection driven media control and
+//! the `org.kairpods` D-Bus interface. `kairpodsd` runs it as a daemon; to
+//! embed it in another application, start a [`KAirPods`] with
+//! [`KAirPods::builder`] and control it through its [`Handle`].
+
+pub mod airpods;
+mod audio;
+mod battery_provider;
+mod battery_study;
+pub mod bluetooth;
+pub mod config;
+pub mod control;
+#[cfg(feature = "control-socket")]
+mod control_socket;
+mod daemon;
+mod dbus;
+mod ear_detection;
+pub mod error;
+pub mod event;
+#[cfg(any(debug_assertions, feature = "event-injection"))]
+pub mod inject;
+pub mod logging;
+pub mod media_control;
+mod noise_profile;
+mod notification;
+mod pause_marker;
+mod paused_state;
+mod quiet_hours;
+mod ringbuf;
+mod session_lock;
+mod supervisor;
+mod support;
+#[cfg(feature = "tray")]
+mod tray;
+
+pub use daemon::{Handle, KAirPods, KAirPodsBuilder};
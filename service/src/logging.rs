@@ -0,0 +1,97 @@
+//! Logger setup for the daemon.
+//!
+//! Logs are human readable by default. With `log_format = "json"`, or
+//! `AIRPODS_LOG_FORMAT=json` in the environment, each record is written as
+//! one JSON object per line for log collectors:
+//!
+//! ```text
+//! {"ts":"2025-01-01T12:00:00.000Z","level":"INFO","target":"kairpods::media_control","message":"Resumed player","player":"org.mpris.MediaPlayer2.spotify"}
+//! ```
+//!
+//! Structured fields attached to a record, such as the device `address` or
+//! the media `player`, become keys of the object.
+
+use std::{env, io::Write};
+
+use log::{
+   Record,
+   kv::{self, Key, Value, VisitSource},
+};
+use serde_json::{Map, json};
+
+use crate::config::LogFormat;
+
+/// Collects the structured fields of a record.
+struct Fields(Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+   fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+      let value = if let Some(value) = value.to_bool() {
+         json!(value)
+      } else if let Some(value) = value.to_i64() {
+         json!(value)
+      } else if let Some(value) = value.to_u64() {
+         json!(value)
+      } else {
+         json!(value.to_string())
+      };
+      self.0.insert(key.to_string(), value);
+      Ok(())
+   }
+}
+
+/// Describes a record as a JSON object, without its timestamp.
+fn to_json(record: &Record<'_>) -> serde_json::Value {
+   let mut fields = Fields(Map::from_iter([
+      ("level".to_string(), json!(record.level().as_str())),
+      ("target".to_string(), json!(record.target())),
+      ("message".to_string(), json!(record.args().to_string())),
+   ]));
+   // Visiting only fails if we do
+   let _ = record.key_values().visit(&mut fields);
+   serde_json::Value::Object(fields.0)
+}
+
+/// Installs the global logger, filtered by `RUST_LOG` or else `default_filter`.
+pub fn init(default_filter: &str, format: LogFormat) {
+   let mut builder =
+      env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(default_filter));
+   let format = match env::var("AIRPODS_LOG_FORMAT").as_deref() {
+      Ok("json") => LogFormat::Json,
+      Ok("human") => LogFormat::Human,
+      _ => format,
+   };
+   if format == LogFormat::Json {
+      builder.format(|buf, record| {
+         let mut line = to_json(record);
+         line["ts"] = json!(buf.timestamp_millis().to_string());
+         writeln!(buf, "{line}")
+      });
+   }
+   builder.init();
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   #[test]
+   fn test_record_to_json() {
+      let fields = [("address", "AA:BB:CC:DD:EE:FF")];
+      let record = Record::builder()
+         .args(format_args!("Connected"))
+         .level(log::Level::Info)
+         .target("kairpods::airpods::device")
+         .key_values(&fields)
+         .build();
+      assert_eq!(
+         to_json(&record),
+         json!({
+            "level": "INFO",
+            "target": "kairpods::airpods::device",
+            "message": "Connected",
+            "address": "AA:BB:CC:DD:EE:FF",
+         })
+      );
+   }
+}
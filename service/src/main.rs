@@ -4,28 +4,14 @@
 //! in KDE Plasma, including battery monitoring, noise control, and
 //! feature management.
 
-use std::{sync::Arc, time::Duration};
+use log::{error, info, warn};
 
-use crossbeam::queue::SegQueue;
-use log::{info, warn};
-use tokio::{signal, sync::Notify, time};
-use zbus::{Connection, connection, object_server::InterfaceRef};
-
-use bluetooth::manager::BluetoothManager;
-use dbus::AirPodsService;
-use event::{AirPodsEvent, EventBus};
-
-mod airpods;
-mod battery_study;
-mod bluetooth;
-mod config;
-mod dbus;
-mod error;
-mod event;
-mod media_control;
-mod ringbuf;
-
-use crate::{airpods::device::AirPods, dbus::AirPodsServiceSignals, error::Result};
+use kairpods::{
+   KAirPods,
+   config::{self, SecondInstance},
+   error::{AirPodsError, Result},
+   logging, media_control,
+};
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -41,8 +27,79 @@ async fn main() -> Result<()> {
             println!("Usage: {} [OPTIONS]", args[0]);
             println!();
             println!("Options:");
-            println!("  -v, --version    Print version information and exit");
-            println!("  -h, --help       Print this help message and exit");
+            println!("  -v, --version        Print version information and exit");
+            println!("      --self-test      Check which media players auto-pause can control");
+            println!("      --preview-pause [--json]");
+            println!(
+               "                       Show which media players auto-pause would pause right now"
+            );
+            println!("      --resume-all     Resume every paused media player");
+            println!("      --export-config  Print the configuration for backing it up");
+            println!("      --import-config  Replace the configuration with one read from stdin");
+            println!("      --noise-profile [NAME]");
+            println!(
+               "                       Pick the noise profile, or pick it by network without NAME"
+            );
+            println!("  -h, --help           Print this help message and exit");
+            return Ok(());
+         },
+         "--self-test" => {
+            if let Ok(config) = config::Config::load() {
+               media_control::configure(config.media);
+            }
+            let report = media_control::self_test().await;
+            println!("{report:#}");
+            return Ok(());
+         },
+         "--preview-pause" => {
+            if let Ok(config) = config::Config::load() {
+               media_control::configure(config.media);
+            }
+            let preview = media_control::preview_pause().await;
+            if args.get(2).is_some_and(|arg| arg == "--json") {
+               println!("{:#}", preview.to_json());
+            } else {
+               print!("{preview}");
+            }
+            return Ok(());
+         },
+         "--resume-all" => {
+            let resumed = match resume_all_in_daemon().await {
+               Ok(resumed) => resumed,
+               // Without a running service there is nothing tracked to clear
+               Err(_) => media_control::resume_all().await,
+            };
+            println!("Resumed {} player(s)", resumed.len());
+            for player in resumed {
+               println!("  {player}");
+            }
+            return Ok(());
+         },
+         "--export-config" => {
+            print!("{}", config::Config::load()?.export()?);
+            return Ok(());
+         },
+         "--import-config" => {
+            let contents = std::io::read_to_string(std::io::stdin())?;
+            let current = config::Config::load().unwrap_or_default();
+            let (config, changed) = current.import(&contents)?;
+            config.save()?;
+            if changed.is_empty() {
+               println!("Imported configuration, nothing changed");
+            } else {
+               println!("Imported configuration, changed: {}", changed.join(", "));
+               println!("Restart the service to apply it");
+            }
+            return Ok(());
+         },
+         "--noise-profile" => {
+            let profile = args.get(2).cloned().unwrap_or_default();
+            set_noise_profile_in_daemon(&profile).await?;
+            if profile.is_empty() {
+               println!("Picking the noise profile by network");
+            } else {
+               println!("Selected noise profile {profile}");
+            }
             return Ok(());
          },
          arg => {
@@ -59,8 +116,7 @@ async fn main() -> Result<()> {
    };
 
    let default_filter = config.log_filter.as_deref().unwrap_or("info");
-   env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(default_filter))
-      .init();
+   logging::init(default_filter, config.log_format);
    info!("Starting kAirPods D-Bus service...");
 
    if let Some(err) = config_err {
@@ -72,196 +128,49 @@ async fn main() -> Result<()> {
       );
    }
 
-   // Create event channel
-   let event_bus = EventProcessor::new();
-
-   // Initialize battery study database
-   let battery_study = match battery_study::BatteryStudy::open() {
-      Ok(study) => {
-         info!("Battery study database initialized");
-         Some(study)
-      },
-      Err(e) => {
-         warn!("Failed to initialize battery study database: {e}");
-         None
-      },
-   };
-
-   // Create Bluetooth manager with event sender and config
-   let bluetooth_manager = BluetoothManager::new(event_bus.clone(), config, battery_study).await?;
-
-   // Create D-Bus service
-   let service = AirPodsService::new(bluetooth_manager);
-
-   // Build D-Bus connection
-   let connection = connection::Builder::session()?
-      .name("org.kairpods")?
-      .serve_at("/org/kairpods/manager", service)?
-      .build()
-      .await?;
-
-   info!("kAirPods D-Bus service started at org.kairpods");
-
-   // Start event processor
-   event_bus.spawn_dispatcher(connection).await?;
-
-   // Wait for shutdown signal
-   signal::ctrl_c().await?;
-   info!("Shutting down kAirPods service...");
-
-   Ok(())
-}
-
-struct EventProcessor {
-   queue: SegQueue<(AirPods, AirPodsEvent)>,
-   notifier: Notify,
-}
-
-impl EventProcessor {
-   fn new() -> Arc<Self> {
-      Arc::new(Self {
-         queue: SegQueue::new(),
-         notifier: Notify::new(),
-      })
-   }
-}
-
-impl EventProcessor {
-   async fn recv(self: &Arc<Self>) -> Option<(AirPods, AirPodsEvent)> {
-      loop {
-         if let Some(event) = self.queue.pop() {
-            return Some(event);
-         }
-         let notify = self.notifier.notified();
-         if let Some(event) = self.queue.pop() {
-            return Some(event);
-         }
-         if Arc::strong_count(self) == 1 {
-            return None;
-         }
-         let _ = time::timeout(Duration::from_secs(1), notify).await;
-      }
-   }
-
-   async fn dispatch(
-      &self,
-      iface: &InterfaceRef<AirPodsService>,
-      (device, event): (AirPods, AirPodsEvent),
-   ) -> Result<()> {
-      let addr_str = device.address_str();
-      match event {
-         AirPodsEvent::DeviceConnected => {
-            iface.device_connected(addr_str).await?;
-            // Emit property changes
-            iface
-               .get_mut()
-               .await
-               .devices_changed(iface.signal_emitter())
-               .await?;
-            iface
-               .get_mut()
-               .await
-               .connected_count_changed(iface.signal_emitter())
-               .await?;
-         },
-         AirPodsEvent::DeviceDisconnected => {
-            iface.device_disconnected(addr_str).await?;
-            // Emit property changes
-            iface
-               .get_mut()
-               .await
-               .devices_changed(iface.signal_emitter())
-               .await?;
-            iface
-               .get_mut()
-               .await
-               .connected_count_changed(iface.signal_emitter())
-               .await?;
-         },
-         AirPodsEvent::BatteryUpdated(battery) => {
-            iface
-               .battery_updated(addr_str, &battery.to_json().to_string())
-               .await?;
-            // Emit property change for devices (battery state changed)
-            iface
-               .get_mut()
-               .await
-               .devices_changed(iface.signal_emitter())
-               .await?;
-         },
-         AirPodsEvent::NoiseControlChanged(mode) => {
-            iface.noise_control_changed(addr_str, mode.to_str()).await?;
-            // Emit property change for devices (noise control state changed)
-            iface
-               .get_mut()
-               .await
-               .devices_changed(iface.signal_emitter())
-               .await?;
-         },
-         AirPodsEvent::EarDetectionChanged(ear_detection) => {
-            iface
-               .ear_detection_changed(addr_str, &ear_detection.to_json().to_string())
-               .await?;
-            // Emit property change for devices (ear detection state changed)
-            iface
-               .get_mut()
-               .await
-               .devices_changed(iface.signal_emitter())
-               .await?;
-
-            // Handle play/pause based on ear detection
-            // Pause when at least one earbud is removed, play only when both are in
-            let both_in_ear = ear_detection.is_left_in_ear() && ear_detection.is_right_in_ear();
-            if both_in_ear {
-               // Both AirPods are in ear - send play command
-               media_control::send_play().await;
-            } else {
-               // At least one AirPod is out of ear - send pause command
-               media_control::send_pause().await;
-            }
-         },
-         AirPodsEvent::DeviceNameChanged(name) => {
-            iface.device_name_changed(addr_str, &name).await?;
-            // Emit property change for devices (name changed)
-            iface
-               .get_mut()
-               .await
-               .devices_changed(iface.signal_emitter())
-               .await?;
+   let second_instance = config.second_instance;
+   match KAirPods::builder().config(config).build().await {
+      Ok(service) => service.run().await,
+      Err(e @ AirPodsError::AlreadyRunning(_)) => match second_instance {
+         SecondInstance::Exit => {
+            error!("{e}, not starting another instance");
+            std::process::exit(1);
          },
-         AirPodsEvent::DeviceError => {
-            iface.device_error(addr_str).await?;
-            // Emit property change for devices (error state might affect device info)
-            iface
-               .get_mut()
-               .await
-               .devices_changed(iface.signal_emitter())
-               .await?;
+         SecondInstance::Forward => {
+            info!("{e}, leaving it in charge");
+            Ok(())
          },
-      }
-      Ok(())
+      },
+      Err(e) => Err(e),
    }
+}
 
-   async fn spawn_dispatcher(self: Arc<Self>, connection: Connection) -> Result<()> {
-      let iface = connection
-         .object_server()
-         .interface::<_, AirPodsService>("/org/kairpods/manager")
-         .await?;
-      tokio::spawn(async move {
-         while let Some(event) = self.recv().await {
-            if let Err(e) = self.dispatch(&iface, event).await {
-               warn!("Error dispatching event: {e}");
-            }
-         }
-      });
-
-      Ok(())
-   }
+/// Asks the running service to resume all players, so it also clears its tracking.
+async fn resume_all_in_daemon() -> zbus::Result<Vec<String>> {
+   let connection = zbus::Connection::session().await?;
+   let reply = connection
+      .call_method(
+         Some("org.kairpods"),
+         "/org/kairpods/manager",
+         Some("org.kairpods.manager"),
+         "ResumeAll",
+         &(),
+      )
+      .await?;
+   reply.body().deserialize()
 }
 
-impl EventBus for EventProcessor {
-   fn emit(&self, device: &AirPods, event: AirPodsEvent) {
-      self.queue.push((device.clone(), event));
-      self.notifier.notify_waiters();
-   }
+/// Asks the running service to pick a noise profile, or to pick it by network if empty.
+async fn set_noise_profile_in_daemon(profile: &str) -> zbus::Result<bool> {
+   let connection = zbus::Connection::session().await?;
+   let reply = connection
+      .call_method(
+         Some("org.kairpods"),
+         "/org/kairpods/manager",
+         Some("org.kairpods.manager"),
+         "SetNoiseProfile",
+         &(profile),
+      )
+      .await?;
+   reply.body().deserialize()
 }
@@ -3,157 +3,2673 @@
 //! This module provides functionality to control media playback using the
 //! MPRIS (Media Player Remote Interfacing Specification) D-Bus interface.
 
-use log::{debug, warn};
-use parking_lot::Mutex;
-use zbus::Connection;
+use std::{
+   cmp::{Ordering, Reverse},
+   collections::{HashMap, VecDeque},
+   env, fmt, io,
+   sync::{
+      LazyLock,
+      atomic::{AtomicBool, Ordering as AtomicOrdering},
+   },
+   time::{Duration, Instant, SystemTime},
+};
 
-/// Tracks which players we paused (so we can resume all of them)
-static PAUSED_PLAYERS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+use bluer::Address;
+use futures::{StreamExt, TryStreamExt, stream::FuturesUnordered};
+use log::{debug, info, warn};
+use parking_lot::{Mutex, RwLock};
+use serde::Serialize;
+use serde_json::json;
+use thiserror::Error;
+use tokio::{sync::mpsc, time};
+use zbus::{Connection, MatchRule, MessageStream, connection, message, zvariant::OwnedValue};
+
+use crate::{
+   config::{MediaConfig, PauseMode, ResumeOrder, ResumePolicy},
+   error::{AirPodsError, Result},
+   pause_marker, paused_state,
+   supervisor::Supervisor,
+};
+
+/// Bus name prefix shared by all MPRIS players
+const MPRIS_PREFIX: &str = "org.mpris.MediaPlayer2.";
+/// Prefix marking players found on the system bus rather than the session bus
+const SYSTEM_BUS_PREFIX: &str = "system:";
+/// Number of volume steps in a resume fade-in or pause fade-out
+const FADE_STEPS: u32 = 10;
+/// How long the volume fades out before a pause, with [`PauseMode::Fade`]
+const PAUSE_FADE: Duration = Duration::from_millis(150);
+/// How long a `Position` reading is compared against to tell if a player moved on
+const POSITION_WINDOW: Duration = Duration::from_secs(300);
+/// Fraction of their volume that ducked players keep
+const DUCK_FACTOR: f64 = 0.3;
+/// Number of actions kept in the recent action history
+const MAX_RECENT_ACTIONS: usize = 32;
+/// Player of action records that summarize an action on all players
+pub const ALL_PLAYERS: &str = "*";
+/// How long status changes of a player we just commanded are treated as our own echo
+const ECHO_WINDOW: Duration = Duration::from_secs(2);
+/// How often to check whether other players stopped, for [`ResumePolicy::WhenIdle`]
+const IDLE_POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// How long a player that rejected Pause as unsupported is left alone
+const UNCONTROLLABLE_COOLDOWN: Duration = Duration::from_secs(300);
+/// D-Bus errors meaning a player can't be controlled, as opposed to a transient failure
+const UNSUPPORTED_ERRORS: &[&str] = &[
+   "org.freedesktop.DBus.Error.AccessDenied",
+   "org.freedesktop.DBus.Error.NotSupported",
+   "org.freedesktop.DBus.Error.UnknownMethod",
+];
+
+/// Players we paused, muted or ducked, with what to restore them to.
+///
+/// All of it is kept behind one lock, so a resume never sees a pause that
+/// is only partly recorded.
+#[derive(Debug, Default)]
+struct Tracked {
+   /// Players we paused (so we can resume all of them)
+   paused: Vec<String>,
+   /// Device whose buds each player was paused for; players paused for
+   /// anything else, such as the session lock, aren't listed
+   paused_by: HashMap<String, Address>,
+   /// When each player we paused had started playing, for [`ResumeOrder::MostRecentFirst`]
+   paused_since: HashMap<String, Instant>,
+   /// When each player we paused was paused, for [`MediaConfig::resume_timeout_sec`]
+   paused_at: HashMap<String, Instant>,
+   /// What each player we paused was doing right before, only resumed if it was playing
+   paused_from: HashMap<String, PlaybackStatus>,
+   /// Volume of each player we faded out before pausing, restored on resume
+   faded_volumes: HashMap<String, f64>,
+   /// Players we muted instead of pausing, with their previous volume
+   muted: Vec<(String, f64)>,
+   /// Players we ducked, with their previous volume
+   ducked: Vec<(String, f64)>,
+   /// Pauses for each device, so its stale idle waits and resume checks give up
+   generations: HashMap<Option<Address>, u64>,
+   /// Pauses for no device in particular, which concern every device
+   shared_generation: u64,
+}
+
+impl Tracked {
+   /// Lists the players we paused that [`send_play`] resumes for `device`.
+   fn paused_for(&self, device: Option<Address>) -> Vec<String> {
+      self
+         .paused
+         .iter()
+         .filter(|player| is_resumed_by(self.paused_by.get(*player).copied(), device))
+         .cloned()
+         .collect()
+   }
+
+   /// Forgets the players we paused that [`send_play`] resumes for `device`,
+   /// returning them.
+   fn take_paused_for(&mut self, device: Option<Address>) -> Vec<String> {
+      let players = self.paused_for(device);
+      self.forget(&players);
+      players
+   }
+
+   /// Counts the pauses that changed what [`send_play`] resumes for `device`.
+   fn generation(&self, device: Option<Address>) -> u64 {
+      self.shared_generation + self.generations.get(&device).copied().unwrap_or(0)
+   }
+
+   /// Notes a new pause for `device`, see [`Self::generation`].
+   ///
+   /// What a device paused is also resumed for no device, and what was
+   /// paused for no device is resumed for every device, see [`is_resumed_by`].
+   fn paused_again(&mut self, device: Option<Address>) {
+      match device {
+         Some(_) => {
+            *self.generations.entry(device).or_default() += 1;
+            *self.generations.entry(None).or_default() += 1;
+         },
+         None => self.shared_generation += 1,
+      }
+   }
+
+   /// Stops tracking paused players, along with everything kept about them.
+   fn forget(&mut self, players: &[String]) {
+      self.paused.retain(|player| !players.contains(player));
+      for player in players {
+         self.paused_by.remove(player);
+         self.paused_since.remove(player);
+         self.paused_at.remove(player);
+         self.paused_from.remove(player);
+         self.faded_volumes.remove(player);
+      }
+   }
+
+   /// Takes the players we muted or ducked, with the volume to restore.
+   ///
+   /// A player muted while ducked goes back to its volume from before ducking.
+   fn take_lowered(&mut self) -> Vec<(String, f64)> {
+      let mut lowered = std::mem::take(&mut self.muted);
+      for (player_name, volume) in std::mem::take(&mut self.ducked) {
+         match lowered.iter_mut().find(|(name, _)| *name == player_name) {
+            Some(entry) => entry.1 = volume,
+            None => lowered.push((player_name, volume)),
+         }
+      }
+      lowered
+   }
+}
+
+/// What we paused or lowered and will restore
+static TRACKED: LazyLock<Mutex<Tracked>> = LazyLock::new(Mutex::default);
+
+/// Last `Position` read from each player and when, to tell if it moves on
+static POSITIONS: LazyLock<Mutex<HashMap<String, (i64, Instant)>>> = LazyLock::new(Mutex::default);
+
+/// Players that rejected Pause as unsupported, with when they did
+static UNCONTROLLABLE_PLAYERS: Mutex<Vec<(String, Instant)>> = Mutex::new(Vec::new());
+
+/// Whether failing to reach the system bus was already reported
+static SYSTEM_BUS_WARNED: AtomicBool = AtomicBool::new(false);
+
+/// Most recent actions taken on players, oldest first
+static RECENT_ACTIONS: Mutex<VecDeque<ActionRecord>> = Mutex::new(VecDeque::new());
+
+/// Why pausing or resuming media failed.
+#[derive(Error, Debug)]
+pub enum MediaControlError {
+   #[error("Cannot reach the players' D-Bus session: {0}")]
+   NoSession(zbus::Error),
+
+   #[error("No MPRIS players found")]
+   NoPlayers,
+
+   #[error("All players failed: {}", .0.join(", "))]
+   AllFailed(Vec<String>),
+}
+
+/// Why a media action was taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ActionReason {
+   /// A bud was taken out
+   EarRemoval,
+   /// The buds were put back in
+   EarInsertion,
+   /// Both buds were put in the case
+   InCase,
+   /// An ear detection gesture was recognized
+   Gesture,
+   /// The session was locked or unlocked
+   SessionLock,
+   /// Requested by the user over a control interface
+   Manual,
+}
+
+/// An action taken on a player, kept for diagnostics.
+#[derive(Debug, Clone, Serialize)]
+pub struct ActionRecord {
+   /// Unix timestamp in milliseconds
+   pub timestamp: u64,
+   /// Bus name of the player, or [`ALL_PLAYERS`] for the summary of a
+   /// pause that failed for some players
+   pub player: String,
+   pub method: &'static str,
+   pub reason: ActionReason,
+   /// `"ok"` or the error that occurred
+   pub outcome: String,
+}
+
+/// Records an action in the bounded history.
+fn record_action<T, E: fmt::Display>(
+   reason: ActionReason,
+   player: &str,
+   method: &'static str,
+   result: &Result<T, E>,
+) {
+   let record = ActionRecord {
+      timestamp: SystemTime::UNIX_EPOCH
+         .elapsed()
+         .map_or(0, |t| t.as_millis() as u64),
+      player: player.to_string(),
+      method,
+      reason,
+      outcome: match result {
+         Ok(_) => "ok".to_string(),
+         Err(e) => e.to_string(),
+      },
+   };
+   debug!(
+      player,
+      method,
+      reason:? = reason,
+      outcome = record.outcome.as_str();
+      "{method} {player} ({reason:?}): {}",
+      record.outcome
+   );
+   let mut actions = RECENT_ACTIONS.lock();
+   if actions.len() == MAX_RECENT_ACTIONS {
+      actions.pop_front();
+   }
+   actions.push_back(record);
+}
+
+/// Returns the most recent actions taken on players, oldest first.
+pub fn recent_actions() -> Vec<ActionRecord> {
+   RECENT_ACTIONS.lock().iter().cloned().collect()
+}
+
+/// When each player started its current stretch of playback.
+///
+/// Fed by `PlaybackStatus` changes and keyed by the player's unique bus name,
+/// which is what signals are sent from.
+#[derive(Debug, Default)]
+struct PlaybackActivity {
+   playing_since: HashMap<String, Instant>,
+   /// When each player was last seen playing, or commanded by us
+   active_at: HashMap<String, Instant>,
+   /// When we last sent each player a command
+   acted_at: HashMap<String, Instant>,
+}
+
+impl PlaybackActivity {
+   /// Notes that we are about to command a player.
+   ///
+   /// Its status changes within [`ECHO_WINDOW`] are our own doing and are
+   /// ignored, so pausing and resuming a player doesn't make it look like it
+   /// just started playing on its own.
+   fn mark_acted(&mut self, owner: &str, now: Instant) {
+      self.playing_since.remove(owner);
+      self.active_at.insert(owner.to_string(), now);
+      self.acted_at.insert(owner.to_string(), now);
+   }
+
+   /// Records a player's new `PlaybackStatus`.
+   fn observe(&mut self, owner: &str, status: &str, now: Instant) {
+      // Starting or stopping playback both mean it was just in use
+      if status == "Playing" || self.playing_since.contains_key(owner) {
+         self.active_at.insert(owner.to_string(), now);
+      }
+      if let Some(&acted) = self.acted_at.get(owner) {
+         if now.duration_since(acted) < ECHO_WINDOW {
+            debug!("Ignoring {status} from {owner}, echo of our own command");
+            return;
+         }
+         self.acted_at.remove(owner);
+      }
+      if status == "Playing" {
+         self.playing_since.entry(owner.to_string()).or_insert(now);
+      } else {
+         self.playing_since.remove(owner);
+      }
+   }
+
+   /// How long a player has been playing without interruption, if it was seen starting.
+   fn playing_for(&self, owner: &str, now: Instant) -> Option<Duration> {
+      self
+         .playing_since
+         .get(owner)
+         .map(|since| now.duration_since(*since))
+   }
+
+   /// Whether a player has been playing for at least `min`.
+   ///
+   /// Players that started before we were watching count as playing long enough.
+   fn is_sustained(&self, owner: &str, min: Duration, now: Instant) -> bool {
+      self
+         .playing_for(owner, now)
+         .is_none_or(|played| played >= min)
+   }
+}
+
+static PLAYBACK_ACTIVITY: LazyLock<Mutex<PlaybackActivity>> = LazyLock::new(Mutex::default);
+
+/// Last known `PlaybackStatus` of session players, kept current from their
+/// signals so it can be read without asking them.
+#[derive(Debug, Default)]
+struct StatusCache {
+   /// Unique name owning each player's bus name
+   owners: HashMap<String, String>,
+   /// Status by unique name, as that is who announces changes
+   statuses: HashMap<String, String>,
+}
+
+impl StatusCache {
+   /// Records a status a player announced.
+   fn observe(&mut self, owner: &str, status: &str) {
+      self.statuses.insert(owner.to_string(), status.to_string());
+   }
+
+   /// Records a status read from a player, unless it announced a newer one meanwhile.
+   fn seed(&mut self, name: &str, owner: &str, status: &str) {
+      self.owners.insert(name.to_string(), owner.to_string());
+      self
+         .statuses
+         .entry(owner.to_string())
+         .or_insert_with(|| status.to_string());
+   }
+
+   /// Follows a player's bus name to its new owner, forgetting the old
+   /// owner's status. An empty owner means the player went away.
+   fn owner_changed(&mut self, name: &str, new_owner: &str) {
+      if let Some(old_owner) = self.owners.remove(name) {
+         self.statuses.remove(&old_owner);
+      }
+      if !new_owner.is_empty() {
+         self.owners.insert(name.to_string(), new_owner.to_string());
+      }
+   }
+
+   fn status(&self, name: &str) -> Option<&str> {
+      let owner = self.owners.get(name)?;
+      self.statuses.get(owner).map(String::as_str)
+   }
+}
+
+/// Cached playback status, `None` while the signals aren't being watched
+static PLAYBACK_STATUS: Mutex<Option<StatusCache>> = Mutex::new(None);
+
+/// Media control settings, installed at startup
+static SETTINGS: LazyLock<RwLock<MediaConfig>> = LazyLock::new(RwLock::default);
+
+/// Players we paused or resumed on our own, for clients to follow.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MediaEvent {
+   /// Players paused or muted by [`send_pause`]
+   AutoPaused(Vec<String>),
+   /// Players resumed or unmuted by [`send_play`], or once other playback stopped
+   AutoResumed(Vec<String>),
+}
+
+/// Where [`MediaEvent`]s go, if anyone subscribed
+static MEDIA_EVENTS: Mutex<Option<mpsc::UnboundedSender<MediaEvent>>> = Mutex::new(None);
+
+/// Starts receiving [`MediaEvent`]s, taking them over from any earlier subscriber.
+pub fn subscribe() -> mpsc::UnboundedReceiver<MediaEvent> {
+   let (tx, rx) = mpsc::unbounded_channel();
+   *MEDIA_EVENTS.lock() = Some(tx);
+   rx
+}
+
+fn notify(event: MediaEvent) {
+   let mut events = MEDIA_EVENTS.lock();
+   if let Some(tx) = events.as_ref()
+      && tx.send(event).is_err()
+   {
+      *events = None;
+   }
+}
+
+/// Master switch for media control, see [`MediaConfig::enabled`]
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Installs the media control settings.
+pub fn configure(config: MediaConfig) {
+   let enabled = config.enabled && env::var_os("AIRPODS_DISABLE_MEDIA_CONTROL").is_none();
+   set_enabled(enabled);
+   *SETTINGS.write() = config;
+}
+
+/// Returns whether media control is enabled.
+pub fn is_enabled() -> bool {
+   ENABLED.load(AtomicOrdering::Relaxed)
+}
+
+/// Enables or disables media control at runtime.
+pub fn set_enabled(enabled: bool) {
+   ENABLED.store(enabled, AtomicOrdering::Relaxed);
+}
+
+/// Whether it matters when players started playing, which `min_playing_ms`
+/// and [`ResumeOrder::MostRecentFirst`] rely on.
+fn watches_playback() -> bool {
+   let settings = SETTINGS.read();
+   settings.min_playing_ms > 0 || settings.resume_order == ResumeOrder::MostRecentFirst
+}
+
+/// Starts tracking the playback status and activity of session players.
+pub fn spawn_playback_watcher(supervisor: &Supervisor) {
+   supervisor.spawn("playback watcher", async {
+      let result = watch_playback().await;
+      *PLAYBACK_STATUS.lock() = None;
+      if let Err(e) = result {
+         warn!("Stopped watching MPRIS playback status: {e}");
+      }
+   });
+}
+
+/// Records `PlaybackStatus` changes of all players in [`PLAYBACK_STATUS`]
+/// and [`PLAYBACK_ACTIVITY`], following players as they come and go.
+async fn watch_playback() -> zbus::Result<()> {
+   let connection = session_bus().await?;
+   let rule = MatchRule::builder()
+      .msg_type(message::Type::Signal)
+      .interface("org.freedesktop.DBus.Properties")?
+      .member("PropertiesChanged")?
+      .path("/org/mpris/MediaPlayer2")?
+      .arg(0, "org.mpris.MediaPlayer2.Player")?
+      .build();
+   let changes = MessageStream::for_match_rule(rule, &connection, None).await?;
+   let rule = MatchRule::builder()
+      .msg_type(message::Type::Signal)
+      .sender("org.freedesktop.DBus")?
+      .interface("org.freedesktop.DBus")?
+      .member("NameOwnerChanged")?
+      .arg0ns("org.mpris.MediaPlayer2")?
+      .build();
+   let owners = MessageStream::for_match_rule(rule, &connection, None).await?;
+   // Start caching once both subscriptions are in place, so no change is missed
+   *PLAYBACK_STATUS.lock() = Some(StatusCache::default());
+
+   let mut stream = futures::stream::select(changes, owners);
+   while let Some(message) = stream.try_next().await? {
+      let header = message.header();
+      if header
+         .member()
+         .is_some_and(|member| member == "NameOwnerChanged")
+      {
+         if let Ok((name, _, new_owner)) = message.body().deserialize::<(String, String, String)>()
+            && let Some(cache) = PLAYBACK_STATUS.lock().as_mut()
+         {
+            cache.owner_changed(&name, &new_owner);
+         }
+         continue;
+      }
+      let Some(sender) = header.sender() else {
+         continue;
+      };
+      let Ok((_, changed, _)) = message
+         .body()
+         .deserialize::<(String, HashMap<String, OwnedValue>, Vec<String>)>()
+      else {
+         continue;
+      };
+      if let Some(status) = changed
+         .get("PlaybackStatus")
+         .and_then(|v| String::try_from(v.clone()).ok())
+      {
+         if let Some(cache) = PLAYBACK_STATUS.lock().as_mut() {
+            cache.observe(sender.as_str(), &status);
+         }
+         PLAYBACK_ACTIVITY
+            .lock()
+            .observe(sender.as_str(), &status, Instant::now());
+      }
+   }
+   Ok(())
+}
+
+/// Connection to the session bus, opened on first use and shared by all calls
+static SESSION_BUS: tokio::sync::Mutex<Option<Connection>> = tokio::sync::Mutex::const_new(None);
+
+/// Connection to the system bus, for the players on it, cached like [`SESSION_BUS`]
+static SYSTEM_BUS: tokio::sync::Mutex<Option<Connection>> = tokio::sync::Mutex::const_new(None);
+
+/// Returns the connection to the session bus whose players are controlled.
+///
+/// That is our own session bus unless [`MediaConfig::session_bus`] names another.
+/// The connection is kept and reused, and opened again once it has closed,
+/// for example because the bus restarted.
+pub async fn session_bus() -> zbus::Result<Connection> {
+   let mut cached = SESSION_BUS.lock().await;
+   if let Some(connection) = cached.as_ref() {
+      return Ok(connection.clone());
+   }
+   let address = SETTINGS.read().session_bus.clone();
+   let connection = match address {
+      Some(address) => {
+         connection::Builder::address(address.as_str())?
+            .build()
+            .await?
+      },
+      None => Connection::session().await?,
+   };
+   forget_when_closed(&connection, &SESSION_BUS, "Session").await?;
+   *cached = Some(connection.clone());
+   Ok(connection)
+}
+
+/// Returns the connection to the system bus, kept and reused like [`session_bus`].
+async fn system_bus() -> zbus::Result<Connection> {
+   let mut cached = SYSTEM_BUS.lock().await;
+   if let Some(connection) = cached.as_ref() {
+      return Ok(connection.clone());
+   }
+   let connection = Connection::system().await?;
+   forget_when_closed(&connection, &SYSTEM_BUS, "System").await?;
+   *cached = Some(connection.clone());
+   Ok(connection)
+}
+
+/// Drops a cached bus connection once it closes.
+async fn forget_when_closed(
+   connection: &Connection,
+   cache: &'static tokio::sync::Mutex<Option<Connection>>,
+   bus: &'static str,
+) -> zbus::Result<()> {
+   // A closed connection ends every stream with an error, whether or not
+   // the rule ever matched. We own no names, so this one never does.
+   let rule = MatchRule::builder()
+      .msg_type(message::Type::Signal)
+      .sender("org.freedesktop.DBus")?
+      .interface("org.freedesktop.DBus")?
+      .member("NameLost")?
+      .build();
+   let mut stream = MessageStream::for_match_rule(rule, connection, Some(1)).await?;
+   let name = connection.unique_name().cloned();
+   tokio::spawn(async move {
+      while let Some(Ok(_)) = stream.next().await {}
+      let mut cached = cache.lock().await;
+      if cached.as_ref().and_then(Connection::unique_name) == name.as_ref() {
+         debug!("{bus} bus connection closed, reconnecting on next use");
+         *cached = None;
+      }
+   });
+   Ok(())
+}
+
+/// Resolves the unique bus name that owns a player's well-known name.
+async fn name_owner(connection: &Connection, service_name: &str) -> zbus::Result<String> {
+   let dbus_proxy = zbus::fdo::DBusProxy::new(connection).await?;
+   let name = zbus::names::BusName::try_from(service_name)?;
+   Ok(dbus_proxy.get_name_owner(name).await?.to_string())
+}
+
+/// When a player started its current stretch of playback, if it was seen starting.
+async fn playing_since(backend: &impl MprisBackend, service_name: &str) -> Option<Instant> {
+   // Only session players are watched
+   if !watches_playback() || service_name.starts_with(SYSTEM_BUS_PREFIX) {
+      return None;
+   }
+   let owner = backend.owner(service_name).await.ok()?;
+   PLAYBACK_ACTIVITY.lock().playing_since.get(&owner).copied()
+}
+
+/// When a player was last seen playing or was last commanded, if known.
+async fn last_active(backend: &impl MprisBackend, service_name: &str) -> Option<Instant> {
+   // Only session players are watched
+   if service_name.starts_with(SYSTEM_BUS_PREFIX) {
+      return None;
+   }
+   let owner = backend.owner(service_name).await.ok()?;
+   PLAYBACK_ACTIVITY.lock().active_at.get(&owner).copied()
+}
+
+/// Sorts players to resume the one that most recently started playing first.
+///
+/// Players without a known start keep their relative order at the end.
+fn sort_most_recent_first(players: &mut [String], since: &HashMap<String, Instant>) {
+   players.sort_by_key(|player| Reverse(since.get(player).copied()));
+}
+
+/// Whether a player has been playing long enough to be resumed later.
+async fn is_sustained_playback(backend: &impl MprisBackend, service_name: &str) -> bool {
+   let min = Duration::from_millis(SETTINGS.read().min_playing_ms);
+   // Only session players are watched
+   if min.is_zero() || service_name.starts_with(SYSTEM_BUS_PREFIX) {
+      return true;
+   }
+   match backend.owner(service_name).await {
+      Ok(owner) => {
+         let activity = PLAYBACK_ACTIVITY.lock();
+         let now = Instant::now();
+         if activity.is_sustained(owner.as_str(), min, now) {
+            return true;
+         }
+         debug!(
+            "Player {service_name} only started playing {:?} ago",
+            activity
+               .playing_for(owner.as_str(), now)
+               .unwrap_or_default()
+         );
+         false
+      },
+      Err(e) => {
+         debug!("Cannot resolve owner of {service_name}: {e}");
+         true
+      },
+   }
+}
+
+/// Checks whether a bus name belongs to an MPRIS player.
+///
+/// The bare `org.mpris.MediaPlayer2` base name is not a player; a player
+/// always has a name after the prefix.
+fn is_mpris_player(name: &str) -> bool {
+   name
+      .strip_prefix(MPRIS_PREFIX)
+      .is_some_and(|player| !player.is_empty())
+}
+
+/// Connects to the bus a player lives on, returning its bus name there.
+///
+/// Players on the system bus carry [`SYSTEM_BUS_PREFIX`] in front of their bus name.
+async fn player_bus(service_name: &str) -> zbus::Result<(Connection, &str)> {
+   match service_name.strip_prefix(SYSTEM_BUS_PREFIX) {
+      Some(name) => Ok((system_bus().await?, name)),
+      None => Ok((session_bus().await?, service_name)),
+   }
+}
+
+/// Checks whether a player's bus name matches a configured player entry.
+///
+/// Entries omit the MPRIS prefix, match case-insensitively and also match
+/// instance suffixes (`firefox` matches `firefox.instance_1_42`). Entries
+/// with `*` or `?` wildcards match the whole name instead.
+fn matches_player(service_name: &str, entry: &str) -> bool {
+   let service_name = service_name
+      .strip_prefix(SYSTEM_BUS_PREFIX)
+      .unwrap_or(service_name);
+   let player = service_name
+      .strip_prefix(MPRIS_PREFIX)
+      .unwrap_or(service_name);
+   if entry.contains(['*', '?']) {
+      return glob_matches(entry, player);
+   }
+   player.eq_ignore_ascii_case(entry)
+      || player
+         .get(..entry.len())
+         .is_some_and(|p| p.eq_ignore_ascii_case(entry))
+         && player[entry.len()..].starts_with(".instance")
+}
+
+/// Matches a name against a pattern with `*` and `?` wildcards, ignoring case.
+fn glob_matches(pattern: &str, name: &str) -> bool {
+   let (pattern, name) = (pattern.as_bytes(), name.as_bytes());
+   let (mut p, mut n) = (0, 0);
+   // Where to pick up after the last `*` if the rest fails to match
+   let mut backtrack = None;
+   while n < name.len() {
+      match pattern.get(p) {
+         Some(b'*') => {
+            p += 1;
+            backtrack = Some((p, n));
+         },
+         Some(&c) if c == b'?' || c.eq_ignore_ascii_case(&name[n]) => {
+            p += 1;
+            n += 1;
+         },
+         _ => match backtrack {
+            // Let the `*` take one more character
+            Some((after_star, start)) => {
+               p = after_star;
+               n = start + 1;
+               backtrack = Some((after_star, n));
+            },
+            None => return false,
+         },
+      }
+   }
+   pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Whether auto-pause may pause a player, as set by `only_players` and
+/// `ignored_players`.
+fn is_pausable(service_name: &str, only: &[String], ignored: &[String]) -> bool {
+   if !only.is_empty() {
+      return only.iter().any(|entry| matches_player(service_name, entry));
+   }
+   !ignored
+      .iter()
+      .any(|entry| matches_player(service_name, entry))
+}
+
+/// Returns the position of a player in the priority list, if listed.
+fn priority_rank(service_name: &str, priority: &[String]) -> Option<usize> {
+   priority
+      .iter()
+      .position(|entry| matches_player(service_name, entry))
+}
+
+/// Sorts players deterministically: prioritized players first in list order,
+/// then everything else alphabetically.
+fn sort_players(players: &mut [String], priority: &[String]) {
+   players.sort_by(|a, b| {
+      match (priority_rank(a, priority), priority_rank(b, priority)) {
+         (Some(x), Some(y)) => x.cmp(&y),
+         (Some(_), None) => Ordering::Less,
+         (None, Some(_)) => Ordering::Greater,
+         (None, None) => Ordering::Equal,
+      }
+      .then_with(|| a.cmp(b))
+   });
+}
 
 /// Sends a play command to all players we previously paused.
 /// Only plays if we previously paused the media.
-pub async fn send_play() {
-   // Get all players we paused
-   let paused_players = PAUSED_PLAYERS.lock().clone();
-   
+///
+/// With a `device`, only the players paused for it, or for no device in
+/// particular, are resumed; see [`is_resumed_by`].
+///
+/// While the session bus can't be reached the players stay tracked, so
+/// calling this again retries them.
+pub async fn send_play(
+   reason: ActionReason,
+   device: Option<Address>,
+) -> Result<(), MediaControlError> {
+   play_with(&Zbus, reason, device).await
+}
+
+async fn play_with(
+   backend: &impl MprisBackend,
+   reason: ActionReason,
+   device: Option<Address>,
+) -> Result<(), MediaControlError> {
+   if !is_enabled() {
+      debug!("Media control is disabled, skipping play command");
+      return Ok(());
+   }
+
+   let fade = Duration::from_millis(SETTINGS.read().resume_fade_ms);
+
+   // Unmute players we muted instead of pausing, and unduck ducked ones
+   let muted_players = TRACKED.lock().take_lowered();
+   let mut restored = Vec::new();
+   for (player_name, volume) in &muted_players {
+      if !fade.is_zero() {
+         restored.push(player_name.clone());
+         let (player_name, volume) = (player_name.clone(), *volume);
+         tokio::spawn(async move {
+            let result = fade_in(&player_name, volume, fade).await;
+            record_action(reason, &player_name, "FadeIn", &result);
+         });
+         continue;
+      }
+      let result = backend.set_volume(player_name, *volume).await;
+      record_action(reason, player_name, "RestoreVolume", &result);
+      match result {
+         Ok(()) => {
+            debug!("Restored volume {volume} of player: {player_name}");
+            restored.push(player_name.clone());
+         },
+         Err(e) => warn!("Failed to restore volume of player {player_name}: {e}"),
+      }
+   }
+   if !restored.is_empty() {
+      notify(MediaEvent::AutoResumed(restored));
+   }
+
+   // Get all players we paused for this device
+   forget_expired_players(reason);
+   let paused_players = TRACKED.lock().paused_for(device);
+
    if paused_players.is_empty() {
-      debug!("No media was paused by us, skipping play command");
+      if muted_players.is_empty() {
+         debug!("No media was paused by us, skipping play command");
+      }
+      return Ok(());
+   }
+   if paused_players
+      .iter()
+      .any(|player| !player.starts_with(SYSTEM_BUS_PREFIX))
+   {
+      // Keep the players for a retry rather than failing each of them
+      backend
+         .connect()
+         .await
+         .map_err(MediaControlError::NoSession)?;
+   }
+
+   let policy = SETTINGS.read().resume_policy;
+   let others_playing = policy != ResumePolicy::Always
+      && other_player_playing(backend, &paused_players, &muted_players).await;
+   match resume_decision(policy, others_playing) {
+      ResumeDecision::Now => {
+         resume_players(backend, &paused_players, reason, fade, device).await?;
+      },
+      ResumeDecision::Skip => {
+         info!("Another player started while paused, not resuming {paused_players:?}");
+         forget_paused_players(reason, device);
+      },
+      ResumeDecision::WhenIdle => {
+         info!("Another player started while paused, resuming {paused_players:?} once it stops");
+         let generation = TRACKED.lock().generation(device);
+         tokio::spawn(resume_when_idle(generation, reason, fade, device));
+      },
+   }
+   Ok(())
+}
+
+/// Forgets the players we paused more than `resume_timeout_sec` ago, so
+/// long forgotten playback doesn't suddenly resume.
+fn forget_expired_players(reason: ActionReason) {
+   let timeout = Duration::from_secs(SETTINGS.read().resume_timeout_sec);
+   if timeout.is_zero() {
+      return;
+   }
+   let expired = {
+      let mut tracked = TRACKED.lock();
+      let expired = expired_players(&tracked.paused, &tracked.paused_at, timeout, Instant::now());
+      tracked.forget(&expired);
+      expired
+   };
+   if expired.is_empty() {
       return;
    }
+   for player_name in &expired {
+      info!("Player {player_name} was paused too long ago, not resuming it");
+      record_action::<_, String>(reason, player_name, "Forget", &Ok(()));
+   }
+   sync_paused();
+}
+
+/// Whether a player we paused is still ours to resume, from its status
+/// `from` before the pause and `now`, as far as they're known.
+///
+/// Only players that were playing are resumed, and not once the user
+/// stopped them meanwhile.
+fn was_paused_by_us(from: Option<PlaybackStatus>, now: Option<PlaybackStatus>) -> bool {
+   matches!(from, None | Some(PlaybackStatus::Playing)) && now != Some(PlaybackStatus::Stopped)
+}
+
+/// Lists the players paused more than `timeout` before `now`.
+///
+/// Players without a known pause time are kept.
+fn expired_players(
+   players: &[String],
+   paused_at: &HashMap<String, Instant>,
+   timeout: Duration,
+   now: Instant,
+) -> Vec<String> {
+   players
+      .iter()
+      .filter(|player| {
+         paused_at
+            .get(*player)
+            .is_some_and(|at| now.saturating_duration_since(*at) > timeout)
+      })
+      .cloned()
+      .collect()
+}
+
+/// Resumes every paused player, whether we paused it or not, and restores
+/// the volume of players we muted or ducked.
+///
+/// This is a manual recovery for media stuck paused; it runs even with media
+/// control disabled and forgets everything we were tracking. Returns the
+/// players that were resumed.
+pub async fn resume_all() -> Vec<String> {
+   resume_all_with(&Zbus).await
+}
+
+async fn resume_all_with(backend: &impl MprisBackend) -> Vec<String> {
+   info!("Manual override: resuming all paused players");
+   let reason = ActionReason::Manual;
+
+   let lowered = {
+      let mut tracked = TRACKED.lock();
+      // Stale idle waits must not fire after this
+      tracked.paused_again(None);
+      tracked.take_paused_for(None);
+      tracked.take_lowered()
+   };
+   sync_paused();
+   for (player_name, volume) in lowered {
+      let result = backend.set_volume(&player_name, volume).await;
+      record_action(reason, &player_name, "RestoreVolume", &result);
+      if let Err(e) = result {
+         warn!("Failed to restore volume of player {player_name}: {e}");
+      }
+   }
+
+   let players = match backend.list_players().await {
+      Ok(players) => players,
+      Err(e) => {
+         warn!("Failed to list MPRIS players: {e}");
+         return vec![];
+      },
+   };
+   let mut resumed = Vec::new();
+   for player_name in players {
+      match backend.playback_status(&player_name).await {
+         Ok(PlaybackStatus::Paused) => {},
+         Ok(_) => continue,
+         Err(e) => {
+            debug!("Could not check playback status for player {player_name}: {e}");
+            continue;
+         },
+      }
+      let result = backend.send_command("Play", &player_name).await;
+      record_action(reason, &player_name, "Play", &result);
+      match result {
+         Ok(()) => {
+            info!("Manual override: resumed player {player_name}");
+            resumed.push(player_name);
+         },
+         Err(e) => {
+            warn!(player = player_name.as_str(); "Failed to resume player {player_name}: {e}");
+         },
+      }
+   }
+   resumed
+}
+
+/// What [`send_play`] does with our paused players.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ResumeDecision {
+   Now,
+   Skip,
+   WhenIdle,
+}
+
+const fn resume_decision(policy: ResumePolicy, others_playing: bool) -> ResumeDecision {
+   match (policy, others_playing) {
+      (ResumePolicy::Always, _) | (_, false) => ResumeDecision::Now,
+      (ResumePolicy::SkipIfOtherPlaying, true) => ResumeDecision::Skip,
+      (ResumePolicy::WhenIdle, true) => ResumeDecision::WhenIdle,
+   }
+}
+
+/// Whether a player other than the ones we paused or muted is playing.
+async fn other_player_playing(
+   backend: &impl MprisBackend,
+   paused: &[String],
+   muted: &[(String, f64)],
+) -> bool {
+   let Ok(players) = backend.list_players().await else {
+      return false;
+   };
+   for player_name in players {
+      let ours =
+         paused.contains(&player_name) || muted.iter().any(|(name, _)| *name == player_name);
+      if !ours && backend.playback_status(&player_name).await.ok() == Some(PlaybackStatus::Playing)
+      {
+         debug!("Player {player_name} started while we were paused");
+         return true;
+      }
+   }
+   false
+}
+
+/// Waits until no other player is playing, then resumes our paused players.
+///
+/// Gives up if the paused players are forgotten or replaced by a new pause
+/// for the same device.
+async fn resume_when_idle(
+   generation: u64,
+   reason: ActionReason,
+   fade: Duration,
+   device: Option<Address>,
+) {
+   loop {
+      time::sleep(IDLE_POLL_INTERVAL).await;
+      if TRACKED.lock().generation(device) != generation {
+         return;
+      }
+      forget_expired_players(reason);
+      let paused_players = TRACKED.lock().paused_for(device);
+      if paused_players.is_empty() {
+         return;
+      }
+      if !other_player_playing(&Zbus, &paused_players, &[]).await {
+         if let Err(e) = resume_players(&Zbus, &paused_players, reason, fade, device).await {
+            warn!("Failed to resume players once idle: {e}");
+         }
+         return;
+      }
+   }
+}
+
+/// Resumes players we paused, fading them in if configured.
+///
+/// Fails if none of them could be resumed.
+async fn resume_players(
+   backend: &impl MprisBackend,
+   paused_players: &[String],
+   reason: ActionReason,
+   fade: Duration,
+   device: Option<Address>,
+) -> Result<(), MediaControlError> {
+   debug!(
+      "Resuming {} previously paused player(s): {:?}",
+      paused_players.len(),
+      paused_players
+   );
+
+   let mut ordered = paused_players.to_vec();
+   if SETTINGS.read().pause_marker
+      && let Some(listed) = pause_marker::read()
+   {
+      ordered.retain(|player_name| {
+         let listed = listed.contains(player_name);
+         if !listed {
+            info!("Player {player_name} was taken over by another tool, not resuming it");
+            record_action::<_, String>(reason, player_name, "Forget", &Ok(()));
+         }
+         listed
+      });
+   }
+   if SETTINGS.read().resume_order == ResumeOrder::MostRecentFirst {
+      sort_most_recent_first(&mut ordered, &TRACKED.lock().paused_since);
+   }
+
+   // Players closed meanwhile are gone for good, there's nothing to resume
+   if let Ok(present) = backend.list_players().await {
+      for player_name in drop_vanished(&mut ordered, &present) {
+         debug!("Player {player_name} went away while paused, forgetting it");
+         record_action::<_, String>(reason, &player_name, "Forget", &Ok(()));
+      }
+   }
+
+   let verify = Duration::from_millis(SETTINGS.read().resume_verify_ms);
 
-   debug!("Resuming {} previously paused player(s): {:?}", paused_players.len(), paused_players);
-   
    // Resume all paused players
-   let mut successful = 0;
-   
-   for player_name in &paused_players {
-      match send_mpris_command_to_player("Play", player_name).await {
+   let mut resumed = Vec::new();
+   let mut failed = Vec::new();
+
+   for player_name in &ordered {
+      let (from, faded) = {
+         let mut tracked = TRACKED.lock();
+         // Players faded out on pause come back at their volume from before
+         let faded = tracked.faded_volumes.remove(player_name);
+         (tracked.paused_from.get(player_name).copied(), faded)
+      };
+      let status = backend.playback_status(player_name).await.ok();
+      if !was_paused_by_us(from, status) {
+         info!("Player {player_name} was stopped or wasn't playing, not resuming it");
+         record_action::<_, String>(reason, player_name, "Forget", &Ok(()));
+         continue;
+      }
+      if let Ok(caps) = backend.capabilities(player_name).await
+         && !caps.can_be_resumed()
+      {
+         info!("Player {player_name} reports it can't be resumed, forgetting it");
+         record_action::<_, String>(reason, player_name, "Forget", &Ok(()));
+         continue;
+      }
+      // Start silent and fade in if the player lets us control its volume
+      let target = if fade.is_zero() {
+         if let Some(volume) = faded {
+            let _ = backend.set_volume(player_name, volume).await;
+         }
+         None
+      } else {
+         let volume = match faded {
+            Some(volume) => Ok(volume),
+            None => backend.volume(player_name).await,
+         };
+         match volume {
+            Ok(volume) if backend.set_volume(player_name, 0.0).await.is_ok() => Some(volume),
+            _ => None,
+         }
+      };
+
+      let result = backend.send_command("Play", player_name).await;
+      record_action(reason, player_name, "Play", &result);
+      match result {
          Ok(_) => {
             debug!("Successfully resumed player: {}", player_name);
-            successful += 1;
+            resumed.push(player_name.clone());
+            if let Some(volume) = target {
+               let player_name = player_name.clone();
+               tokio::spawn(async move {
+                  let _ = fade_in(&player_name, volume, fade).await;
+               });
+            }
+            if !verify.is_zero() {
+               let generation = TRACKED.lock().generation(device);
+               let player_name = player_name.clone();
+               tokio::spawn(async move {
+                  verify_resumed(&Zbus, &player_name, reason, verify, device, generation).await;
+               });
+            }
          },
          Err(e) => {
-            warn!("Failed to resume player {}: {}", player_name, e);
+            warn!(player = player_name.as_str(); "Failed to resume player {player_name}: {e}");
+            failed.push(player_name.clone());
+         },
+      }
+   }
+
+   debug!(
+      "Resumed {}/{} players successfully",
+      resumed.len(),
+      ordered.len()
+   );
+
+   // Forget the players we've resumed, keeping any a concurrent pause added meanwhile
+   TRACKED.lock().forget(paused_players);
+   sync_paused();
+   if resumed.is_empty() {
+      if !failed.is_empty() {
+         return Err(MediaControlError::AllFailed(failed));
+      }
+   } else {
+      notify(MediaEvent::AutoResumed(resumed));
+   }
+   Ok(())
+}
+
+/// Removes the players that aren't `present` on the bus anymore, returning them.
+fn drop_vanished(players: &mut Vec<String>, present: &[String]) -> Vec<String> {
+   let (kept, vanished) = std::mem::take(players)
+      .into_iter()
+      .partition(|player| present.contains(player));
+   *players = kept;
+   vanished
+}
+
+/// Sends Play once more if a player we resumed isn't playing after `delay`.
+///
+/// Some players ignore the first Play after being paused for a while. Gives
+/// up if media was paused again meanwhile for the device it was resumed for.
+async fn verify_resumed(
+   backend: &impl MprisBackend,
+   player_name: &str,
+   reason: ActionReason,
+   delay: Duration,
+   device: Option<Address>,
+   generation: u64,
+) {
+   time::sleep(delay).await;
+   if TRACKED.lock().generation(device) != generation {
+      return;
+   }
+   match backend.playback_status(player_name).await {
+      Ok(PlaybackStatus::Playing) => return,
+      Ok(status) => info!("Player {player_name} is {status} after resuming, sending Play again"),
+      Err(e) => {
+         debug!("Could not verify that player {player_name} resumed: {e}");
+         return;
+      },
+   }
+   let result = backend.send_command("Play", player_name).await;
+   record_action(reason, player_name, "RetryPlay", &result);
+   if let Err(e) = result {
+      warn!("Failed to resume player {player_name} again: {e}");
+   }
+}
+
+/// Returns the players we paused and will resume.
+pub fn paused_players() -> Vec<String> {
+   TRACKED.lock().paused.clone()
+}
+
+/// Forgets the players we paused, so they won't be resumed.
+///
+/// With a `device`, only the players [`send_play`] would resume for it are forgotten.
+pub fn forget_paused_players(reason: ActionReason, device: Option<Address>) {
+   let forgotten = TRACKED.lock().take_paused_for(device);
+   for player_name in forgotten {
+      record_action::<_, String>(reason, &player_name, "Forget", &Ok(()));
+   }
+   sync_paused();
+}
+
+/// Whether a player paused for `owner` is resumed for `device`.
+///
+/// A device only resumes what was paused for it, so one set of buds going
+/// back in doesn't resume what another set paused. Players paused for no
+/// device in particular are resumed by any, and resuming for no device
+/// resumes them all, so with a single device nothing changes.
+fn is_resumed_by(owner: Option<Address>, device: Option<Address>) -> bool {
+   owner.is_none() || device.is_none() || owner == device
+}
+
+/// Writes the players we paused to the state kept across restarts, and to
+/// the pause marker if enabled.
+fn sync_paused() {
+   let paused = paused_players();
+   let settings = SETTINGS.read();
+   if settings.resume_after_restart_sec > 0 {
+      paused_state::save(&paused, unix_ms());
+   }
+   if settings.pause_marker {
+      pause_marker::write(&paused);
+   }
+}
+
+/// Picks up the players paused before the daemon restarted, so that they are
+/// resumed as usual.
+pub fn restore_paused() {
+   let max_age_ms = SETTINGS.read().resume_after_restart_sec * 1000;
+   if max_age_ms == 0 {
+      return;
+   }
+   let now_ms = unix_ms();
+   let entries = paused_state::load(max_age_ms, now_ms);
+   if entries.is_empty() {
+      return;
+   }
+   let now = Instant::now();
+   let mut players = Vec::with_capacity(entries.len());
+   {
+      let mut tracked = TRACKED.lock();
+      for (player, paused_at) in entries {
+         let ago = Duration::from_millis(now_ms.saturating_sub(paused_at));
+         if let Some(at) = now.checked_sub(ago) {
+            tracked.paused_at.insert(player.clone(), at);
+         }
+         players.push(player);
+      }
+      info!("Restored players paused before the restart, resuming them as usual: {players:?}");
+      track_paused(
+         &mut tracked.paused,
+         players,
+         &SETTINGS.read().player_priority,
+      );
+   }
+   sync_paused();
+}
+
+fn unix_ms() -> u64 {
+   SystemTime::UNIX_EPOCH
+      .elapsed()
+      .map_or(0, |t| t.as_millis() as u64)
+}
+
+/// Stops the active player, as picked by [`pick_active_player`].
+///
+/// If we had paused it, it is forgotten so it isn't resumed later.
+pub async fn send_stop(reason: ActionReason) {
+   stop_with(&Zbus, reason).await;
+}
+
+async fn stop_with(backend: &impl MprisBackend, reason: ActionReason) {
+   if !is_enabled() {
+      debug!("Media control is disabled, skipping stop command");
+      return;
+   }
+
+   let player_name = match active_player(backend).await {
+      Ok(player) => player,
+      Err(e) => {
+         debug!("No player to stop: {e}");
+         return;
+      },
+   };
+   let result = backend.send_command("Stop", &player_name).await;
+   record_action(reason, &player_name, "Stop", &result);
+   match result {
+      Ok(()) => debug!("Successfully stopped player: {}", player_name),
+      Err(e) => warn!(player = player_name.as_str(); "Failed to stop player {player_name}: {e}"),
+   }
+   let was_paused = {
+      let mut tracked = TRACKED.lock();
+      let was_paused = tracked.paused.contains(&player_name);
+      tracked.forget(std::slice::from_ref(&player_name));
+      was_paused
+   };
+   if was_paused {
+      sync_paused();
+   }
+}
+
+/// Stops the players we paused for `device`, or all playing players if we
+/// paused none.
+///
+/// Stopped players are forgotten, so they aren't resumed later.
+pub async fn send_stop_paused(reason: ActionReason, device: Option<Address>) {
+   stop_paused_with(&Zbus, reason, device).await;
+}
+
+async fn stop_paused_with(
+   backend: &impl MprisBackend,
+   reason: ActionReason,
+   device: Option<Address>,
+) {
+   if !is_enabled() {
+      debug!("Media control is disabled, skipping stop command");
+      return;
+   }
+
+   let (players, _) = gesture_targets(backend, device).await;
+   for player_name in &players {
+      let result = backend.send_command("Stop", player_name).await;
+      record_action(reason, player_name, "Stop", &result);
+      match result {
+         Ok(_) => debug!("Successfully stopped player: {}", player_name),
+         Err(e) => warn!(player = player_name.as_str(); "Failed to stop player {player_name}: {e}"),
+      }
+   }
+}
+
+/// Skips to the next track on the players we paused for `device` (resuming
+/// them), or on all playing players if we paused none.
+pub async fn send_next_and_resume(reason: ActionReason, device: Option<Address>) {
+   next_and_resume_with(&Zbus, reason, device).await;
+}
+
+async fn next_and_resume_with(
+   backend: &impl MprisBackend,
+   reason: ActionReason,
+   device: Option<Address>,
+) {
+   if !is_enabled() {
+      debug!("Media control is disabled, skipping next command");
+      return;
+   }
+
+   let (players, were_paused) = gesture_targets(backend, device).await;
+   for player_name in &players {
+      let result = match backend.send_command("Next", player_name).await {
+         Ok(()) if were_paused => backend.send_command("Play", player_name).await,
+         result => result,
+      };
+      record_action(reason, player_name, "Next", &result);
+      match result {
+         Ok(()) => debug!("Skipped to next track on player: {}", player_name),
+         Err(e) => {
+            warn!(player = player_name.as_str(); "Failed to skip track on player {player_name}: {e}")
+         },
+      }
+   }
+}
+
+/// Skips to the next track on the playing player.
+pub async fn send_next(reason: ActionReason) {
+   send_to_playing(&Zbus, reason, "Next").await;
+}
+
+/// Goes back to the previous track on the playing player.
+pub async fn send_previous(reason: ActionReason) {
+   send_to_playing(&Zbus, reason, "Previous").await;
+}
+
+/// Sends `method` to the first playing player that takes it, trying the
+/// next one if it fails. Does nothing if no player is playing.
+///
+/// Each player is tried at most once.
+async fn send_to_playing(backend: &impl MprisBackend, reason: ActionReason, method: &'static str) {
+   if !is_enabled() {
+      debug!("Media control is disabled, skipping {method} command");
+      return;
+   }
+
+   let players = backend.list_players().await.unwrap_or_default();
+   for (player_name, activity) in rank_players(backend, &players).await {
+      if !activity.playing {
+         continue;
+      }
+      let result = backend.send_command(method, &player_name).await;
+      record_action(reason, &player_name, method, &result);
+      match result {
+         Ok(()) => {
+            debug!("Sent {method} to player: {player_name}");
+            return;
+         },
+         Err(e) => {
+            warn!(player = player_name.as_str(); "Failed to send {method} to player {player_name}: {e}")
+         },
+      }
+   }
+   debug!("No playing player for {method}");
+}
+
+/// Takes the players paused by us for `device`, falling back to the
+/// currently playing ones.
+///
+/// Returns the players and whether they were paused by us.
+async fn gesture_targets(
+   backend: &impl MprisBackend,
+   device: Option<Address>,
+) -> (Vec<String>, bool) {
+   let paused = TRACKED.lock().take_paused_for(device);
+   if !paused.is_empty() {
+      sync_paused();
+      return (paused, true);
+   }
+
+   let mut playing = Vec::new();
+   for name in backend.list_players().await.unwrap_or_default() {
+      if backend.playback_status(&name).await.ok() == Some(PlaybackStatus::Playing) {
+         playing.push(name);
+      }
+   }
+   (playing, false)
+}
+
+/// What [`send_pause`] did with the players it found.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct PauseOutcome {
+   /// Players paused or muted
+   pub paused: usize,
+   /// Players left alone, because they weren't playing or couldn't be checked
+   pub skipped: usize,
+   /// Players that failed to pause
+   pub failed: Vec<String>,
+}
+
+impl PauseOutcome {
+   /// Counts a player that was sent Pause or muted.
+   fn add<T, E>(&mut self, player: &str, result: &Result<T, E>) {
+      match result {
+         Ok(_) => self.paused += 1,
+         Err(_) => self.failed.push(player.to_string()),
+      }
+   }
+
+   /// Whether some players paused but others failed to.
+   pub fn is_partial(&self) -> bool {
+      self.paused > 0 && !self.failed.is_empty()
+   }
+
+   /// Fails if every player that was playing failed to pause.
+   fn into_result(self) -> Result<Self, MediaControlError> {
+      if self.paused == 0 && !self.failed.is_empty() {
+         Err(MediaControlError::AllFailed(self.failed))
+      } else {
+         Ok(self)
+      }
+   }
+}
+
+impl fmt::Display for PauseOutcome {
+   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+      write!(f, "paused {}, skipped {}", self.paused, self.skipped)?;
+      if !self.failed.is_empty() {
+         write!(f, ", failed {}", self.failed.join(", "))?;
+      }
+      Ok(())
+   }
+}
+
+/// Sends a pause command to all playing media players via MPRIS.
+/// Stores all players that were paused (only if they were playing), as
+/// paused for `device` if given.
+///
+/// Finding nothing playing isn't an error, but finding no players at all is.
+pub async fn send_pause(
+   reason: ActionReason,
+   device: Option<Address>,
+) -> Result<PauseOutcome, MediaControlError> {
+   pause_with(&Zbus, reason, device).await
+}
+
+async fn pause_with(
+   backend: &impl MprisBackend,
+   reason: ActionReason,
+   device: Option<Address>,
+) -> Result<PauseOutcome, MediaControlError> {
+   let mut outcome = PauseOutcome::default();
+   if !is_enabled() {
+      debug!("Media control is disabled, skipping pause command");
+      return Ok(outcome);
+   }
+
+   // Find all playing players and pause them all
+   let mpris_services = backend
+      .list_players()
+      .await
+      .map_err(MediaControlError::NoSession)?;
+   if mpris_services.is_empty() {
+      return Err(MediaControlError::NoPlayers);
+   }
+
+   debug!(
+      "Found {} MPRIS player(s), checking which are playing",
+      mpris_services.len()
+   );
+
+   let already_muted = muted_player_names();
+   let limit = SETTINGS.read().max_concurrent_players;
+   // Check and pause all players at once, within the limit
+   let pauses: Vec<_> = mpris_services
+      .iter()
+      .map(|service_name| pause_player(backend, reason, service_name, &already_muted))
+      .collect();
+   let results = for_each_player(pauses, limit).await;
+
+   let mut paused_players = Vec::new();
+   let mut muted_players = Vec::new();
+   let now = Instant::now();
+   let mut affected = Vec::new();
+   // Record everything at once, a concurrent resume sees all of it or nothing
+   let mut tracked = TRACKED.lock();
+   for (service_name, paused) in mpris_services.iter().zip(results) {
+      if paused == PlayerPaused::Skipped {
+         outcome.skipped += 1;
+         continue;
+      }
+      let sent = if paused == PlayerPaused::Failed {
+         Err(())
+      } else {
+         Ok(())
+      };
+      outcome.add(service_name, &sent);
+      if sent.is_ok() {
+         affected.push(service_name.clone());
+      }
+      match paused {
+         PlayerPaused::Muted(volume) => muted_players.push((service_name.clone(), volume)),
+         PlayerPaused::Paused {
+            track: true,
+            since,
+            from,
+            volume,
+         } => {
+            paused_players.push(service_name.clone());
+            tracked.paused_at.insert(service_name.clone(), now);
+            match from {
+               Some(from) => tracked.paused_from.insert(service_name.clone(), from),
+               None => tracked.paused_from.remove(service_name),
+            };
+            match volume {
+               Some(volume) => tracked.faded_volumes.insert(service_name.clone(), volume),
+               None => tracked.faded_volumes.remove(service_name),
+            };
+            match device {
+               Some(device) => tracked.paused_by.insert(service_name.clone(), device),
+               None => tracked.paused_by.remove(service_name),
+            };
+            match since {
+               Some(since) => tracked.paused_since.insert(service_name.clone(), since),
+               None => tracked.paused_since.remove(service_name),
+            };
+         },
+         _ => {},
+      }
+   }
+   track_lowered(&mut tracked.muted, muted_players);
+   if !paused_players.is_empty() {
+      track_paused(
+         &mut tracked.paused,
+         paused_players.clone(),
+         &SETTINGS.read().player_priority,
+      );
+      tracked.paused_again(device);
+   }
+   drop(tracked);
+
+   if outcome.is_partial() {
+      // Only the players that paused are resumed later
+      warn!("Pause partly failed: {outcome}");
+      record_action(
+         reason,
+         ALL_PLAYERS,
+         "Pause",
+         &Err::<(), _>(format!("partial: {outcome}")),
+      );
+   }
+
+   if paused_players.is_empty() {
+      debug!("No playing players found to pause");
+   } else {
+      debug!(
+         "Paused {} player(s), storing for resume: {:?}",
+         paused_players.len(),
+         paused_players
+      );
+      sync_paused();
+   }
+   if !affected.is_empty() {
+      notify(MediaEvent::AutoPaused(affected));
+   }
+   outcome.into_result()
+}
+
+/// What pausing a single player came to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PlayerPaused {
+   Skipped,
+   /// Muted instead, with the volume to restore
+   Muted(f64),
+   /// Sent Pause, with when it started playing, its status before and the
+   /// volume it was faded out from, if known; only tracked for resume if it
+   /// played for long enough
+   Paused {
+      track: bool,
+      since: Option<Instant>,
+      from: Option<PlaybackStatus>,
+      volume: Option<f64>,
+   },
+   Failed,
+}
+
+/// Runs the futures for each player, up to `limit` at once.
+///
+/// Players answer in any order, so the results are put back in the order
+/// the futures were given in.
+async fn for_each_player<T>(
+   futures: impl IntoIterator<Item = impl Future<Output = T>>,
+   limit: usize,
+) -> Vec<T> {
+   let mut futures = futures.into_iter().enumerate();
+   let mut running = FuturesUnordered::new();
+   let mut results = Vec::new();
+   loop {
+      while running.len() < limit.max(1)
+         && let Some((i, future)) = futures.next()
+      {
+         running.push(async move { (i, future.await) });
+      }
+      match running.next().await {
+         Some(result) => results.push(result),
+         None => break,
+      }
+   }
+   results.sort_by_key(|(i, _)| *i);
+   results.into_iter().map(|(_, result)| result).collect()
+}
+
+/// Pauses or mutes one player if it's playing, as part of [`send_pause`].
+async fn pause_player(
+   backend: &impl MprisBackend,
+   reason: ActionReason,
+   service_name: &str,
+   already_muted: &[String],
+) -> PlayerPaused {
+   let (step, from) = plan_pause(backend, service_name, already_muted).await;
+   if let PauseStep::Skip(_) = step {
+      return PlayerPaused::Skipped;
+   }
+   if step == PauseStep::Mute {
+      let result = mute_player(backend, service_name).await;
+      record_action(reason, service_name, "Mute", &result);
+      match result {
+         Ok(volume) => {
+            debug!("Muted player {service_name} (volume was {volume})");
+            return PlayerPaused::Muted(volume);
+         },
+         Err(e) => debug!("Cannot mute player {service_name}, pausing instead: {e}"),
+      }
+   }
+   debug!("Player {} is playing, pausing it", service_name);
+   // Read before pausing, our own command resets them
+   let since = playing_since(backend, service_name).await;
+   let track = is_sustained_playback(backend, service_name).await;
+   let volume = if SETTINGS.read().pause_mode == PauseMode::Fade {
+      fade_out(backend, service_name).await
+   } else {
+      None
+   };
+   let result = backend.send_command("Pause", service_name).await;
+   record_action(reason, service_name, "Pause", &result);
+   if let Some(volume) = volume
+      && let Err(e) = backend.set_volume(service_name, volume).await
+   {
+      warn!("Failed to restore volume of player {service_name} after fading it out: {e}");
+   }
+   match result {
+      Ok(_) if track => {
+         debug!("Successfully paused player: {}", service_name);
+         PlayerPaused::Paused {
+            track: true,
+            since,
+            from,
+            volume,
+         }
+      },
+      Ok(_) => {
+         debug!("Paused player {service_name}, not tracking a brief blip for resume");
+         PlayerPaused::Paused {
+            track: false,
+            since,
+            from,
+            volume,
+         }
+      },
+      Err(e) if is_unsupported(e.as_ref()) => {
+         debug!("Player {service_name} doesn't support pausing, ignoring it for a while: {e}");
+         mark_uncontrollable(service_name, Instant::now());
+         PlayerPaused::Failed
+      },
+      Err(e) => {
+         warn!(player = service_name; "Failed to pause player {service_name}: {e}");
+         PlayerPaused::Failed
+      },
+   }
+}
+
+/// What a pause does with a player.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PauseStep {
+   Pause,
+   /// Mute instead, as listed in `mute_players`
+   Mute,
+   /// Leave it alone, for the given reason
+   Skip(&'static str),
+}
+
+impl PauseStep {
+   const fn action(self) -> &'static str {
+      match self {
+         Self::Pause => "pause",
+         Self::Mute => "mute",
+         Self::Skip(_) => "skip",
+      }
+   }
+}
+
+fn muted_player_names() -> Vec<String> {
+   TRACKED
+      .lock()
+      .muted
+      .iter()
+      .map(|(name, _)| name.clone())
+      .collect()
+}
+
+/// Decides what a pause does with a player, only reading its status.
+///
+/// Also gives the status it read, if any, to remember what the player was
+/// doing before the pause.
+async fn plan_pause(
+   backend: &impl MprisBackend,
+   service_name: &str,
+   already_muted: &[String],
+) -> (PauseStep, Option<PlaybackStatus>) {
+   if already_muted.iter().any(|name| name == service_name) {
+      return (PauseStep::Skip("already muted"), None);
+   }
+   let pausable = {
+      let settings = SETTINGS.read();
+      is_pausable(
+         service_name,
+         &settings.only_players,
+         &settings.ignored_players,
+      )
+   };
+   if !pausable {
+      return (PauseStep::Skip("ignored"), None);
+   }
+   if is_uncontrollable(service_name, Instant::now()) {
+      debug!("Player {service_name} can't be paused, skipping");
+      return (PauseStep::Skip("can't be paused"), None);
+   }
+   // Only players playing right before our pause are ours to resume
+   let status = backend.playback_status(service_name).await;
+   let from = status.as_ref().ok().copied();
+   let playing = status.map(|status| status == PlaybackStatus::Playing);
+   let pause_unknown = SETTINGS.read().pause_unknown_status;
+   if !is_ours_to_pause(service_name, playing, pause_unknown) {
+      return (PauseStep::Skip("not playing"), from);
+   }
+   let muted = SETTINGS
+      .read()
+      .mute_players
+      .iter()
+      .any(|e| matches_player(service_name, e));
+   if muted {
+      return (PauseStep::Mute, from);
+   }
+   // Players whose capabilities can't be read are still tried
+   let step = match backend.capabilities(service_name).await {
+      Ok(caps) if !caps.can_be_paused() => {
+         debug!("Player {service_name} reports it can't be paused, skipping");
+         PauseStep::Skip("can't be paused")
+      },
+      _ => PauseStep::Pause,
+   };
+   (step, from)
+}
+
+/// What [`send_pause`] would do right now, see [`preview_pause`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PausePreview {
+   pub media_control_enabled: bool,
+   /// Why the players couldn't be listed
+   pub error: Option<String>,
+   players: Vec<(String, PauseStep)>,
+}
+
+impl PausePreview {
+   /// Number of players that would be paused or muted.
+   pub fn affected(&self) -> usize {
+      self
+         .players
+         .iter()
+         .filter(|(_, step)| !matches!(step, PauseStep::Skip(_)))
+         .count()
+   }
+
+   pub fn to_json(&self) -> serde_json::Value {
+      let players: Vec<_> = self
+         .players
+         .iter()
+         .map(|(name, step)| {
+            let mut player = json!({"name": name, "action": step.action()});
+            if let PauseStep::Skip(reason) = step {
+               player["reason"] = json!(reason);
+            }
+            player
+         })
+         .collect();
+      let mut preview = json!({
+         "media_control_enabled": self.media_control_enabled,
+         "affected": self.affected(),
+         "players": players,
+      });
+      if let Some(error) = &self.error {
+         preview["error"] = json!(error);
+      }
+      preview
+   }
+}
+
+impl fmt::Display for PausePreview {
+   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+      if let Some(error) = &self.error {
+         return writeln!(f, "Failed to list MPRIS players: {error}");
+      }
+      if !self.media_control_enabled {
+         writeln!(f, "Media control is disabled, auto-pause does nothing")?;
+      }
+      writeln!(
+         f,
+         "Auto-pause would pause {} of {} player(s)",
+         self.affected(),
+         self.players.len()
+      )?;
+      for (name, step) in &self.players {
+         match step {
+            PauseStep::Skip(reason) => writeln!(f, "  skip   {name} ({reason})")?,
+            _ => writeln!(f, "  {:<6} {name}", step.action())?,
+         }
+      }
+      Ok(())
+   }
+}
+
+/// Lists what [`send_pause`] would do with each player right now.
+///
+/// Takes the same decisions without acting on them: players are only read,
+/// and nothing is paused or tracked.
+pub async fn preview_pause() -> PausePreview {
+   let mut preview = PausePreview {
+      media_control_enabled: is_enabled(),
+      ..Default::default()
+   };
+   let players = match list_players().await {
+      Ok(players) => players,
+      Err(e) => {
+         preview.error = Some(e.to_string());
+         return preview;
+      },
+   };
+   let already_muted = muted_player_names();
+   for name in players {
+      let (step, _) = plan_pause(&Zbus, &name, &already_muted).await;
+      preview.players.push((name, step));
+   }
+   preview
+}
+
+/// Decides whether a pause takes a player from playing to paused.
+///
+/// Only such players are paused and later resumed; one the user had paused
+/// or stopped already is always left alone. A player whose status can't be
+/// read is paused only with `pause_unknown_status`.
+fn is_ours_to_pause<E: fmt::Display>(
+   service_name: &str,
+   playing: Result<bool, E>,
+   pause_unknown: bool,
+) -> bool {
+   match playing {
+      Ok(true) => true,
+      Ok(false) => {
+         debug!("Player {service_name} is not playing, skipping");
+         false
+      },
+      Err(e) if pause_unknown => {
+         debug!(
+            "Could not check playback status for player {service_name} ({e}), pausing it anyway"
+         );
+         true
+      },
+      Err(e) => {
+         debug!("Could not check playback status for player {service_name} ({e}), skipping");
+         false
+      },
+   }
+}
+
+/// Adds players to the ones we paused, keeping them unique and in priority order.
+///
+/// Pauses can run concurrently and each takes a while to check all players, so
+/// the list is merged instead of being replaced.
+fn track_paused(tracked: &mut Vec<String>, players: Vec<String>, priority: &[String]) {
+   for player in players {
+      if !tracked.contains(&player) {
+         tracked.push(player);
+      }
+   }
+   sort_players(tracked, priority);
+}
+
+/// Adds players to the ones we muted or ducked, with the volume to restore.
+///
+/// A player lowered twice keeps the volume from the first time, as the second
+/// one only read back the lowered volume.
+fn track_lowered(tracked: &mut Vec<(String, f64)>, players: Vec<(String, f64)>) {
+   for (player, volume) in players {
+      if !tracked.iter().any(|(name, _)| *name == player) {
+         tracked.push((player, volume));
+      }
+   }
+}
+
+/// The MPRIS calls that pausing and resuming players come down to.
+///
+/// [`Zbus`] makes them over D-Bus; tests stand in for the players instead.
+trait MprisBackend: Sync {
+   /// Checks that the session bus can be reached.
+   fn connect(&self) -> impl Future<Output = zbus::Result<()>> + Send;
+
+   fn list_players(&self) -> impl Future<Output = zbus::Result<Vec<String>>> + Send;
+
+   /// Resolves the unique bus name owning a session player's name, which
+   /// [`PLAYBACK_ACTIVITY`] is keyed by.
+   fn owner(&self, player: &str) -> impl Future<Output = zbus::Result<String>> + Send;
+
+   fn playback_status(
+      &self,
+      player: &str,
+   ) -> impl Future<Output = Result<PlaybackStatus, Box<dyn std::error::Error + Send + Sync>>> + Send;
+
+   fn capabilities(
+      &self,
+      player: &str,
+   ) -> impl Future<Output = Result<PlayerCapabilities, Box<dyn std::error::Error + Send + Sync>>> + Send;
+
+   /// Reads all `Player` properties, to tell what a player is doing.
+   fn properties(
+      &self,
+      player: &str,
+   ) -> impl Future<Output = zbus::Result<HashMap<String, OwnedValue>>> + Send;
+
+   fn volume(
+      &self,
+      player: &str,
+   ) -> impl Future<Output = Result<f64, Box<dyn std::error::Error + Send + Sync>>> + Send;
+
+   fn set_volume(
+      &self,
+      player: &str,
+      volume: f64,
+   ) -> impl Future<Output = Result<(), Box<dyn std::error::Error + Send + Sync>>> + Send;
+
+   /// Calls a `Player` method taking no arguments, such as `Pause`.
+   fn send_command(
+      &self,
+      method: &'static str,
+      player: &str,
+   ) -> impl Future<Output = Result<(), Box<dyn std::error::Error + Send + Sync>>> + Send;
+}
+
+/// Controls the players on the session bus, and the system bus if enabled.
+struct Zbus;
+
+impl MprisBackend for Zbus {
+   async fn connect(&self) -> zbus::Result<()> {
+      session_bus().await.map(drop)
+   }
+
+   async fn list_players(&self) -> zbus::Result<Vec<String>> {
+      list_players().await
+   }
+
+   async fn owner(&self, player: &str) -> zbus::Result<String> {
+      name_owner(&session_bus().await?, player).await
+   }
+
+   async fn playback_status(
+      &self,
+      player: &str,
+   ) -> Result<PlaybackStatus, Box<dyn std::error::Error + Send + Sync>> {
+      playback_status(player).await
+   }
+
+   async fn capabilities(
+      &self,
+      player: &str,
+   ) -> Result<PlayerCapabilities, Box<dyn std::error::Error + Send + Sync>> {
+      player_capabilities(player).await
+   }
+
+   async fn properties(&self, player: &str) -> zbus::Result<HashMap<String, OwnedValue>> {
+      player_properties(player).await
+   }
+
+   async fn volume(&self, player: &str) -> Result<f64, Box<dyn std::error::Error + Send + Sync>> {
+      get_player_volume(player).await
+   }
+
+   async fn set_volume(
+      &self,
+      player: &str,
+      volume: f64,
+   ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+      set_player_volume(player, volume).await
+   }
+
+   async fn send_command(
+      &self,
+      method: &'static str,
+      player: &str,
+   ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+      send_mpris_command_to_player(method, player).await
+   }
+}
+
+/// Lists the MPRIS players media control acts on, in priority order.
+pub async fn list_players() -> zbus::Result<Vec<String>> {
+   let mut mpris_services = bus_players(&session_bus().await?).await?;
+
+   if SETTINGS.read().system_bus_players {
+      let system = async { bus_players(&system_bus().await?).await };
+      match system.await {
+         Ok(players) => {
+            SYSTEM_BUS_WARNED.store(false, AtomicOrdering::Relaxed);
+            mpris_services.extend(
+               players
+                  .into_iter()
+                  .map(|name| format!("{SYSTEM_BUS_PREFIX}{name}")),
+            );
+         },
+         // Access may be denied by policy, session players still work then
+         Err(e) if SYSTEM_BUS_WARNED.swap(true, AtomicOrdering::Relaxed) => {
+            debug!("Cannot list MPRIS players on the system bus: {e}");
+         },
+         Err(e) => warn!("Cannot list MPRIS players on the system bus: {e}"),
+      }
+   }
+
+   // Bus name order is arbitrary, so order players by priority for a stable pause/resume order
+   sort_players(&mut mpris_services, &SETTINGS.read().player_priority);
+   Ok(mpris_services)
+}
+
+/// Lists the MPRIS players on a bus.
+async fn bus_players(connection: &Connection) -> zbus::Result<Vec<String>> {
+   let dbus_proxy = zbus::fdo::DBusProxy::new(connection).await?;
+   let names = dbus_proxy.list_names().await?;
+
+   // Find all MPRIS media players (excluding KDE Connect, which is for remote control)
+   Ok(names
+      .iter()
+      .map(|name| name.as_str())
+      .filter(|name| {
+         is_mpris_player(name) && !name.contains("kdeconnect") && !name.contains("KDEConnect")
+      })
+      .map(str::to_string)
+      .collect())
+}
+
+/// What a player reports it supports, as read from its `Player` properties.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct PlayerCapabilities {
+   pub playback_status: Option<String>,
+   pub can_control: bool,
+   pub can_play: bool,
+   pub can_pause: bool,
+   /// Current `LoopStatus`, if the player implements it
+   pub loop_status: Option<String>,
+   /// Current `Shuffle`, if the player implements it
+   pub shuffle: Option<bool>,
+}
+
+impl PlayerCapabilities {
+   fn from_properties(properties: &HashMap<String, OwnedValue>) -> Self {
+      let flag = |name: &str| {
+         properties
+            .get(name)
+            .and_then(|v| bool::try_from(v).ok())
+            .unwrap_or(false)
+      };
+      Self {
+         playback_status: properties
+            .get("PlaybackStatus")
+            .and_then(|v| String::try_from(v.clone()).ok()),
+         can_control: flag("CanControl"),
+         can_play: flag("CanPlay"),
+         can_pause: flag("CanPause"),
+         loop_status: properties
+            .get("LoopStatus")
+            .and_then(|v| String::try_from(v.clone()).ok()),
+         shuffle: properties
+            .get("Shuffle")
+            .and_then(|v| bool::try_from(v).ok()),
+      }
+   }
+
+   /// Whether auto-pause can pause this player and resume it later.
+   pub const fn is_controllable(&self) -> bool {
+      self.can_control && self.can_play && self.can_pause
+   }
+
+   /// Whether the player accepts Pause.
+   pub const fn can_be_paused(&self) -> bool {
+      self.can_control && self.can_pause
+   }
+
+   /// Whether the player accepts Play.
+   pub const fn can_be_resumed(&self) -> bool {
+      self.can_control && self.can_play
+   }
+}
+
+/// Reads a player's capabilities without changing its playback state.
+pub async fn player_capabilities(
+   service_name: &str,
+) -> Result<PlayerCapabilities, Box<dyn std::error::Error + Send + Sync>> {
+   let properties = player_properties(service_name).await?;
+   Ok(PlayerCapabilities::from_properties(&properties))
+}
+
+/// Calls a method on a player's MPRIS object.
+///
+/// Gives up after [`MediaConfig::call_timeout_ms`], so a wedged player fails
+/// on its own instead of holding up the others.
+async fn call_player<B>(
+   connection: &Connection,
+   service_name: &str,
+   interface: &str,
+   method: &str,
+   body: &B,
+) -> zbus::Result<message::Message>
+where
+   B: Serialize + zbus::zvariant::DynamicType,
+{
+   let timeout = Duration::from_millis(SETTINGS.read().call_timeout_ms);
+   let call = connection.call_method(
+      Some(service_name),
+      "/org/mpris/MediaPlayer2",
+      Some(interface),
+      method,
+      body,
+   );
+   within(timeout, call, || format!("{method} on {service_name}")).await
+}
+
+/// Runs a D-Bus call, failing with a timeout error if it takes longer than
+/// `timeout`. Zero waits as long as it takes.
+async fn within<T>(
+   timeout: Duration,
+   call: impl Future<Output = zbus::Result<T>>,
+   what: impl FnOnce() -> String,
+) -> zbus::Result<T> {
+   if timeout.is_zero() {
+      return call.await;
+   }
+   match time::timeout(timeout, call).await {
+      Ok(result) => result,
+      Err(_) => Err(
+         io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("{} timed out after {timeout:?}", what()),
+         )
+         .into(),
+      ),
+   }
+}
+
+/// Reads all `Player` properties of a player.
+async fn player_properties(service_name: &str) -> zbus::Result<HashMap<String, OwnedValue>> {
+   let (connection, service_name) = player_bus(service_name).await?;
+   let reply = call_player(
+      &connection,
+      service_name,
+      "org.freedesktop.DBus.Properties",
+      "GetAll",
+      &("org.mpris.MediaPlayer2.Player",),
+   )
+   .await?;
+   reply.body().deserialize()
+}
+
+/// Whether a player is playing, the MPRIS `PlaybackStatus`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum::Display, strum::EnumString)]
+pub enum PlaybackStatus {
+   Playing,
+   Paused,
+   Stopped,
+}
+
+/// Repeat mode of a player, the MPRIS `LoopStatus`.
+#[derive(
+   Debug, Clone, Copy, PartialEq, Eq, strum::Display, strum::EnumString, strum::IntoStaticStr,
+)]
+#[strum(serialize_all = "lowercase", ascii_case_insensitive)]
+pub enum LoopStatus {
+   /// Stop at the end of the playlist
+   None,
+   /// Repeat the current track
+   Track,
+   /// Repeat the playlist
+   Playlist,
+}
+
+impl LoopStatus {
+   /// The property value as MPRIS spells it.
+   const fn mpris_value(self) -> &'static str {
+      match self {
+         Self::None => "None",
+         Self::Track => "Track",
+         Self::Playlist => "Playlist",
+      }
+   }
+}
+
+/// Picks the player remote controls act on, see [`pick_active_player`].
+async fn active_player(backend: &impl MprisBackend) -> Result<String> {
+   let players = backend.list_players().await?;
+   pick_active_player(backend, &players)
+      .await
+      .ok_or(AirPodsError::NoMediaPlayer)
+}
+
+/// What a player is doing, to tell which one the user is listening to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct PlayerActivity {
+   playing: bool,
+   /// It reports a track
+   has_metadata: bool,
+   /// Its `Position` moved since we last read it, not long ago
+   advancing: bool,
+   /// When it was last seen playing or was last commanded
+   active_at: Option<Instant>,
+}
+
+impl PlayerActivity {
+   /// Orders players from least to most likely to be the active one.
+   fn rank(&self) -> (bool, bool, bool, Option<Instant>) {
+      (
+         self.playing,
+         self.has_metadata && self.advancing,
+         self.has_metadata,
+         self.active_at,
+      )
+   }
+}
+
+/// Sorts players most likely to be the active one first, keeping the
+/// listed order among equals.
+fn sort_most_active(players: &mut [(String, PlayerActivity)]) {
+   players.sort_by_key(|(_, activity)| Reverse(activity.rank()));
+}
+
+/// Reads what a player is doing, noting its position for the next time.
+async fn player_activity(backend: &impl MprisBackend, service_name: &str) -> PlayerActivity {
+   let properties = backend.properties(service_name).await.unwrap_or_default();
+   let playing = properties
+      .get("PlaybackStatus")
+      .and_then(|v| String::try_from(v.clone()).ok())
+      .is_some_and(|status| status == "Playing");
+   let has_metadata = properties
+      .get("Metadata")
+      .and_then(|v| HashMap::<String, OwnedValue>::try_from(v.clone()).ok())
+      .is_some_and(|metadata| !metadata.is_empty());
+   let position = properties
+      .get("Position")
+      .and_then(|v| i64::try_from(v).ok());
+   let now = Instant::now();
+   let advancing = position.is_some_and(|position| {
+      POSITIONS
+         .lock()
+         .insert(service_name.to_string(), (position, now))
+         .is_some_and(|(last, at)| last != position && now.duration_since(at) <= POSITION_WINDOW)
+   });
+   PlayerActivity {
+      playing,
+      has_metadata,
+      advancing,
+      active_at: last_active(backend, service_name).await,
+   }
+}
+
+/// Reads what each player is doing, most likely to be the active one first.
+async fn rank_players(
+   backend: &impl MprisBackend,
+   players: &[String],
+) -> Vec<(String, PlayerActivity)> {
+   let limit = SETTINGS.read().max_concurrent_players;
+   let reads: Vec<_> = players
+      .iter()
+      .map(|name| player_activity(backend, name))
+      .collect();
+   let activities = for_each_player(reads, limit).await;
+   let mut ranked: Vec<_> = players.iter().cloned().zip(activities).collect();
+   sort_most_active(&mut ranked);
+   ranked
+}
+
+/// Picks the player the user is most likely listening to.
+///
+/// Playing players come first, then those with a track whose position
+/// moved on lately, then those with a track at all. Ties go to the one
+/// most recently playing or commanded, then to the listed order.
+async fn pick_active_player(backend: &impl MprisBackend, players: &[String]) -> Option<String> {
+   rank_players(backend, players)
+      .await
+      .into_iter()
+      .next()
+      .map(|(name, _)| name)
+}
+
+/// Writes an optional `Player` property, failing if the player doesn't implement it.
+async fn set_player_property(
+   service_name: &str,
+   property: &'static str,
+   value: zbus::zvariant::Value<'_>,
+) -> Result<()> {
+   let unsupported = || AirPodsError::FeatureNotSupported(format!("{property} on {service_name}"));
+   let properties = player_properties(service_name).await?;
+   if !PlayerCapabilities::from_properties(&properties).can_control
+      || !properties.contains_key(property)
+   {
+      return Err(unsupported());
+   }
+
+   let (connection, bus_name) = player_bus(service_name).await?;
+   call_player(
+      &connection,
+      bus_name,
+      "org.freedesktop.DBus.Properties",
+      "Set",
+      &("org.mpris.MediaPlayer2.Player", property, value),
+   )
+   .await
+   .map_err(|e| {
+      if is_unsupported(&e) {
+         unsupported()
+      } else {
+         e.into()
+      }
+   })?;
+   Ok(())
+}
+
+/// Sets the repeat mode of the active player, returning the player's name.
+pub async fn set_loop_status(status: LoopStatus) -> Result<String> {
+   let player = active_player(&Zbus).await?;
+   let result = set_player_property(
+      &player,
+      "LoopStatus",
+      zbus::zvariant::Value::from(status.mpris_value()),
+   )
+   .await;
+   record_action(ActionReason::Manual, &player, "SetLoopStatus", &result);
+   result?;
+   info!("Set loop status of {player} to {status}");
+   Ok(player)
+}
+
+/// Turns shuffle on or off for the active player, returning the player's name.
+pub async fn set_shuffle(enabled: bool) -> Result<String> {
+   let player = active_player(&Zbus).await?;
+   let result = set_player_property(&player, "Shuffle", zbus::zvariant::Value::from(enabled)).await;
+   record_action(ActionReason::Manual, &player, "SetShuffle", &result);
+   result?;
+   info!("Set shuffle of {player} to {enabled}");
+   Ok(player)
+}
+
+/// Checks which players auto-pause would be able to control.
+///
+/// Only reads player properties; nothing is paused or resumed.
+pub async fn self_test() -> serde_json::Value {
+   let players = match list_players().await {
+      Ok(players) => players,
+      Err(e) => {
+         return json!({
+            "media_control_enabled": is_enabled(),
+            "error": format!("Failed to list MPRIS players: {e}"),
+            "players": [],
+         });
+      },
+   };
+
+   let mut reports = Vec::with_capacity(players.len());
+   let mut controllable = 0;
+   for name in &players {
+      let report = match player_capabilities(name).await {
+         Ok(caps) => {
+            controllable += usize::from(caps.is_controllable());
+            json!({
+               "name": name,
+               "controllable": caps.is_controllable(),
+               "capabilities": caps,
+            })
          },
-      }
+         Err(e) => json!({
+            "name": name,
+            "controllable": false,
+            "error": e.to_string(),
+         }),
+      };
+      reports.push(report);
    }
-   
-   debug!("Resumed {}/{} players successfully", successful, paused_players.len());
-   
-   // Clear the stored players since we've resumed them all
-   PAUSED_PLAYERS.lock().clear();
+
+   json!({
+      "media_control_enabled": is_enabled(),
+      "controllable": controllable,
+      "players": reports,
+   })
 }
 
-/// Sends a pause command to all playing media players via MPRIS.
-/// Stores all players that were paused (only if they were playing).
-pub async fn send_pause() {
-   // Find all playing players and pause them all
-   let connection = Connection::session().await;
-   let Ok(connection) = connection else {
-      warn!("Failed to connect to D-Bus session");
-      return;
-   };
+/// Lowers the volume of all playing players until the next [`send_play`].
+pub async fn send_duck(reason: ActionReason) {
+   duck_with(&Zbus, reason).await;
+}
 
-   let dbus_proxy = match zbus::fdo::DBusProxy::new(&connection).await {
-      Ok(proxy) => proxy,
-      Err(e) => {
-         warn!("Failed to create D-Bus proxy: {}", e);
-         return;
-      }
-   };
+async fn duck_with(backend: &impl MprisBackend, reason: ActionReason) {
+   if !is_enabled() {
+      debug!("Media control is disabled, skipping duck command");
+      return;
+   }
 
-   let names = match dbus_proxy.list_names().await {
-      Ok(names) => names,
+   let mpris_services = match backend.list_players().await {
+      Ok(players) => players,
       Err(e) => {
-         warn!("Failed to list D-Bus names: {}", e);
+         warn!("Failed to list MPRIS players: {}", e);
          return;
-      }
+      },
    };
 
-   // Find all MPRIS media players (excluding KDE Connect, which is for remote control)
-   let mpris_services: Vec<_> = names
+   let already_ducked: Vec<String> = TRACKED
+      .lock()
+      .ducked
       .iter()
-      .filter(|name| {
-         let name_str = name.as_str();
-         name_str.starts_with("org.mpris.MediaPlayer2.")
-            && !name_str.contains("kdeconnect")
-            && !name_str.contains("KDEConnect")
-      })
+      .map(|(name, _)| name.clone())
       .collect();
-
-   if mpris_services.is_empty() {
-      debug!("No MPRIS media players found");
-      return;
+   let mut ducked_players = Vec::new();
+   for service_name in &mpris_services {
+      if already_ducked.contains(service_name)
+         || backend.playback_status(service_name).await.ok() != Some(PlaybackStatus::Playing)
+      {
+         continue;
+      }
+      let result = async {
+         let volume = backend.volume(service_name).await?;
+         backend
+            .set_volume(service_name, volume * DUCK_FACTOR)
+            .await?;
+         Ok::<_, Box<dyn std::error::Error + Send + Sync>>(volume)
+      };
+      let result = result.await;
+      record_action(reason, service_name, "Duck", &result);
+      match result {
+         Ok(volume) => {
+            debug!("Ducked player {service_name} (volume was {volume})");
+            ducked_players.push((service_name.clone(), volume));
+         },
+         Err(e) => debug!("Cannot duck player {service_name}: {e}"),
+      }
    }
 
-   debug!("Found {} MPRIS player(s), checking which are playing", mpris_services.len());
+   track_lowered(&mut TRACKED.lock().ducked, ducked_players);
+}
 
-   let mut paused_players = Vec::new();
+/// Mutes a player, returning its previous volume.
+async fn mute_player(
+   backend: &impl MprisBackend,
+   service_name: &str,
+) -> Result<f64, Box<dyn std::error::Error + Send + Sync>> {
+   let volume = backend.volume(service_name).await?;
 
-   // Check each player and pause all that are playing
-   for service_name in &mpris_services {
-      // Check if this player is playing
-      if let Ok(was_playing) = is_player_playing(service_name.as_str()).await {
-         if was_playing {
-            debug!("Player {} is playing, pausing it", service_name);
-            // Pause this player
-            match send_mpris_command_to_player("Pause", service_name.as_str()).await {
-               Ok(_) => {
-                  debug!("Successfully paused player: {}", service_name);
-                  paused_players.push(service_name.as_str().to_string());
-               },
-               Err(e) => {
-                  warn!("Failed to pause player {}: {}", service_name, e);
-               },
-            }
-         } else {
-            debug!("Player {} is not playing, skipping", service_name);
-         }
-      } else {
-         debug!("Could not check playback status for player {}, skipping", service_name);
+   // Players without a writable volume reject the write
+   backend.set_volume(service_name, 0.0).await?;
+   Ok(volume)
+}
+
+/// Returns the volume steps of a fade-in to `target`, one per `FADE_STEPS` of `duration`.
+fn fade_steps(target: f64, duration: Duration) -> impl Iterator<Item = (Duration, f64)> {
+   let interval = duration / FADE_STEPS;
+   (1..=FADE_STEPS).map(move |i| (interval, target * f64::from(i) / f64::from(FADE_STEPS)))
+}
+
+/// Returns the volume steps of a fade-out from `volume` to silence over `duration`.
+fn fade_out_steps(volume: f64, duration: Duration) -> impl Iterator<Item = (Duration, f64)> {
+   fade_steps(volume, duration).map(move |(interval, step)| (interval, volume - step))
+}
+
+/// Ramps a player's volume down to silence over [`PAUSE_FADE`], returning
+/// the volume it had.
+///
+/// Returns `None`, with the volume left as it was, if the player doesn't
+/// let us change it.
+async fn fade_out(backend: &impl MprisBackend, service_name: &str) -> Option<f64> {
+   let volume = match backend.volume(service_name).await {
+      Ok(volume) => volume,
+      Err(e) => {
+         debug!("Cannot read volume of player {service_name}, pausing without a fade: {e}");
+         return None;
+      },
+   };
+   for (interval, step) in fade_out_steps(volume, PAUSE_FADE) {
+      if let Err(e) = backend.set_volume(service_name, step).await {
+         debug!("Cannot fade out player {service_name}, pausing without a fade: {e}");
+         let _ = backend.set_volume(service_name, volume).await;
+         return None;
       }
+      time::sleep(interval).await;
    }
+   Some(volume)
+}
 
-   if paused_players.is_empty() {
-      debug!("No playing players found to pause");
-   } else {
-      debug!("Paused {} player(s), storing for resume: {:?}", paused_players.len(), paused_players);
-      // Store all paused players
-      *PAUSED_PLAYERS.lock() = paused_players;
+/// Ramps a player's volume from silence up to `target` over `duration`.
+///
+/// If a step fails, the volume is set straight to `target` and the error returned.
+async fn fade_in(
+   service_name: &str,
+   target: f64,
+   duration: Duration,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+   for (interval, volume) in fade_steps(target, duration) {
+      time::sleep(interval).await;
+      if let Err(e) = set_player_volume(service_name, volume).await {
+         warn!("Failed to fade in player {service_name}, restoring volume: {e}");
+         let _ = set_player_volume(service_name, target).await;
+         return Err(e);
+      }
    }
+   debug!("Faded in player {service_name} to volume {target}");
+   Ok(())
 }
 
-/// Checks if a specific player is currently playing.
-async fn is_player_playing(service_name: &str) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
-   let connection = Connection::session().await?;
-   let path = zbus::zvariant::ObjectPath::from_str_unchecked("/org/mpris/MediaPlayer2");
+/// Reads the volume of a player.
+async fn get_player_volume(
+   service_name: &str,
+) -> Result<f64, Box<dyn std::error::Error + Send + Sync>> {
+   let (connection, service_name) = player_bus(service_name).await?;
+   let reply = call_player(
+      &connection,
+      service_name,
+      "org.freedesktop.DBus.Properties",
+      "Get",
+      &("org.mpris.MediaPlayer2.Player", "Volume"),
+   )
+   .await?;
+   Ok(reply.body().deserialize::<OwnedValue>()?.try_into()?)
+}
+
+/// Sets the volume of a player.
+async fn set_player_volume(
+   service_name: &str,
+   volume: f64,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+   let (connection, service_name) = player_bus(service_name).await?;
+   call_player(
+      &connection,
+      service_name,
+      "org.freedesktop.DBus.Properties",
+      "Set",
+      &(
+         "org.mpris.MediaPlayer2.Player",
+         "Volume",
+         zbus::zvariant::Value::from(volume),
+      ),
+   )
+   .await?;
+   Ok(())
+}
+
+/// Reads a player's `PlaybackStatus`.
+///
+/// Session players are only asked the first time, after that their status
+/// is kept current from the signals they send.
+async fn playback_status(
+   service_name: &str,
+) -> Result<PlaybackStatus, Box<dyn std::error::Error + Send + Sync>> {
+   // Only session players are watched
+   let watched = !service_name.starts_with(SYSTEM_BUS_PREFIX);
+   if watched
+      && let Some(status) = PLAYBACK_STATUS
+         .lock()
+         .as_ref()
+         .and_then(|cache| cache.status(service_name))
+   {
+      return parse_playback_status(status);
+   }
+   let (connection, service_name) = player_bus(service_name).await?;
    let interface = "org.mpris.MediaPlayer2.Player";
    let property = "PlaybackStatus";
-   
-   let reply = connection
-      .call_method(
-         Some(service_name),
-         &path,
-         Some("org.freedesktop.DBus.Properties"),
-         "Get",
-         &(interface, property),
-      )
-      .await?;
+
+   let reply = call_player(
+      &connection,
+      service_name,
+      "org.freedesktop.DBus.Properties",
+      "Get",
+      &(interface, property),
+   )
+   .await?;
 
    let body = reply.body();
    let variant: zbus::zvariant::Value = body.deserialize()?;
    let status = match variant {
       zbus::zvariant::Value::Str(s) => s.to_string(),
-      _ => {
-         if let Ok(s) = String::try_from(variant) {
-            s
-         } else {
-            return Ok(false);
-         }
-      }
+      _ => String::try_from(variant).unwrap_or_default(),
    };
-   
-   Ok(status == "Playing")
+   if watched
+      && let Some(owner) = reply.header().sender()
+      && let Some(cache) = PLAYBACK_STATUS.lock().as_mut()
+   {
+      cache.seed(service_name, owner.as_str(), &status);
+   }
+
+   parse_playback_status(&status)
+}
+
+fn parse_playback_status(
+   status: &str,
+) -> Result<PlaybackStatus, Box<dyn std::error::Error + Send + Sync>> {
+   status
+      .parse()
+      .map_err(|_| format!("unknown playback status {status:?}").into())
+}
+
+/// Whether an MPRIS call failed because the player can't do it at all.
+fn is_unsupported(error: &(dyn std::error::Error + 'static)) -> bool {
+   match error.downcast_ref::<zbus::Error>() {
+      Some(zbus::Error::MethodError(name, ..)) => UNSUPPORTED_ERRORS.contains(&name.as_str()),
+      Some(zbus::Error::FDO(e)) => matches!(
+         **e,
+         zbus::fdo::Error::AccessDenied(_)
+            | zbus::fdo::Error::NotSupported(_)
+            | zbus::fdo::Error::UnknownMethod(_)
+      ),
+      _ => false,
+   }
+}
+
+/// Remembers that a player rejected control, so it is skipped during the cooldown.
+fn mark_uncontrollable(service_name: &str, now: Instant) {
+   let mut players = UNCONTROLLABLE_PLAYERS.lock();
+   players.retain(|(name, since)| name != service_name && now - *since < UNCONTROLLABLE_COOLDOWN);
+   players.push((service_name.to_string(), now));
+}
+
+/// Whether a player rejected control within the cooldown.
+fn is_uncontrollable(service_name: &str, now: Instant) -> bool {
+   UNCONTROLLABLE_PLAYERS
+      .lock()
+      .iter()
+      .any(|(name, since)| name == service_name && now - *since < UNCONTROLLABLE_COOLDOWN)
 }
 
 /// Sends a command to a specific player by service name.
@@ -161,22 +2677,1273 @@ async fn send_mpris_command_to_player(
    method: &str,
    service_name: &str,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-   let connection = Connection::session().await?;
-   let path = zbus::zvariant::ObjectPath::from_str_unchecked("/org/mpris/MediaPlayer2");
+   debug!(
+      "Sending {} command to specific player: {}",
+      method, service_name
+   );
+
+   // Only session players are watched
+   let watched = !service_name.starts_with(SYSTEM_BUS_PREFIX);
+   let (connection, service_name) = player_bus(service_name).await?;
    let interface = "org.mpris.MediaPlayer2.Player";
 
-   debug!("Sending {} command to specific player: {}", method, service_name);
+   // Mark before sending, the status change may arrive before the reply
+   if watched {
+      match name_owner(&connection, service_name).await {
+         Ok(owner) => PLAYBACK_ACTIVITY.lock().mark_acted(&owner, Instant::now()),
+         Err(e) => debug!("Cannot resolve owner of {service_name}: {e}"),
+      }
+   }
 
-   connection
-      .call_method(
-         Some(service_name),
-         &path,
-         Some(interface),
-         method,
-         &(),
-      )
-      .await?;
+   call_player(&connection, service_name, interface, method, &()).await?;
 
    Ok(())
 }
 
+#[cfg(test)]
+mod tests {
+   use std::sync::atomic::AtomicU64;
+
+   use super::*;
+
+   /// Held by tests that go through the module's global state
+   static GLOBALS: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+   /// Players with a playback status, following the commands they're sent.
+   #[derive(Default)]
+   struct FakePlayers {
+      players: Mutex<Vec<(String, PlaybackStatus)>>,
+      commands: Mutex<Vec<(&'static str, String)>>,
+      /// Players that fail every command, still recorded
+      broken: Vec<String>,
+      /// Volume of the players that let it be changed
+      volumes: Mutex<HashMap<String, f64>>,
+   }
+
+   impl FakePlayers {
+      fn new(players: &[(&str, PlaybackStatus)]) -> Self {
+         let players = players
+            .iter()
+            .map(|(name, status)| (format!("{MPRIS_PREFIX}{name}"), *status))
+            .collect();
+         Self {
+            players: Mutex::new(players),
+            ..Default::default()
+         }
+      }
+
+      fn status(&self, player: &str) -> Option<PlaybackStatus> {
+         self
+            .players
+            .lock()
+            .iter()
+            .find(|(name, _)| name == player)
+            .map(|(_, status)| *status)
+      }
+   }
+
+   impl MprisBackend for FakePlayers {
+      async fn connect(&self) -> zbus::Result<()> {
+         Ok(())
+      }
+
+      async fn list_players(&self) -> zbus::Result<Vec<String>> {
+         Ok(self
+            .players
+            .lock()
+            .iter()
+            .map(|(name, _)| name.clone())
+            .collect())
+      }
+
+      async fn owner(&self, player: &str) -> zbus::Result<String> {
+         Ok(player.to_string())
+      }
+
+      async fn playback_status(
+         &self,
+         player: &str,
+      ) -> Result<PlaybackStatus, Box<dyn std::error::Error + Send + Sync>> {
+         // Let concurrent pauses and resumes interleave, as real calls do
+         tokio::task::yield_now().await;
+         self.status(player).ok_or_else(|| "No such player".into())
+      }
+
+      async fn capabilities(
+         &self,
+         player: &str,
+      ) -> Result<PlayerCapabilities, Box<dyn std::error::Error + Send + Sync>> {
+         Ok(PlayerCapabilities {
+            playback_status: self.status(player).map(|status| status.to_string()),
+            can_control: true,
+            can_play: true,
+            can_pause: true,
+            ..Default::default()
+         })
+      }
+
+      async fn properties(&self, player: &str) -> zbus::Result<HashMap<String, OwnedValue>> {
+         let status = self.status(player).ok_or(zbus::Error::Unsupported)?;
+         let status = zbus::zvariant::Value::from(status.to_string());
+         Ok(HashMap::from([(
+            "PlaybackStatus".to_string(),
+            OwnedValue::try_from(status)?,
+         )]))
+      }
+
+      async fn volume(
+         &self,
+         player: &str,
+      ) -> Result<f64, Box<dyn std::error::Error + Send + Sync>> {
+         let volume = self.volumes.lock().get(player).copied();
+         volume.ok_or_else(|| "No volume".into())
+      }
+
+      async fn set_volume(
+         &self,
+         player: &str,
+         volume: f64,
+      ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+         let mut volumes = self.volumes.lock();
+         let current = volumes.get_mut(player).ok_or("No volume")?;
+         *current = volume;
+         Ok(())
+      }
+
+      async fn send_command(
+         &self,
+         method: &'static str,
+         player: &str,
+      ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+         self.commands.lock().push((method, player.to_string()));
+         // Like the real backend, before the player gets to answer
+         PLAYBACK_ACTIVITY.lock().mark_acted(player, Instant::now());
+         if self.broken.iter().any(|name| name == player) {
+            return Err("Player is broken".into());
+         }
+         let mut players = self.players.lock();
+         let (_, status) = players
+            .iter_mut()
+            .find(|(name, _)| name == player)
+            .ok_or("No such player")?;
+         *status = match method {
+            "Play" => PlaybackStatus::Playing,
+            "Pause" => PlaybackStatus::Paused,
+            "Stop" => PlaybackStatus::Stopped,
+            // Changing tracks keeps it playing or paused
+            _ => *status,
+         };
+         Ok(())
+      }
+   }
+
+   fn sorted(players: &[&str], priority: &[&str]) -> Vec<String> {
+      let mut players: Vec<String> = players
+         .iter()
+         .map(|p| format!("{MPRIS_PREFIX}{p}"))
+         .collect();
+      let priority: Vec<String> = priority.iter().map(|p| p.to_string()).collect();
+      sort_players(&mut players, &priority);
+      players
+         .iter()
+         .map(|p| p.strip_prefix(MPRIS_PREFIX).unwrap().to_string())
+         .collect()
+   }
+
+   #[test]
+   fn test_sort_players_alphabetical_without_priority() {
+      assert_eq!(
+         sorted(&["vlc", "spotify", "firefox.instance_1_42"], &[]),
+         ["firefox.instance_1_42", "spotify", "vlc"]
+      );
+   }
+
+   #[test]
+   fn test_sort_players_priority_first() {
+      assert_eq!(
+         sorted(
+            &[
+               "vlc",
+               "chromium.instance_7",
+               "spotify",
+               "elisa",
+               "firefox.instance_1_42"
+            ],
+            &["Spotify", "firefox"]
+         ),
+         [
+            "spotify",
+            "firefox.instance_1_42",
+            "chromium.instance_7",
+            "elisa",
+            "vlc"
+         ]
+      );
+   }
+
+   #[test]
+   fn test_sort_most_recent_first() {
+      let start = Instant::now();
+      let since = HashMap::from([
+         ("spotify".to_string(), start),
+         ("vlc".to_string(), start + Duration::from_secs(30)),
+      ]);
+      let mut players: Vec<String> = ["elisa", "spotify", "firefox", "vlc"]
+         .iter()
+         .map(ToString::to_string)
+         .collect();
+      sort_most_recent_first(&mut players, &since);
+      assert_eq!(players, ["vlc", "spotify", "elisa", "firefox"]);
+   }
+
+   #[test]
+   fn test_capabilities_from_properties() {
+      let properties = HashMap::from([
+         ("CanControl".to_string(), OwnedValue::from(true)),
+         ("CanPlay".to_string(), OwnedValue::from(true)),
+         ("CanPause".to_string(), OwnedValue::from(false)),
+         (
+            "PlaybackStatus".to_string(),
+            OwnedValue::try_from(zbus::zvariant::Value::from("Playing")).unwrap(),
+         ),
+      ]);
+      let caps = PlayerCapabilities::from_properties(&properties);
+      assert_eq!(
+         caps,
+         PlayerCapabilities {
+            playback_status: Some("Playing".to_string()),
+            can_control: true,
+            can_play: true,
+            can_pause: false,
+            loop_status: None,
+            shuffle: None,
+         }
+      );
+      assert!(!caps.is_controllable());
+      assert!(!caps.can_be_paused() && caps.can_be_resumed());
+      assert!(!PlayerCapabilities::from_properties(&HashMap::new()).is_controllable());
+
+      // Nothing goes through without CanControl
+      let properties = HashMap::from([
+         ("CanPlay".to_string(), OwnedValue::from(true)),
+         ("CanPause".to_string(), OwnedValue::from(true)),
+      ]);
+      let caps = PlayerCapabilities::from_properties(&properties);
+      assert!(!caps.can_be_paused() && !caps.can_be_resumed());
+   }
+
+   #[test]
+   fn test_loop_status_and_shuffle() {
+      assert_eq!("track".parse(), Ok(LoopStatus::Track));
+      assert_eq!("Playlist".parse(), Ok(LoopStatus::Playlist));
+      assert!("forever".parse::<LoopStatus>().is_err());
+      assert_eq!(LoopStatus::None.mpris_value(), "None");
+
+      let properties = HashMap::from([
+         (
+            "LoopStatus".to_string(),
+            OwnedValue::try_from(zbus::zvariant::Value::from("Track")).unwrap(),
+         ),
+         ("Shuffle".to_string(), OwnedValue::from(true)),
+      ]);
+      let caps = PlayerCapabilities::from_properties(&properties);
+      assert_eq!(caps.loop_status.as_deref(), Some("Track"));
+      assert_eq!(caps.shuffle, Some(true));
+   }
+
+   #[test]
+   fn test_unsupported_pause_cools_down() {
+      let unsupported = zbus::Error::FDO(Box::new(zbus::fdo::Error::NotSupported(
+         "Pause".to_string(),
+      )));
+      assert!(is_unsupported(&unsupported));
+      assert!(!is_unsupported(&zbus::Error::InterfaceNotFound));
+
+      let player = "org.mpris.MediaPlayer2.webplayer";
+      let now = Instant::now();
+      assert!(!is_uncontrollable(player, now));
+      mark_uncontrollable(player, now);
+      assert!(is_uncontrollable(player, now + Duration::from_secs(60)));
+      assert!(!is_uncontrollable("org.mpris.MediaPlayer2.vlc", now));
+      assert!(!is_uncontrollable(player, now + UNCONTROLLABLE_COOLDOWN));
+   }
+
+   #[test]
+   fn test_recent_actions_are_bounded() {
+      let _globals = GLOBALS.blocking_lock();
+      for i in 0..MAX_RECENT_ACTIONS + 5 {
+         let result: Result<(), String> = if i % 2 == 0 {
+            Ok(())
+         } else {
+            Err("No such player".to_string())
+         };
+         record_action(
+            ActionReason::EarRemoval,
+            &format!("player{i}"),
+            "Pause",
+            &result,
+         );
+      }
+
+      let actions = recent_actions();
+      assert_eq!(actions.len(), MAX_RECENT_ACTIONS);
+      assert_eq!(actions[0].player, "player5");
+      let last = actions.last().unwrap();
+      assert_eq!(last.player, format!("player{}", MAX_RECENT_ACTIONS + 4));
+      assert_eq!(last.outcome, "ok");
+      assert_eq!(actions[0].outcome, "No such player");
+      assert_eq!(json!(last)["reason"], "ear-removal");
+   }
+
+   #[test]
+   fn test_fade_steps_reach_target() {
+      let steps: Vec<_> = fade_steps(0.8, Duration::from_millis(500)).collect();
+      assert_eq!(steps.len(), FADE_STEPS as usize);
+      assert!(
+         steps
+            .iter()
+            .all(|(interval, _)| *interval == Duration::from_millis(50))
+      );
+      assert!(steps.windows(2).all(|w| w[0].1 < w[1].1));
+      assert!((steps[0].1 - 0.08).abs() < 1e-9);
+      assert!((steps.last().unwrap().1 - 0.8).abs() < 1e-9);
+
+      // Fading out before a pause goes the other way, down to silence
+      let steps: Vec<_> = fade_out_steps(0.8, PAUSE_FADE).collect();
+      assert_eq!(steps.len(), FADE_STEPS as usize);
+      assert!(steps.windows(2).all(|w| w[0].1 > w[1].1));
+      assert!((steps[0].1 - 0.72).abs() < 1e-9);
+      assert!(steps.last().unwrap().1.abs() < 1e-9);
+   }
+
+   #[test]
+   fn test_resume_decision() {
+      for policy in [
+         ResumePolicy::Always,
+         ResumePolicy::SkipIfOtherPlaying,
+         ResumePolicy::WhenIdle,
+      ] {
+         assert_eq!(resume_decision(policy, false), ResumeDecision::Now);
+      }
+      assert_eq!(
+         resume_decision(ResumePolicy::Always, true),
+         ResumeDecision::Now
+      );
+      assert_eq!(
+         resume_decision(ResumePolicy::SkipIfOtherPlaying, true),
+         ResumeDecision::Skip
+      );
+      assert_eq!(
+         resume_decision(ResumePolicy::WhenIdle, true),
+         ResumeDecision::WhenIdle
+      );
+   }
+
+   #[test]
+   fn test_playback_blip_is_not_sustained() {
+      let min = Duration::from_millis(1000);
+      let start = Instant::now();
+      let at = |ms| start + Duration::from_millis(ms);
+      let mut activity = PlaybackActivity::default();
+
+      // Never seen starting, e.g. playing since before the daemon started
+      assert!(activity.is_sustained(":1.42", min, at(0)));
+
+      // A notification sound flickers into Playing and gets paused right away
+      activity.observe(":1.42", "Playing", at(0));
+      assert_eq!(
+         activity.playing_for(":1.42", at(300)),
+         Some(Duration::from_millis(300))
+      );
+      assert!(!activity.is_sustained(":1.42", min, at(300)));
+
+      // Repeated Playing updates don't restart the stretch
+      activity.observe(":1.42", "Playing", at(800));
+      assert!(activity.is_sustained(":1.42", min, at(1000)));
+
+      // Stopping resets it
+      activity.observe(":1.42", "Paused", at(1200));
+      activity.observe(":1.42", "Playing", at(1500));
+      assert!(!activity.is_sustained(":1.42", min, at(2000)));
+      assert!(activity.is_sustained(":1.42", min, at(2500)));
+   }
+
+   #[test]
+   fn test_own_commands_are_not_mistaken_for_playback() {
+      let min = Duration::from_millis(1000);
+      let start = Instant::now();
+      let at = |ms| start + Duration::from_millis(ms);
+      let mut activity = PlaybackActivity::default();
+
+      activity.observe(":1.42", "Playing", at(0));
+      assert!(activity.is_sustained(":1.42", min, at(5000)));
+
+      // We pause and resume; the player echoes both changes back
+      activity.mark_acted(":1.42", at(5000));
+      activity.observe(":1.42", "Paused", at(5050));
+      activity.mark_acted(":1.42", at(6000));
+      activity.observe(":1.42", "Playing", at(6050));
+      // The echoed Playing doesn't restart the stretch, so the player
+      // isn't taken for a blip if the buds come out again right away
+      assert_eq!(activity.playing_for(":1.42", at(6200)), None);
+      assert!(activity.is_sustained(":1.42", min, at(6200)));
+
+      // Changes after the window are the user's again
+      activity.observe(":1.42", "Paused", at(9000));
+      activity.observe(":1.42", "Playing", at(9100));
+      assert!(!activity.is_sustained(":1.42", min, at(9500)));
+   }
+
+   #[tokio::test]
+   async fn test_concurrent_pauses_merge_tracked_players() {
+      use PlaybackStatus::Playing;
+
+      let _globals = GLOBALS.lock().await;
+      configure(MediaConfig {
+         player_priority: vec!["spotify".to_string()],
+         resume_after_restart_sec: 0,
+         ..Default::default()
+      });
+      set_enabled(true);
+      *TRACKED.lock() = Tracked::default();
+      let players = |names: &[&str]| {
+         names
+            .iter()
+            .map(|name| format!("{MPRIS_PREFIX}{name}"))
+            .collect::<Vec<_>>()
+      };
+
+      // Both check their players at the same time, each yielding on every read
+      let first = FakePlayers::new(&[("vlc", Playing), ("spotify", Playing)]);
+      let second =
+         FakePlayers::new(&[("spotify", Playing), ("firefox", Playing), ("mpv", Playing)]);
+      let (a, b) = tokio::join!(
+         pause_with(&first, ActionReason::EarRemoval, None),
+         pause_with(&second, ActionReason::SessionLock, None),
+      );
+      assert_eq!((a.unwrap().paused, b.unwrap().paused), (2, 3));
+      assert_eq!(
+         paused_players(),
+         players(&["spotify", "firefox", "mpv", "vlc"])
+      );
+      // Nothing was recorded for only some of them
+      let tracked = TRACKED.lock();
+      assert!(
+         tracked
+            .paused
+            .iter()
+            .all(|player| tracked.paused_at.contains_key(player)
+               && tracked.paused_from.contains_key(player))
+      );
+      drop(tracked);
+      *TRACKED.lock() = Tracked::default();
+
+      // A player muted twice keeps its original volume
+      let mut muted = Vec::new();
+      track_lowered(&mut muted, vec![("vlc".to_string(), 0.8)]);
+      track_lowered(
+         &mut muted,
+         vec![("vlc".to_string(), 0.0), ("mpv".to_string(), 0.5)],
+      );
+      assert_eq!(muted, [("vlc".to_string(), 0.8), ("mpv".to_string(), 0.5)]);
+   }
+
+   #[test]
+   fn test_paused_players_are_not_ours_to_pause() {
+      let player = "org.mpris.MediaPlayer2.vlc";
+      for pause_unknown in [false, true] {
+         // Already paused by the user, so never tracked for resume
+         assert!(!is_ours_to_pause::<String>(
+            player,
+            Ok(false),
+            pause_unknown
+         ));
+         assert!(is_ours_to_pause::<String>(player, Ok(true), pause_unknown));
+      }
+      let unreadable = || Err("no reply".to_string());
+      assert!(!is_ours_to_pause(player, unreadable(), false));
+      assert!(is_ours_to_pause(player, unreadable(), true));
+   }
+
+   #[test]
+   fn test_pause_preview_output() {
+      let preview = PausePreview {
+         media_control_enabled: true,
+         error: None,
+         players: vec![
+            (
+               "org.mpris.MediaPlayer2.spotify".to_string(),
+               PauseStep::Pause,
+            ),
+            ("org.mpris.MediaPlayer2.mpv".to_string(), PauseStep::Mute),
+            (
+               "org.mpris.MediaPlayer2.vlc".to_string(),
+               PauseStep::Skip("not playing"),
+            ),
+         ],
+      };
+      assert_eq!(
+         preview.to_string(),
+         "Auto-pause would pause 2 of 3 player(s)\n  pause  org.mpris.MediaPlayer2.spotify\n  \
+          mute   org.mpris.MediaPlayer2.mpv\n  skip   org.mpris.MediaPlayer2.vlc (not playing)\n"
+      );
+      let json = preview.to_json();
+      assert_eq!(json["affected"], 2);
+      assert_eq!(
+         json["players"][2],
+         json!({"name": "org.mpris.MediaPlayer2.vlc", "action": "skip", "reason": "not playing"})
+      );
+   }
+
+   #[test]
+   fn test_players_sharing_a_prefix_are_targeted_once() {
+      // Stop and next gestures act on each tracked player once, so players whose
+      // names merely share a prefix must neither be merged nor repeated
+      let mut tracked = Vec::new();
+      let priority = ["spotify".to_string()];
+      let players = |names: &[&str]| {
+         names
+            .iter()
+            .map(|name| format!("{MPRIS_PREFIX}{name}"))
+            .collect::<Vec<_>>()
+      };
+
+      track_paused(&mut tracked, players(&["spotifyd", "spotify"]), &priority);
+      track_paused(
+         &mut tracked,
+         players(&["spotify", "spotify.instance_1_42", "spotifyd"]),
+         &priority,
+      );
+      assert_eq!(
+         tracked,
+         players(&["spotify", "spotify.instance_1_42", "spotifyd"])
+      );
+   }
+
+   #[test]
+   fn test_is_mpris_player() {
+      assert!(is_mpris_player("org.mpris.MediaPlayer2.spotify"));
+      assert!(is_mpris_player(
+         "org.mpris.MediaPlayer2.firefox.instance_1_42"
+      ));
+
+      // The base name on its own, with or without the separator
+      assert!(!is_mpris_player("org.mpris.MediaPlayer2"));
+      assert!(!is_mpris_player("org.mpris.MediaPlayer2."));
+
+      assert!(!is_mpris_player("org.mpris.MediaPlayer2spotify"));
+      assert!(!is_mpris_player("org.freedesktop.Notifications"));
+      assert!(!is_mpris_player(":1.42"));
+      assert!(!is_mpris_player(""));
+   }
+
+   #[test]
+   fn test_matches_player() {
+      assert!(matches_player("org.mpris.MediaPlayer2.vlc", "VLC"));
+      assert!(matches_player(
+         "org.mpris.MediaPlayer2.firefox.instance_1_42",
+         "firefox"
+      ));
+      assert!(!matches_player(
+         "org.mpris.MediaPlayer2.firefoxpwa",
+         "firefox"
+      ));
+
+      // System bus players are matched by their name on that bus
+      assert!(matches_player(
+         "system:org.mpris.MediaPlayer2.kiosk",
+         "kiosk"
+      ));
+   }
+
+   #[test]
+   fn test_priority_does_not_match_other_prefixes() {
+      assert_eq!(
+         priority_rank("org.mpris.MediaPlayer2.spotifyd", &["spotify".into()]),
+         None
+      );
+      assert_eq!(
+         priority_rank("org.mpris.MediaPlayer2.spotify", &["spotify".into()]),
+         Some(0)
+      );
+   }
+
+   #[test]
+   fn test_pause_outcome_reports_partial_failures() {
+      let mut outcome = PauseOutcome::default();
+      outcome.add("org.mpris.MediaPlayer2.spotify", &Ok::<_, String>(()));
+      outcome.add("org.mpris.MediaPlayer2.vlc", &Err::<(), _>("timed out"));
+      outcome.add("org.mpris.MediaPlayer2.mpv", &Ok::<_, String>(()));
+      outcome.skipped += 1;
+      assert_eq!(outcome.paused, 2);
+      assert_eq!(outcome.failed, ["org.mpris.MediaPlayer2.vlc"]);
+      assert!(outcome.is_partial());
+      assert_eq!(
+         outcome.to_string(),
+         "paused 2, skipped 1, failed org.mpris.MediaPlayer2.vlc"
+      );
+
+      // Failing everywhere isn't partial
+      let mut outcome = PauseOutcome::default();
+      outcome.add("org.mpris.MediaPlayer2.vlc", &Err::<(), _>("timed out"));
+      assert!(!outcome.is_partial());
+   }
+
+   #[test]
+   fn test_pause_fails_only_if_every_player_failed() {
+      let mut outcome = PauseOutcome::default();
+      outcome.add("org.mpris.MediaPlayer2.vlc", &Err::<(), _>("timed out"));
+      outcome.skipped += 1;
+      assert!(matches!(
+         outcome.clone().into_result(),
+         Err(MediaControlError::AllFailed(failed)) if failed == ["org.mpris.MediaPlayer2.vlc"]
+      ));
+
+      outcome.add("org.mpris.MediaPlayer2.mpv", &Ok::<_, String>(()));
+      assert_eq!(outcome.clone().into_result().unwrap(), outcome);
+      // Nothing playing is no failure
+      assert!(PauseOutcome::default().into_result().is_ok());
+   }
+
+   #[tokio::test]
+   async fn test_players_are_paused_concurrently() {
+      let players: Vec<String> = (0..32)
+         .map(|i| format!("org.mpris.MediaPlayer2.player{i:02}"))
+         .collect();
+      let in_flight = AtomicU64::new(0);
+      let most_in_flight = AtomicU64::new(0);
+      let pauses = players.iter().map(|player| {
+         let (in_flight, most_in_flight) = (&in_flight, &most_in_flight);
+         async move {
+            let now = in_flight.fetch_add(1, AtomicOrdering::SeqCst) + 1;
+            most_in_flight.fetch_max(now, AtomicOrdering::SeqCst);
+            // Later players answer first
+            let i: u64 = player[player.len() - 2..].parse().unwrap();
+            time::sleep(Duration::from_millis(32 - i)).await;
+            in_flight.fetch_sub(1, AtomicOrdering::SeqCst);
+            (!i.is_multiple_of(3)).then(|| player.clone())
+         }
+      });
+      let results = for_each_player(pauses, 8).await;
+      let most_in_flight = most_in_flight.into_inner();
+      assert!(most_in_flight > 1 && most_in_flight <= 8);
+
+      // Results come back in player order, whatever order they finished in
+      let paused: Vec<String> = results.into_iter().flatten().collect();
+      let expected: Vec<String> = players
+         .iter()
+         .enumerate()
+         .filter(|(i, _)| i % 3 != 0)
+         .map(|(_, player)| player.clone())
+         .collect();
+      assert_eq!(paused, expected);
+
+      // And are merged with players paused meanwhile
+      let mut tracked = vec![players[0].clone()];
+      track_paused(&mut tracked, paused, &[]);
+      assert_eq!(tracked.len(), expected.len() + 1);
+   }
+
+   #[test]
+   fn test_only_and_ignored_players() {
+      assert!(glob_matches("chromium*", "chromium.instance123"));
+      assert!(glob_matches("*browser*", "plasma-browser-integration"));
+      assert!(glob_matches("VLC?", "vlc2"));
+      assert!(!glob_matches("*browser", "plasma-browser-integration"));
+      assert!(!glob_matches("vlc?", "vlc"));
+
+      let spotify = "org.mpris.MediaPlayer2.spotify";
+      let browser = "org.mpris.MediaPlayer2.plasma-browser-integration";
+      // Nothing configured pauses every player
+      assert!(is_pausable(spotify, &[], &[]));
+      assert!(is_pausable(browser, &[], &[]));
+
+      let ignored = ["plasma-*".to_string()];
+      assert!(is_pausable(spotify, &[], &ignored));
+      assert!(!is_pausable(browser, &[], &ignored));
+
+      // The allowlist wins over the denylist
+      let only = ["plasma-browser-integration".to_string()];
+      assert!(is_pausable(browser, &only, &ignored));
+      assert!(!is_pausable(spotify, &only, &ignored));
+   }
+
+   #[tokio::test]
+   async fn test_wedged_player_times_out_alone() {
+      let timeout = Duration::from_millis(20);
+      let calls = (0..4).map(|i| {
+         within(
+            timeout,
+            async move {
+               if i == 1 {
+                  // Never answers
+                  std::future::pending::<()>().await;
+               }
+               Ok(i)
+            },
+            move || format!("Pause on player{i}"),
+         )
+      });
+      let results = for_each_player(calls, 1).await;
+      assert_eq!(results.len(), 4);
+      assert!(matches!(
+         &results[1],
+         Err(zbus::Error::InputOutput(e)) if e.kind() == io::ErrorKind::TimedOut
+      ));
+      // The players after it are still reached, one at a time
+      assert!(matches!(results[3], Ok(3)));
+   }
+
+   #[test]
+   fn test_status_cache_follows_signals_and_owners() {
+      let spotify = "org.mpris.MediaPlayer2.spotify";
+      let mut cache = StatusCache::default();
+      assert_eq!(cache.status(spotify), None);
+
+      // A change announced while the first read was in flight wins
+      cache.observe(":1.42", "Paused");
+      cache.seed(spotify, ":1.42", "Playing");
+      assert_eq!(cache.status(spotify), Some("Paused"));
+      cache.observe(":1.42", "Playing");
+      assert_eq!(cache.status(spotify), Some("Playing"));
+
+      // A restarted player has to be asked again
+      cache.owner_changed(spotify, "");
+      assert_eq!(cache.status(spotify), None);
+      cache.owner_changed(spotify, ":1.57");
+      assert_eq!(cache.status(spotify), None);
+      cache.observe(":1.57", "Stopped");
+      assert_eq!(cache.status(spotify), Some("Stopped"));
+   }
+
+   #[test]
+   fn test_vanished_players_are_dropped() {
+      let mut players = vec![
+         "org.mpris.MediaPlayer2.spotify".to_string(),
+         "org.mpris.MediaPlayer2.firefox.instance_1_42".to_string(),
+         "system:org.mpris.MediaPlayer2.mpd".to_string(),
+      ];
+      let present = [
+         "system:org.mpris.MediaPlayer2.mpd".to_string(),
+         "org.mpris.MediaPlayer2.spotify".to_string(),
+         "org.mpris.MediaPlayer2.firefox.instance_1_57".to_string(),
+      ];
+      let vanished = drop_vanished(&mut players, &present);
+      assert_eq!(vanished, ["org.mpris.MediaPlayer2.firefox.instance_1_42"]);
+      // The rest keep their resume order
+      assert_eq!(
+         players,
+         [
+            "org.mpris.MediaPlayer2.spotify",
+            "system:org.mpris.MediaPlayer2.mpd"
+         ]
+      );
+   }
+
+   #[test]
+   fn test_players_paused_too_long_ago_expire() {
+      let start = Instant::now();
+      let now = start + Duration::from_secs(3600);
+      let timeout = Duration::from_secs(300);
+      let players = [
+         "org.mpris.MediaPlayer2.spotify".to_string(),
+         "org.mpris.MediaPlayer2.vlc".to_string(),
+         "org.mpris.MediaPlayer2.mpv".to_string(),
+      ];
+      let paused_at = HashMap::from([
+         (players[0].clone(), start),
+         (players[1].clone(), now - Duration::from_secs(60)),
+      ]);
+      // A player without a known pause time is kept
+      assert_eq!(
+         expired_players(&players, &paused_at, timeout, now),
+         ["org.mpris.MediaPlayer2.spotify"]
+      );
+   }
+
+   #[test]
+   fn test_only_players_paused_while_playing_are_resumed() {
+      use PlaybackStatus::{Paused, Playing, Stopped};
+
+      assert_eq!("Playing".parse(), Ok(Playing));
+      assert!(parse_playback_status("Buffering").is_err());
+
+      assert!(was_paused_by_us(Some(Playing), Some(Paused)));
+      // The user stopped it meanwhile
+      assert!(!was_paused_by_us(Some(Playing), Some(Stopped)));
+      // It was already paused or stopped when we paused
+      assert!(!was_paused_by_us(Some(Paused), Some(Paused)));
+      assert!(!was_paused_by_us(Some(Stopped), Some(Paused)));
+      // Paused with `pause_unknown_status`, or the status can't be read now
+      assert!(was_paused_by_us(None, Some(Paused)));
+      assert!(was_paused_by_us(Some(Playing), None));
+   }
+
+   #[test]
+   fn test_most_active_player_comes_first() {
+      let start = Instant::now();
+      let at = |s| Some(start + Duration::from_secs(s));
+      let player = |name: &str, activity| (name.to_string(), activity);
+      let idle = PlayerActivity::default();
+      let with_track = PlayerActivity {
+         has_metadata: true,
+         ..idle
+      };
+      let mut players = vec![
+         player("idle", idle),
+         player(
+            "recent",
+            PlayerActivity {
+               active_at: at(60),
+               ..with_track
+            },
+         ),
+         player(
+            "stale",
+            PlayerActivity {
+               active_at: at(10),
+               ..with_track
+            },
+         ),
+         player(
+            "advancing",
+            PlayerActivity {
+               advancing: true,
+               ..with_track
+            },
+         ),
+         player(
+            "playing",
+            PlayerActivity {
+               playing: true,
+               ..idle
+            },
+         ),
+      ];
+      sort_most_active(&mut players);
+      let names: Vec<_> = players.iter().map(|(name, _)| name.as_str()).collect();
+      assert_eq!(names, ["playing", "advancing", "recent", "stale", "idle"]);
+
+      // A moving position without a track says nothing
+      let mut players = vec![
+         player("first", idle),
+         player(
+            "no track",
+            PlayerActivity {
+               advancing: true,
+               ..idle
+            },
+         ),
+      ];
+      sort_most_active(&mut players);
+      assert_eq!(players[0].0, "first");
+   }
+
+   #[tokio::test]
+   async fn test_devices_only_resume_what_they_paused() {
+      use PlaybackStatus::{Paused, Playing};
+
+      let first = Address::new([0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0x01]);
+      let second = Address::new([0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0x02]);
+
+      assert!(is_resumed_by(Some(first), Some(first)));
+      // The other set's buds going back in leaves these paused
+      assert!(!is_resumed_by(Some(first), Some(second)));
+      // Paused for the session lock, or restored after a restart
+      assert!(is_resumed_by(None, Some(second)));
+      // Resuming without a device, e.g. on unlock, covers everything
+      assert!(is_resumed_by(Some(first), None));
+      assert!(is_resumed_by(None, None));
+
+      let _globals = GLOBALS.lock().await;
+      configure(MediaConfig {
+         // vlc is still playing when spotify is resumed
+         resume_policy: ResumePolicy::Always,
+         resume_after_restart_sec: 0,
+         ..Default::default()
+      });
+      set_enabled(true);
+      *TRACKED.lock() = Tracked::default();
+      let spotify = format!("{MPRIS_PREFIX}spotify");
+      let vlc = format!("{MPRIS_PREFIX}vlc");
+      let players = FakePlayers::new(&[("spotify", Playing)]);
+
+      // The first set comes out and pauses spotify, then vlc starts and the
+      // second set comes out too
+      pause_with(&players, ActionReason::EarRemoval, Some(first))
+         .await
+         .unwrap();
+      let waiting = TRACKED.lock().generation(Some(first));
+      players.players.lock().push((vlc.clone(), Playing));
+      pause_with(&players, ActionReason::EarRemoval, Some(second))
+         .await
+         .unwrap();
+      assert_eq!(paused_players(), [spotify.clone(), vlc.clone()]);
+      // Pending waits for the first set aren't cancelled by the second
+      assert_eq!(TRACKED.lock().generation(Some(first)), waiting);
+
+      // The second set going back in only resumes vlc
+      play_with(&players, ActionReason::EarInsertion, Some(second))
+         .await
+         .unwrap();
+      assert_eq!(players.status(&vlc), Some(Playing));
+      assert_eq!(players.status(&spotify), Some(Paused));
+      assert_eq!(paused_players(), std::slice::from_ref(&spotify));
+
+      play_with(&players, ActionReason::EarInsertion, Some(first))
+         .await
+         .unwrap();
+      assert_eq!(players.status(&spotify), Some(Playing));
+      assert!(paused_players().is_empty());
+   }
+
+   #[tokio::test]
+   async fn test_only_playing_players_are_paused_and_resumed() {
+      use PlaybackStatus::{Paused, Playing, Stopped};
+
+      let _globals = GLOBALS.lock().await;
+      configure(MediaConfig {
+         mute_players: vec!["firefox".to_string()],
+         // Keep the state file out of the runtime directory
+         resume_after_restart_sec: 0,
+         ..Default::default()
+      });
+      set_enabled(true);
+      *TRACKED.lock() = Tracked::default();
+      let spotify = format!("{MPRIS_PREFIX}spotify");
+      let firefox = format!("{MPRIS_PREFIX}firefox");
+      let players = FakePlayers::new(&[
+         ("spotify", Playing),
+         ("vlc", Paused),
+         ("mpv", Stopped),
+         ("firefox", Playing),
+      ]);
+      players.volumes.lock().insert(firefox.clone(), 0.6);
+      let mut events = subscribe();
+
+      let outcome = pause_with(&players, ActionReason::EarRemoval, None)
+         .await
+         .unwrap();
+      assert_eq!((outcome.paused, outcome.skipped), (2, 2));
+      assert_eq!(paused_players(), std::slice::from_ref(&spotify));
+      assert_eq!(players.status(&spotify), Some(Paused));
+      assert_eq!(players.volumes.lock()[&firefox], 0.0);
+      // Clients only hear about the players that were paused or muted
+      assert_eq!(
+         events.try_recv(),
+         Ok(MediaEvent::AutoPaused(vec![
+            spotify.clone(),
+            firefox.clone()
+         ]))
+      );
+
+      play_with(&players, ActionReason::EarInsertion, None)
+         .await
+         .unwrap();
+      assert!(paused_players().is_empty());
+      assert_eq!(players.status(&spotify), Some(Playing));
+      // The player the user had paused stays paused
+      assert_eq!(players.status(&format!("{MPRIS_PREFIX}vlc")), Some(Paused));
+      assert_eq!(
+         *players.commands.lock(),
+         [("Pause", spotify.clone()), ("Play", spotify.clone())]
+      );
+      assert_eq!(players.volumes.lock()[&firefox], 0.6);
+      assert_eq!(
+         events.try_recv(),
+         Ok(MediaEvent::AutoResumed(vec![firefox.clone()]))
+      );
+      assert_eq!(
+         events.try_recv(),
+         Ok(MediaEvent::AutoResumed(vec![spotify.clone()]))
+      );
+
+      // Nothing left to resume the next time
+      play_with(&players, ActionReason::EarInsertion, None)
+         .await
+         .unwrap();
+      assert_eq!(players.commands.lock().len(), 2);
+      assert!(events.try_recv().is_err());
+   }
+
+   #[tokio::test]
+   async fn test_brief_playback_is_paused_but_not_resumed() {
+      let _globals = GLOBALS.lock().await;
+      configure(MediaConfig {
+         min_playing_ms: 60_000,
+         resume_order: ResumeOrder::MostRecentFirst,
+         resume_after_restart_sec: 0,
+         ..Default::default()
+      });
+      set_enabled(true);
+      *TRACKED.lock() = Tracked::default();
+      let spotify = format!("{MPRIS_PREFIX}spotify");
+      let vlc = format!("{MPRIS_PREFIX}vlc");
+      // Fake players own their own names, activity is looked up by them
+      PLAYBACK_ACTIVITY
+         .lock()
+         .observe(&vlc, "Playing", Instant::now());
+      let players = FakePlayers::new(&[
+         ("spotify", PlaybackStatus::Playing),
+         ("vlc", PlaybackStatus::Playing),
+      ]);
+
+      let outcome = pause_with(&players, ActionReason::EarRemoval, None)
+         .await
+         .unwrap();
+      // Both are paused, but vlc only just started so it isn't resumed
+      assert_eq!(outcome.paused, 2);
+      assert_eq!(paused_players(), std::slice::from_ref(&spotify));
+
+      *TRACKED.lock() = Tracked::default();
+      *PLAYBACK_ACTIVITY.lock() = PlaybackActivity::default();
+   }
+
+   #[tokio::test]
+   async fn test_failed_command_tries_each_other_player_once() {
+      use PlaybackStatus::{Paused, Playing};
+
+      let _globals = GLOBALS.lock().await;
+      configure(MediaConfig::default());
+      set_enabled(true);
+      let spotify = format!("{MPRIS_PREFIX}spotify");
+      let instance = format!("{MPRIS_PREFIX}spotify.instance_2");
+      let vlc = format!("{MPRIS_PREFIX}vlc");
+      let mut players = FakePlayers::new(&[
+         ("spotify.instance_2", Playing),
+         ("elisa", Paused),
+         ("vlc", Playing),
+         ("spotify", Playing),
+      ]);
+      // spotify was played last, so it is tried first
+      let played_spotify = || {
+         *PLAYBACK_ACTIVITY.lock() = PlaybackActivity::default();
+         PLAYBACK_ACTIVITY
+            .lock()
+            .active_at
+            .insert(spotify.clone(), Instant::now());
+      };
+      played_spotify();
+      players.broken = vec![spotify.clone(), instance.clone()];
+
+      send_to_playing(&players, ActionReason::Gesture, "Next").await;
+      assert_eq!(
+         *players.commands.lock(),
+         [
+            ("Next", spotify.clone()),
+            ("Next", instance.clone()),
+            ("Next", vlc.clone())
+         ]
+      );
+
+      // With every player failing, each is still tried just once
+      players.commands.lock().clear();
+      players.broken.push(vlc.clone());
+      // Commanding the others made them the most recently active
+      played_spotify();
+      send_to_playing(&players, ActionReason::Gesture, "Previous").await;
+      assert_eq!(
+         *players.commands.lock(),
+         [
+            ("Previous", spotify.clone()),
+            ("Previous", instance),
+            ("Previous", vlc)
+         ]
+      );
+      *PLAYBACK_ACTIVITY.lock() = PlaybackActivity::default();
+   }
+
+   #[tokio::test]
+   async fn test_stop_forgets_only_the_stopped_player() {
+      use PlaybackStatus::{Paused, Playing, Stopped};
+
+      let _globals = GLOBALS.lock().await;
+      configure(MediaConfig {
+         resume_after_restart_sec: 0,
+         ..Default::default()
+      });
+      set_enabled(true);
+      *TRACKED.lock() = Tracked::default();
+      *PLAYBACK_ACTIVITY.lock() = PlaybackActivity::default();
+      let spotify = format!("{MPRIS_PREFIX}spotify");
+      let vlc = format!("{MPRIS_PREFIX}vlc");
+      let players = FakePlayers::new(&[("vlc", Playing)]);
+      pause_with(&players, ActionReason::SessionLock, None)
+         .await
+         .unwrap();
+
+      // The playing player is stopped, what we paused waits to be resumed
+      players.players.lock().push((spotify.clone(), Playing));
+      stop_with(&players, ActionReason::Gesture).await;
+      assert_eq!(players.status(&spotify), Some(Stopped));
+      assert_eq!(players.status(&vlc), Some(Paused));
+      assert_eq!(paused_players(), std::slice::from_ref(&vlc));
+
+      // Stopping a player we paused forgets it, and all kept about it
+      players.players.lock().retain(|(name, _)| *name == vlc);
+      stop_with(&players, ActionReason::Gesture).await;
+      assert_eq!(players.status(&vlc), Some(Stopped));
+      assert!(paused_players().is_empty());
+      let tracked = TRACKED.lock();
+      assert!(tracked.paused_at.is_empty() && tracked.paused_from.is_empty());
+   }
+
+   #[tokio::test]
+   async fn test_gestures_take_paused_players_first() {
+      use PlaybackStatus::{Paused, Playing, Stopped};
+
+      let _globals = GLOBALS.lock().await;
+      configure(MediaConfig {
+         resume_after_restart_sec: 0,
+         ..Default::default()
+      });
+      set_enabled(true);
+      *TRACKED.lock() = Tracked::default();
+      *PLAYBACK_ACTIVITY.lock() = PlaybackActivity::default();
+      let spotify = format!("{MPRIS_PREFIX}spotify");
+      let vlc = format!("{MPRIS_PREFIX}vlc");
+      let players = FakePlayers::new(&[("spotify", Playing), ("vlc", Paused)]);
+      pause_with(&players, ActionReason::EarRemoval, None)
+         .await
+         .unwrap();
+
+      // What we paused skips ahead and plays again, and isn't ours anymore
+      players.commands.lock().clear();
+      next_and_resume_with(&players, ActionReason::Gesture, None).await;
+      assert_eq!(
+         *players.commands.lock(),
+         [("Next", spotify.clone()), ("Play", spotify.clone())]
+      );
+      assert_eq!(players.status(&spotify), Some(Playing));
+      assert!(paused_players().is_empty());
+
+      // With nothing paused, the playing players are stopped
+      players.commands.lock().clear();
+      stop_paused_with(&players, ActionReason::Gesture, None).await;
+      assert_eq!(*players.commands.lock(), [("Stop", spotify.clone())]);
+      assert_eq!(players.status(&spotify), Some(Stopped));
+      assert_eq!(players.status(&vlc), Some(Paused));
+   }
+
+   #[tokio::test]
+   async fn test_ducked_players_are_restored_on_play() {
+      use PlaybackStatus::{Paused, Playing};
+
+      let _globals = GLOBALS.lock().await;
+      configure(MediaConfig {
+         resume_after_restart_sec: 0,
+         ..Default::default()
+      });
+      set_enabled(true);
+      *TRACKED.lock() = Tracked::default();
+      let spotify = format!("{MPRIS_PREFIX}spotify");
+      let vlc = format!("{MPRIS_PREFIX}vlc");
+      let players = FakePlayers::new(&[("spotify", Playing), ("vlc", Paused)]);
+      players.volumes.lock().insert(spotify.clone(), 0.5);
+      players.volumes.lock().insert(vlc.clone(), 0.8);
+
+      // Only playing players are ducked, and only once
+      duck_with(&players, ActionReason::Manual).await;
+      duck_with(&players, ActionReason::Manual).await;
+      assert_eq!(players.volumes.lock()[&spotify], 0.5 * DUCK_FACTOR);
+      assert_eq!(players.volumes.lock()[&vlc], 0.8);
+
+      play_with(&players, ActionReason::Manual, None)
+         .await
+         .unwrap();
+      assert_eq!(players.volumes.lock()[&spotify], 0.5);
+      assert!(TRACKED.lock().ducked.is_empty());
+      assert!(players.commands.lock().is_empty());
+   }
+
+   #[tokio::test]
+   async fn test_resume_all_resumes_every_paused_player() {
+      use PlaybackStatus::{Paused, Playing, Stopped};
+
+      let _globals = GLOBALS.lock().await;
+      configure(MediaConfig {
+         mute_players: vec!["firefox".to_string()],
+         resume_after_restart_sec: 0,
+         ..Default::default()
+      });
+      set_enabled(true);
+      *TRACKED.lock() = Tracked::default();
+      *PLAYBACK_ACTIVITY.lock() = PlaybackActivity::default();
+      let firefox = format!("{MPRIS_PREFIX}firefox");
+      let spotify = format!("{MPRIS_PREFIX}spotify");
+      let vlc = format!("{MPRIS_PREFIX}vlc");
+      let players = FakePlayers::new(&[
+         ("firefox", Playing),
+         ("spotify", Playing),
+         ("vlc", Paused),
+         ("mpv", Stopped),
+      ]);
+      players.volumes.lock().insert(firefox.clone(), 0.6);
+      pause_with(&players, ActionReason::EarRemoval, None)
+         .await
+         .unwrap();
+
+      // The user paused vlc, it is resumed all the same
+      players.commands.lock().clear();
+      assert_eq!(
+         resume_all_with(&players).await,
+         [spotify.clone(), vlc.clone()]
+      );
+      assert_eq!(
+         *players.commands.lock(),
+         [("Play", spotify.clone()), ("Play", vlc.clone())]
+      );
+      assert_eq!(players.volumes.lock()[&firefox], 0.6);
+      assert_eq!(players.status(&format!("{MPRIS_PREFIX}mpv")), Some(Stopped));
+      assert!(paused_players().is_empty());
+      assert!(TRACKED.lock().muted.is_empty());
+   }
+
+   #[tokio::test]
+   async fn test_resume_is_retried_once_if_ignored() {
+      use PlaybackStatus::{Paused, Playing};
+
+      let _globals = GLOBALS.lock().await;
+      *TRACKED.lock() = Tracked::default();
+      let vlc = format!("{MPRIS_PREFIX}vlc");
+      let players = FakePlayers::new(&[("vlc", Paused)]);
+
+      // Paused again meanwhile, the resume is stale
+      TRACKED.lock().paused_again(None);
+      verify_resumed(
+         &players,
+         &vlc,
+         ActionReason::Manual,
+         Duration::ZERO,
+         None,
+         0,
+      )
+      .await;
+      assert!(players.commands.lock().is_empty());
+
+      let generation = TRACKED.lock().generation(None);
+      verify_resumed(
+         &players,
+         &vlc,
+         ActionReason::Manual,
+         Duration::ZERO,
+         None,
+         generation,
+      )
+      .await;
+      assert_eq!(*players.commands.lock(), [("Play", vlc.clone())]);
+      assert_eq!(players.status(&vlc), Some(Playing));
+
+      // A player that kept playing is left alone
+      verify_resumed(
+         &players,
+         &vlc,
+         ActionReason::Manual,
+         Duration::ZERO,
+         None,
+         generation,
+      )
+      .await;
+      assert_eq!(players.commands.lock().len(), 1);
+   }
+}
@@ -0,0 +1,208 @@
+//! Noise control modes picked by location profile.
+//!
+//! A profile names a place, such as `home` or `office`, and the noise control
+//! mode the `AirPods` switch to when they connect there. The current profile
+//! is the one set with `SetNoiseProfile`, or else the one bound to an active
+//! NetworkManager connection, or else the configured default. Nothing is
+//! applied unless profiles are configured:
+//!
+//! ```toml
+//! [noise_profiles]
+//! default = "home"
+//!
+//! [noise_profiles.modes]
+//! home = "transparency"
+//! office = "anc"
+//!
+//! [noise_profiles.networks]
+//! "Office Wi-Fi" = "office"
+//! ```
+
+use std::collections::BTreeMap;
+
+use log::{debug, info, warn};
+use parking_lot::{Mutex, RwLock};
+use serde_json::json;
+use zbus::{Connection, Proxy, zvariant::OwnedObjectPath};
+
+use crate::{
+   airpods::{device::AirPods, model::AirPodsModel, protocol::NoiseControlMode},
+   config::NoiseProfilesConfig,
+   error::{AirPodsError, Result},
+};
+
+/// Profiles with their modes parsed, installed at startup
+struct Profiles {
+   modes: BTreeMap<String, NoiseControlMode>,
+   networks: BTreeMap<String, String>,
+   default: Option<String>,
+}
+
+impl Profiles {
+   /// Picks the profile, preferring the one set by hand over the networks
+   /// over the default.
+   fn pick<'a>(&'a self, selected: Option<&'a str>, connections: &[String]) -> Option<&'a str> {
+      selected
+         .or_else(|| {
+            connections
+               .iter()
+               .find_map(|connection| self.networks.get(connection))
+               .map(String::as_str)
+         })
+         .or(self.default.as_deref())
+   }
+}
+
+static PROFILES: RwLock<Profiles> = RwLock::new(Profiles {
+   modes: BTreeMap::new(),
+   networks: BTreeMap::new(),
+   default: None,
+});
+
+/// Profile set with `SetNoiseProfile`, until cleared
+static SELECTED: Mutex<Option<String>> = Mutex::new(None);
+
+/// Installs the configured profiles, skipping those with invalid modes.
+pub fn configure(config: &NoiseProfilesConfig) {
+   let mut modes = BTreeMap::new();
+   for (profile, mode) in &config.modes {
+      match mode.parse() {
+         Ok(mode) => {
+            modes.insert(profile.clone(), mode);
+         },
+         Err(_) => warn!("Ignoring noise profile {profile:?} with invalid mode {mode:?}"),
+      }
+   }
+   for (network, profile) in &config.networks {
+      if !modes.contains_key(profile) {
+         warn!("Network {network:?} is bound to unknown noise profile {profile:?}");
+      }
+   }
+   *PROFILES.write() = Profiles {
+      modes,
+      networks: config.networks.clone(),
+      default: config.default.clone(),
+   };
+}
+
+/// Sets the current profile, or goes back to picking it by network if `None`.
+pub fn select(profile: Option<String>) -> Result<()> {
+   if let Some(profile) = &profile
+      && !PROFILES.read().modes.contains_key(profile)
+   {
+      return Err(AirPodsError::NoiseProfileNotFound(profile.clone()));
+   }
+   match &profile {
+      Some(profile) => info!("Selected noise profile {profile:?}"),
+      None => info!("Cleared the selected noise profile"),
+   }
+   *SELECTED.lock() = profile;
+   Ok(())
+}
+
+/// Returns the current profile and its mode, if any.
+pub async fn current() -> Option<(String, NoiseControlMode)> {
+   if PROFILES.read().modes.is_empty() {
+      return None;
+   }
+   let connections = active_connections().await.unwrap_or_else(|e| {
+      debug!("Failed to list network connections: {e}");
+      Vec::new()
+   });
+   let selected = SELECTED.lock().clone();
+   let profiles = PROFILES.read();
+   let profile = profiles.pick(selected.as_deref(), &connections)?;
+   let mode = *profiles.modes.get(profile)?;
+   Some((profile.to_string(), mode))
+}
+
+/// Describes the profiles and which one is current.
+pub async fn to_json() -> serde_json::Value {
+   let current = current().await;
+   let profiles = PROFILES.read();
+   let modes: BTreeMap<_, _> = profiles
+      .modes
+      .iter()
+      .map(|(profile, mode)| (profile, mode.to_str()))
+      .collect();
+   json!({
+      "current": current.map(|(profile, _)| profile),
+      "selected": *SELECTED.lock(),
+      "modes": modes,
+   })
+}
+
+/// Switches a device to the mode of the current profile.
+pub async fn apply(device: &AirPods) {
+   let Some((profile, mode)) = current().await else {
+      return;
+   };
+   let model = device.model();
+   if model != AirPodsModel::Unknown && !model.noise_modes().contains(&mode) {
+      debug!(
+         "{}: {model} has no {mode} mode, ignoring noise profile {profile:?}",
+         device.address()
+      );
+      return;
+   }
+   if device.noise_mode() == Some(mode) {
+      return;
+   }
+   match device.set_noise_control(mode).await {
+      Ok(()) => info!(
+         "{}: Switched noise control to {mode} for profile {profile:?}",
+         device.address()
+      ),
+      Err(e) => warn!(
+         "{}: Failed to switch noise control to {mode}: {e}",
+         device.address()
+      ),
+   }
+}
+
+/// Lists the names of the active NetworkManager connections.
+async fn active_connections() -> zbus::Result<Vec<String>> {
+   let connection = Connection::system().await?;
+   let manager = Proxy::new(
+      &connection,
+      "org.freedesktop.NetworkManager",
+      "/org/freedesktop/NetworkManager",
+      "org.freedesktop.NetworkManager",
+   )
+   .await?;
+   let paths: Vec<OwnedObjectPath> = manager.get_property("ActiveConnections").await?;
+   let mut names = Vec::with_capacity(paths.len());
+   for path in paths {
+      let active = Proxy::new(
+         &connection,
+         "org.freedesktop.NetworkManager",
+         path,
+         "org.freedesktop.NetworkManager.Connection.Active",
+      )
+      .await?;
+      names.push(active.get_property("Id").await?);
+   }
+   Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   #[test]
+   fn test_profile_precedence() {
+      let profiles = Profiles {
+         modes: BTreeMap::from([
+            ("home".to_string(), NoiseControlMode::Transparency),
+            ("office".to_string(), NoiseControlMode::Active),
+         ]),
+         networks: BTreeMap::from([("Office Wi-Fi".to_string(), "office".to_string())]),
+         default: Some("home".to_string()),
+      };
+      let office = ["Wired".to_string(), "Office Wi-Fi".to_string()];
+      assert_eq!(profiles.pick(None, &office), Some("office"));
+      assert_eq!(profiles.pick(None, &["Cafe".to_string()]), Some("home"));
+      // A profile set by hand wins over the network
+      assert_eq!(profiles.pick(Some("home"), &office), Some("home"));
+   }
+}
@@ -0,0 +1,469 @@
+//! Desktop notifications through `org.freedesktop.Notifications`.
+//!
+//! Besides the notifications other modules show directly, [`Notifier`]
+//! shows configurable ones for device events, and can read some of them out
+//! through a text-to-speech command. Their texts are [`summary`] templates,
+//! checked when the configuration is loaded.
+
+use std::{
+   collections::{HashMap, HashSet},
+   process::Stdio,
+};
+
+use bluer::Address;
+use log::{debug, warn};
+use parking_lot::Mutex;
+use tokio::process::Command;
+use zbus::{Connection, zvariant};
+
+use crate::{
+   airpods::{
+      device::AirPods,
+      protocol::{BatteryInfo, BatteryState, NoiseControlMode},
+      summary,
+   },
+   battery_provider,
+   config::{NotificationTemplate, NotificationsConfig, SpeechConfig},
+   event::AirPodsEvent,
+   quiet_hours,
+};
+
+/// How long notifications stay up, in milliseconds
+const EXPIRE_TIMEOUT_MS: i32 = 5000;
+/// Icon used unless another is given
+const DEFAULT_ICON: &str = "audio-headphones";
+/// Placeholders notification templates have on top of the summary ones
+const EXTRA_PLACEHOLDERS: &[&str] = &["level"];
+
+/// Shows a notification on the session bus.
+pub async fn show(summary: &str, body: &str) -> zbus::Result<()> {
+   show_with_icon(DEFAULT_ICON, summary, body).await
+}
+
+/// Shows a notification with an icon from the desktop icon theme, unless it
+/// is the quiet hours.
+pub async fn show_with_icon(icon: &str, summary: &str, body: &str) -> zbus::Result<()> {
+   if quiet_hours::is_quiet() {
+      debug!("Quiet hours, not showing {summary:?}");
+      return Ok(());
+   }
+   let connection = Connection::session().await?;
+   let hints: HashMap<&str, zvariant::Value<'_>> = HashMap::new();
+   connection
+      .call_method(
+         Some("org.freedesktop.Notifications"),
+         "/org/freedesktop/Notifications",
+         Some("org.freedesktop.Notifications"),
+         "Notify",
+         &(
+            "kAirPods",
+            0u32,
+            icon,
+            summary,
+            body,
+            Vec::<&str>::new(),
+            hints,
+            EXPIRE_TIMEOUT_MS,
+         ),
+      )
+      .await?;
+   Ok(())
+}
+
+/// Formats the available battery levels for a notification body.
+pub fn battery_summary(battery: &BatteryInfo) -> String {
+   let format = |label: &str, state: BatteryState| {
+      state.is_available().then(|| {
+         let charging = if state.is_charging() {
+            " (charging)"
+         } else {
+            ""
+         };
+         format!("{label} {}%{charging}", state.level)
+      })
+   };
+   [
+      format("Left", battery.left),
+      format("Right", battery.right),
+      format("Case", battery.case),
+      format("Battery", battery.headphone),
+   ]
+   .into_iter()
+   .flatten()
+   .collect::<Vec<_>>()
+   .join(" · ")
+}
+
+/// Device events with a configurable notification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+   Connected,
+   Disconnected,
+   LowBattery,
+   NoiseMode,
+   SingleBud,
+}
+
+impl Kind {
+   const ALL: [Self; 5] = [
+      Self::Connected,
+      Self::Disconnected,
+      Self::LowBattery,
+      Self::NoiseMode,
+      Self::SingleBud,
+   ];
+
+   /// Name of the event in the configuration.
+   const fn name(self) -> &'static str {
+      match self {
+         Self::Connected => "connected",
+         Self::Disconnected => "disconnected",
+         Self::LowBattery => "low_battery",
+         Self::NoiseMode => "noise_mode",
+         Self::SingleBud => "single_bud",
+      }
+   }
+
+   /// Default summary, body and icon.
+   const fn defaults(self) -> (&'static str, &'static str, &'static str) {
+      match self {
+         Self::Connected => (
+            "{name} connected",
+            "Left {left}% · Right {right}% · Case {case}% · Battery {battery}%",
+            DEFAULT_ICON,
+         ),
+         Self::Disconnected => ("{name} disconnected", "", DEFAULT_ICON),
+         Self::LowBattery => (
+            "{name} battery low",
+            "{level}% remaining",
+            "battery-caution",
+         ),
+         Self::NoiseMode => ("{name}", "{noise_mode}", DEFAULT_ICON),
+         Self::SingleBud => ("{name}", "Playing in mono on one bud", DEFAULT_ICON),
+      }
+   }
+
+   /// Default announcement, for the events that can be read out.
+   const fn spoken(self) -> Option<&'static str> {
+      match self {
+         Self::Connected => Some(
+            "{name} connected · left {left} percent · right {right} percent · {battery} percent",
+         ),
+         Self::LowBattery => Some("{name} battery low · {level} percent"),
+         Self::Disconnected | Self::NoiseMode | Self::SingleBud => None,
+      }
+   }
+
+   fn config(self, config: &NotificationsConfig) -> &NotificationTemplate {
+      match self {
+         Self::Connected => &config.connected,
+         Self::Disconnected => &config.disconnected,
+         Self::LowBattery => &config.low_battery,
+         Self::NoiseMode => &config.noise_mode,
+         Self::SingleBud => &config.single_bud,
+      }
+   }
+}
+
+/// A notification's templates, checked and with defaults filled in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Template {
+   summary: String,
+   body: String,
+   icon: String,
+}
+
+impl Template {
+   /// Builds the template of an enabled notification, replacing invalid
+   /// texts with the defaults.
+   fn new(kind: Kind, config: &NotificationTemplate) -> Option<Self> {
+      if !config.enabled {
+         return None;
+      }
+      let (summary, body, icon) = kind.defaults();
+      let text = |field: &str, value: &Option<String>, default: &str| {
+         let setting = format!("notifications.{}.{field}", kind.name());
+         checked_template(&setting, value.as_deref(), default)
+      };
+      Some(Self {
+         summary: text("summary", &config.summary, summary),
+         body: text("body", &config.body, body),
+         icon: config.icon.clone().unwrap_or_else(|| icon.to_string()),
+      })
+   }
+}
+
+/// Returns a configured template, or `default` if it is unset or invalid.
+fn checked_template(setting: &str, template: Option<&str>, default: &str) -> String {
+   match template {
+      Some(template) => match summary::validate(template, EXTRA_PLACEHOLDERS) {
+         Ok(()) => template.to_string(),
+         Err(e) => {
+            warn!("Invalid {setting} {template:?}: {e}, using the default");
+            default.to_string()
+         },
+      },
+      None => default.to_string(),
+   }
+}
+
+/// Announcements read out through a text-to-speech command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Speech {
+   command: Vec<String>,
+   /// Texts of the announced events, by [`Kind`]
+   texts: [Option<String>; 5],
+}
+
+impl Speech {
+   /// Builds the announcements, `None` if no command is configured.
+   fn new(config: &SpeechConfig) -> Option<Self> {
+      if config.command.is_empty() {
+         return None;
+      }
+      let configured = |kind| match kind {
+         Kind::Connected => config.connected.as_deref(),
+         Kind::LowBattery => config.low_battery.as_deref(),
+         Kind::Disconnected | Kind::NoiseMode | Kind::SingleBud => None,
+      };
+      let texts = Kind::ALL.map(|kind| {
+         let default = kind.spoken()?;
+         match configured(kind) {
+            Some("") => None,
+            template => {
+               let setting = format!("notifications.speech.{}", kind.name());
+               Some(checked_template(&setting, template, default))
+            },
+         }
+      });
+      Some(Self {
+         command: config.command.clone(),
+         texts,
+      })
+   }
+
+   /// Runs the command on a text, without waiting for it to finish, unless
+   /// it is the quiet hours.
+   fn say(&self, text: &str) {
+      let Some((program, args)) = self.command.split_first() else {
+         return;
+      };
+      if quiet_hours::is_quiet() {
+         debug!("Quiet hours, not reading out {text:?}");
+         return;
+      }
+      let child = Command::new(program)
+         .args(args)
+         .arg(text)
+         .stdin(Stdio::null())
+         .stdout(Stdio::null())
+         .stderr(Stdio::null())
+         .spawn();
+      match child {
+         // Reap it in the background
+         Ok(mut child) => {
+            tokio::spawn(async move {
+               let _ = child.wait().await;
+            });
+         },
+         Err(e) => warn!("Failed to run {program}: {e}"),
+      }
+   }
+}
+
+/// Shows the configured notifications for device events.
+pub struct Notifier {
+   /// Templates of the enabled notifications, by [`Kind`]
+   templates: [Option<Template>; 5],
+   speech: Option<Speech>,
+   low_battery_level: u8,
+   /// Devices whose low battery was already shown this discharge
+   low_battery_shown: Mutex<HashSet<Address>>,
+   /// Last known noise control mode, so the first report isn't shown as a change
+   noise_modes: Mutex<HashMap<Address, NoiseControlMode>>,
+   /// Devices with only one bud in an ear
+   single_bud: Mutex<HashSet<Address>>,
+}
+
+impl Notifier {
+   pub fn new(config: &NotificationsConfig) -> Self {
+      Self {
+         templates: Kind::ALL.map(|kind| Template::new(kind, kind.config(config))),
+         speech: Speech::new(&config.speech),
+         low_battery_level: config.low_battery_level,
+         low_battery_shown: Mutex::default(),
+         noise_modes: Mutex::default(),
+         single_bud: Mutex::default(),
+      }
+   }
+
+   /// Shows the notification for an event and reads out its announcement,
+   /// if it has them and they are enabled.
+   pub fn handle(&self, device: &AirPods, event: &AirPodsEvent) {
+      let kind = match event {
+         AirPodsEvent::DeviceConnected => Kind::Connected,
+         AirPodsEvent::DeviceDisconnected => {
+            self.low_battery_shown.lock().remove(&device.address());
+            self.noise_modes.lock().remove(&device.address());
+            self.single_bud.lock().remove(&device.address());
+            Kind::Disconnected
+         },
+         AirPodsEvent::BatteryUpdated(battery)
+            if self.reached_low_battery(device.address(), battery) =>
+         {
+            Kind::LowBattery
+         },
+         AirPodsEvent::NoiseControlChanged(mode)
+         | AirPodsEvent::QueuedNoiseControlApplied(mode) => {
+            match self.noise_modes.lock().insert(device.address(), *mode) {
+               Some(previous) if previous != *mode => Kind::NoiseMode,
+               _ => return,
+            }
+         },
+         AirPodsEvent::EarDetectionChanged(status) => {
+            let mut single_bud = self.single_bud.lock();
+            if !status.is_single_bud() {
+               single_bud.remove(&device.address());
+               return;
+            }
+            if !single_bud.insert(device.address()) {
+               return;
+            }
+            Kind::SingleBud
+         },
+         _ => return,
+      };
+      let template = &self.templates[kind as usize];
+      let spoken = self
+         .speech
+         .as_ref()
+         .and_then(|speech| Some((speech, speech.texts[kind as usize].as_ref()?)));
+      if template.is_none() && spoken.is_none() {
+         return;
+      }
+
+      let level = device
+         .battery_info()
+         .and_then(|battery| battery_provider::combined_level(&battery));
+      let lookup = |field: &str| match field {
+         "level" => level.map(|level| level.to_string()),
+         _ => device.summary_field(field),
+      };
+      if let Some((speech, text)) = spoken {
+         // Segments become separate phrases
+         speech.say(&summary::render(text, lookup).replace(summary::SEPARATOR, ", "));
+      }
+      let Some(template) = template else {
+         return;
+      };
+      let summary = summary::render(&template.summary, lookup);
+      let body = summary::render(&template.body, lookup);
+      let icon = template.icon.clone();
+      tokio::spawn(async move {
+         if let Err(e) = show_with_icon(&icon, &summary, &body).await {
+            warn!("Failed to show notification: {e}");
+         }
+      });
+   }
+
+   /// Whether the battery just became low, which is reported once until it
+   /// charges or rises above the level again.
+   fn reached_low_battery(&self, address: Address, battery: &BatteryInfo) -> bool {
+      let charging = [battery.left, battery.right, battery.headphone]
+         .iter()
+         .any(|state| state.is_available() && state.is_charging());
+      let mut shown = self.low_battery_shown.lock();
+      match battery_provider::combined_level(battery) {
+         Some(level) if level <= self.low_battery_level && !charging => shown.insert(address),
+         Some(_) => {
+            shown.remove(&address);
+            false
+         },
+         None => false,
+      }
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::airpods::protocol::BatteryStatus;
+
+   #[test]
+   fn test_battery_summary() {
+      let state = |level, status| BatteryState { level, status };
+      let battery = BatteryInfo {
+         left: state(80, BatteryStatus::Discharging),
+         right: state(75, BatteryStatus::Discharging),
+         case: state(40, BatteryStatus::Charging),
+         headphone: BatteryState::new(),
+      };
+      assert_eq!(
+         battery_summary(&battery),
+         "Left 80% · Right 75% · Case 40% (charging)"
+      );
+   }
+
+   #[test]
+   fn test_invalid_templates_fall_back_to_defaults() {
+      let config = NotificationTemplate {
+         enabled: true,
+         summary: Some("{name} ist verbunden".to_string()),
+         body: Some("{left}% {bogus}".to_string()),
+         icon: None,
+      };
+      assert_eq!(
+         Template::new(Kind::Connected, &config),
+         Some(Template {
+            summary: "{name} ist verbunden".to_string(),
+            body: Kind::Connected.defaults().1.to_string(),
+            icon: DEFAULT_ICON.to_string(),
+         })
+      );
+      assert_eq!(
+         Template::new(Kind::Connected, &NotificationTemplate::default()),
+         None
+      );
+   }
+
+   #[test]
+   fn test_speech_needs_a_command() {
+      let mut config = SpeechConfig {
+         command: Vec::new(),
+         connected: Some("{name} ist verbunden".to_string()),
+         low_battery: Some(String::new()),
+      };
+      assert_eq!(Speech::new(&config), None);
+
+      config.command = vec!["spd-say".to_string()];
+      let speech = Speech::new(&config).unwrap();
+      assert_eq!(
+         speech.texts[Kind::Connected as usize].as_deref(),
+         Some("{name} ist verbunden")
+      );
+      // An empty text turns the announcement off
+      assert_eq!(speech.texts[Kind::LowBattery as usize], None);
+      assert_eq!(speech.texts[Kind::Disconnected as usize], None);
+   }
+
+   #[test]
+   fn test_low_battery_is_shown_once_per_discharge() {
+      let notifier = Notifier::new(&NotificationsConfig::default());
+      let address = Address::any();
+      let battery = |level, status| BatteryInfo {
+         left: BatteryState { level, status },
+         right: BatteryState {
+            level: 90,
+            status: BatteryStatus::Discharging,
+         },
+         case: BatteryState::new(),
+         headphone: BatteryState::new(),
+      };
+      assert!(!notifier.reached_low_battery(address, &battery(30, BatteryStatus::Discharging)));
+      assert!(notifier.reached_low_battery(address, &battery(20, BatteryStatus::Discharging)));
+      assert!(!notifier.reached_low_battery(address, &battery(15, BatteryStatus::Discharging)));
+      // Charging re-arms the warning
+      assert!(!notifier.reached_low_battery(address, &battery(15, BatteryStatus::Charging)));
+      assert!(notifier.reached_low_battery(address, &battery(14, BatteryStatus::Discharging)));
+   }
+}
@@ -0,0 +1,91 @@
+//! Marker file telling other tools which players kAirPods paused.
+//!
+//! While auto-paused players are waiting to be resumed, their MPRIS bus
+//! names are listed one per line in `$XDG_RUNTIME_DIR/kairpods-paused`:
+//!
+//! ```text
+//! org.mpris.MediaPlayer2.spotify
+//! org.mpris.MediaPlayer2.firefox.instance_1_42
+//! ```
+//!
+//! A playerctl script can check that a pause was automatic before acting
+//! on it. To take a player over, so that kAirPods won't resume it, remove
+//! its line or the whole file; the file is read again right before
+//! resuming. The file is removed once nothing is waiting to be resumed.
+
+use std::{fs, io, path::PathBuf};
+
+use log::debug;
+
+/// File name of the marker in the runtime directory
+const FILE_NAME: &str = "kairpods-paused";
+
+fn path() -> Option<PathBuf> {
+   dirs::runtime_dir().map(|dir| dir.join(FILE_NAME))
+}
+
+/// Lists `players` in the marker, or removes it if there are none.
+pub fn write(players: &[String]) {
+   let Some(path) = path() else {
+      return;
+   };
+   let result = if players.is_empty() {
+      match fs::remove_file(&path) {
+         Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+         result => result,
+      }
+   } else {
+      // Replace the file at once, so readers never see a partial list
+      let temp = path.with_extension("tmp");
+      fs::write(&temp, format(players)).and_then(|()| fs::rename(&temp, &path))
+   };
+   if let Err(e) = result {
+      debug!("Failed to update pause marker {}: {e}", path.display());
+   }
+}
+
+/// Reads the players listed in the marker, `None` if it can't be read.
+///
+/// A missing marker lists no players.
+pub fn read() -> Option<Vec<String>> {
+   match fs::read_to_string(path()?) {
+      Ok(contents) => Some(parse(&contents)),
+      Err(e) if e.kind() == io::ErrorKind::NotFound => Some(Vec::new()),
+      Err(e) => {
+         debug!("Failed to read pause marker: {e}");
+         None
+      },
+   }
+}
+
+fn format(players: &[String]) -> String {
+   players.iter().map(|player| format!("{player}\n")).collect()
+}
+
+fn parse(contents: &str) -> Vec<String> {
+   contents
+      .lines()
+      .map(str::trim)
+      .filter(|line| !line.is_empty())
+      .map(str::to_string)
+      .collect()
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   #[test]
+   fn test_marker_round_trip() {
+      let players = vec![
+         "org.mpris.MediaPlayer2.spotify".to_string(),
+         "system:org.mpris.MediaPlayer2.mpd".to_string(),
+      ];
+      assert_eq!(parse(&format(&players)), players);
+      // Edits by other tools may leave stray whitespace behind
+      assert_eq!(
+         parse("\n  org.mpris.MediaPlayer2.spotify \n\n"),
+         ["org.mpris.MediaPlayer2.spotify"]
+      );
+   }
+}
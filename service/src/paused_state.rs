@@ -0,0 +1,141 @@
+//! Players waiting to be resumed, kept across restarts.
+//!
+//! Whenever the players we paused change, they are written with when each
+//! was paused to `$XDG_RUNTIME_DIR/kairpods-resume.json`:
+//!
+//! ```json
+//! [{"player":"org.mpris.MediaPlayer2.spotify","paused_at":1735732800000}]
+//! ```
+//!
+//! The daemon reads the file back on startup, so putting the buds back in
+//! after a restart still resumes what was paused before it. Entries older
+//! than `resume_after_restart_sec` are dropped, so a file left over from
+//! long ago doesn't resume anything.
+
+use std::{fs, io, path::PathBuf};
+
+use log::debug;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+/// File name of the state in the runtime directory
+const FILE_NAME: &str = "kairpods-resume.json";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Entry {
+   player: String,
+   /// Unix timestamp in milliseconds
+   paused_at: u64,
+}
+
+/// Entries last written, to keep when each player was paused across updates
+static ENTRIES: Mutex<Vec<Entry>> = Mutex::new(Vec::new());
+
+fn path() -> Option<PathBuf> {
+   dirs::runtime_dir().map(|dir| dir.join(FILE_NAME))
+}
+
+/// Records `players` as waiting to be resumed, or removes the file if there are none.
+pub fn save(players: &[String], now: u64) {
+   let entries = {
+      let mut entries = ENTRIES.lock();
+      *entries = merge(&entries, players, now);
+      entries.clone()
+   };
+   let Some(path) = path() else {
+      return;
+   };
+   let result = if entries.is_empty() {
+      match fs::remove_file(&path) {
+         Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+         result => result,
+      }
+   } else {
+      // Replace the file at once, so a crash never leaves half of it behind
+      let temp = path.with_extension("tmp");
+      let contents = serde_json::to_string(&entries).unwrap_or_default();
+      fs::write(&temp, contents).and_then(|()| fs::rename(&temp, &path))
+   };
+   if let Err(e) = result {
+      debug!("Failed to update paused players in {}: {e}", path.display());
+   }
+}
+
+/// Reads back the players waiting to be resumed with when they were paused,
+/// dropping those paused more than `max_age_ms` before `now`.
+pub fn load(max_age_ms: u64, now: u64) -> Vec<(String, u64)> {
+   let Some(path) = path() else {
+      return Vec::new();
+   };
+   let entries = match fs::read_to_string(&path) {
+      Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|e| {
+         debug!(
+            "Ignoring unreadable paused players in {}: {e}",
+            path.display()
+         );
+         Vec::new()
+      }),
+      Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+      Err(e) => {
+         debug!("Failed to read paused players from {}: {e}", path.display());
+         Vec::new()
+      },
+   };
+   let entries = fresh(entries, max_age_ms, now);
+   let players = entries
+      .iter()
+      .map(|entry| (entry.player.clone(), entry.paused_at))
+      .collect();
+   *ENTRIES.lock() = entries;
+   players
+}
+
+/// Lists `players` with when they were paused, keeping recorded times.
+fn merge(recorded: &[Entry], players: &[String], now: u64) -> Vec<Entry> {
+   players
+      .iter()
+      .map(|player| {
+         recorded
+            .iter()
+            .find(|entry| entry.player == *player)
+            .cloned()
+            .unwrap_or_else(|| Entry {
+               player: player.clone(),
+               paused_at: now,
+            })
+      })
+      .collect()
+}
+
+/// Drops entries paused more than `max_age_ms` before `now`.
+fn fresh(entries: Vec<Entry>, max_age_ms: u64, now: u64) -> Vec<Entry> {
+   entries
+      .into_iter()
+      .filter(|entry| now.saturating_sub(entry.paused_at) <= max_age_ms)
+      .collect()
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   #[test]
+   fn test_pause_times_are_kept_and_stale_entries_dropped() {
+      let spotify = "org.mpris.MediaPlayer2.spotify".to_string();
+      let vlc = "org.mpris.MediaPlayer2.vlc".to_string();
+      let recorded = merge(&[], std::slice::from_ref(&spotify), 1_000);
+
+      // A player paused later gets its own time, the first keeps its own
+      let entries = merge(&recorded, &[spotify.clone(), vlc.clone()], 5_000);
+      assert_eq!(entries[0].paused_at, 1_000);
+      assert_eq!(entries[1].paused_at, 5_000);
+
+      let json = serde_json::to_string(&entries).unwrap();
+      let entries: Vec<Entry> = serde_json::from_str(&json).unwrap();
+      let players: Vec<_> = fresh(entries, 3_000, 6_000)
+         .into_iter()
+         .map(|entry| entry.player)
+         .collect();
+      assert_eq!(players, [vlc]);
+   }
+}
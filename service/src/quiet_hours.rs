@@ -0,0 +1,111 @@
+//! Quiet hours, in which the daemon keeps quiet.
+//!
+//! Between two configured times of day, in the system timezone, nothing is
+//! announced. Clients can follow the `QuietHoursActive` D-Bus property to
+//! hold back their own notifications as well.
+
+use jiff::{Zoned, civil::Time};
+use log::{info, warn};
+use parking_lot::Mutex;
+
+use crate::config::QuietHoursConfig;
+
+/// Quiet hours, installed at startup
+static QUIET_HOURS: Mutex<Option<QuietHours>> = Mutex::new(None);
+
+/// Hours of the day in which nothing is announced, see [`QuietHoursConfig`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct QuietHours {
+   start: Time,
+   end: Time,
+}
+
+impl QuietHours {
+   /// Reads the quiet hours, `None` unless both times are set and valid.
+   fn new(config: &QuietHoursConfig) -> Option<Self> {
+      let time = |setting: &str, text: &str| match text.parse::<Time>() {
+         Ok(time) => Some(time),
+         Err(e) => {
+            warn!("Invalid quiet_hours.{setting} {text:?}: {e}, quiet hours are off");
+            None
+         },
+      };
+      match (config.start.as_deref(), config.end.as_deref()) {
+         (Some(start), Some(end)) => Some(Self {
+            start: time("start", start)?,
+            end: time("end", end)?,
+         }),
+         (None, None) => None,
+         (start, _) => {
+            let missing = if start.is_some() { "end" } else { "start" };
+            warn!("quiet_hours.{missing} is not set, quiet hours are off");
+            None
+         },
+      }
+   }
+
+   /// Whether a time of day is within the quiet hours.
+   ///
+   /// A start after the end spans midnight; equal ones make no quiet hours.
+   fn contains(self, time: Time) -> bool {
+      if self.start <= self.end {
+         self.start <= time && time < self.end
+      } else {
+         time >= self.start || time < self.end
+      }
+   }
+}
+
+/// Installs the quiet hours, logging invalid or half-set times.
+pub fn configure(config: &QuietHoursConfig) {
+   let quiet_hours = QuietHours::new(config);
+   if let Some(QuietHours { start, end }) = quiet_hours {
+      info!("Quiet hours from {start} to {end}");
+   }
+   *QUIET_HOURS.lock() = quiet_hours;
+}
+
+/// Returns whether it is the quiet hours now.
+pub fn is_quiet() -> bool {
+   QUIET_HOURS
+      .lock()
+      .is_some_and(|quiet| quiet.contains(Zoned::now().time()))
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   fn quiet_hours(start: Option<&str>, end: Option<&str>) -> Option<QuietHours> {
+      QuietHours::new(&QuietHoursConfig {
+         start: start.map(str::to_string),
+         end: end.map(str::to_string),
+      })
+   }
+
+   #[test]
+   fn test_quiet_hours() {
+      let time = |text: &str| text.parse::<Time>().unwrap();
+      let night = quiet_hours(Some("22:00"), Some("07:30")).unwrap();
+      assert!(night.contains(time("23:15")));
+      assert!(night.contains(time("00:00")));
+      assert!(night.contains(time("07:29")));
+      assert!(!night.contains(time("07:30")));
+      assert!(!night.contains(time("12:00")));
+
+      let lunch = quiet_hours(Some("12:00"), Some("13:00")).unwrap();
+      assert!(lunch.contains(time("12:30")));
+      assert!(!lunch.contains(time("23:00")));
+      assert!(
+         !quiet_hours(Some("09:00"), Some("09:00"))
+            .unwrap()
+            .contains(time("09:00"))
+      );
+
+      // Both times are needed, and must be valid
+      assert_eq!(quiet_hours(None, None), None);
+      assert_eq!(quiet_hours(Some("22:00"), None), None);
+      assert_eq!(quiet_hours(None, Some("07:30")), None);
+      assert_eq!(quiet_hours(Some("25:00"), Some("07:00")), None);
+   }
+}
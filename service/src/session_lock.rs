@@ -0,0 +1,103 @@
+//! Session lock tracking for pausing media while the screen is locked.
+//!
+//! Locking is reported by the screen saver (`org.freedesktop.ScreenSaver`
+//! `ActiveChanged` on the session bus, as KDE Plasma and GNOME send it) and
+//! by logind (`Lock`/`Unlock` of our own session on the system bus), so
+//! either source is enough. Changes are forwarded to ear detection, which
+//! coordinates them with the ear state.
+
+use std::{fs, os::unix::fs::MetadataExt};
+
+use futures::TryStreamExt;
+use log::{debug, warn};
+use zbus::{
+   Connection, MatchRule, MessageStream, message,
+   zvariant::{OwnedObjectPath, OwnedValue},
+};
+
+use crate::{ear_detection, media_control, supervisor::Supervisor};
+
+/// Starts watching the session lock state.
+pub fn spawn_watcher(supervisor: &Supervisor) {
+   supervisor.spawn("screen saver watcher", async {
+      if let Err(e) = watch_screensaver().await {
+         warn!("Stopped watching the screen saver: {e}");
+      }
+   });
+   supervisor.spawn("logind session watcher", async {
+      if let Err(e) = watch_logind().await {
+         debug!("Not watching logind session locks: {e}");
+      }
+   });
+}
+
+async fn watch_screensaver() -> zbus::Result<()> {
+   // The screen saver of the session whose players we control
+   let connection = media_control::session_bus().await?;
+   let rule = MatchRule::builder()
+      .msg_type(message::Type::Signal)
+      .interface("org.freedesktop.ScreenSaver")?
+      .member("ActiveChanged")?
+      .build();
+   let mut stream = MessageStream::for_match_rule(rule, &connection, None).await?;
+   while let Some(message) = stream.try_next().await? {
+      if let Ok(active) = message.body().deserialize::<bool>() {
+         ear_detection::session_lock_changed(active);
+      }
+   }
+   Ok(())
+}
+
+/// Finds our logind session, or the graphical session of our user when
+/// running as a user service outside of any session.
+async fn own_session(connection: &Connection) -> zbus::Result<OwnedObjectPath> {
+   let manager = |method: &'static str, arg: u32| async move {
+      connection
+         .call_method(
+            Some("org.freedesktop.login1"),
+            "/org/freedesktop/login1",
+            Some("org.freedesktop.login1.Manager"),
+            method,
+            &(arg),
+         )
+         .await?
+         .body()
+         .deserialize::<OwnedObjectPath>()
+   };
+   if let Ok(session) = manager("GetSessionByPID", std::process::id()).await {
+      return Ok(session);
+   }
+   let process = fs::metadata("/proc/self").map_err(|e| zbus::Error::Failure(e.to_string()))?;
+   let user = manager("GetUser", process.uid()).await?;
+   let reply = connection
+      .call_method(
+         Some("org.freedesktop.login1"),
+         &user,
+         Some("org.freedesktop.DBus.Properties"),
+         "Get",
+         &("org.freedesktop.login1.User", "Display"),
+      )
+      .await?;
+   let display: OwnedValue = reply.body().deserialize()?;
+   let (_, session): (String, OwnedObjectPath) = display.try_into()?;
+   Ok(session)
+}
+
+async fn watch_logind() -> zbus::Result<()> {
+   let connection = Connection::system().await?;
+   let session = own_session(&connection).await?;
+   let rule = MatchRule::builder()
+      .msg_type(message::Type::Signal)
+      .interface("org.freedesktop.login1.Session")?
+      .path(session.clone())?
+      .build();
+   let mut stream = MessageStream::for_match_rule(rule, &connection, None).await?;
+   while let Some(message) = stream.try_next().await? {
+      match message.header().member().map(|member| member.as_str()) {
+         Some("Lock") => ear_detection::session_lock_changed(true),
+         Some("Unlock") => ear_detection::session_lock_changed(false),
+         _ => {},
+      }
+   }
+   Ok(())
+}
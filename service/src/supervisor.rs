@@ -0,0 +1,111 @@
+//! Ownership of long-running background tasks.
+//!
+//! Subscription tasks (MPRIS playback watching, the control socket, ...) are
+//! spawned through a [`Supervisor`] instead of being detached, so that
+//! [`Supervisor::shutdown`] can abort them and wait until they are gone. A
+//! task is only dropped at an `.await`, so any D-Bus connection or socket it
+//! holds is released by the time shutdown returns.
+
+use std::future::Future;
+
+use log::debug;
+use parking_lot::Mutex;
+use tokio::task::JoinSet;
+
+/// Owns background tasks and tears them down together.
+#[derive(Debug)]
+pub struct Supervisor {
+   /// `None` once shut down
+   tasks: Mutex<Option<JoinSet<()>>>,
+}
+
+impl Default for Supervisor {
+   fn default() -> Self {
+      Self::new()
+   }
+}
+
+impl Supervisor {
+   pub fn new() -> Self {
+      Self {
+         tasks: Mutex::new(Some(JoinSet::new())),
+      }
+   }
+
+   /// Spawns a background task. Tasks spawned after shutdown are dropped.
+   pub fn spawn(&self, name: &'static str, task: impl Future<Output = ()> + Send + 'static) {
+      let mut tasks = self.tasks.lock();
+      let Some(tasks) = tasks.as_mut() else {
+         debug!("Not starting {name}, shutting down");
+         return;
+      };
+      // Reap tasks that already finished so the set doesn't grow
+      while tasks.try_join_next().is_some() {}
+      tasks.spawn(async move {
+         task.await;
+         debug!("Background task {name} finished");
+      });
+   }
+
+   /// Number of tasks that are still running.
+   #[cfg(test)]
+   fn len(&self) -> usize {
+      let mut tasks = self.tasks.lock();
+      let Some(tasks) = tasks.as_mut() else {
+         return 0;
+      };
+      while tasks.try_join_next().is_some() {}
+      tasks.len()
+   }
+
+   /// Aborts all tasks and waits for them to complete.
+   pub async fn shutdown(&self) {
+      let Some(mut tasks) = self.tasks.lock().take() else {
+         return;
+      };
+      debug!("Stopping {} background task(s)", tasks.len());
+      tasks.abort_all();
+      while tasks.join_next().await.is_some() {}
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use std::{future, sync::Arc};
+
+   use super::*;
+
+   #[tokio::test]
+   async fn test_shutdown_drops_tasks() {
+      let supervisor = Supervisor::new();
+      let resource = Arc::new(());
+
+      for _ in 0..3 {
+         let resource = resource.clone();
+         supervisor.spawn("pending", async move {
+            let _held = resource;
+            future::pending::<()>().await;
+         });
+      }
+      supervisor.spawn("finished", async {});
+      tokio::task::yield_now().await;
+      assert_eq!(supervisor.len(), 3);
+      assert_eq!(Arc::strong_count(&resource), 4);
+
+      supervisor.shutdown().await;
+      assert_eq!(supervisor.len(), 0);
+      assert_eq!(Arc::strong_count(&resource), 1);
+
+      // Nothing starts once shut down
+      let late = resource.clone();
+      supervisor.spawn("late", async move {
+         let _held = late;
+         future::pending::<()>().await;
+      });
+      assert_eq!(supervisor.len(), 0);
+      assert_eq!(Arc::strong_count(&resource), 1);
+
+      // Shutting down twice is fine
+      supervisor.shutdown().await;
+   }
+}
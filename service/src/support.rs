@@ -0,0 +1,182 @@
+//! Support bundle export.
+//!
+//! A support bundle is a zip archive with everything needed to make sense of
+//! a bug report: daemon version and health, ear detection statistics, device
+//! state including the negotiated L2CAP parameters, the current
+//! configuration, recent media actions and a short capture of the packets
+//! each device sent. With
+//! redaction enabled, device names and all but the last two octets of
+//! addresses are masked, and packets that carry the device name and serial
+//! numbers are cut down to their header.
+
+use std::{
+   fs::{self, File},
+   io::Write,
+   path::PathBuf,
+   time::{SystemTime, UNIX_EPOCH},
+};
+
+use bluer::Address;
+use log::info;
+use serde_json::json;
+use zip::{CompressionMethod, ZipWriter, write::SimpleFileOptions};
+
+use crate::{
+   airpods::{device::AirPods, protocol::HDR_METADATA},
+   bluetooth::manager::BluetoothManager,
+   config::Config,
+   ear_detection,
+   error::{AirPodsError, Result},
+   media_control,
+};
+
+/// Placeholder for redacted names
+const REDACTED: &str = "<redacted>";
+
+/// Writes a support bundle to `path`, or to the cache directory if `None`.
+///
+/// Returns the path of the written archive.
+pub async fn export(
+   manager: &BluetoothManager,
+   path: Option<PathBuf>,
+   redact: bool,
+) -> Result<PathBuf> {
+   let devices = manager.all_devices().await;
+
+   let mut health = json!({
+      "version": env!("CARGO_PKG_VERSION"),
+      "redacted": redact,
+      "media_control_enabled": media_control::is_enabled(),
+      "devices": devices.iter().map(AirPods::to_json).collect::<Vec<_>>(),
+      "paused_players": media_control::paused_players(),
+      "ear_stats": ear_detection::ear_stats(),
+      "self_test": media_control::self_test().await,
+   });
+   let mut actions = json!(media_control::recent_actions());
+   let mut config = Config::load()?;
+   if redact {
+      redact_json(&mut health);
+      redact_json(&mut actions);
+      for device in &mut config.known_devices {
+         device.address = redact_address(&device.address);
+         device.name = REDACTED.to_string();
+      }
+   }
+   let packets = capture_text(&devices, redact);
+
+   let path = match path {
+      Some(path) => path,
+      None => default_path()?,
+   };
+   if let Some(parent) = path.parent() {
+      fs::create_dir_all(parent)?;
+   }
+
+   let mut zip = ZipWriter::new(File::create(&path)?);
+   let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+   for (name, contents) in [
+      ("health.json", format!("{health:#}")),
+      ("recent_actions.json", format!("{actions:#}")),
+      ("config.toml", toml::to_string_pretty(&config)?),
+      ("packets.txt", packets),
+   ] {
+      zip.start_file(name, options)?;
+      zip.write_all(contents.as_bytes())?;
+   }
+   zip.finish()?;
+
+   info!("Wrote support bundle to {}", path.display());
+   Ok(path)
+}
+
+fn default_path() -> Result<PathBuf> {
+   let timestamp = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or_default()
+      .as_secs();
+   Ok(dirs::cache_dir()
+      .ok_or(AirPodsError::ConfigDirNotFound)?
+      .join("kairpods")
+      .join(format!("support-{timestamp}.zip")))
+}
+
+/// Formats the packet capture of each device, one packet per line.
+fn capture_text(devices: &[AirPods], redact: bool) -> String {
+   let mut text = String::new();
+   for device in devices {
+      let address = if redact {
+         redact_address(device.address_str())
+      } else {
+         device.address_str().to_string()
+      };
+      for (age, packet) in device.recent_packets() {
+         // Metadata packets carry the device name and serial numbers
+         let data = if redact && packet.starts_with(HDR_METADATA) {
+            format!("{}..", hex::encode(HDR_METADATA))
+         } else {
+            hex::encode(&packet)
+         };
+         text.push_str(&format!("{address} -{:.1}s {data}\n", age.as_secs_f64()));
+      }
+   }
+   text
+}
+
+/// Masks all but the last two octets of a Bluetooth address.
+fn redact_address(address: &str) -> String {
+   match address.parse::<Address>() {
+      Ok(addr) => format!("XX:XX:XX:XX:{:02X}:{:02X}", addr.0[4], addr.0[5]),
+      Err(_) => REDACTED.to_string(),
+   }
+}
+
+/// Masks addresses and device names anywhere in a JSON document.
+fn redact_json(value: &mut serde_json::Value) {
+   match value {
+      serde_json::Value::Object(map) => {
+         for (key, value) in map.iter_mut() {
+            match (key.as_str(), &*value) {
+               ("name", serde_json::Value::String(_)) => *value = json!(REDACTED),
+               ("address", serde_json::Value::String(address)) => {
+                  *value = json!(redact_address(address));
+               },
+               _ => redact_json(value),
+            }
+         }
+      },
+      serde_json::Value::Array(items) => items.iter_mut().for_each(redact_json),
+      _ => {},
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   #[test]
+   fn test_redaction() {
+      assert_eq!(redact_address("AA:BB:CC:DD:EE:FF"), "XX:XX:XX:XX:EE:FF");
+      assert_eq!(redact_address("not an address"), REDACTED);
+
+      let mut value = json!({
+         "devices": [{
+            "address": "AA:BB:CC:DD:EE:FF",
+            "name": "Jane's AirPods Pro",
+            "model": "AirPods Pro",
+         }],
+         "players": ["org.mpris.MediaPlayer2.spotify"],
+      });
+      redact_json(&mut value);
+      assert_eq!(
+         value,
+         json!({
+            "devices": [{
+               "address": "XX:XX:XX:XX:EE:FF",
+               "name": REDACTED,
+               "model": "AirPods Pro",
+            }],
+            "players": ["org.mpris.MediaPlayer2.spotify"],
+         })
+      );
+   }
+}
@@ -0,0 +1,493 @@
+//! Minimal system tray icon for desktops without the Plasma applet.
+//!
+//! The icon is a `StatusNotifierItem` registered with the
+//! `StatusNotifierWatcher` on the session bus. Its tooltip shows the status
+//! summary of each connected device, and its menu, served through
+//! `com.canonical.dbusmenu`, switches their noise control mode. The item is
+//! refreshed from the event processor on battery, noise control and
+//! connection events.
+
+use std::collections::HashMap;
+
+use bluer::Address;
+use log::{debug, info, warn};
+use tokio::sync::mpsc;
+use zbus::{
+   Connection, connection, fdo, interface,
+   object_server::SignalEmitter,
+   zvariant::{ObjectPath, OwnedObjectPath, OwnedValue, Str, Structure},
+};
+
+use crate::{
+   airpods::{device::AirPods, protocol::NoiseControlMode},
+   battery_provider,
+   bluetooth::manager::BluetoothManager,
+   control::{self, Command},
+   error::Result,
+   supervisor::Supervisor,
+};
+
+/// Object path of the item
+const ITEM_PATH: &str = "/StatusNotifierItem";
+/// Object path of the item's menu
+const MENU_PATH: &str = "/MenuBar";
+/// Icon from the desktop icon theme
+const ICON: &str = "audio-headphones";
+/// Overlay shown while a device runs low
+const LOW_BATTERY_ICON: &str = "battery-caution";
+/// Combined battery level at or below which a device runs low
+const LOW_BATTERY_LEVEL: u8 = 20;
+
+/// Handle to the background task serving the tray icon.
+#[derive(Clone)]
+pub struct Tray {
+   tx: mpsc::UnboundedSender<()>,
+}
+
+impl Tray {
+   /// Spawns the tray task.
+   pub fn spawn(supervisor: &Supervisor, manager: BluetoothManager) -> Self {
+      let (tx, rx) = mpsc::unbounded_channel();
+      supervisor.spawn("tray icon", async move {
+         if let Err(e) = run(manager, rx).await {
+            warn!("Tray icon stopped: {e}");
+         }
+      });
+      Self { tx }
+   }
+
+   /// Updates the icon, tooltip and menu from the current device state.
+   pub fn refresh(&self) {
+      let _ = self.tx.send(());
+   }
+}
+
+/// What the tray shows about a connected device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Entry {
+   address: Address,
+   name: String,
+   summary: String,
+   noise_mode: Option<NoiseControlMode>,
+   adaptive: bool,
+   low_battery: bool,
+}
+
+impl Entry {
+   fn new(device: &AirPods) -> Self {
+      Self {
+         address: device.address(),
+         name: device.name().to_string(),
+         summary: device.status_summary(),
+         noise_mode: device.noise_mode(),
+         adaptive: device.model().has_adaptive_anc(),
+         low_battery: device
+            .battery_info()
+            .and_then(|battery| battery_provider::combined_level(&battery))
+            .is_some_and(|level| level <= LOW_BATTERY_LEVEL),
+      }
+   }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum MenuItem {
+   /// Device name heading its modes
+   Header(String),
+   Separator,
+   Mode {
+      address: Address,
+      mode: NoiseControlMode,
+      selected: bool,
+   },
+}
+
+/// Lays out the menu, listing the noise control modes of each device that reports one.
+fn menu_items(entries: &[Entry]) -> Vec<MenuItem> {
+   let mut items = Vec::new();
+   for entry in entries {
+      let Some(current) = entry.noise_mode else {
+         continue;
+      };
+      if !items.is_empty() {
+         items.push(MenuItem::Separator);
+      }
+      items.push(MenuItem::Header(entry.name.clone()));
+      let modes = [
+         NoiseControlMode::Off,
+         NoiseControlMode::Active,
+         NoiseControlMode::Transparency,
+      ]
+      .into_iter()
+      .chain(entry.adaptive.then_some(NoiseControlMode::Adaptive));
+      items.extend(modes.map(|mode| MenuItem::Mode {
+         address: entry.address,
+         mode,
+         selected: mode == current,
+      }));
+   }
+   items
+}
+
+fn tooltip(entries: &[Entry]) -> String {
+   if entries.is_empty() {
+      return "No AirPods connected".to_string();
+   }
+   entries
+      .iter()
+      .map(|entry| entry.summary.as_str())
+      .collect::<Vec<_>>()
+      .join("\n")
+}
+
+/// Tooltip of the item: icon name, icon pixmaps, title and text
+type ToolTip = (String, Vec<(i32, i32, Vec<u8>)>, String, String);
+
+#[derive(Default)]
+struct Item {
+   tooltip: String,
+   active: bool,
+   low_battery: bool,
+}
+
+#[interface(name = "org.kde.StatusNotifierItem")]
+impl Item {
+   #[zbus(property)]
+   fn category(&self) -> &str {
+      "Hardware"
+   }
+
+   #[zbus(property)]
+   fn id(&self) -> &str {
+      "kairpods"
+   }
+
+   #[zbus(property)]
+   fn title(&self) -> &str {
+      "kAirPods"
+   }
+
+   #[zbus(property)]
+   fn status(&self) -> &str {
+      if self.active { "Active" } else { "Passive" }
+   }
+
+   #[zbus(property)]
+   fn icon_name(&self) -> &str {
+      ICON
+   }
+
+   #[zbus(property)]
+   fn overlay_icon_name(&self) -> &str {
+      if self.low_battery {
+         LOW_BATTERY_ICON
+      } else {
+         ""
+      }
+   }
+
+   #[zbus(property)]
+   fn tool_tip(&self) -> ToolTip {
+      (
+         ICON.to_string(),
+         Vec::new(),
+         "kAirPods".to_string(),
+         self.tooltip.clone(),
+      )
+   }
+
+   #[zbus(property)]
+   fn item_is_menu(&self) -> bool {
+      true
+   }
+
+   #[zbus(property)]
+   fn menu(&self) -> OwnedObjectPath {
+      ObjectPath::from_static_str_unchecked(MENU_PATH).into()
+   }
+
+   fn activate(&self, _x: i32, _y: i32) {}
+
+   fn secondary_activate(&self, _x: i32, _y: i32) {}
+
+   fn context_menu(&self, _x: i32, _y: i32) {}
+
+   fn scroll(&self, _delta: i32, _orientation: String) {}
+
+   #[zbus(signal)]
+   async fn new_tool_tip(emitter: &SignalEmitter<'_>) -> zbus::Result<()>;
+
+   #[zbus(signal)]
+   async fn new_overlay_icon(emitter: &SignalEmitter<'_>) -> zbus::Result<()>;
+
+   #[zbus(signal)]
+   async fn new_status(emitter: &SignalEmitter<'_>, status: &str) -> zbus::Result<()>;
+}
+
+struct Menu {
+   manager: BluetoothManager,
+   revision: u32,
+   items: Vec<MenuItem>,
+}
+
+/// Layout of a menu entry: its id, properties and children.
+type Layout = (i32, HashMap<String, OwnedValue>, Vec<OwnedValue>);
+
+impl Menu {
+   /// Gets an item by its id, which is its position counting from 1.
+   fn item(&self, id: i32) -> Option<&MenuItem> {
+      self.items.get(usize::try_from(id).ok()?.checked_sub(1)?)
+   }
+
+   fn properties(&self, id: i32) -> HashMap<String, OwnedValue> {
+      let mut properties = HashMap::new();
+      let mut set = |name: &str, value: OwnedValue| {
+         properties.insert(name.to_string(), value);
+      };
+      match self.item(id) {
+         None if id == 0 => set("children-display", Str::from("submenu").into()),
+         None => {},
+         Some(MenuItem::Header(name)) => {
+            set("label", Str::from(name.clone()).into());
+            set("enabled", false.into());
+         },
+         Some(MenuItem::Separator) => set("type", Str::from("separator").into()),
+         Some(MenuItem::Mode { mode, selected, .. }) => {
+            set("label", Str::from(mode.label()).into());
+            set("toggle-type", Str::from("radio").into());
+            set("toggle-state", i32::from(*selected).into());
+         },
+      }
+      properties
+   }
+
+   fn layout(&self, id: i32) -> Layout {
+      let children = if id == 0 {
+         (1..=self.items.len() as i32)
+            .filter_map(|child| {
+               let (id, properties, children) = self.layout(child);
+               OwnedValue::try_from(Structure::from((id, properties, children))).ok()
+            })
+            .collect()
+      } else {
+         Vec::new()
+      };
+      (id, self.properties(id), children)
+   }
+
+   fn clicked(&self, id: i32) {
+      let Some(&MenuItem::Mode { address, mode, .. }) = self.item(id) else {
+         return;
+      };
+      let manager = self.manager.clone();
+      tokio::spawn(async move {
+         if let Err(e) = control::execute(&manager, Command::SetNoiseMode(address, mode)).await {
+            warn!("Failed to set noise mode from the tray: {e}");
+         }
+      });
+   }
+}
+
+#[interface(name = "com.canonical.dbusmenu")]
+impl Menu {
+   fn get_layout(
+      &self,
+      parent_id: i32,
+      _recursion_depth: i32,
+      _property_names: Vec<String>,
+   ) -> (u32, Layout) {
+      (self.revision, self.layout(parent_id))
+   }
+
+   fn get_group_properties(
+      &self,
+      ids: Vec<i32>,
+      _property_names: Vec<String>,
+   ) -> Vec<(i32, HashMap<String, OwnedValue>)> {
+      ids.into_iter()
+         .map(|id| (id, self.properties(id)))
+         .collect()
+   }
+
+   fn get_property(&self, id: i32, name: String) -> fdo::Result<OwnedValue> {
+      self
+         .properties(id)
+         .remove(&name)
+         .ok_or_else(|| fdo::Error::InvalidArgs(format!("No property {name} on item {id}")))
+   }
+
+   fn event(&self, id: i32, event_id: String, _data: OwnedValue, _timestamp: u32) {
+      if event_id == "clicked" {
+         self.clicked(id);
+      }
+   }
+
+   fn event_group(&self, events: Vec<(i32, String, OwnedValue, u32)>) -> Vec<i32> {
+      let mut unknown = Vec::new();
+      for (id, event_id, _, _) in events {
+         if self.item(id).is_none() {
+            unknown.push(id);
+         } else if event_id == "clicked" {
+            self.clicked(id);
+         }
+      }
+      unknown
+   }
+
+   fn about_to_show(&self, _id: i32) -> bool {
+      false
+   }
+
+   fn about_to_show_group(&self, _ids: Vec<i32>) -> (Vec<i32>, Vec<i32>) {
+      (Vec::new(), Vec::new())
+   }
+
+   #[zbus(property)]
+   fn version(&self) -> u32 {
+      3
+   }
+
+   #[zbus(property)]
+   fn text_direction(&self) -> &str {
+      "ltr"
+   }
+
+   #[zbus(property)]
+   fn status(&self) -> &str {
+      "normal"
+   }
+
+   #[zbus(property)]
+   fn icon_theme_path(&self) -> Vec<String> {
+      Vec::new()
+   }
+
+   #[zbus(signal)]
+   async fn layout_updated(
+      emitter: &SignalEmitter<'_>,
+      revision: u32,
+      parent: i32,
+   ) -> zbus::Result<()>;
+}
+
+async fn register(connection: &Connection, service: &str) -> zbus::Result<()> {
+   connection
+      .call_method(
+         Some("org.kde.StatusNotifierWatcher"),
+         "/StatusNotifierWatcher",
+         Some("org.kde.StatusNotifierWatcher"),
+         "RegisterStatusNotifierItem",
+         &(service),
+      )
+      .await?;
+   Ok(())
+}
+
+async fn refresh(connection: &Connection, manager: &BluetoothManager) -> Result<()> {
+   let entries: Vec<_> = manager
+      .all_devices()
+      .await
+      .iter()
+      .filter(|device| device.is_connected())
+      .map(Entry::new)
+      .collect();
+   let object_server = connection.object_server();
+
+   let item = object_server.interface::<_, Item>(ITEM_PATH).await?;
+   let status = {
+      let mut item = item.get_mut().await;
+      item.tooltip = tooltip(&entries);
+      item.active = !entries.is_empty();
+      item.low_battery = entries.iter().any(|entry| entry.low_battery);
+      item.status().to_string()
+   };
+   Item::new_tool_tip(item.signal_emitter()).await?;
+   Item::new_overlay_icon(item.signal_emitter()).await?;
+   Item::new_status(item.signal_emitter(), &status).await?;
+
+   let menu = object_server.interface::<_, Menu>(MENU_PATH).await?;
+   let items = menu_items(&entries);
+   let revision = {
+      let mut menu = menu.get_mut().await;
+      if menu.items == items {
+         return Ok(());
+      }
+      menu.items = items;
+      menu.revision += 1;
+      menu.revision
+   };
+   Menu::layout_updated(menu.signal_emitter(), revision, 0).await?;
+   Ok(())
+}
+
+async fn run(manager: BluetoothManager, mut rx: mpsc::UnboundedReceiver<()>) -> Result<()> {
+   let service = format!("org.kde.StatusNotifierItem-{}-1", std::process::id());
+   let menu = Menu {
+      manager: manager.clone(),
+      revision: 0,
+      items: Vec::new(),
+   };
+   let connection = connection::Builder::session()?
+      .name(service.as_str())?
+      .serve_at(ITEM_PATH, Item::default())?
+      .serve_at(MENU_PATH, menu)?
+      .build()
+      .await?;
+   register(&connection, &service).await?;
+   info!("Tray icon registered as {service}");
+
+   loop {
+      if let Err(e) = refresh(&connection, &manager).await {
+         debug!("Failed to refresh the tray icon: {e}");
+      }
+      if rx.recv().await.is_none() {
+         return Ok(());
+      }
+      // Events come in bursts, one refresh covers them all
+      while rx.try_recv().is_ok() {}
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   #[test]
+   fn test_menu_lists_modes_of_devices_with_noise_control() {
+      let entry = |name: &str, noise_mode, adaptive| Entry {
+         address: Address::any(),
+         name: name.to_string(),
+         summary: String::new(),
+         noise_mode,
+         adaptive,
+         low_battery: false,
+      };
+      let entries = [
+         entry("AirPods", None, false),
+         entry("AirPods Pro", Some(NoiseControlMode::Transparency), true),
+         entry("AirPods Max", Some(NoiseControlMode::Active), false),
+      ];
+      let items = menu_items(&entries);
+      let selected = |items: &[MenuItem]| {
+         items
+            .iter()
+            .filter_map(|item| match item {
+               MenuItem::Mode { mode, selected, .. } => Some((*mode, *selected)),
+               _ => None,
+            })
+            .collect::<Vec<_>>()
+      };
+      assert_eq!(items[0], MenuItem::Header("AirPods Pro".to_string()));
+      assert_eq!(
+         selected(&items[1..5]),
+         [
+            (NoiseControlMode::Off, false),
+            (NoiseControlMode::Active, false),
+            (NoiseControlMode::Transparency, true),
+            (NoiseControlMode::Adaptive, false),
+         ]
+      );
+      assert_eq!(items[5], MenuItem::Separator);
+      assert_eq!(items[6], MenuItem::Header("AirPods Max".to_string()));
+      assert_eq!(selected(&items[7..]).len(), 3);
+   }
+}